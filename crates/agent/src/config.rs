@@ -1,7 +1,9 @@
 //! Agent configuration.
 
 use clap::Parser;
-use ttcore::model::Storage;
+use std::collections::BTreeMap;
+use ttcore::log::{DEFAULT_FILTER, LogFormat};
+use ttcore::model::{Storage, parse_labels};
 
 /// TTstack host agent — manages VMs and containers on this host.
 #[derive(Parser, Debug)]
@@ -46,6 +48,40 @@ pub struct Config {
     /// `Authorization: Bearer <key>`. Can also be provided via TT_API_KEY env var.
     #[arg(long, env = "TT_API_KEY")]
     pub api_key: Option<String>,
+
+    /// Host label as key=value (repeatable or comma-separated), e.g.
+    /// `--label disk=nvme,rack=b`; VMs can require it with a node selector.
+    #[arg(long, value_delimiter = ',')]
+    pub label: Vec<String>,
+
+    /// Controller to register with and send heartbeats to, e.g.
+    /// `10.0.0.1:9200`; without it the host is added with `tt host add`.
+    #[arg(long)]
+    pub controller: Option<String>,
+
+    /// Key to send heartbeats to `--controller` with, as printed by
+    /// `tt host key <host-id>`. Can also be provided via TT_CONTROLLER_KEY
+    /// env var.
+    #[arg(long, env = "TT_CONTROLLER_KEY")]
+    pub controller_key: Option<String>,
+
+    /// Address the controller should reach this agent at, e.g. the
+    /// public side of a port forward [default: --listen].
+    #[arg(long)]
+    pub advertise: Option<String>,
+
+    /// Seconds between heartbeats to the controller.
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    pub heartbeat_interval: u64,
+
+    /// Log output: `pretty` (readable lines) or `json`.
+    #[arg(long, env = "TT_LOG_FORMAT", default_value_t = LogFormat::Pretty)]
+    pub log_format: LogFormat,
+
+    /// Log filter in `RUST_LOG` syntax, e.g. `info` or `warn,tt_agent=debug`;
+    /// it can be changed while running through `/api/log-level`.
+    #[arg(long, env = "TT_LOG", default_value = DEFAULT_FILTER)]
+    pub log_level: String,
 }
 
 impl Config {
@@ -53,6 +89,11 @@ impl Config {
         self.storage.parse().unwrap_or(Storage::File)
     }
 
+    /// Labels given with `--label`.
+    pub fn labels(&self) -> Result<BTreeMap<String, String>, String> {
+        parse_labels(&self.label)
+    }
+
     /// Auto-detect CPU count if set to 0.
     pub fn effective_cpu(&self) -> u32 {
         if self.cpu_total == 0 {
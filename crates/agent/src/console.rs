@@ -0,0 +1,198 @@
+//! Interactive console and VNC endpoints.
+//!
+//! Both are WebSockets relaying raw bytes: binary frames carry terminal
+//! (or RFB) data in both directions. Text frames from the client are
+//! treated as input as well, so browsers can send keystrokes as strings.
+//! The console is opened before the upgrade, so failures are reported
+//! as ordinary JSON errors.
+
+use crate::handler::{AppState, lock_rt};
+use axum::Json;
+use axum::body::Bytes;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use futures_util::stream::{self, BoxStream};
+use futures_util::{SinkExt, StreamExt};
+use std::io::SeekFrom;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::process::Child;
+use tokio_util::io::ReaderStream;
+use ttcore::api::ApiRespEmpty;
+use ttcore::engine::{self, Console};
+use ttcore::model::{Vm, VmState};
+
+/// Console output replayed on attach for engines that log it to a file.
+const SCROLLBACK: u64 = 16 * 1024;
+/// How often a console log is polled for new output.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(100);
+
+type Output = BoxStream<'static, std::io::Result<Bytes>>;
+type Input = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// GET /api/vms/:id/console — attach to the serial console (WebSocket).
+///
+/// Containers and jails get a shell instead of a serial port.
+pub async fn console(
+    State(rt): State<AppState>,
+    Path(id): Path<String>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let vm = match running_vm(&rt, &id) {
+        Ok(vm) => vm,
+        Err((code, msg)) => return fail(code, msg),
+    };
+    let console = match engine::create_engine(vm.engine).console(&vm) {
+        Ok(c) => c,
+        Err(e) => return fail(StatusCode::CONFLICT, e),
+    };
+    let (output, input, child) = match open(console).await {
+        Ok(io) => io,
+        Err(e) => {
+            return fail(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("failed to open console of VM {id}: {e}"),
+            );
+        }
+    };
+
+    ws.on_upgrade(move |socket| async move {
+        relay(socket, output, input).await;
+        // Dropping the child kills it (`docker attach`, `jexec`, ...)
+        drop(child);
+    })
+}
+
+/// GET /api/vms/:id/vnc — relay the VNC display (WebSocket, RFB bytes).
+pub async fn vnc(
+    State(rt): State<AppState>,
+    Path(id): Path<String>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let vm = match running_vm(&rt, &id) {
+        Ok(vm) => vm,
+        Err((code, msg)) => return fail(code, msg),
+    };
+    let sock = match engine::create_engine(vm.engine).vnc(&vm) {
+        Ok(s) => s,
+        Err(e) => return fail(StatusCode::CONFLICT, e),
+    };
+    let (r, w) = match tokio::net::UnixStream::connect(&sock).await {
+        Ok(s) => s.into_split(),
+        Err(e) => {
+            return fail(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("failed to open VNC display of VM {id}: {e}"),
+            );
+        }
+    };
+
+    // noVNC asks for the "binary" subprotocol
+    ws.protocols(["binary"])
+        .on_upgrade(move |socket| relay(socket, ReaderStream::new(r).boxed(), Box::new(w)))
+}
+
+// ── Helpers ─────────────────────────────────────────────────────────
+
+/// Look up a VM that is currently running.
+fn running_vm(rt: &AppState, id: &str) -> std::result::Result<Vm, (StatusCode, String)> {
+    match lock_rt(rt).get_vm(id) {
+        Some(vm) if vm.state == VmState::Running => Ok(vm),
+        Some(vm) => Err((
+            StatusCode::CONFLICT,
+            format!("VM {id} is {}, not running", vm.state),
+        )),
+        None => Err((StatusCode::NOT_FOUND, format!("VM not found: {id}"))),
+    }
+}
+
+/// Open both directions of a console.
+async fn open(console: Console) -> std::io::Result<(Output, Input, Option<Child>)> {
+    match console {
+        Console::Socket(path) => {
+            let (r, w) = tokio::net::UnixStream::connect(path).await?.into_split();
+            Ok((ReaderStream::new(r).boxed(), Box::new(w), None))
+        }
+        Console::Fifo { input, output } => {
+            let w = tokio::fs::OpenOptions::new()
+                .write(true)
+                .open(input)
+                .await?;
+            let mut log = tokio::fs::File::open(output).await?;
+            let len = log.metadata().await?.len();
+            log.seek(SeekFrom::Start(len.saturating_sub(SCROLLBACK)))
+                .await?;
+            Ok((follow(log), Box::new(w), None))
+        }
+        Console::Command(cmd) => {
+            let mut child = tokio::process::Command::from(cmd)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()?;
+            let (Some(stdin), Some(stdout), Some(stderr)) =
+                (child.stdin.take(), child.stdout.take(), child.stderr.take())
+            else {
+                return Err(std::io::Error::other("console process has no stdio"));
+            };
+            let output = stream::select(ReaderStream::new(stdout), ReaderStream::new(stderr));
+            Ok((output.boxed(), Box::new(stdin), Some(child)))
+        }
+    }
+}
+
+/// Stream a file's contents, waiting for more at EOF like `tail -f`.
+fn follow(file: tokio::fs::File) -> Output {
+    stream::unfold(file, |mut file| async move {
+        let mut buf = vec![0u8; 4096];
+        loop {
+            match file.read(&mut buf).await {
+                Ok(0) => tokio::time::sleep(FOLLOW_INTERVAL).await,
+                Ok(n) => {
+                    buf.truncate(n);
+                    return Some((Ok(Bytes::from(buf)), file));
+                }
+                Err(e) => return Some((Err(e), file)),
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Shuttle bytes between the WebSocket and the console until either
+/// side closes.
+async fn relay(socket: WebSocket, mut output: Output, mut input: Input) {
+    let (mut tx, mut rx) = socket.split();
+    loop {
+        tokio::select! {
+            chunk = output.next() => match chunk {
+                Some(Ok(data)) => {
+                    if tx.send(Message::Binary(data)).await.is_err() {
+                        break;
+                    }
+                }
+                _ => break,
+            },
+            msg = rx.next() => {
+                let data = match msg {
+                    Some(Ok(Message::Binary(data))) => data,
+                    Some(Ok(Message::Text(text))) => Bytes::copy_from_slice(text.as_bytes()),
+                    Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                    _ => break,
+                };
+                if input.write_all(&data).await.is_err() || input.flush().await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    let _ = tx.send(Message::Close(None)).await;
+}
+
+fn fail(code: StatusCode, msg: impl ToString) -> Response {
+    (code, Json(ApiRespEmpty::err(msg.to_string()))).into_response()
+}
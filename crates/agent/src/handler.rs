@@ -1,22 +1,26 @@
 //! HTTP API handlers for the host agent.
 
+use crate::metrics;
 use crate::runtime::Runtime;
+use crate::trace;
 use axum::Json;
-use axum::extract::{Path, State};
+use axum::extract::{Path, Query, State};
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use std::sync::{Arc, Mutex, MutexGuard};
+use tracing::{info, warn};
 use ttcore::api::*;
-use ttcore::model::Vm;
+use ttcore::engine::VmEngine;
+use ttcore::model::{Vm, VmState};
 
 /// Shared application state.
 pub type AppState = Arc<Mutex<Runtime>>;
 
 /// Lock the runtime mutex, recovering from poisoning if a prior
 /// handler panicked while holding the lock.
-fn lock_rt(rt: &AppState) -> MutexGuard<'_, Runtime> {
+pub(crate) fn lock_rt(rt: &AppState) -> MutexGuard<'_, Runtime> {
     rt.lock().unwrap_or_else(|e| {
-        eprintln!("[agent] WARN: runtime mutex was poisoned, recovering");
+        warn!("runtime mutex was poisoned, recovering");
         e.into_inner()
     })
 }
@@ -40,14 +44,20 @@ pub async fn create_vm(
 ) -> impl IntoResponse {
     let mut rt = lock_rt(&rt);
     match rt.create_vm(&req) {
-        Ok(vm) => (
-            StatusCode::CREATED,
-            Json(ApiResp::success(CreateVmResp { vm })),
-        ),
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(ApiResp::<CreateVmResp>::err(e.to_string())),
-        ),
+        Ok(vm) => {
+            info!("created VM {} ({}, {})", vm.id, vm.engine, vm.image);
+            (
+                StatusCode::CREATED,
+                Json(ApiResp::success(CreateVmResp { vm })),
+            )
+        }
+        Err(e) => {
+            warn!("failed to create VM {}: {e}", req.vm_id);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiResp::<CreateVmResp>::err(e.to_string())),
+            )
+        }
     }
 }
 
@@ -81,10 +91,37 @@ pub async fn destroy_vm(State(rt): State<AppState>, Path(id): Path<String>) -> i
     }
 }
 
-/// POST /api/vms/:id/stop — stop a VM.
+/// POST /api/vms/:id/stop — power a VM off, releasing its CPU and memory.
+///
+/// The runtime lock is not held while the guest shuts down, which may
+/// take up to a minute.
 pub async fn stop_vm(State(rt): State<AppState>, Path(id): Path<String>) -> impl IntoResponse {
+    let vm = match lock_rt(&rt).get_vm(&id) {
+        Some(vm) => vm,
+        None => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(ApiRespEmpty::err(format!("VM not found: {id}"))),
+            );
+        }
+    };
+    if matches!(vm.state, VmState::Running | VmState::Paused) {
+        let result = trace::blocking(move || metrics::engine(vm.engine).stop(&vm)).await;
+        let err = match result {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(e.to_string()),
+            Err(e) => Some(e.to_string()),
+        };
+        if let Some(e) = err {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiRespEmpty::err(e)),
+            );
+        }
+    }
+
     let mut rt = lock_rt(&rt);
-    match rt.stop_vm(&id) {
+    match rt.mark_stopped(&id) {
         Ok(()) => (StatusCode::OK, Json(ApiRespEmpty::ok())),
         Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -93,7 +130,8 @@ pub async fn stop_vm(State(rt): State<AppState>, Path(id): Path<String>) -> impl
     }
 }
 
-/// POST /api/vms/:id/start — start a stopped VM.
+/// POST /api/vms/:id/start — start a stopped VM, or resume a suspended
+/// or hibernated one.
 pub async fn start_vm(State(rt): State<AppState>, Path(id): Path<String>) -> impl IntoResponse {
     let mut rt = lock_rt(&rt);
     match rt.start_vm(&id) {
@@ -104,3 +142,228 @@ pub async fn start_vm(State(rt): State<AppState>, Path(id): Path<String>) -> imp
         ),
     }
 }
+
+/// POST /api/vms/:id/suspend — pause a running VM in place.
+pub async fn suspend_vm(State(rt): State<AppState>, Path(id): Path<String>) -> impl IntoResponse {
+    let mut rt = lock_rt(&rt);
+    match rt.suspend_vm(&id) {
+        Ok(()) => (StatusCode::OK, Json(ApiRespEmpty::ok())),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiRespEmpty::err(e.to_string())),
+        ),
+    }
+}
+
+/// POST /api/vms/:id/resume — resume a suspended or hibernated VM.
+pub async fn resume_vm(State(rt): State<AppState>, Path(id): Path<String>) -> impl IntoResponse {
+    let mut rt = lock_rt(&rt);
+    match rt.resume_vm(&id) {
+        Ok(()) => (StatusCode::OK, Json(ApiRespEmpty::ok())),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiRespEmpty::err(e.to_string())),
+        ),
+    }
+}
+
+/// POST /api/vms/:id/hibernate — save a VM's memory to disk and free
+/// its CPU and memory; `resume` brings it back.
+pub async fn hibernate_vm(State(rt): State<AppState>, Path(id): Path<String>) -> impl IntoResponse {
+    let (vm, state_dir) = {
+        let rt = lock_rt(&rt);
+        match rt.get_vm(&id) {
+            Some(vm) if matches!(vm.state, VmState::Running | VmState::Paused) => {
+                (vm, rt.state_dir(&id))
+            }
+            Some(vm) if vm.state == VmState::Hibernated => {
+                return (StatusCode::OK, Json(ApiRespEmpty::ok()));
+            }
+            Some(vm) => {
+                return (
+                    StatusCode::CONFLICT,
+                    Json(ApiRespEmpty::err(format!(
+                        "VM {id} is not running ({})",
+                        vm.state
+                    ))),
+                );
+            }
+            None => {
+                return (
+                    StatusCode::NOT_FOUND,
+                    Json(ApiRespEmpty::err(format!("VM not found: {id}"))),
+                );
+            }
+        }
+    };
+
+    // Writing out the memory takes a while; the runtime lock is not held
+    let result = trace::blocking(move || {
+        std::fs::create_dir_all(&state_dir).map_err(|e| ruc::eg!(e))?;
+        let saved = metrics::engine(vm.engine).hibernate(&vm, &state_dir);
+        if saved.is_err() {
+            let _ = std::fs::remove_dir_all(&state_dir);
+        }
+        saved
+    })
+    .await;
+    let err = match result {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(e) => Some(e.to_string()),
+    };
+    if let Some(e) = err {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiRespEmpty::err(e)),
+        );
+    }
+
+    let mut rt = lock_rt(&rt);
+    match rt.mark_hibernated(&id) {
+        Ok(()) => (StatusCode::OK, Json(ApiRespEmpty::ok())),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiRespEmpty::err(e.to_string())),
+        ),
+    }
+}
+
+/// POST /api/vms/:id/reset — hard-reset a running VM.
+pub async fn reset_vm(State(rt): State<AppState>, Path(id): Path<String>) -> impl IntoResponse {
+    power_op(&rt, &id, |eng, vm| eng.reset(vm)).await
+}
+
+/// POST /api/vms/:id/reboot — reboot a running VM, letting its guest
+/// shut down first.
+pub async fn reboot_vm(State(rt): State<AppState>, Path(id): Path<String>) -> impl IntoResponse {
+    power_op(&rt, &id, |eng, vm| eng.reboot(vm)).await
+}
+
+/// Run an engine operation on a running VM.
+///
+/// The runtime lock is not held meanwhile, since a guest may take a
+/// while to shut down; the VM stays running throughout.
+async fn power_op(
+    rt: &AppState,
+    id: &str,
+    op: fn(&dyn VmEngine, &Vm) -> ruc::Result<()>,
+) -> (StatusCode, Json<ApiRespEmpty>) {
+    let vm = match lock_rt(rt).get_vm(id) {
+        Some(vm) if vm.state == VmState::Running => vm,
+        Some(vm) => {
+            return (
+                StatusCode::CONFLICT,
+                Json(ApiRespEmpty::err(format!(
+                    "VM {id} is not running ({})",
+                    vm.state
+                ))),
+            );
+        }
+        None => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(ApiRespEmpty::err(format!("VM not found: {id}"))),
+            );
+        }
+    };
+    let result = trace::blocking(move || op(metrics::engine(vm.engine).as_ref(), &vm)).await;
+    match result {
+        Ok(Ok(())) => (StatusCode::OK, Json(ApiRespEmpty::ok())),
+        Ok(Err(e)) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiRespEmpty::err(e.to_string())),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiRespEmpty::err(e.to_string())),
+        ),
+    }
+}
+
+/// POST /api/vms/:id/snapshots — take a disk snapshot.
+pub async fn create_snapshot(
+    State(rt): State<AppState>,
+    Path(id): Path<String>,
+    Json(req): Json<SnapshotReq>,
+) -> impl IntoResponse {
+    let mut rt = lock_rt(&rt);
+    match rt.snapshot_vm(&id, &req.name) {
+        Ok(()) => (StatusCode::CREATED, Json(ApiRespEmpty::ok())),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiRespEmpty::err(e.to_string())),
+        ),
+    }
+}
+
+/// GET /api/vms/:id/snapshots — list disk snapshots.
+pub async fn list_snapshots(
+    State(rt): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let rt = lock_rt(&rt);
+    match rt.list_vm_snapshots(&id) {
+        Ok(snaps) => (StatusCode::OK, Json(ApiResp::success(snaps))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResp::<Vec<String>>::err(e.to_string())),
+        ),
+    }
+}
+
+/// POST /api/vms/:id/snapshots/:name/rollback — restore a snapshot.
+pub async fn rollback_snapshot(
+    State(rt): State<AppState>,
+    Path((id, name)): Path<(String, String)>,
+    Query(q): Query<RollbackQuery>,
+) -> impl IntoResponse {
+    let mut rt = lock_rt(&rt);
+    match rt.rollback_vm(&id, &name, q.force) {
+        Ok(()) => (StatusCode::OK, Json(ApiRespEmpty::ok())),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiRespEmpty::err(e.to_string())),
+        ),
+    }
+}
+
+/// DELETE /api/vms/:id/snapshots/:name — delete a snapshot.
+pub async fn delete_snapshot(
+    State(rt): State<AppState>,
+    Path((id, name)): Path<(String, String)>,
+) -> impl IntoResponse {
+    let rt = lock_rt(&rt);
+    match rt.delete_vm_snapshot(&id, &name) {
+        Ok(()) => (StatusCode::OK, Json(ApiRespEmpty::ok())),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiRespEmpty::err(e.to_string())),
+        ),
+    }
+}
+
+/// PUT /api/networks/:vni — create or update an env network.
+pub async fn put_network(
+    State(rt): State<AppState>,
+    Path(vni): Path<u32>,
+    Json(req): Json<NetworkReq>,
+) -> impl IntoResponse {
+    let mut rt = lock_rt(&rt);
+    match rt.ensure_network(vni, &req) {
+        Ok(()) => (StatusCode::OK, Json(ApiRespEmpty::ok())),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiRespEmpty::err(e.to_string())),
+        ),
+    }
+}
+
+/// DELETE /api/networks/:vni — tear down an env network.
+pub async fn delete_network(State(rt): State<AppState>, Path(vni): Path<u32>) -> impl IntoResponse {
+    let mut rt = lock_rt(&rt);
+    match rt.remove_network(vni) {
+        Ok(()) => (StatusCode::OK, Json(ApiRespEmpty::ok())),
+        Err(e) => (StatusCode::CONFLICT, Json(ApiRespEmpty::err(e.to_string()))),
+    }
+}
@@ -0,0 +1,143 @@
+//! Registration with the controller and heartbeats.
+//!
+//! With `--controller`, the agent pushes a [`Heartbeat`] every
+//! `--heartbeat-interval` seconds: its info and resources, and the VMs
+//! whose state changed since the controller last took one. The first
+//! registers the host, so new hosts join the fleet on their own, and
+//! the controller never has to reach the agent to know it is alive.
+
+use crate::handler::{AppState, lock_rt};
+use ruc::*;
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+use ttcore::api::{ApiResp, Heartbeat, VmStatus};
+use ttcore::model::{Host, VmState};
+
+/// Send heartbeats to `controller` until the agent exits.
+pub async fn run(
+    rt: AppState,
+    controller: String,
+    addr: String,
+    interval: u64,
+    controller_key: Option<String>,
+) {
+    let base = if controller.starts_with("http") {
+        controller.clone()
+    } else {
+        format!("http://{controller}")
+    };
+    let client = client(controller_key.as_deref(), interval);
+    // VM states as of the last heartbeat the controller took
+    let mut sent: HashMap<String, VmState> = HashMap::new();
+    let mut up = false;
+    let mut first = true;
+
+    let mut tick = tokio::time::interval(Duration::from_secs(interval));
+    tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tick.tick().await;
+        let (info, current) = {
+            let rt = lock_rt(&rt);
+            let vms = rt.list_vms().into_iter().map(|v| (v.id, v.state));
+            (rt.agent_info(), vms.collect::<HashMap<_, _>>())
+        };
+        let url = format!("{base}/api/hosts/{}/heartbeat", info.host_id);
+        let beat = Heartbeat {
+            addr: addr.clone(),
+            interval,
+            info,
+            vms: changes(&sent, &current),
+        };
+
+        match send(&client, &url, &beat).await {
+            Ok(host) => {
+                if !up {
+                    info!(
+                        "registered with controller {controller} as {} ({}, {})",
+                        host.id, host.addr, host.state
+                    );
+                }
+                up = true;
+                sent = current;
+            }
+            // Say it once; the controller may be down for a while
+            Err(e) if up || first => {
+                warn!("heartbeat to controller {controller} failed: {e}");
+                up = false;
+            }
+            Err(e) => debug!("heartbeat to controller {controller} failed: {e}"),
+        }
+        first = false;
+    }
+}
+
+fn client(controller_key: Option<&str>, interval: u64) -> reqwest::Client {
+    let mut headers = reqwest::header::HeaderMap::new();
+    if let Some(key) = controller_key
+        && let Ok(val) = reqwest::header::HeaderValue::from_str(&format!("Bearer {key}"))
+    {
+        headers.insert(reqwest::header::AUTHORIZATION, val);
+    }
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(interval.max(5)))
+        .default_headers(headers)
+        .build()
+        .unwrap()
+}
+
+async fn send(client: &reqwest::Client, url: &str, beat: &Heartbeat) -> Result<Host> {
+    let resp = client.post(url).json(beat).send().await.c(d!())?;
+    let status = resp.status();
+    let body: ApiResp<Host> = resp.json().await.c(d!("invalid response"))?;
+    match body.data {
+        Some(host) if body.ok => Ok(host),
+        _ => Err(eg!(body.error.unwrap_or_else(|| format!("HTTP {status}")))),
+    }
+}
+
+/// VMs whose state is not the one last sent, by ID.
+fn changes(sent: &HashMap<String, VmState>, current: &HashMap<String, VmState>) -> Vec<VmStatus> {
+    let mut out: Vec<VmStatus> = current
+        .iter()
+        .filter(|(id, state)| sent.get(*id) != Some(*state))
+        .map(|(id, state)| VmStatus {
+            id: id.clone(),
+            state: *state,
+        })
+        .collect();
+    out.sort_by(|a, b| a.id.cmp(&b.id));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_changed_vms_are_sent() {
+        let current = HashMap::from([
+            ("a".to_string(), VmState::Running),
+            ("b".to_string(), VmState::Stopped),
+        ]);
+        // Everything goes out until the controller took a heartbeat
+        let all = changes(&HashMap::new(), &current);
+        assert_eq!(
+            all.iter().map(|v| v.id.as_str()).collect::<Vec<_>>(),
+            ["a", "b"]
+        );
+
+        let sent = HashMap::from([
+            ("a".to_string(), VmState::Running),
+            ("b".to_string(), VmState::Running),
+        ]);
+        assert_eq!(
+            changes(&sent, &current),
+            [VmStatus {
+                id: "b".into(),
+                state: VmState::Stopped,
+            }]
+        );
+        assert!(changes(&current, &current).is_empty());
+    }
+}
@@ -10,30 +10,48 @@
 
 mod auth;
 mod config;
+mod console;
 mod handler;
+mod heartbeat;
+mod metrics;
+mod migrate;
 mod runtime;
+mod trace;
+mod transfer;
+mod usage;
 
 use axum::Router;
-use axum::routing::{get, post};
+use axum::extract::DefaultBodyLimit;
+use axum::routing::{delete, get, post, put};
 use clap::Parser;
 use config::Config;
 use handler::AppState;
 use runtime::Runtime;
 use std::sync::{Arc, Mutex};
+use tracing::{error, info, warn};
 use ttcore::model::Resource;
 
 #[tokio::main]
 async fn main() {
     let cfg = Config::parse();
+    if let Err(e) = ttcore::log::init(cfg.log_format, &cfg.log_level) {
+        eprintln!("Failed to set up logging: {e}");
+        std::process::exit(1);
+    }
 
     let db_path = format!("{}/agent.db", cfg.data_dir);
     std::fs::create_dir_all(&cfg.data_dir).unwrap_or_else(|e| {
-        eprintln!("Failed to create data dir {}: {e}", cfg.data_dir);
+        error!("Failed to create data dir {}: {e}", cfg.data_dir);
         std::process::exit(1);
     });
 
     let host_id = runtime::resolve_host_id(&db_path, cfg.host_id.clone()).unwrap_or_else(|e| {
-        eprintln!("Failed to resolve host_id: {e}");
+        error!("Failed to resolve host_id: {e}");
+        std::process::exit(1);
+    });
+
+    let labels = cfg.labels().unwrap_or_else(|e| {
+        error!("Invalid --label: {e}");
         std::process::exit(1);
     });
 
@@ -44,7 +62,7 @@ async fn main() {
         ..Default::default()
     };
 
-    let rt = Runtime::new(
+    let mut rt = Runtime::new(
         host_id.clone(),
         cfg.storage_kind(),
         cfg.image_dir.clone(),
@@ -53,50 +71,139 @@ async fn main() {
         resource,
     )
     .unwrap_or_else(|e| {
-        eprintln!("Failed to initialize runtime: {e}");
+        error!("Failed to initialize runtime: {e}");
         std::process::exit(1);
     });
+    rt.labels = labels;
 
     let state: AppState = Arc::new(Mutex::new(rt));
+    tokio::spawn(usage::run(state.clone()));
+    if let Some(controller) = cfg.controller.clone() {
+        let addr = cfg.advertise.clone().unwrap_or_else(|| cfg.listen.clone());
+        tokio::spawn(heartbeat::run(
+            state.clone(),
+            controller,
+            addr,
+            cfg.heartbeat_interval,
+            cfg.controller_key.clone(),
+        ));
+    }
 
     let app = Router::new()
+        .route("/metrics", get(metrics::metrics))
         .route("/api/info", get(handler::get_info))
+        .route("/api/usage", get(usage::host_usage))
+        .route(
+            "/api/log-level",
+            get(trace::get_log_level).put(trace::set_log_level),
+        )
         .route("/api/images", get(handler::list_images))
+        .route("/api/images/{name}/manifest", get(transfer::get_manifest))
+        .route("/api/images/{name}/send", get(transfer::send_image))
+        .route(
+            "/api/images/{name}/recv",
+            put(transfer::recv_image).layer(DefaultBodyLimit::disable()),
+        )
+        .route(
+            "/api/images/{name}/chunk",
+            get(transfer::read_chunk)
+                .put(transfer::write_chunk)
+                .layer(DefaultBodyLimit::max(
+                    ttcore::api::IMAGE_CHUNK_SIZE as usize,
+                )),
+        )
+        .route(
+            "/api/images/{name}/incoming",
+            delete(transfer::discard_incoming),
+        )
+        .route("/api/images/{name}/install", post(transfer::install_image))
         .route("/api/vms", get(handler::list_vms).post(handler::create_vm))
+        .route("/api/vms/incoming", post(migrate::receive_vm))
         .route(
             "/api/vms/{id}",
             get(handler::get_vm).delete(handler::destroy_vm),
         )
         .route("/api/vms/{id}/stop", post(handler::stop_vm))
         .route("/api/vms/{id}/start", post(handler::start_vm))
+        .route("/api/vms/{id}/suspend", post(handler::suspend_vm))
+        .route("/api/vms/{id}/resume", post(handler::resume_vm))
+        .route("/api/vms/{id}/hibernate", post(handler::hibernate_vm))
+        .route("/api/vms/{id}/reset", post(handler::reset_vm))
+        .route("/api/vms/{id}/reboot", post(handler::reboot_vm))
+        .route("/api/vms/{id}/migrate", post(migrate::send_vm))
+        .route(
+            "/api/vms/{id}/incoming/finish",
+            post(migrate::finish_receive),
+        )
+        .route("/api/vms/{id}/disk", delete(migrate::discard_disk))
+        .route("/api/vms/{id}/disk/size", get(migrate::disk_size))
+        .route("/api/vms/{id}/disk/manifest", get(migrate::disk_manifest))
+        .route("/api/vms/{id}/disk/send", get(migrate::send_disk))
+        .route(
+            "/api/vms/{id}/disk/recv",
+            put(migrate::recv_disk).layer(DefaultBodyLimit::disable()),
+        )
+        .route(
+            "/api/vms/{id}/disk/chunk",
+            get(migrate::read_disk_chunk)
+                .put(migrate::write_disk_chunk)
+                .layer(DefaultBodyLimit::max(
+                    ttcore::api::IMAGE_CHUNK_SIZE as usize,
+                )),
+        )
+        .route(
+            "/api/vms/{id}/disk/incoming",
+            delete(migrate::discard_disk_incoming),
+        )
+        .route("/api/vms/{id}/disk/install", post(migrate::install_disk))
+        .route("/api/vms/{id}/usage", get(usage::vm_usage))
+        .route("/api/vms/{id}/console", get(console::console))
+        .route("/api/vms/{id}/vnc", get(console::vnc))
+        .route(
+            "/api/vms/{id}/snapshots",
+            get(handler::list_snapshots).post(handler::create_snapshot),
+        )
+        .route(
+            "/api/vms/{id}/snapshots/{name}",
+            delete(handler::delete_snapshot),
+        )
+        .route(
+            "/api/vms/{id}/snapshots/{name}/rollback",
+            post(handler::rollback_snapshot),
+        )
+        .route(
+            "/api/networks/{vni}",
+            put(handler::put_network).delete(handler::delete_network),
+        )
         .with_state(state);
 
     let app = if let Some(key) = cfg.api_key {
-        eprintln!("API key authentication enabled");
+        info!("API key authentication enabled");
         app.layer(axum::middleware::from_fn(auth::make_auth_layer(key)))
     } else {
-        eprintln!("WARNING: no --api-key set, all agent endpoints are unauthenticated!");
+        warn!("no --api-key set, all agent endpoints are unauthenticated!");
         app
     };
+    let app = app.layer(axum::middleware::from_fn(trace::request_span));
 
     let listener = tokio::net::TcpListener::bind(&cfg.listen)
         .await
         .unwrap_or_else(|e| {
-            eprintln!("Failed to bind {}: {e}", cfg.listen);
+            error!("Failed to bind {}: {e}", cfg.listen);
             std::process::exit(1);
         });
 
-    eprintln!("tt-agent [{host_id}] listening on {}", cfg.listen);
+    info!("tt-agent [{host_id}] listening on {}", cfg.listen);
 
     axum::serve(listener, app)
         .with_graceful_shutdown(shutdown_signal())
         .await
-        .unwrap_or_else(|e| eprintln!("Server error: {e}"));
+        .unwrap_or_else(|e| error!("Server error: {e}"));
 
-    eprintln!("tt-agent shutting down");
+    info!("tt-agent shutting down");
 }
 
 async fn shutdown_signal() {
     let _ = tokio::signal::ctrl_c().await;
-    eprintln!("received shutdown signal");
+    info!("received shutdown signal");
 }
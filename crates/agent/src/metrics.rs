@@ -0,0 +1,367 @@
+//! `GET /metrics`: the agent's Prometheus metrics.
+//!
+//! Host resources and per-VM usage are read when scraped. Engine
+//! operations are timed as they run: the agent gets its engines from
+//! [`engine`], which wraps them in [`Timed`].
+
+use crate::handler::{AppState, lock_rt};
+use crate::trace;
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use ruc::*;
+use std::collections::BTreeMap;
+use std::time::Instant;
+use tracing::{debug, info_span, warn};
+use ttcore::api::{AgentInfo, MigrationPorts};
+use ttcore::engine::{self, Console, VmEngine, VmStats};
+use ttcore::metrics::{self, CounterVec, HistogramVec, Kind, Text};
+use ttcore::model::{Engine, Vm, VmState};
+
+static ENGINE_OPS: HistogramVec = HistogramVec::new(
+    "tt_agent_engine_op_duration_seconds",
+    "Time taken by engine operations on VMs.",
+    &["engine", "op"],
+    metrics::LATENCY_BUCKETS,
+);
+
+static ENGINE_OP_FAILURES: CounterVec = CounterVec::new(
+    "tt_agent_engine_op_failures_total",
+    "Engine operations on VMs that failed.",
+    &["engine", "op"],
+);
+
+/// The engine for `kind`, with its operations timed.
+pub fn engine(kind: Engine) -> Box<dyn VmEngine> {
+    Box::new(Timed(engine::create_engine(kind)))
+}
+
+/// An engine whose operations are recorded in [`ENGINE_OPS`] and
+/// [`ENGINE_OP_FAILURES`] and run in an `engine` span, which is where
+/// their failures are logged. Queries (state, console, stats) are not.
+pub struct Timed(Box<dyn VmEngine>);
+
+impl Timed {
+    fn time(&self, op: &str, vm: &Vm, f: impl FnOnce(&dyn VmEngine) -> Result<()>) -> Result<()> {
+        let span = info_span!("engine", engine = self.0.name(), op, vm = %vm.id);
+        let _guard = span.enter();
+        let start = Instant::now();
+        let res = f(self.0.as_ref());
+        let labels = [self.0.name(), op];
+        ENGINE_OPS.observe(&labels, start.elapsed().as_secs_f64());
+        match &res {
+            Ok(()) => debug!("done in {:.3}s", start.elapsed().as_secs_f64()),
+            Err(e) => {
+                ENGINE_OP_FAILURES.inc(&labels);
+                warn!("{op} of VM {} failed: {e}", vm.id);
+            }
+        }
+        res
+    }
+}
+
+impl VmEngine for Timed {
+    fn create(
+        &self,
+        vm: &Vm,
+        image_path: &str,
+        disk_format: &str,
+        ssh_keys: &[String],
+    ) -> Result<()> {
+        self.time("create", vm, |e| {
+            e.create(vm, image_path, disk_format, ssh_keys)
+        })
+    }
+
+    fn start(&self, vm: &Vm, image_path: &str, disk_format: &str) -> Result<()> {
+        self.time("start", vm, |e| e.start(vm, image_path, disk_format))
+    }
+
+    fn stop(&self, vm: &Vm) -> Result<()> {
+        self.time("stop", vm, |e| e.stop(vm))
+    }
+
+    fn suspend(&self, vm: &Vm) -> Result<()> {
+        self.time("suspend", vm, |e| e.suspend(vm))
+    }
+
+    fn resume(&self, vm: &Vm) -> Result<()> {
+        self.time("resume", vm, |e| e.resume(vm))
+    }
+
+    fn hibernate(&self, vm: &Vm, state_dir: &str) -> Result<()> {
+        self.time("hibernate", vm, |e| e.hibernate(vm, state_dir))
+    }
+
+    fn restore(&self, vm: &Vm, image_path: &str, disk_format: &str, state_dir: &str) -> Result<()> {
+        self.time("restore", vm, |e| {
+            e.restore(vm, image_path, disk_format, state_dir)
+        })
+    }
+
+    fn migrate_in(
+        &self,
+        vm: &Vm,
+        image_path: &str,
+        disk_format: &str,
+        ports: &MigrationPorts,
+    ) -> Result<()> {
+        self.time("migrate_in", vm, |e| {
+            e.migrate_in(vm, image_path, disk_format, ports)
+        })
+    }
+
+    fn migrate_out(&self, vm: &Vm, host: &str, ports: &MigrationPorts) -> Result<()> {
+        self.time("migrate_out", vm, |e| e.migrate_out(vm, host, ports))
+    }
+
+    fn finish_migrate_in(&self, vm: &Vm) -> Result<()> {
+        self.time("finish_migrate_in", vm, |e| e.finish_migrate_in(vm))
+    }
+
+    fn reset(&self, vm: &Vm) -> Result<()> {
+        self.time("reset", vm, |e| e.reset(vm))
+    }
+
+    fn reboot(&self, vm: &Vm) -> Result<()> {
+        self.time("reboot", vm, |e| e.reboot(vm))
+    }
+
+    fn destroy(&self, vm: &Vm) -> Result<()> {
+        self.time("destroy", vm, |e| e.destroy(vm))
+    }
+
+    fn state(&self, vm: &Vm) -> Result<VmState> {
+        self.0.state(vm)
+    }
+
+    fn console(&self, vm: &Vm) -> Result<Console> {
+        self.0.console(vm)
+    }
+
+    fn vnc(&self, vm: &Vm) -> Result<String> {
+        self.0.vnc(vm)
+    }
+
+    fn stats(&self, vm: &Vm) -> Result<VmStats> {
+        self.0.stats(vm)
+    }
+
+    fn name(&self) -> &'static str {
+        self.0.name()
+    }
+}
+
+/// GET /metrics — host resources, VMs and their usage, and engine
+/// operation times, in the Prometheus text format.
+pub async fn metrics(State(rt): State<AppState>) -> impl IntoResponse {
+    let (info, vms) = {
+        let rt = lock_rt(&rt);
+        (rt.agent_info(), rt.list_vms())
+    };
+    // Engines read /proc or run `docker`, so off the async threads
+    let body = trace::blocking(move || {
+        let usage: Vec<_> = vms
+            .iter()
+            .filter(|vm| matches!(vm.state, VmState::Running | VmState::Paused))
+            .filter_map(|vm| {
+                let s = engine::create_engine(vm.engine).stats(vm).ok()?;
+                Some((vm, s))
+            })
+            .collect();
+        render(&info, &vms, &usage)
+    })
+    .await
+    .unwrap_or_default();
+    ([(CONTENT_TYPE, metrics::CONTENT_TYPE)], body)
+}
+
+const MIB: f64 = 1024.0 * 1024.0;
+
+fn render(info: &AgentInfo, vms: &[Vm], usage: &[(&Vm, VmStats)]) -> String {
+    let mut t = Text::default();
+    let r = &info.resource;
+
+    t.family("tt_agent_info", Kind::Gauge, "The host this agent runs on.");
+    t.sample("tt_agent_info", &[("host_id", &info.host_id)], 1.0);
+    t.gauge("tt_agent_cpus", "CPUs offered to VMs.", r.cpu_total as f64);
+    t.gauge(
+        "tt_agent_cpus_allocated",
+        "CPUs allocated to VMs.",
+        r.cpu_used as f64,
+    );
+    t.gauge(
+        "tt_agent_memory_bytes",
+        "Memory offered to VMs.",
+        r.mem_total as f64 * MIB,
+    );
+    t.gauge(
+        "tt_agent_memory_allocated_bytes",
+        "Memory allocated to VMs.",
+        r.mem_used as f64 * MIB,
+    );
+    t.gauge(
+        "tt_agent_disk_bytes",
+        "Disk offered to VMs.",
+        r.disk_total as f64 * MIB,
+    );
+    t.gauge(
+        "tt_agent_disk_allocated_bytes",
+        "Disk allocated to VMs.",
+        r.disk_used as f64 * MIB,
+    );
+
+    let mut counts: BTreeMap<(String, String), u32> = BTreeMap::new();
+    for vm in vms {
+        *counts
+            .entry((vm.engine.to_string(), vm.state.to_string()))
+            .or_default() += 1;
+    }
+    t.family("tt_agent_vms", Kind::Gauge, "VMs on this host.");
+    for ((engine, state), n) in &counts {
+        t.sample(
+            "tt_agent_vms",
+            &[("engine", engine), ("state", state)],
+            *n as f64,
+        );
+    }
+
+    type Field = fn(&VmStats) -> Option<f64>;
+    let per_vm: [(&str, Kind, &str, Field); 6] = [
+        (
+            "tt_vm_cpu_seconds_total",
+            Kind::Counter,
+            "CPU time used by the VM.",
+            |s| s.cpu_seconds,
+        ),
+        (
+            "tt_vm_memory_bytes",
+            Kind::Gauge,
+            "Host memory used by the VM.",
+            |s| s.mem_bytes.map(|v| v as f64),
+        ),
+        (
+            "tt_vm_disk_read_bytes_total",
+            Kind::Counter,
+            "Bytes the VM read from its disk.",
+            |s| s.disk_read_bytes.map(|v| v as f64),
+        ),
+        (
+            "tt_vm_disk_written_bytes_total",
+            Kind::Counter,
+            "Bytes the VM wrote to its disk.",
+            |s| s.disk_written_bytes.map(|v| v as f64),
+        ),
+        (
+            "tt_vm_network_receive_bytes_total",
+            Kind::Counter,
+            "Bytes the VM received over its NICs.",
+            |s| s.net_rx_bytes.map(|v| v as f64),
+        ),
+        (
+            "tt_vm_network_transmit_bytes_total",
+            Kind::Counter,
+            "Bytes the VM sent over its NICs.",
+            |s| s.net_tx_bytes.map(|v| v as f64),
+        ),
+    ];
+    for (name, kind, help, field) in per_vm {
+        t.family(name, kind, help);
+        for (vm, s) in usage {
+            if let Some(v) = field(s) {
+                let engine = vm.engine.to_string();
+                let labels = [
+                    ("vm", vm.id.as_str()),
+                    ("env", vm.env_id.as_str()),
+                    ("name", vm.name.as_str()),
+                    ("engine", engine.as_str()),
+                ];
+                t.sample(name, &labels, v);
+            }
+        }
+    }
+
+    ENGINE_OPS.write(&mut t);
+    ENGINE_OP_FAILURES.write(&mut t);
+    t.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An engine that can only be created.
+    struct Fake;
+
+    impl VmEngine for Fake {
+        fn create(&self, _: &Vm, _: &str, _: &str, _: &[String]) -> Result<()> {
+            Ok(())
+        }
+        fn start(&self, _: &Vm, _: &str, _: &str) -> Result<()> {
+            Ok(())
+        }
+        fn stop(&self, _: &Vm) -> Result<()> {
+            Ok(())
+        }
+        fn destroy(&self, _: &Vm) -> Result<()> {
+            Ok(())
+        }
+        fn state(&self, _: &Vm) -> Result<VmState> {
+            Ok(VmState::Running)
+        }
+        fn console(&self, _: &Vm) -> Result<Console> {
+            Err(eg!("no console"))
+        }
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+    }
+
+    fn vm(id: &str, state: VmState) -> Vm {
+        Vm {
+            id: id.into(),
+            env_id: "e1".into(),
+            engine: Engine::Docker,
+            state,
+            name: "web".into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn timed_engine_records_ops_and_failures() {
+        let eng = Timed(Box::new(Fake));
+        let v = vm("vm-1", VmState::Running);
+        eng.create(&v, "", "raw", &[]).unwrap();
+        assert!(eng.suspend(&v).is_err());
+        assert_eq!(ENGINE_OP_FAILURES.get(&["fake", "suspend"]), 1);
+        assert_eq!(ENGINE_OP_FAILURES.get(&["fake", "create"]), 0);
+
+        let info = AgentInfo {
+            host_id: "h1".into(),
+            resource: Default::default(),
+            engines: vec![],
+            storage: ttcore::model::Storage::File,
+            images: vec![],
+            labels: Default::default(),
+        };
+        let vms = [vm("vm-1", VmState::Running), vm("vm-2", VmState::Stopped)];
+        let usage = [(
+            &vms[0],
+            VmStats {
+                cpu_seconds: Some(1.5),
+                ..Default::default()
+            },
+        )];
+        let text = render(&info, &vms, &usage);
+        for line in [
+            "tt_agent_info{host_id=\"h1\"} 1\n",
+            "tt_agent_vms{engine=\"docker\",state=\"stopped\"} 1\n",
+            "tt_vm_cpu_seconds_total{vm=\"vm-1\",env=\"e1\",name=\"web\",engine=\"docker\"} 1.5\n",
+            "tt_agent_engine_op_duration_seconds_count{engine=\"fake\",op=\"create\"} 1\n",
+        ] {
+            assert!(text.contains(line), "missing {line:?} in\n{text}");
+        }
+        assert!(!text.contains("tt_vm_memory_bytes{"));
+    }
+}
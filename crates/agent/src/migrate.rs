@@ -0,0 +1,327 @@
+//! VM migration endpoints.
+//!
+//! The controller moves a VM to another host in one of two ways:
+//!
+//! - **cold**: the VM's image clone is copied with the same I/O as image
+//!   distribution (`/api/vms/{id}/disk/*`), then the VM is created on the
+//!   target from the copied disk (`migrated` in [`CreateVmReq`]).
+//! - **live** (QEMU): the target launches the VM waiting for its memory
+//!   and exporting an empty disk (`POST /api/vms/incoming`), the source
+//!   mirrors disk and memory into it (`POST /api/vms/{id}/migrate`), and
+//!   the target takes over (`POST /api/vms/{id}/incoming/finish`).
+//!   The target binds its ports to the address the controller reaches
+//!   it at and, on Linux and FreeBSD, drops connections to them from
+//!   any host but the source until the VM is running or destroyed.
+//!
+//! Disk endpoints that write refuse to touch the disk of a VM that
+//! exists on this host.
+
+use crate::handler::{AppState, lock_rt};
+use crate::metrics;
+use crate::trace;
+use crate::transfer::{self, ChunkQuery, SendQuery, fail};
+use axum::Json;
+use axum::body::{Body, Bytes};
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use std::net::Ipv4Addr;
+use ttcore::api::*;
+use ttcore::model::{Engine, Storage, Vm, VmState, validate_name};
+use ttcore::storage::{self, zvol::ZvolStore};
+
+/// Prefix of the zvol snapshots taken to send a VM's disk.
+const MIGRATE_SNAP_PREFIX: &str = "migrate-";
+
+/// GET /api/vms/:id/disk/size — virtual size in bytes of a VM's disk.
+pub async fn disk_size(State(rt): State<AppState>, Path(id): Path<String>) -> Response {
+    let (kind, path) = match source_disk(&rt, &id, None) {
+        Ok(d) => d,
+        Err((code, msg)) => return fail(code, msg),
+    };
+    let result = trace::blocking(move || storage::create_store(kind).disk_size(&path)).await;
+    match result {
+        Ok(Ok(bytes)) => (StatusCode::OK, Json(ApiResp::success(bytes))).into_response(),
+        Ok(Err(e)) => fail(StatusCode::INTERNAL_SERVER_ERROR, e),
+        Err(e) => fail(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// GET /api/vms/:id/disk/manifest — describe a VM's disk.
+pub async fn disk_manifest(State(rt): State<AppState>, Path(id): Path<String>) -> Response {
+    match source_disk(&rt, &id, None) {
+        Ok((kind, path)) => transfer::manifest(kind, path, id, "VM has no disk").await,
+        Err((code, msg)) => fail(code, msg),
+    }
+}
+
+/// GET /api/vms/:id/disk/send — stream a VM's disk as a zfs send stream.
+///
+/// Sends a fresh snapshot, named in the `x-tt-snapshot` header,
+/// incrementally from `from` when given. A full send first drops the
+/// snapshots of earlier attempts.
+pub async fn send_disk(
+    State(rt): State<AppState>,
+    Path(id): Path<String>,
+    Query(q): Query<SendQuery>,
+) -> Response {
+    let dataset = match source_disk(&rt, &id, Some(Storage::Zvol)) {
+        Ok((_, d)) => d,
+        Err((code, msg)) => return fail(code, msg),
+    };
+
+    let snapshot = || -> ruc::Result<String> {
+        if q.from.is_none() {
+            for snap in ZvolStore.snapshot_names(&dataset)? {
+                if snap.starts_with(MIGRATE_SNAP_PREFIX) {
+                    ZvolStore.destroy_snapshot(&format!("{dataset}@{snap}"))?;
+                }
+            }
+        }
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let snap = format!("{MIGRATE_SNAP_PREFIX}{nanos}");
+        ZvolStore.create_snapshot(&dataset, &snap)?;
+        Ok(snap)
+    };
+    let snap = match snapshot() {
+        Ok(s) => s,
+        Err(e) => return fail(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+
+    let mut resp = transfer::send_stream(&dataset, &snap, q.from.as_deref());
+    if let Ok(v) = HeaderValue::from_str(&snap) {
+        resp.headers_mut().insert(DISK_SNAPSHOT_HEADER, v);
+    }
+    resp
+}
+
+/// PUT /api/vms/:id/disk/recv — receive a zfs send stream as a VM's disk.
+pub async fn recv_disk(State(rt): State<AppState>, Path(id): Path<String>, body: Body) -> Response {
+    match target_disk(&rt, &id, Some(Storage::Zvol)) {
+        Ok(dataset) => transfer::recv_stream(&dataset, body).await,
+        Err((code, msg)) => fail(code, msg),
+    }
+}
+
+/// GET /api/vms/:id/disk/chunk — read one chunk of a file-backed disk.
+pub async fn read_disk_chunk(
+    State(rt): State<AppState>,
+    Path(id): Path<String>,
+    Query(q): Query<ChunkQuery>,
+) -> Response {
+    match source_disk(&rt, &id, Some(Storage::File)) {
+        Ok((_, path)) => transfer::read_chunk_of(path, q).await,
+        Err((code, msg)) => fail(code, msg),
+    }
+}
+
+/// PUT /api/vms/:id/disk/chunk — stage one chunk of an incoming disk.
+pub async fn write_disk_chunk(
+    State(rt): State<AppState>,
+    Path(id): Path<String>,
+    Query(q): Query<ChunkQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    match target_disk(&rt, &id, Some(Storage::File)) {
+        Ok(path) => transfer::write_chunk_to(staging(&path, &id), q, &headers, body).await,
+        Err((code, msg)) => fail(code, msg),
+    }
+}
+
+/// DELETE /api/vms/:id/disk/incoming — discard a partially staged disk.
+pub async fn discard_disk_incoming(State(rt): State<AppState>, Path(id): Path<String>) -> Response {
+    match target_disk(&rt, &id, Some(Storage::File)) {
+        Ok(path) => transfer::discard(&staging(&path, &id)),
+        Err((code, msg)) => fail(code, msg),
+    }
+}
+
+/// POST /api/vms/:id/disk/install — verify the staged disk and install it.
+pub async fn install_disk(
+    State(rt): State<AppState>,
+    Path(id): Path<String>,
+    Json(manifest): Json<ImageManifest>,
+) -> Response {
+    match target_disk(&rt, &id, Some(Storage::File)) {
+        Ok(path) => transfer::install(staging(&path, &id), path, manifest).await,
+        Err((code, msg)) => fail(code, msg),
+    }
+}
+
+/// DELETE /api/vms/:id/disk — discard a disk received for a VM that was
+/// never created here.
+pub async fn discard_disk(State(rt): State<AppState>, Path(id): Path<String>) -> Response {
+    let path = match target_disk(&rt, &id, None) {
+        Ok(p) => p,
+        Err((code, msg)) => return fail(code, msg),
+    };
+    let kind = lock_rt(&rt).disk_location(&id).0;
+    let result = trace::blocking(move || {
+        if kind == Storage::File {
+            storage::create_store(kind).remove_image(&staging(&path, &id))?;
+        }
+        storage::create_store(kind).remove_image(&path)
+    })
+    .await;
+    match result {
+        Ok(Ok(())) => (StatusCode::OK, Json(ApiRespEmpty::ok())).into_response(),
+        Ok(Err(e)) => fail(StatusCode::INTERNAL_SERVER_ERROR, e),
+        Err(e) => fail(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// POST /api/vms/incoming — launch a VM waiting for a live migration.
+///
+/// Returns the ports the source is to send its memory and disk to,
+/// bound to `listen` and closed to every host but `peer`.
+pub async fn receive_vm(State(rt): State<AppState>, Json(req): Json<IncomingVmReq>) -> Response {
+    let (Ok(listen), Ok(peer)) = (req.listen.parse::<Ipv4Addr>(), req.peer.parse::<Ipv4Addr>())
+    else {
+        return fail(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "live migration needs IPv4 host addresses, got {} and {}",
+                req.listen, req.peer
+            ),
+        );
+    };
+    let ports = match free_ports(listen) {
+        Ok(p) => p,
+        Err(e) => return fail(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+    let mut rt = lock_rt(&rt);
+    match rt.receive_vm(&req.vm, req.disk_bytes, &ports, &peer.to_string()) {
+        Ok(_) => (StatusCode::CREATED, Json(ApiResp::success(ports))).into_response(),
+        Err(e) => fail(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// POST /api/vms/:id/incoming/finish — run a VM once its live migration
+/// has completed.
+pub async fn finish_receive(State(rt): State<AppState>, Path(id): Path<String>) -> Response {
+    let mut rt = lock_rt(&rt);
+    match rt.finish_receive(&id) {
+        Ok(vm) => (StatusCode::OK, Json(ApiResp::success(vm))).into_response(),
+        Err(e) => fail(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// POST /api/vms/:id/migrate — live-migrate a running VM to a target
+/// prepared with `POST /api/vms/incoming`.
+///
+/// Runs without holding the runtime lock, since copying the disk and
+/// memory may take long. The VM is left paused here.
+pub async fn send_vm(
+    State(rt): State<AppState>,
+    Path(id): Path<String>,
+    Json(req): Json<MigrateOutReq>,
+) -> Response {
+    let vm = match lock_rt(&rt).get_vm(&id) {
+        Some(vm) => vm,
+        None => return fail(StatusCode::NOT_FOUND, format!("VM not found: {id}")),
+    };
+    if vm.state != VmState::Running {
+        return fail(
+            StatusCode::CONFLICT,
+            format!("cannot live-migrate VM in state {}", vm.state),
+        );
+    }
+
+    let result =
+        trace::blocking(move || metrics::engine(vm.engine).migrate_out(&vm, &req.host, &req.ports))
+            .await;
+    let err = match result {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(e) => Some(e.to_string()),
+    };
+    if let Some(e) = err {
+        return fail(StatusCode::INTERNAL_SERVER_ERROR, e);
+    }
+
+    let mut rt = lock_rt(&rt);
+    match rt.mark_sent(&id) {
+        Ok(()) => (StatusCode::OK, Json(ApiRespEmpty::ok())).into_response(),
+        Err(e) => fail(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+// ── Helpers ─────────────────────────────────────────────────────────
+
+/// Resolve the disk of local VM `id` to send, optionally requiring a
+/// storage backend.
+fn source_disk(
+    rt: &AppState,
+    id: &str,
+    want: Option<Storage>,
+) -> std::result::Result<(Storage, String), (StatusCode, String)> {
+    let rt = lock_rt(rt);
+    let vm: Vm = rt
+        .get_vm(id)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("VM not found: {id}")))?;
+    if vm.engine == Engine::Docker {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "docker containers have no disk to transfer".to_string(),
+        ));
+    }
+    let (kind, path) = rt.disk_location(id);
+    check_kind(kind, want)?;
+    Ok((kind, path))
+}
+
+/// Resolve where the disk of VM `id`, not on this host yet, is received.
+fn target_disk(
+    rt: &AppState,
+    id: &str,
+    want: Option<Storage>,
+) -> std::result::Result<String, (StatusCode, String)> {
+    if let Err(e) = validate_name(id, "vm_id") {
+        return Err((StatusCode::BAD_REQUEST, e));
+    }
+    let rt = lock_rt(rt);
+    if rt.get_vm(id).is_some() {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("VM {id} already exists on this host"),
+        ));
+    }
+    let (kind, path) = rt.disk_location(id);
+    check_kind(kind, want)?;
+    Ok(path)
+}
+
+fn check_kind(
+    kind: Storage,
+    want: Option<Storage>,
+) -> std::result::Result<(), (StatusCode, String)> {
+    match want {
+        Some(want) if want != kind => Err((
+            StatusCode::BAD_REQUEST,
+            format!("this host uses {kind} storage, not {want}"),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Where the chunks of VM `id`'s incoming disk are staged.
+fn staging(path: &str, id: &str) -> String {
+    transfer::staging_path(path, &format!("clone-{id}"))
+}
+
+/// Two ports of `addr` nothing listens on right now, for an incoming
+/// migration.
+fn free_ports(addr: Ipv4Addr) -> std::io::Result<MigrationPorts> {
+    // Hold both listeners so the two ports differ
+    let memory = std::net::TcpListener::bind((addr, 0))?;
+    let disk = std::net::TcpListener::bind((addr, 0))?;
+    Ok(MigrationPorts {
+        addr: addr.to_string(),
+        memory: memory.local_addr()?.port(),
+        disk: disk.local_addr()?.port(),
+    })
+}
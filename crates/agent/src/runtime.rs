@@ -3,11 +3,14 @@
 //! Owns the lifecycle of all VMs on this host, backed by SQLite for
 //! crash-recoverable persistent state.
 
+use crate::metrics;
+use crate::usage;
 use ruc::*;
 use rusqlite::Connection;
 use std::collections::{BTreeMap, HashSet};
 use std::sync::atomic::{AtomicU32, Ordering};
-use ttcore::api::{AgentInfo, CreateVmReq};
+use tracing::{info, warn};
+use ttcore::api::{AgentInfo, CreateVmReq, MigrationPorts, NetworkReq, UsageSample};
 use ttcore::engine;
 use ttcore::model::*;
 use ttcore::net;
@@ -23,6 +26,8 @@ pub struct Runtime {
     image_dir: String,
     runtime_dir: String,
     pub resource: Resource,
+    /// Labels reported to the controller.
+    pub labels: BTreeMap<String, String>,
     next_ip_idx: AtomicU32,
 }
 
@@ -53,16 +58,19 @@ impl Runtime {
         // These VMs may have partial resources allocated that need cleanup.
         for vm in &vms {
             if vm.state == VmState::Creating {
-                eprintln!(
-                    "[agent] cleaning up orphaned VM {} (stuck in Creating state)",
+                info!(
+                    "cleaning up orphaned VM {} (stuck in Creating state)",
                     vm.id
                 );
-                let eng = engine::create_engine(vm.engine);
+                let eng = metrics::engine(vm.engine);
                 let _ = eng.destroy(vm);
                 let _ = storage::create_store(storage)
                     .remove_image(&format!("{}/clone-{}", runtime_dir, vm.id));
                 #[cfg(any(target_os = "linux", target_os = "freebsd"))]
-                net::destroy_tap(&vm.id).unwrap_or(());
+                {
+                    net::destroy_tap(&vm.id).unwrap_or(());
+                    net::remove_migration_rules(&vm.id).unwrap_or(());
+                }
                 let _ = delete_vm(&db, &vm.id);
             }
         }
@@ -81,12 +89,14 @@ impl Runtime {
         let mut disk_used = 0u32;
         let mut vm_count = 0u32;
         for vm in &vms {
+            // Stopped and hibernated VMs keep their disk, but not their
+            // CPU and memory
             if vm.state == VmState::Running || vm.state == VmState::Paused {
                 cpu_used += vm.cpu;
                 mem_used += vm.mem;
-                disk_used += vm.disk;
-                vm_count += 1;
             }
+            disk_used += vm.disk;
+            vm_count += 1;
         }
 
         let resource = Resource {
@@ -104,17 +114,33 @@ impl Runtime {
             net::setup_nat().c(d!("NAT setup"))?;
         }
 
-        // Restore network rules for persisted VMs
+        // Restore env networks, then network rules for persisted VMs
+        #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+        for (vni, req) in load_all_networks(&db)? {
+            if let Err(e) = net::ensure_overlay(vni, &req.peers) {
+                warn!("failed to restore env network {vni}: {e}");
+            }
+        }
+
         #[cfg(any(target_os = "linux", target_os = "freebsd"))]
         for vm in &vms {
-            if vm.state == VmState::Running || vm.state == VmState::Paused {
+            if matches!(
+                vm.state,
+                VmState::Running | VmState::Paused | VmState::Stopped | VmState::Hibernated
+            ) {
                 if let Err(e) = net::create_tap(&vm.id, &vm.ip) {
-                    eprintln!("[agent] WARN: failed to restore TAP for VM {}: {e}", vm.id);
+                    warn!("failed to restore TAP for VM {}: {e}", vm.id);
+                }
+                if let Some(overlay) = &vm.overlay
+                    && vm.engine != Engine::Jail
+                    && let Err(e) = net::create_overlay_tap(&vm.id, overlay.vni)
+                {
+                    warn!("failed to restore env network TAP for VM {}: {e}", vm.id);
                 }
                 for (&guest, &host) in &vm.port_map {
                     if let Err(e) = net::add_port_forward(host, &vm.ip, guest) {
-                        eprintln!(
-                            "[agent] WARN: failed to restore port forward {}->{}:{} for VM {}: {e}",
+                        warn!(
+                            "failed to restore port forward {}->{}:{} for VM {}: {e}",
                             host, vm.ip, guest, vm.id
                         );
                     }
@@ -131,12 +157,80 @@ impl Runtime {
             image_dir,
             runtime_dir,
             resource,
+            labels: BTreeMap::new(),
             next_ip_idx: AtomicU32::new(max_idx + 1),
         })
     }
 
     /// Create a new VM.
+    #[tracing::instrument(skip_all, fields(vm = %req.vm_id, env = %req.env_id, engine = %req.engine))]
     pub fn create_vm(&mut self, req: &CreateVmReq) -> Result<Vm> {
+        self.create(req, None)
+    }
+
+    /// Set up a VM to receive a live migration into an empty disk of
+    /// `disk_bytes`, listening on `ports` for `peer` only.
+    ///
+    /// The VM holds its resources but stays in `Creating` until
+    /// [`finish_receive`](Self::finish_receive); if the agent restarts
+    /// meanwhile it is cleaned up like any half-created VM.
+    pub fn receive_vm(
+        &mut self,
+        req: &CreateVmReq,
+        disk_bytes: u64,
+        ports: &MigrationPorts,
+        peer: &str,
+    ) -> Result<Vm> {
+        if req.engine == Engine::Docker {
+            return Err(eg!("docker containers cannot be live-migrated"));
+        }
+        let req = CreateVmReq {
+            migrated: true,
+            ..req.clone()
+        };
+        let vm = self.create(&req, Some((disk_bytes, ports)))?;
+
+        #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+        if let Err(e) = net::restrict_migration(&vm.id, peer, &[ports.memory, ports.disk]) {
+            let _ = self.destroy_vm(&vm.id);
+            return Err(e).c(d!("restrict migration ports"));
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+        let _ = peer;
+        Ok(vm)
+    }
+
+    /// Run a VM whose incoming live migration has completed.
+    pub fn finish_receive(&mut self, vm_id: &str) -> Result<Vm> {
+        let mut vm = load_vm(&self.db, vm_id)?.ok_or_else(|| eg!("VM not found: {}", vm_id))?;
+        if vm.state != VmState::Creating {
+            return Err(eg!("VM {} is not being migrated here", vm_id));
+        }
+
+        let eng = metrics::engine(vm.engine);
+        eng.finish_migrate_in(&vm).c(d!("finish migration"))?;
+        #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+        net::remove_migration_rules(vm_id).unwrap_or(());
+
+        vm.state = VmState::Running;
+        save_vm(&self.db, &vm)?;
+        Ok(vm)
+    }
+
+    /// Record that a VM was live-migrated away. Its process stays here,
+    /// paused, until the controller destroys it, or resumes it should
+    /// the target fail to take over.
+    pub fn mark_sent(&mut self, vm_id: &str) -> Result<()> {
+        let mut vm = load_vm(&self.db, vm_id)?.ok_or_else(|| eg!("VM not found: {}", vm_id))?;
+        vm.state = VmState::Paused;
+        save_vm(&self.db, &vm)
+    }
+
+    fn create(
+        &mut self,
+        req: &CreateVmReq,
+        incoming: Option<(u64, &MigrationPorts)>,
+    ) -> Result<Vm> {
         // Input validation
         validate_name(&req.vm_id, "vm_id").map_err(|e| eg!(e))?;
         validate_name(&req.image, "image").map_err(|e| eg!(e))?;
@@ -165,15 +259,29 @@ impl Runtime {
             .into_iter()
             .map(|vm| vm.ip)
             .collect();
-        let (ip_idx, ip) = loop {
-            let idx = self.next_ip_idx.fetch_add(1, Ordering::SeqCst);
-            if idx > 65000 {
-                return Err(eg!("IP address space exhausted"));
-            }
-            let candidate = net::vm_ip(idx);
-            if !existing_ips.contains(&candidate) {
-                break (idx, candidate);
+        let (ip_idx, ip) = match &req.ip {
+            // A migrated VM keeps its address, and with it its ports
+            Some(ip) => {
+                let idx = ip_to_index(ip).ok_or_else(|| eg!("invalid VM address: {}", ip))?;
+                if existing_ips.contains(ip) {
+                    return Err(eg!(
+                        "address {} is already in use on host {}",
+                        ip,
+                        self.host_id
+                    ));
+                }
+                (idx, ip.clone())
             }
+            None => loop {
+                let idx = self.next_ip_idx.fetch_add(1, Ordering::SeqCst);
+                if idx > 65000 {
+                    return Err(eg!("IP address space exhausted"));
+                }
+                let candidate = net::vm_ip(idx);
+                if !existing_ips.contains(&candidate) {
+                    break (idx, candidate);
+                }
+            },
         };
 
         // Docker/Podman manages its own images, networking, and port mapping;
@@ -186,15 +294,56 @@ impl Runtime {
 
         let clone_path = format!("{}/clone-{}", self.runtime_dir, req.vm_id);
         if host_managed_net {
-            let base_image = format!("{}/{}", self.image_dir, req.image);
-            self.store
-                .clone_image(&base_image, &clone_path)
-                .c(d!("image clone"))?;
+            if let Some((disk_bytes, _)) = incoming {
+                self.store
+                    .create_disk(&clone_path, disk_bytes)
+                    .c(d!("create disk"))?;
+            } else if req.migrated {
+                if !self.store.image_exists(&clone_path)? {
+                    return Err(eg!("no disk was transferred for VM {}", req.vm_id));
+                }
+                // Drop the snapshots the transfer left behind; migrated
+                // disks carry no others
+                for snap in self.store.list_image_snapshots(&clone_path)? {
+                    let _ = self.store.remove_image_snapshot(&clone_path, &snap);
+                }
+            } else {
+                let base_image = format!("{}/{}", self.image_dir, req.image);
+                self.store
+                    .clone_image(&base_image, &clone_path)
+                    .c(d!("image clone"))?;
+            }
+        }
+
+        // Docker containers are never attached to env networks
+        let overlay = req.overlay.clone().filter(|_| host_managed_net);
+        if let Some(o) = &overlay
+            && !load_all_networks(&self.db)?.contains_key(&o.vni)
+        {
+            let _ = self.store.remove_image(&clone_path);
+            return Err(eg!("env network {} is not set up on this host", o.vni));
         }
 
         if host_managed_net {
             #[cfg(any(target_os = "linux", target_os = "freebsd"))]
-            net::create_tap(&req.vm_id, &ip).c(d!("TAP setup"))?;
+            {
+                let taps = || -> Result<()> {
+                    net::create_tap(&req.vm_id, &ip).c(d!("TAP setup"))?;
+                    // Jails take their env address as an alias on the bridge
+                    if let Some(o) = &overlay
+                        && req.engine != Engine::Jail
+                    {
+                        net::create_overlay_tap(&req.vm_id, o.vni)
+                            .c(d!("env network TAP setup"))?;
+                    }
+                    Ok(())
+                };
+                if let Err(e) = taps() {
+                    let _ = net::destroy_tap(&req.vm_id);
+                    let _ = self.store.remove_image(&clone_path);
+                    return Err(e);
+                }
+            }
         }
 
         // Allocate port mappings (use checked arithmetic to avoid u16 overflow)
@@ -226,6 +375,13 @@ impl Runtime {
             port_map: port_map.clone(),
             state: VmState::Creating,
             created_at: now(),
+            overlay,
+            name: req.name.clone(),
+            deny_outgoing: req.deny_outgoing,
+            ssh_keys: req.ssh_keys.clone(),
+            user_data: req.user_data.clone(),
+            anti_affinity: req.anti_affinity.clone(),
+            node_selector: req.node_selector.clone(),
         };
 
         save_vm(&self.db, &vm)?;
@@ -235,8 +391,12 @@ impl Runtime {
         let disk_format = self.store.disk_format();
 
         // Launch using the appropriate engine
-        let eng = engine::create_engine(req.engine);
-        if let Err(e) = eng.create(&vm, &disk_path, disk_format, &req.ssh_keys) {
+        let eng = metrics::engine(req.engine);
+        let launched = match incoming {
+            Some((_, ports)) => eng.migrate_in(&vm, &disk_path, disk_format, ports),
+            None => eng.create(&vm, &disk_path, disk_format, &req.ssh_keys),
+        };
+        if let Err(e) = launched {
             if host_managed_net {
                 let _ = self.store.remove_image(&clone_path);
                 #[cfg(any(target_os = "linux", target_os = "freebsd"))]
@@ -276,65 +436,146 @@ impl Runtime {
         self.resource.disk_used += req.disk;
         self.resource.vm_count += 1;
 
-        vm.state = VmState::Running;
-        save_vm(&self.db, &vm)?;
+        if incoming.is_none() {
+            vm.state = VmState::Running;
+            save_vm(&self.db, &vm)?;
+        }
 
         Ok(vm)
     }
 
-    pub fn stop_vm(&mut self, vm_id: &str) -> Result<()> {
+    /// Record that a VM was powered off by its engine, releasing its
+    /// CPU and memory. Its disk and network stay for the next start.
+    ///
+    /// The shutdown itself may take a while, so the agent runs it
+    /// without holding the runtime lock; see [`engine::VmEngine::stop`].
+    pub fn mark_stopped(&mut self, vm_id: &str) -> Result<()> {
         let mut vm = load_vm(&self.db, vm_id)?.ok_or_else(|| eg!("VM not found: {}", vm_id))?;
 
-        match vm.state {
-            VmState::Running => {}
-            VmState::Paused | VmState::Stopped => return Ok(()),
+        let prev_state = vm.state;
+        match prev_state {
+            VmState::Running | VmState::Paused | VmState::Hibernated => {}
+            VmState::Stopped => return Ok(()),
             _ => return Err(eg!("cannot stop VM in state {}", vm.state)),
         }
 
-        let eng = engine::create_engine(vm.engine);
-        eng.stop(&vm).c(d!("stop VM"))?;
-
-        // FC/QEMU pause (preserve process); others fully stop
-        let pauses = matches!(vm.engine, Engine::Qemu | Engine::Firecracker);
-        if pauses {
-            vm.state = VmState::Paused;
+        vm.state = VmState::Stopped;
+        save_vm(&self.db, &vm)?;
+        if prev_state == VmState::Hibernated {
+            // Its saved memory is of no use for a cold boot
+            let _ = std::fs::remove_dir_all(self.state_dir(vm_id));
         } else {
-            vm.state = VmState::Stopped;
-            self.resource.cpu_used = self.resource.cpu_used.saturating_sub(vm.cpu);
-            self.resource.mem_used = self.resource.mem_used.saturating_sub(vm.mem);
+            self.release(&vm);
+        }
+
+        Ok(())
+    }
+
+    /// Record that a VM was hibernated by its engine, releasing its CPU
+    /// and memory. Like stopping, the agent runs the engine part
+    /// without holding the runtime lock.
+    pub fn mark_hibernated(&mut self, vm_id: &str) -> Result<()> {
+        let mut vm = load_vm(&self.db, vm_id)?.ok_or_else(|| eg!("VM not found: {}", vm_id))?;
+
+        match vm.state {
+            VmState::Running | VmState::Paused => {}
+            VmState::Hibernated => return Ok(()),
+            _ => return Err(eg!("cannot hibernate VM in state {}", vm.state)),
         }
+
+        vm.state = VmState::Hibernated;
         save_vm(&self.db, &vm)?;
+        self.release(&vm);
 
         Ok(())
     }
 
+    /// Directory beside the VM's image clone that holds its memory
+    /// while it is hibernated.
+    pub fn state_dir(&self, vm_id: &str) -> String {
+        format!("{}/state-{}", self.runtime_dir, vm_id)
+    }
+
+    /// Give the CPU and memory of a VM back to the host.
+    fn release(&mut self, vm: &Vm) {
+        self.resource.cpu_used = self.resource.cpu_used.saturating_sub(vm.cpu);
+        self.resource.mem_used = self.resource.mem_used.saturating_sub(vm.mem);
+    }
+
+    /// Start a stopped VM, cold-booting it from its disk, or resume a
+    /// suspended or hibernated one.
     pub fn start_vm(&mut self, vm_id: &str) -> Result<()> {
         let mut vm = load_vm(&self.db, vm_id)?.ok_or_else(|| eg!("VM not found: {}", vm_id))?;
 
-        let prev_state = vm.state;
-        match prev_state {
-            VmState::Stopped | VmState::Paused => {}
+        match vm.state {
+            VmState::Stopped => {}
+            VmState::Paused | VmState::Hibernated => return self.resume_vm(vm_id),
             VmState::Running => return Ok(()),
             _ => return Err(eg!("cannot start VM in state {}", vm.state)),
         }
 
-        // Only check/allocate resources when resuming from fully Stopped
-        if prev_state == VmState::Stopped && !self.resource.can_fit(vm.cpu, vm.mem, 0) {
+        if !self.resource.can_fit(vm.cpu, vm.mem, 0) {
             return Err(eg!("insufficient resources to restart VM"));
         }
 
-        let eng = engine::create_engine(vm.engine);
-        eng.start(&vm).c(d!("start VM"))?;
+        let clone_path = format!("{}/clone-{}", self.runtime_dir, vm.id);
+        let disk_path = self.store.resolve_disk(&clone_path);
+        let eng = metrics::engine(vm.engine);
+        eng.start(&vm, &disk_path, self.store.disk_format())
+            .c(d!("start VM"))?;
 
         vm.state = VmState::Running;
         save_vm(&self.db, &vm)?;
+        self.resource.cpu_used += vm.cpu;
+        self.resource.mem_used += vm.mem;
 
-        if prev_state == VmState::Stopped {
-            self.resource.cpu_used += vm.cpu;
-            self.resource.mem_used += vm.mem;
+        Ok(())
+    }
+
+    /// Pause a running VM in place. It keeps its CPU and memory.
+    pub fn suspend_vm(&mut self, vm_id: &str) -> Result<()> {
+        let mut vm = load_vm(&self.db, vm_id)?.ok_or_else(|| eg!("VM not found: {}", vm_id))?;
+
+        match vm.state {
+            VmState::Running => {}
+            VmState::Paused => return Ok(()),
+            _ => return Err(eg!("cannot suspend VM in state {}", vm.state)),
         }
 
-        Ok(())
+        let eng = metrics::engine(vm.engine);
+        eng.suspend(&vm).c(d!("suspend VM"))?;
+
+        vm.state = VmState::Paused;
+        save_vm(&self.db, &vm)
+    }
+
+    /// Resume a suspended VM, or restore a hibernated one from its
+    /// saved memory.
+    pub fn resume_vm(&mut self, vm_id: &str) -> Result<()> {
+        let mut vm = load_vm(&self.db, vm_id)?.ok_or_else(|| eg!("VM not found: {}", vm_id))?;
+
+        let eng = metrics::engine(vm.engine);
+        match vm.state {
+            VmState::Paused => eng.resume(&vm).c(d!("resume VM"))?,
+            VmState::Hibernated => {
+                if !self.resource.can_fit(vm.cpu, vm.mem, 0) {
+                    return Err(eg!("insufficient resources to restore VM"));
+                }
+                let clone_path = format!("{}/clone-{}", self.runtime_dir, vm.id);
+                let disk_path = self.store.resolve_disk(&clone_path);
+                let state_dir = self.state_dir(vm_id);
+                eng.restore(&vm, &disk_path, self.store.disk_format(), &state_dir)
+                    .c(d!("restore VM"))?;
+                let _ = std::fs::remove_dir_all(&state_dir);
+                self.resource.cpu_used += vm.cpu;
+                self.resource.mem_used += vm.mem;
+            }
+            VmState::Running => return Ok(()),
+            _ => return Err(eg!("cannot resume VM in state {}", vm.state)),
+        }
+
+        vm.state = VmState::Running;
+        save_vm(&self.db, &vm)
     }
 
     pub fn destroy_vm(&mut self, vm_id: &str) -> Result<()> {
@@ -343,7 +584,7 @@ impl Runtime {
             None => return Ok(()),
         };
 
-        let eng = engine::create_engine(vm.engine);
+        let eng = metrics::engine(vm.engine);
         let _ = eng.destroy(&vm);
 
         // Clean up host-managed networking and image clones.
@@ -353,12 +594,16 @@ impl Runtime {
             net::remove_port_forwards(&vm.ip).unwrap_or(());
             net::allow_outgoing(&vm.ip).unwrap_or(());
             net::destroy_tap(vm_id).unwrap_or(());
+            if vm.state == VmState::Creating {
+                net::remove_migration_rules(vm_id).unwrap_or(());
+            }
         }
 
         if vm.engine != Engine::Docker {
             let clone_path = format!("{}/clone-{}", self.runtime_dir, vm_id);
             let _ = self.store.remove_image(&clone_path);
         }
+        let _ = std::fs::remove_dir_all(self.state_dir(vm_id));
 
         if vm.state == VmState::Running
             || vm.state == VmState::Paused
@@ -375,10 +620,165 @@ impl Runtime {
         Ok(())
     }
 
+    /// Take a named disk snapshot of a VM.
+    ///
+    /// QEMU and Firecracker VMs are paused for the duration of the
+    /// snapshot so the captured disk is consistent.
+    pub fn snapshot_vm(&mut self, vm_id: &str, name: &str) -> Result<()> {
+        validate_name(name, "snapshot name").map_err(|e| eg!(e))?;
+        let (vm, clone_path) = self.snapshot_target(vm_id)?;
+
+        let eng = metrics::engine(vm.engine);
+        let pause =
+            vm.state == VmState::Running && matches!(vm.engine, Engine::Qemu | Engine::Firecracker);
+        if pause {
+            eng.suspend(&vm).c(d!("pause VM for snapshot"))?;
+        }
+
+        let result = self.store.snapshot_image(&clone_path, name);
+
+        if pause && let Err(e) = eng.resume(&vm) {
+            warn!("failed to resume VM {} after snapshot: {e}", vm.id);
+        }
+
+        result.c(d!("snapshot image"))
+    }
+
+    /// List the disk snapshots of a VM.
+    pub fn list_vm_snapshots(&self, vm_id: &str) -> Result<Vec<String>> {
+        let (_, clone_path) = self.snapshot_target(vm_id)?;
+        self.store.list_image_snapshots(&clone_path)
+    }
+
+    /// Roll a VM back to a snapshot.
+    ///
+    /// The running instance is torn down, the disk is restored, and the
+    /// VM is cold-booted from the restored disk. Snapshots newer than
+    /// `name` are deleted with `force`, and block the rollback otherwise.
+    pub fn rollback_vm(&mut self, vm_id: &str, name: &str, force: bool) -> Result<()> {
+        validate_name(name, "snapshot name").map_err(|e| eg!(e))?;
+        let (mut vm, clone_path) = self.snapshot_target(vm_id)?;
+
+        // Stopped and hibernated VMs hold no CPU or memory until booted
+        let prev_state = vm.state;
+        let holds_nothing = matches!(prev_state, VmState::Stopped | VmState::Hibernated);
+        if holds_nothing && !self.resource.can_fit(vm.cpu, vm.mem, 0) {
+            return Err(eg!("insufficient resources to restart VM"));
+        }
+
+        // Refuse before the VM is torn down
+        let snaps = self.store.list_image_snapshots(&clone_path)?;
+        storage::newer_snapshots(&snaps, name, force)?;
+
+        let eng = metrics::engine(vm.engine);
+        eng.destroy(&vm).c(d!("tear down VM"))?;
+        wait_for_exit(eng.as_ref(), &vm);
+
+        self.store
+            .rollback_image(&clone_path, name, force)
+            .c(d!("rollback image"))?;
+
+        // Tearing the VM down dropped its seed ISO too: boot it as it
+        // was created, keys included
+        let disk_path = self.store.resolve_disk(&clone_path);
+        if let Err(e) = eng.create(&vm, &disk_path, self.store.disk_format(), &vm.ssh_keys) {
+            vm.state = VmState::Failed;
+            save_vm(&self.db, &vm)?;
+            return Err(e).c(d!("reboot VM after rollback"));
+        }
+
+        if holds_nothing {
+            self.resource.cpu_used += vm.cpu;
+            self.resource.mem_used += vm.mem;
+        }
+        // Saved memory no longer matches the restored disk
+        let _ = std::fs::remove_dir_all(self.state_dir(vm_id));
+        vm.state = VmState::Running;
+        save_vm(&self.db, &vm)
+    }
+
+    /// Delete a single disk snapshot of a VM.
+    pub fn delete_vm_snapshot(&self, vm_id: &str, name: &str) -> Result<()> {
+        validate_name(name, "snapshot name").map_err(|e| eg!(e))?;
+        let (_, clone_path) = self.snapshot_target(vm_id)?;
+        self.store.remove_image_snapshot(&clone_path, name)
+    }
+
+    /// Resolve a VM and the path of its image clone for snapshot operations.
+    fn snapshot_target(&self, vm_id: &str) -> Result<(Vm, String)> {
+        let vm = load_vm(&self.db, vm_id)?.ok_or_else(|| eg!("VM not found: {}", vm_id))?;
+        if vm.engine == Engine::Docker {
+            return Err(eg!("snapshots are not supported for docker containers"));
+        }
+        if vm.state == VmState::Creating {
+            return Err(eg!("VM {} is still being created", vm_id));
+        }
+        let clone_path = format!("{}/clone-{}", self.runtime_dir, vm.id);
+        Ok((vm, clone_path))
+    }
+
+    /// Create or update an env network on this host.
+    ///
+    /// Idempotent: the controller calls it again whenever the set of
+    /// hosts carrying the network changes.
+    pub fn ensure_network(&mut self, vni: u32, req: &NetworkReq) -> Result<()> {
+        #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+        net::ensure_overlay(vni, &req.peers).c(d!("env network setup"))?;
+        save_network(&self.db, vni, req)
+    }
+
+    /// Tear down an env network once no local VM is attached to it.
+    pub fn remove_network(&mut self, vni: u32) -> Result<()> {
+        let attached = load_all_vms(&self.db)?
+            .into_iter()
+            .filter(|vm| vm.overlay.as_ref().is_some_and(|o| o.vni == vni))
+            .count();
+        if attached > 0 {
+            return Err(eg!(
+                "env network {} is still used by {} VM(s)",
+                vni,
+                attached
+            ));
+        }
+        #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+        net::destroy_overlay(vni)?;
+        delete_network(&self.db, vni)
+    }
+
     pub fn get_vm(&self, vm_id: &str) -> Option<Vm> {
         load_vm(&self.db, vm_id).ok().flatten()
     }
 
+    /// Store one round of usage samples: `vms` by VM ID, and `host` for
+    /// the host as a whole if given.
+    pub fn record_usage(
+        &self,
+        vms: &[(String, UsageSample)],
+        host: Option<&UsageSample>,
+    ) -> Result<()> {
+        let tx = self.db.unchecked_transaction().c(d!("begin"))?;
+        for (id, sample) in vms {
+            save_usage(&tx, id, sample, usage::KEPT_SAMPLES)?;
+        }
+        if let Some(sample) = host {
+            save_usage(&tx, HOST_USAGE, sample, usage::KEPT_SAMPLES)?;
+        }
+        tx.commit().c(d!("commit usage"))
+    }
+
+    /// Usage samples of a VM taken after `since`, oldest first.
+    pub fn vm_usage(&self, vm_id: &str, since: u64) -> Result<Vec<UsageSample>> {
+        if load_vm(&self.db, vm_id)?.is_none() {
+            return Err(eg!("VM not found: {}", vm_id));
+        }
+        load_usage(&self.db, vm_id, since)
+    }
+
+    /// Usage samples of the host taken after `since`, oldest first.
+    pub fn host_usage(&self, since: u64) -> Result<Vec<UsageSample>> {
+        load_usage(&self.db, HOST_USAGE, since)
+    }
+
     pub fn list_vms(&self) -> Vec<Vm> {
         load_all_vms(&self.db).unwrap_or_default()
     }
@@ -387,6 +787,26 @@ impl Runtime {
         self.store.list_images(&self.image_dir).unwrap_or_default()
     }
 
+    /// Storage backend and path (or dataset) of a VM's image clone,
+    /// whether or not the VM exists here yet.
+    ///
+    /// Disk transfers use this to do their I/O without holding the
+    /// runtime lock.
+    pub fn disk_location(&self, vm_id: &str) -> (Storage, String) {
+        (
+            self.storage,
+            format!("{}/clone-{}", self.runtime_dir, vm_id),
+        )
+    }
+
+    /// Storage backend and image directory (or parent dataset).
+    ///
+    /// Image transfers use this to do their I/O without holding the
+    /// runtime lock.
+    pub fn image_location(&self) -> (Storage, String) {
+        (self.storage, self.image_dir.clone())
+    }
+
     pub fn agent_info(&self) -> AgentInfo {
         AgentInfo {
             host_id: self.host_id.clone(),
@@ -394,6 +814,7 @@ impl Runtime {
             engines: self.engines.clone(),
             storage: self.storage,
             images: self.list_images(),
+            labels: self.labels.clone(),
         }
     }
 }
@@ -401,7 +822,7 @@ impl Runtime {
 // ── SQLite Schema & Operations ──────────────────────────────────────
 
 /// Current agent schema version.
-const SCHEMA_VERSION: u32 = 1;
+const SCHEMA_VERSION: u32 = 3;
 
 fn init_db(db: &Connection) -> Result<()> {
     db.execute_batch(
@@ -434,11 +855,34 @@ fn init_db(db: &Connection) -> Result<()> {
         .c(d!("migration v1"))?;
     }
 
-    // Future migrations: if current < 2 { ... }
+    if current < 2 {
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS networks (
+                vni      INTEGER PRIMARY KEY,
+                data     TEXT NOT NULL
+            );",
+        )
+        .c(d!("migration v2"))?;
+    }
+
+    if current < 3 {
+        // `subject` is a VM ID, or HOST_USAGE for the host as a whole
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS usage (
+                subject  TEXT NOT NULL,
+                at       INTEGER NOT NULL,
+                data     TEXT NOT NULL,
+                PRIMARY KEY (subject, at)
+            );",
+        )
+        .c(d!("migration v3"))?;
+    }
+
+    // Future migrations: if current < 4 { ... }
 
     set_schema_version(db, SCHEMA_VERSION)?;
     if current < SCHEMA_VERSION {
-        eprintln!("agent DB migrated: v{current} → v{SCHEMA_VERSION}");
+        info!("agent DB migrated: v{current} → v{SCHEMA_VERSION}");
     }
 
     Ok(())
@@ -559,6 +1003,86 @@ fn load_all_vms(db: &Connection) -> Result<Vec<Vm>> {
 fn delete_vm(db: &Connection, id: &str) -> Result<()> {
     db.execute("DELETE FROM vms WHERE id = ?1", rusqlite::params![id])
         .c(d!("delete VM"))?;
+    db.execute(
+        "DELETE FROM usage WHERE subject = ?1",
+        rusqlite::params![id],
+    )
+    .c(d!("delete VM usage"))?;
+    Ok(())
+}
+
+/// `usage` subject of the samples for the host as a whole.
+const HOST_USAGE: &str = "";
+
+/// Add a usage sample of `subject`, dropping all but its `keep` latest.
+fn save_usage(db: &Connection, subject: &str, sample: &UsageSample, keep: u32) -> Result<()> {
+    let data = serde_json::to_string(sample).c(d!("serialize usage"))?;
+    db.execute(
+        "INSERT OR REPLACE INTO usage (subject, at, data) VALUES (?1, ?2, ?3)",
+        rusqlite::params![subject, sample.at, data],
+    )
+    .c(d!("save usage"))?;
+    db.execute(
+        "DELETE FROM usage WHERE subject = ?1 AND at NOT IN
+             (SELECT at FROM usage WHERE subject = ?1 ORDER BY at DESC LIMIT ?2)",
+        rusqlite::params![subject, keep],
+    )
+    .c(d!("trim usage"))?;
+    Ok(())
+}
+
+/// Usage samples of `subject` taken after `since`, oldest first.
+fn load_usage(db: &Connection, subject: &str, since: u64) -> Result<Vec<UsageSample>> {
+    let mut stmt = db
+        .prepare("SELECT data FROM usage WHERE subject = ?1 AND at > ?2 ORDER BY at")
+        .c(d!("prepare list usage"))?;
+    let rows = stmt
+        .query_map(rusqlite::params![subject, since], |row| {
+            row.get::<_, String>(0)
+        })
+        .c(d!("query usage"))?;
+    let mut samples = Vec::new();
+    for row in rows {
+        let data = row.c(d!("read row"))?;
+        samples.push(serde_json::from_str(&data).c(d!("deserialize usage"))?);
+    }
+    Ok(samples)
+}
+
+fn save_network(db: &Connection, vni: u32, req: &NetworkReq) -> Result<()> {
+    let data = serde_json::to_string(req).c(d!("serialize network"))?;
+    db.execute(
+        "INSERT OR REPLACE INTO networks (vni, data) VALUES (?1, ?2)",
+        rusqlite::params![vni, data],
+    )
+    .c(d!("save network"))?;
+    Ok(())
+}
+
+fn load_all_networks(db: &Connection) -> Result<BTreeMap<u32, NetworkReq>> {
+    let mut stmt = db
+        .prepare("SELECT vni, data FROM networks")
+        .c(d!("prepare list networks"))?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?))
+        })
+        .c(d!("query all networks"))?;
+    let mut nets = BTreeMap::new();
+    for row in rows {
+        let (vni, data) = row.c(d!("read row"))?;
+        let req: NetworkReq = serde_json::from_str(&data).c(d!("deserialize network"))?;
+        nets.insert(vni, req);
+    }
+    Ok(nets)
+}
+
+fn delete_network(db: &Connection, vni: u32) -> Result<()> {
+    db.execute(
+        "DELETE FROM networks WHERE vni = ?1",
+        rusqlite::params![vni],
+    )
+    .c(d!("delete network"))?;
     Ok(())
 }
 
@@ -571,6 +1095,17 @@ fn now() -> u64 {
         .as_secs()
 }
 
+/// Wait (up to a few seconds) for a destroyed VM's process to exit so
+/// its disk is no longer held open.
+fn wait_for_exit(eng: &dyn engine::VmEngine, vm: &Vm) {
+    for _ in 0..50 {
+        if matches!(eng.state(vm), Ok(VmState::Stopped)) {
+            return;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+}
+
 fn detect_engines() -> Vec<Engine> {
     let mut engines = Vec::new();
 
@@ -669,14 +1204,13 @@ mod tests {
             env_id: "env1".into(),
             host_id: "h1".into(),
             image: "ubuntu".into(),
-            engine: Engine::Qemu,
             cpu: 2,
             mem: 1024,
             disk: 40960,
             ip: "10.10.0.2".into(),
-            port_map: BTreeMap::new(),
             state,
             created_at: 1000,
+            ..Default::default()
         }
     }
 
@@ -716,6 +1250,34 @@ mod tests {
         assert!(load_vm(&db, "vm1").unwrap().is_none());
     }
 
+    #[test]
+    fn db_usage_keeps_latest_samples_per_subject() {
+        let db = test_db();
+        let sample = |at| UsageSample {
+            at,
+            cpu: Some(0.5),
+            ..Default::default()
+        };
+        for at in 1..=5 {
+            save_usage(&db, "vm1", &sample(at), 3).unwrap();
+        }
+        save_usage(&db, HOST_USAGE, &sample(5), 3).unwrap();
+
+        let ats: Vec<u64> = load_usage(&db, "vm1", 0)
+            .unwrap()
+            .iter()
+            .map(|s| s.at)
+            .collect();
+        assert_eq!(ats, [3, 4, 5]);
+        assert_eq!(load_usage(&db, "vm1", 4).unwrap(), [sample(5)]);
+        assert_eq!(load_usage(&db, HOST_USAGE, 0).unwrap().len(), 1);
+
+        // A VM's samples go with it
+        save_vm(&db, &make_vm("vm1", VmState::Running)).unwrap();
+        delete_vm(&db, "vm1").unwrap();
+        assert!(load_usage(&db, "vm1", 0).unwrap().is_empty());
+    }
+
     #[test]
     fn db_delete_nonexistent_vm() {
         let db = test_db();
@@ -738,6 +1300,25 @@ mod tests {
         assert_eq!(load_all_vms(&db).unwrap().len(), 1);
     }
 
+    #[test]
+    fn db_networks_roundtrip() {
+        let db = test_db();
+        let req = NetworkReq {
+            peers: vec!["10.0.0.2".into(), "10.0.0.3".into()],
+        };
+        save_network(&db, 10001, &req).unwrap();
+        save_network(&db, 10001, &NetworkReq::default()).unwrap();
+        save_network(&db, 10002, &req).unwrap();
+
+        let nets = load_all_networks(&db).unwrap();
+        assert_eq!(nets.len(), 2);
+        assert!(nets[&10001].peers.is_empty());
+        assert_eq!(nets[&10002].peers, req.peers);
+
+        delete_network(&db, 10001).unwrap();
+        assert!(!load_all_networks(&db).unwrap().contains_key(&10001));
+    }
+
     #[test]
     fn db_schema_version_persisted() {
         let db = test_db();
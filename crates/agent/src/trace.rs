@@ -0,0 +1,71 @@
+//! Request spans and the runtime log filter.
+//!
+//! Every call is served in a `request` span carrying the controller's
+//! request ID, or a new one for calls that did not come through the
+//! controller. Blocking work done for a call is started with
+//! [`blocking`] so that what it logs stays in that span.
+
+use axum::Json;
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use tokio::task::JoinHandle;
+use tracing::{Instrument, Span, info, info_span};
+use ttcore::api::{ApiResp, LogLevel};
+use ttcore::log::{self, REQUEST_ID_HEADER};
+
+/// Longest request ID taken from the header; longer ones are replaced.
+const MAX_REQUEST_ID: usize = 64;
+
+/// Middleware that runs each call in its `request` span.
+pub async fn request_span(req: Request<Body>, next: Next) -> Response {
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= MAX_REQUEST_ID)
+        .map_or_else(log::new_request_id, str::to_string);
+    let span = info_span!(
+        "request",
+        request_id = %id,
+        method = %req.method(),
+        path = req.uri().path(),
+    );
+    next.run(req).instrument(span).await
+}
+
+/// `spawn_blocking` in the current span.
+pub fn blocking<F, R>(f: F) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let span = Span::current();
+    tokio::task::spawn_blocking(move || span.in_scope(f))
+}
+
+/// GET /api/log-level
+pub async fn get_log_level() -> impl IntoResponse {
+    match log::filter() {
+        Some(filter) => (StatusCode::OK, Json(ApiResp::success(LogLevel { filter }))),
+        None => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResp::err("logging is not initialized")),
+        ),
+    }
+}
+
+/// PUT /api/log-level — replace the log filter until the agent restarts.
+pub async fn set_log_level(Json(req): Json<LogLevel>) -> impl IntoResponse {
+    match log::set_filter(&req.filter) {
+        Ok(()) => {
+            info!("log filter set to {}", req.filter);
+            (StatusCode::OK, Json(ApiResp::success(req)))
+        }
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(ApiResp::<LogLevel>::err(e.to_string())),
+        ),
+    }
+}
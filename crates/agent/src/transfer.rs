@@ -0,0 +1,381 @@
+//! Image distribution endpoints.
+//!
+//! The controller copies base images between hosts by reading a manifest
+//! from both sides and then either piping a `zfs send` stream into
+//! `zfs recv` (zvol) or relaying checksummed chunks into a staging path
+//! that is verified before install (file). All I/O runs without holding
+//! the runtime lock so large copies do not block VM operations.
+//!
+//! VM migrations move a VM's image clone with the same I/O, through the
+//! disk endpoints in [`crate::migrate`].
+
+use crate::handler::{AppState, lock_rt};
+use crate::trace;
+use axum::Json;
+use axum::body::{Body, Bytes};
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use futures_util::StreamExt;
+use serde::Deserialize;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+use tracing::{Instrument, warn};
+use ttcore::api::*;
+use ttcore::model::{Storage, validate_name};
+use ttcore::storage::file::FileStore;
+use ttcore::storage::zvol::ZvolStore;
+use ttcore::storage::{self, sha256_hex};
+
+#[derive(Debug, Deserialize)]
+pub struct SendQuery {
+    /// Snapshot the receiver already has; omit for a full stream.
+    pub from: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChunkQuery {
+    /// Path inside the image; empty for single-file images.
+    #[serde(default)]
+    pub file: String,
+    pub offset: u64,
+    pub len: Option<u64>,
+}
+
+/// GET /api/images/:name/manifest — describe a local base image.
+pub async fn get_manifest(State(rt): State<AppState>, Path(name): Path<String>) -> Response {
+    match locate(&rt, &name) {
+        Ok((kind, path)) => manifest(kind, path, name, "image not found").await,
+        Err((code, msg)) => fail(code, msg),
+    }
+}
+
+/// GET /api/images/:name/send — stream the image as a zfs send stream.
+///
+/// Sends the newest snapshot of the image, incrementally from `from`
+/// when given.
+pub async fn send_image(
+    State(rt): State<AppState>,
+    Path(name): Path<String>,
+    Query(q): Query<SendQuery>,
+) -> Response {
+    let dataset = match locate_kind(&rt, &name, Storage::Zvol) {
+        Ok(d) => d,
+        Err((code, msg)) => return fail(code, msg),
+    };
+    let snap = match ZvolStore.ensure_send_snapshot(&dataset) {
+        Ok(s) => s,
+        Err(e) => return fail(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+    send_stream(&dataset, &snap, q.from.as_deref())
+}
+
+/// PUT /api/images/:name/recv — receive a zfs send stream into the image.
+pub async fn recv_image(
+    State(rt): State<AppState>,
+    Path(name): Path<String>,
+    body: Body,
+) -> Response {
+    let dataset = match locate_kind(&rt, &name, Storage::Zvol) {
+        Ok(d) => d,
+        Err((code, msg)) => return fail(code, msg),
+    };
+    recv_stream(&dataset, body).await
+}
+
+/// GET /api/images/:name/chunk — read one chunk of a file-backed image.
+///
+/// The body's SHA-256 is returned in the `x-tt-sha256` header.
+pub async fn read_chunk(
+    State(rt): State<AppState>,
+    Path(name): Path<String>,
+    Query(q): Query<ChunkQuery>,
+) -> Response {
+    let path = match locate_kind(&rt, &name, Storage::File) {
+        Ok(p) => p,
+        Err((code, msg)) => return fail(code, msg),
+    };
+    read_chunk_of(path, q).await
+}
+
+/// PUT /api/images/:name/chunk — stage one chunk of an incoming image.
+///
+/// Rejected unless the body matches the `x-tt-sha256` header.
+pub async fn write_chunk(
+    State(rt): State<AppState>,
+    Path(name): Path<String>,
+    Query(q): Query<ChunkQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let staging = match locate_kind(&rt, &name, Storage::File) {
+        Ok(p) => staging_path(&p, &name),
+        Err((code, msg)) => return fail(code, msg),
+    };
+    write_chunk_to(staging, q, &headers, body).await
+}
+
+/// DELETE /api/images/:name/incoming — discard a partially staged image.
+pub async fn discard_incoming(State(rt): State<AppState>, Path(name): Path<String>) -> Response {
+    let staging = match locate_kind(&rt, &name, Storage::File) {
+        Ok(p) => staging_path(&p, &name),
+        Err((code, msg)) => return fail(code, msg),
+    };
+    discard(&staging)
+}
+
+/// POST /api/images/:name/install — verify the staged image and install it.
+pub async fn install_image(
+    State(rt): State<AppState>,
+    Path(name): Path<String>,
+    Json(manifest): Json<ImageManifest>,
+) -> Response {
+    let path = match locate_kind(&rt, &name, Storage::File) {
+        Ok(p) => p,
+        Err((code, msg)) => return fail(code, msg),
+    };
+    let staging = staging_path(&path, &name);
+    install(staging, path, manifest).await
+}
+
+// ── Transfer I/O ────────────────────────────────────────────────────
+
+/// Describe the image at `path`; `missing` is the error when there is none.
+pub(crate) async fn manifest(
+    kind: Storage,
+    path: String,
+    name: String,
+    missing: &'static str,
+) -> Response {
+    let result = trace::blocking(move || -> ruc::Result<Option<ImageManifest>> {
+        if !storage::create_store(kind).image_exists(&path)? {
+            return Ok(None);
+        }
+        let mut manifest = ImageManifest {
+            name,
+            storage: kind,
+            snapshots: vec![],
+            is_dir: false,
+            files: vec![],
+            links: vec![],
+        };
+        match kind {
+            Storage::Zvol => manifest.snapshots = ZvolStore.snapshot_names(&path)?,
+            Storage::File => {
+                (manifest.is_dir, manifest.files, manifest.links) = FileStore.manifest(&path)?
+            }
+        }
+        Ok(Some(manifest))
+    })
+    .await;
+
+    match result {
+        Ok(Ok(Some(m))) => (StatusCode::OK, Json(ApiResp::success(m))).into_response(),
+        Ok(Ok(None)) => fail(StatusCode::NOT_FOUND, missing),
+        Ok(Err(e)) => fail(StatusCode::INTERNAL_SERVER_ERROR, e),
+        Err(e) => fail(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// Stream snapshot `snap` of `dataset` with zfs send, incrementally
+/// from snapshot `from` when given.
+pub(crate) fn send_stream(dataset: &str, snap: &str, from: Option<&str>) -> Response {
+    if let Some(from) = from
+        && let Err(e) = validate_name(from, "snapshot")
+    {
+        return fail(StatusCode::BAD_REQUEST, e);
+    }
+
+    let to = format!("{dataset}@{snap}");
+    let cmd = match from {
+        Some(from) => ZvolStore.send_incremental_cmd(&format!("{dataset}@{from}"), &to),
+        None => ZvolStore.send_cmd(&to),
+    };
+
+    let mut child = match tokio::process::Command::from(cmd)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+    {
+        Ok(c) => c,
+        Err(e) => return fail(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+    let Some(stdout) = child.stdout.take() else {
+        return fail(StatusCode::INTERNAL_SERVER_ERROR, "zfs send has no stdout");
+    };
+
+    // A failed send shows up on the receiver as a truncated stream;
+    // log it here too so the source host has a record.
+    tokio::spawn(
+        async move {
+            if let Ok(status) = child.wait().await
+                && !status.success()
+            {
+                warn!("zfs send of {to} exited with {status}");
+            }
+        }
+        .in_current_span(),
+    );
+
+    (StatusCode::OK, Body::from_stream(ReaderStream::new(stdout))).into_response()
+}
+
+/// Receive a zfs send stream into `dataset`.
+pub(crate) async fn recv_stream(dataset: &str, body: Body) -> Response {
+    let mut child = match tokio::process::Command::from(ZvolStore.recv_cmd(dataset))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(c) => c,
+        Err(e) => return fail(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+    let Some(mut stdin) = child.stdin.take() else {
+        return fail(StatusCode::INTERNAL_SERVER_ERROR, "zfs recv has no stdin");
+    };
+
+    let mut stream = body.into_data_stream();
+    let mut copy_err = None;
+    while let Some(chunk) = stream.next().await {
+        let res = match chunk {
+            Ok(data) => stdin.write_all(&data).await.map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = res {
+            copy_err = Some(e);
+            break;
+        }
+    }
+    drop(stdin);
+
+    // Never let zfs commit a stream we could not deliver in full
+    if let Some(e) = copy_err {
+        let _ = child.kill().await;
+        return fail(StatusCode::BAD_GATEWAY, format!("stream interrupted: {e}"));
+    }
+
+    match child.wait_with_output().await {
+        Ok(out) if out.status.success() => {
+            (StatusCode::OK, Json(ApiRespEmpty::ok())).into_response()
+        }
+        Ok(out) => fail(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!(
+                "zfs recv failed: {}",
+                String::from_utf8_lossy(&out.stderr).trim()
+            ),
+        ),
+        Err(e) => fail(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// Read one chunk of the file-backed image at `path`, returning its
+/// SHA-256 in the `x-tt-sha256` header.
+pub(crate) async fn read_chunk_of(path: String, q: ChunkQuery) -> Response {
+    let len = q.len.unwrap_or(IMAGE_CHUNK_SIZE).min(IMAGE_CHUNK_SIZE);
+
+    let result = trace::blocking(move || FileStore.read_chunk(&path, &q.file, q.offset, len)).await;
+    match result {
+        Ok(Ok(data)) => {
+            let sum = sha256_hex(&data);
+            (StatusCode::OK, [(CHUNK_SHA256_HEADER, sum)], data).into_response()
+        }
+        Ok(Err(e)) => fail(StatusCode::INTERNAL_SERVER_ERROR, e),
+        Err(e) => fail(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// Stage one chunk under `staging`, unless it does not match the
+/// `x-tt-sha256` header.
+pub(crate) async fn write_chunk_to(
+    staging: String,
+    q: ChunkQuery,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Response {
+    let expected = headers
+        .get(CHUNK_SHA256_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if expected != sha256_hex(&body) {
+        return fail(StatusCode::BAD_REQUEST, "chunk checksum mismatch");
+    }
+
+    let result =
+        trace::blocking(move || FileStore.write_chunk(&staging, &q.file, q.offset, &body)).await;
+    match result {
+        Ok(Ok(())) => (StatusCode::OK, Json(ApiRespEmpty::ok())).into_response(),
+        Ok(Err(e)) => fail(StatusCode::INTERNAL_SERVER_ERROR, e),
+        Err(e) => fail(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// Remove whatever was staged under `staging`.
+pub(crate) fn discard(staging: &str) -> Response {
+    match storage::create_store(Storage::File).remove_image(staging) {
+        Ok(()) => (StatusCode::OK, Json(ApiRespEmpty::ok())).into_response(),
+        Err(e) => fail(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// Verify what was staged under `staging` against `manifest` and move
+/// it to `path`.
+pub(crate) async fn install(staging: String, path: String, manifest: ImageManifest) -> Response {
+    let result = trace::blocking(move || {
+        FileStore.install_image(
+            &staging,
+            &path,
+            manifest.is_dir,
+            &manifest.files,
+            &manifest.links,
+        )
+    })
+    .await;
+    match result {
+        Ok(Ok(())) => (StatusCode::OK, Json(ApiRespEmpty::ok())).into_response(),
+        Ok(Err(e)) => fail(StatusCode::INTERNAL_SERVER_ERROR, e),
+        Err(e) => fail(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+// ── Helpers ─────────────────────────────────────────────────────────
+
+/// Validate an image name and resolve its storage kind and path.
+fn locate(
+    rt: &AppState,
+    name: &str,
+) -> std::result::Result<(Storage, String), (StatusCode, String)> {
+    if let Err(e) = validate_name(name, "image") {
+        return Err((StatusCode::BAD_REQUEST, e));
+    }
+    let (kind, image_dir) = lock_rt(rt).image_location();
+    Ok((kind, format!("{image_dir}/{name}")))
+}
+
+/// Like [`locate`], but reject hosts using a different storage backend.
+fn locate_kind(
+    rt: &AppState,
+    name: &str,
+    want: Storage,
+) -> std::result::Result<String, (StatusCode, String)> {
+    let (kind, path) = locate(rt, name)?;
+    if kind != want {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("this host uses {kind} storage, not {want}"),
+        ));
+    }
+    Ok(path)
+}
+
+/// Hidden sibling of an image path where incoming chunks are staged.
+pub(crate) fn staging_path(image_path: &str, name: &str) -> String {
+    let dir = image_path.strip_suffix(name).unwrap_or(image_path);
+    format!("{dir}.incoming-{name}")
+}
+
+pub(crate) fn fail(code: StatusCode, msg: impl ToString) -> Response {
+    (code, Json(ApiRespEmpty::err(msg.to_string()))).into_response()
+}
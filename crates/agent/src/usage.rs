@@ -0,0 +1,224 @@
+//! Usage history: what VMs actually use, as opposed to what they were
+//! allocated.
+//!
+//! Every [`SAMPLE_INTERVAL`] the agent reads the usage counters of its
+//! running VMs and stores the rates since the previous reading as a
+//! [`UsageSample`] per VM, plus their sum for the host, keeping the
+//! last [`KEPT_SAMPLES`] of each. The controller serves them at
+//! `/api/vms/{id}/usage` and `/api/hosts/{id}/usage`.
+
+use crate::handler::{AppState, lock_rt};
+use axum::Json;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::warn;
+use ttcore::api::{ApiResp, UsageSample};
+use ttcore::engine::{self, VmStats};
+use ttcore::model::VmState;
+
+/// How often VMs are sampled.
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Samples kept per VM and for the host: a day's worth.
+pub const KEPT_SAMPLES: u32 = 24 * 60;
+
+/// Sample the usage of the host's VMs until the agent exits.
+pub async fn run(rt: AppState) {
+    let mut tick = tokio::time::interval(SAMPLE_INTERVAL);
+    // The last counters read from each running VM, and when
+    let mut last: HashMap<String, (u64, VmStats)> = HashMap::new();
+    let mut first = true;
+    loop {
+        tick.tick().await;
+        let vms = lock_rt(&rt).list_vms();
+        let read = tokio::task::spawn_blocking(move || {
+            vms.into_iter()
+                .filter(|vm| matches!(vm.state, VmState::Running | VmState::Paused))
+                .filter_map(|vm| {
+                    let stats = engine::create_engine(vm.engine).stats(&vm).ok()?;
+                    Some((vm.id, stats))
+                })
+                .collect::<Vec<_>>()
+        })
+        .await
+        .unwrap_or_default();
+
+        let at = now();
+        let mut samples = vec![];
+        let mut seen = HashMap::new();
+        for (id, stats) in read {
+            if let Some((then, prev)) = last.get(&id) {
+                samples.push((
+                    id.clone(),
+                    sample(prev, &stats, at.saturating_sub(*then), at),
+                ));
+            }
+            seen.insert(id, (at, stats));
+        }
+        // VMs that stopped start over when they run again
+        last = seen;
+
+        // The first round only has counters, no rates
+        let host = (!first).then(|| total(at, samples.iter().map(|(_, s)| s)));
+        first = false;
+        if let Err(e) = lock_rt(&rt).record_usage(&samples, host.as_ref()) {
+            warn!("failed to record usage: {e}");
+        }
+    }
+}
+
+/// Usage over the `secs` between two readings of a VM's counters.
+fn sample(prev: &VmStats, cur: &VmStats, secs: u64, at: u64) -> UsageSample {
+    let secs = secs.max(1) as f64;
+    // A counter that went down was reset, e.g. by the VM restarting
+    let rate = |a: Option<u64>, b: Option<u64>| {
+        let (a, b) = (a?, b?);
+        Some((if b >= a { b - a } else { b }) as f64 / secs)
+    };
+    let cpu = match (prev.cpu_seconds, cur.cpu_seconds) {
+        (Some(a), Some(b)) if b >= a => Some((b - a) / secs),
+        (Some(_), Some(b)) => Some(b / secs),
+        _ => None,
+    };
+    UsageSample {
+        at,
+        cpu,
+        mem_bytes: cur.mem_bytes,
+        disk_read_bps: rate(prev.disk_read_bytes, cur.disk_read_bytes),
+        disk_write_bps: rate(prev.disk_written_bytes, cur.disk_written_bytes),
+        net_rx_bps: rate(prev.net_rx_bytes, cur.net_rx_bytes),
+        net_tx_bps: rate(prev.net_tx_bytes, cur.net_tx_bytes),
+    }
+}
+
+/// The host's usage: the sum of its VMs'. A field none of the VMs
+/// could tell is left out, unless there are no VMs to tell it.
+fn total<'a>(at: u64, vms: impl Iterator<Item = &'a UsageSample>) -> UsageSample {
+    fn add<T: std::ops::Add<Output = T>>(sum: Option<T>, v: Option<T>) -> Option<T> {
+        match (sum, v) {
+            (Some(s), Some(v)) => Some(s + v),
+            (s, v) => s.or(v),
+        }
+    }
+    let mut sum = UsageSample {
+        at,
+        ..Default::default()
+    };
+    let mut any = false;
+    for s in vms {
+        any = true;
+        sum.cpu = add(sum.cpu, s.cpu);
+        sum.mem_bytes = add(sum.mem_bytes, s.mem_bytes);
+        sum.disk_read_bps = add(sum.disk_read_bps, s.disk_read_bps);
+        sum.disk_write_bps = add(sum.disk_write_bps, s.disk_write_bps);
+        sum.net_rx_bps = add(sum.net_rx_bps, s.net_rx_bps);
+        sum.net_tx_bps = add(sum.net_tx_bps, s.net_tx_bps);
+    }
+    if !any {
+        sum = UsageSample {
+            at,
+            cpu: Some(0.0),
+            mem_bytes: Some(0),
+            disk_read_bps: Some(0.0),
+            disk_write_bps: Some(0.0),
+            net_rx_bps: Some(0.0),
+            net_tx_bps: Some(0.0),
+        };
+    }
+    sum
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[derive(Deserialize)]
+pub struct UsageQuery {
+    /// Only samples taken after this Unix time.
+    #[serde(default)]
+    since: u64,
+}
+
+/// GET /api/vms/:id/usage — a VM's usage samples, oldest first.
+pub async fn vm_usage(
+    State(rt): State<AppState>,
+    Path(id): Path<String>,
+    Query(q): Query<UsageQuery>,
+) -> impl IntoResponse {
+    let rt = lock_rt(&rt);
+    match rt.vm_usage(&id, q.since) {
+        Ok(samples) => (StatusCode::OK, Json(ApiResp::success(samples))),
+        Err(e) => (
+            StatusCode::NOT_FOUND,
+            Json(ApiResp::<Vec<UsageSample>>::err(e.to_string())),
+        ),
+    }
+}
+
+/// GET /api/usage — the usage samples of the host's VMs combined.
+pub async fn host_usage(
+    State(rt): State<AppState>,
+    Query(q): Query<UsageQuery>,
+) -> impl IntoResponse {
+    let rt = lock_rt(&rt);
+    match rt.host_usage(q.since) {
+        Ok(samples) => (StatusCode::OK, Json(ApiResp::success(samples))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResp::<Vec<UsageSample>>::err(e.to_string())),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_are_rates_and_survive_counter_resets() {
+        let prev = VmStats {
+            cpu_seconds: Some(10.0),
+            mem_bytes: Some(100),
+            disk_read_bytes: Some(1000),
+            net_rx_bytes: Some(600),
+            ..Default::default()
+        };
+        let cur = VmStats {
+            cpu_seconds: Some(40.0),
+            mem_bytes: Some(200),
+            disk_read_bytes: Some(7000),
+            net_rx_bytes: Some(60),
+            net_tx_bytes: Some(5),
+            ..Default::default()
+        };
+        let s = sample(&prev, &cur, 60, 1000);
+        assert_eq!(s.cpu, Some(0.5));
+        assert_eq!(s.mem_bytes, Some(200));
+        assert_eq!(s.disk_read_bps, Some(100.0));
+        assert_eq!(s.net_rx_bps, Some(1.0));
+        assert_eq!(s.net_tx_bps, None);
+        assert_eq!(s.disk_write_bps, None);
+
+        let docker = UsageSample {
+            cpu: None,
+            mem_bytes: Some(50),
+            ..Default::default()
+        };
+        let host = total(1000, [s.clone(), docker].iter());
+        assert_eq!(host.at, 1000);
+        assert_eq!(host.cpu, Some(0.5));
+        assert_eq!(host.mem_bytes, Some(250));
+        assert_eq!(host.net_tx_bps, None);
+
+        let idle = total(1000, [].iter());
+        assert_eq!(idle.cpu, Some(0.0));
+        assert_eq!(idle.mem_bytes, Some(0));
+    }
+}
@@ -4,11 +4,18 @@ use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderValue};
 use ruc::*;
 use serde::Serialize;
 use serde::de::DeserializeOwned;
-use ttcore::api::ApiResp;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, tungstenite};
+use ttcore::api::{ApiResp, ApiRespEmpty};
+
+/// WebSocket connection to the controller.
+pub type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
 
 /// Controller API client.
 pub struct Client {
     base_url: String,
+    api_key: Option<String>,
     http: reqwest::Client,
 }
 
@@ -29,6 +36,7 @@ impl Client {
 
         Self {
             base_url,
+            api_key: api_key.map(str::to_string),
             http: reqwest::Client::builder()
                 .timeout(std::time::Duration::from_secs(60))
                 .default_headers(headers)
@@ -71,6 +79,26 @@ impl Client {
         }
     }
 
+    /// PUT request with JSON body, returning deserialized data.
+    pub async fn put<B: Serialize, T: DeserializeOwned>(&self, path: &str, body: &B) -> Result<T> {
+        let url = format!("{}{path}", self.base_url);
+        let resp = self
+            .http
+            .put(&url)
+            .json(body)
+            .send()
+            .await
+            .c(d!("request failed"))?;
+        let status = resp.status();
+        let body: ApiResp<T> = resp.json().await.c(d!("invalid response"))?;
+
+        if body.ok {
+            body.data.ok_or_else(|| eg!("empty response"))
+        } else {
+            Err(eg!(body.error.unwrap_or_else(|| format!("HTTP {status}"))))
+        }
+    }
+
     /// POST request with no request body, no response body.
     pub async fn post_action(&self, path: &str) -> Result<()> {
         let url = format!("{}{path}", self.base_url);
@@ -85,6 +113,80 @@ impl Client {
         }
     }
 
+    /// POST request with no request body, no response body, and a
+    /// custom timeout instead of the default 60 seconds.
+    pub async fn post_action_timeout(
+        &self,
+        path: &str,
+        timeout: std::time::Duration,
+    ) -> Result<()> {
+        let url = format!("{}{path}", self.base_url);
+        let resp = self
+            .http
+            .post(&url)
+            .timeout(timeout)
+            .send()
+            .await
+            .c(d!("request failed"))?;
+        let status = resp.status();
+        let body: ApiResp<()> = resp.json().await.c(d!("invalid response"))?;
+
+        if body.ok {
+            Ok(())
+        } else {
+            Err(eg!(body.error.unwrap_or_else(|| format!("HTTP {status}"))))
+        }
+    }
+
+    /// POST request with JSON body, no response body.
+    pub async fn post_action_with<B: Serialize>(&self, path: &str, body: &B) -> Result<()> {
+        let url = format!("{}{path}", self.base_url);
+        let resp = self
+            .http
+            .post(&url)
+            .json(body)
+            .send()
+            .await
+            .c(d!("request failed"))?;
+        let status = resp.status();
+        let body: ApiResp<()> = resp.json().await.c(d!("invalid response"))?;
+
+        if body.ok {
+            Ok(())
+        } else {
+            Err(eg!(body.error.unwrap_or_else(|| format!("HTTP {status}"))))
+        }
+    }
+
+    /// Open a WebSocket (`http` becomes `ws`, `https` becomes `wss`).
+    pub async fn websocket(&self, path: &str) -> Result<WsStream> {
+        let url = format!(
+            "ws{}{path}",
+            self.base_url.strip_prefix("http").unwrap_or(&self.base_url)
+        );
+        let mut req = url.into_client_request().c(d!("invalid URL"))?;
+        if let Some(key) = &self.api_key
+            && let Ok(val) = HeaderValue::from_str(&format!("Bearer {key}"))
+        {
+            req.headers_mut().insert(AUTHORIZATION, val);
+        }
+
+        match tokio_tungstenite::connect_async(req).await {
+            Ok((ws, _)) => Ok(ws),
+            Err(tungstenite::Error::Http(resp)) => {
+                let status = resp.status();
+                let msg = resp
+                    .body()
+                    .as_deref()
+                    .and_then(|b| serde_json::from_slice::<ApiRespEmpty>(b).ok())
+                    .and_then(|r| r.error)
+                    .unwrap_or_else(|| format!("HTTP {status}"));
+                Err(eg!(msg))
+            }
+            Err(e) => Err(eg!("connection failed: {}", e)),
+        }
+    }
+
     /// DELETE request.
     pub async fn delete(&self, path: &str) -> Result<()> {
         let url = format!("{}{path}", self.base_url);
@@ -103,6 +205,44 @@ impl Client {
             Err(eg!(body.error.unwrap_or_else(|| format!("HTTP {status}"))))
         }
     }
+
+    /// GET a server-sent event stream. Unlike other requests it has no
+    /// overall timeout, since the stream stays open.
+    pub async fn event_stream(&self, path: &str) -> Result<reqwest::Response> {
+        let url = format!("{}{path}", self.base_url);
+        let mut req = reqwest::Client::new()
+            .get(&url)
+            .header(reqwest::header::ACCEPT, "text/event-stream");
+        if let Some(key) = &self.api_key {
+            req = req.bearer_auth(key);
+        }
+        let resp = req.send().await.c(d!("request failed"))?;
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(resp);
+        }
+        let body: ApiRespEmpty = resp.json().await.c(d!("invalid response"))?;
+        Err(eg!(body.error.unwrap_or_else(|| format!("HTTP {status}"))))
+    }
+
+    /// DELETE request, returning deserialized data.
+    pub async fn delete_data<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let url = format!("{}{path}", self.base_url);
+        let resp = self
+            .http
+            .delete(&url)
+            .send()
+            .await
+            .c(d!("request failed"))?;
+        let status = resp.status();
+        let body: ApiResp<T> = resp.json().await.c(d!("invalid response"))?;
+
+        if body.ok {
+            body.data.ok_or_else(|| eg!("empty response"))
+        } else {
+            Err(eg!(body.error.unwrap_or_else(|| format!("HTTP {status}"))))
+        }
+    }
 }
 
 // ── Configuration File ──────────────────────────────────────────────
@@ -157,6 +297,43 @@ pub fn save_config(addr: &str, api_key: Option<&str>) -> Result<()> {
     Ok(())
 }
 
+/// Prompt for a secret on the terminal without echoing it.
+pub fn read_secret(prompt: &str) -> Result<String> {
+    use std::io::Write;
+
+    eprint!("{prompt}");
+    let _ = std::io::stderr().flush();
+
+    #[cfg(unix)]
+    let saved = {
+        use nix::sys::termios;
+        let stdin = std::io::stdin();
+        let saved = termios::tcgetattr(&stdin).ok();
+        if let Some(orig) = &saved {
+            let mut quiet = orig.clone();
+            quiet.local_flags.remove(termios::LocalFlags::ECHO);
+            let _ = termios::tcsetattr(&stdin, termios::SetArg::TCSANOW, &quiet);
+        }
+        saved
+    };
+
+    let mut line = String::new();
+    let result = std::io::stdin().read_line(&mut line);
+
+    #[cfg(unix)]
+    if let Some(orig) = &saved {
+        let _ = nix::sys::termios::tcsetattr(
+            std::io::stdin(),
+            nix::sys::termios::SetArg::TCSANOW,
+            orig,
+        );
+        eprintln!();
+    }
+
+    result.c(d!("read input"))?;
+    Ok(line.trim().to_string())
+}
+
 fn dirs_path() -> String {
     let home = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
     format!("{home}/{CONFIG_FILE}")
@@ -0,0 +1,191 @@
+//! Interactive VM consoles and a local VNC bridge.
+//!
+//! Both talk to the controller's WebSocket endpoints, which relay raw
+//! bytes to and from the VM's host.
+
+use crate::client::{Client, WsStream};
+use futures_util::{SinkExt, StreamExt};
+use ruc::*;
+use std::io::{IsTerminal, Read};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Ctrl-] detaches from the console, as in telnet and `virsh console`.
+const DETACH_KEY: u8 = 0x1d;
+
+/// Attach the terminal to a VM's console until the VM side closes or
+/// the user presses Ctrl-].
+pub async fn attach(c: &Client, vm_id: &str) -> Result<()> {
+    let ws = c.websocket(&format!("/api/vms/{vm_id}/console")).await?;
+    eprintln!("Connected to {vm_id}. Press Ctrl-] to detach.");
+
+    let raw = RawMode::enable();
+    let result = pump_terminal(ws).await;
+    drop(raw);
+
+    eprintln!("\r\nDetached from {vm_id}.");
+    result
+}
+
+async fn pump_terminal(ws: WsStream) -> Result<()> {
+    let (mut tx, mut rx) = ws.split();
+    let mut keys = spawn_stdin_reader();
+    let mut stdout = tokio::io::stdout();
+
+    loop {
+        tokio::select! {
+            input = keys.recv() => {
+                let Some(mut data) = input else { break };
+                let detach = match data.iter().position(|&b| b == DETACH_KEY) {
+                    Some(pos) => {
+                        data.truncate(pos);
+                        true
+                    }
+                    None => false,
+                };
+                if !data.is_empty() {
+                    tx.send(Message::binary(data)).await.c(d!("console send"))?;
+                }
+                if detach {
+                    break;
+                }
+            }
+            msg = rx.next() => {
+                let data = match msg {
+                    Some(Ok(Message::Binary(data))) => data.to_vec(),
+                    Some(Ok(Message::Text(text))) => text.as_bytes().to_vec(),
+                    Some(Ok(Message::Close(_))) | None => break,
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(eg!("console connection lost: {}", e)),
+                };
+                stdout.write_all(&data).await.c(d!())?;
+                stdout.flush().await.c(d!())?;
+            }
+        }
+    }
+
+    let _ = tx.send(Message::Close(None)).await;
+    Ok(())
+}
+
+/// Read stdin on a plain thread: a pending read on tokio's stdin would
+/// keep the runtime from shutting down after we detach.
+fn spawn_stdin_reader() -> mpsc::Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel(16);
+    std::thread::spawn(move || {
+        let mut stdin = std::io::stdin();
+        let mut buf = [0u8; 1024];
+        loop {
+            match stdin.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if tx.blocking_send(buf[..n].to_vec()).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    rx
+}
+
+/// Serve a VM's VNC display on a local TCP address, so any VNC viewer
+/// can connect to it. Runs until interrupted.
+pub async fn serve_vnc(c: &Client, vm_id: &str, listen: &str) -> Result<()> {
+    let path = format!("/api/vms/{vm_id}/vnc");
+
+    // Fail early if the VM has no display
+    let probe = c.websocket(&path).await?;
+    drop(probe);
+
+    let listener = TcpListener::bind(listen).await.c(d!("bind {}", listen))?;
+    println!("VNC display of {vm_id} available on {listen} (Ctrl-C to stop)");
+
+    loop {
+        let (tcp, peer) = listener.accept().await.c(d!("accept"))?;
+        match c.websocket(&path).await {
+            Ok(ws) => {
+                tokio::spawn(bridge(tcp, ws));
+            }
+            Err(e) => eprintln!("{peer}: {e}"),
+        }
+    }
+}
+
+/// Relay one VNC viewer connection over the WebSocket.
+async fn bridge(tcp: TcpStream, ws: WsStream) {
+    let (mut tcp_rx, mut tcp_tx) = tcp.into_split();
+    let (mut ws_tx, mut ws_rx) = ws.split();
+    let mut buf = vec![0u8; 64 * 1024];
+
+    loop {
+        tokio::select! {
+            n = tcp_rx.read(&mut buf) => {
+                let n = match n {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => n,
+                };
+                if ws_tx.send(Message::binary(buf[..n].to_vec())).await.is_err() {
+                    break;
+                }
+            }
+            msg = ws_rx.next() => {
+                let data = match msg {
+                    Some(Ok(Message::Binary(data))) => data,
+                    Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+                    Some(Ok(_)) => continue,
+                };
+                if tcp_tx.write_all(&data).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    let _ = ws_tx.send(Message::Close(None)).await;
+}
+
+/// Puts the terminal into raw mode while alive, so keystrokes (Ctrl-C
+/// included) go to the VM instead of being handled locally.
+struct RawMode {
+    #[cfg(unix)]
+    saved: Option<nix::sys::termios::Termios>,
+}
+
+impl RawMode {
+    fn enable() -> Self {
+        #[cfg(unix)]
+        {
+            use nix::sys::termios;
+
+            let stdin = std::io::stdin();
+            if !stdin.is_terminal() {
+                return Self { saved: None };
+            }
+            let saved = termios::tcgetattr(&stdin).ok();
+            if let Some(orig) = &saved {
+                let mut raw = orig.clone();
+                termios::cfmakeraw(&mut raw);
+                let _ = termios::tcsetattr(&stdin, termios::SetArg::TCSANOW, &raw);
+            }
+            Self { saved }
+        }
+        #[cfg(not(unix))]
+        Self {}
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(orig) = &self.saved {
+            let _ = nix::sys::termios::tcsetattr(
+                std::io::stdin(),
+                nix::sys::termios::SetArg::TCSANOW,
+                orig,
+            );
+        }
+    }
+}
@@ -10,6 +10,11 @@ use ruc::*;
 use serde::Deserialize;
 use std::path::{Path, PathBuf};
 use tokio::process::Command;
+use ttcore::auth::host_key;
+use ttcore::model::validate_name;
+
+/// Host ID of the agent of a local `all` deploy.
+const LOCAL_HOST_ID: &str = "local";
 
 // ── Deploy config (deploy.toml) ─────────────────────────────────────
 
@@ -551,11 +556,23 @@ pub async fn deploy_local(role: &str, release_dir: &str) -> Result<()> {
             }
             local_install_bin(&bin, prefix).await?;
 
-            let cmd = format!(
+            let mut cmd = format!(
                 "{prefix}/bin/tt-agent --listen 0.0.0.0:9100 \
                  --image-dir {home}/images --runtime-dir {home}/runtime \
                  --data-dir {home}/data --storage file"
             );
+            // Next to a controller the host registers itself, under an
+            // ID known here to derive its key from
+            let mut env_content = env_content.clone();
+            if role == "all" {
+                cmd.push_str(&format!(
+                    " --host-id {LOCAL_HOST_ID} --controller 127.0.0.1:9200"
+                ));
+                env_content.push_str(&format!(
+                    "TT_CONTROLLER_KEY={}\n",
+                    host_key(&api_key, LOCAL_HOST_ID)
+                ));
+            }
             let env_path = format!("{prefix}/etc/tt-agent.env");
             local_install_systemd("tt-agent", &cmd, true, Some((&env_path, &env_content))).await?;
             local_restart_service("tt-agent").await?;
@@ -637,7 +654,7 @@ pub async fn deploy_distributed(config_path: &str) -> Result<()> {
             .clone()
             .unwrap_or_else(|| format!("{home}/ctl"));
         let exec_cmd = format!(
-            "{prefix}/bin/tt-ctl --listen {listen} --data-dir {data_dir}",
+            "{prefix}/bin/tt-ctl --listen {listen} --data-dir {data_dir} --web-dir {prefix}/web",
             listen = ctl.listen,
         );
         let env_path = format!("{prefix}/etc/tt-ctl.env");
@@ -700,7 +717,19 @@ pub async fn deploy_distributed(config_path: &str) -> Result<()> {
             cpu = agent.cpu_total,
             mem = agent.mem_total,
         );
-        if let Some(hid) = &agent.host_id {
+        let mut env_content = env_content.clone();
+        if let Some(ctl) = &cfg.controller {
+            // The host's key is derived from its ID, so the ID is fixed
+            let hid = agent.host_id.clone().unwrap_or_else(|| agent.host.clone());
+            validate_name(&hid, "host ID")
+                .map_err(|e| eg!("agent {}: {e}; set its host_id", agent.host))?;
+            exec_cmd.push_str(&format!(
+                " --host-id {hid} --controller {}:{}",
+                ctl.host,
+                ctl.listen.rsplit(':').next().unwrap_or("9200")
+            ));
+            env_content.push_str(&format!("TT_CONTROLLER_KEY={}\n", host_key(&api_key, &hid)));
+        } else if let Some(hid) = &agent.host_id {
             exec_cmd.push_str(&format!(" --host-id {hid}"));
         }
         let env_path = format!("{prefix}/etc/tt-agent.env");
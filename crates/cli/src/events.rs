@@ -0,0 +1,171 @@
+//! `tt events`: read the controller's stream of fleet changes.
+//!
+//! `GET /api/events` is a server-sent event stream. Asked with
+//! `?since=0`, it first replays the changes the controller still keeps,
+//! then sends a `ready` event and carries on with live changes. Without
+//! `--follow` we stop at `ready`; with it we keep printing, and pick up
+//! where we left off if the connection drops.
+
+use crate::client::Client;
+use crate::{fmt_duration, now};
+use ruc::*;
+use ttcore::api::*;
+
+/// One event of a server-sent event stream.
+#[derive(Debug, Default, PartialEq)]
+struct Frame {
+    /// The `event:` field; `None` for plain messages.
+    event: Option<String>,
+    data: String,
+}
+
+/// Splits a server-sent event stream into [`Frame`]s as bytes arrive.
+#[derive(Default)]
+struct Parser {
+    buf: Vec<u8>,
+}
+
+impl Parser {
+    /// Feed a chunk of the stream, returning the frames it completed.
+    fn push(&mut self, chunk: &[u8]) -> Vec<Frame> {
+        self.buf.extend(chunk.iter().filter(|b| **b != b'\r'));
+        let mut frames = vec![];
+        while let Some(end) = self.buf.windows(2).position(|w| w == b"\n\n") {
+            let raw: Vec<u8> = self.buf.drain(..end + 2).collect();
+            let mut frame = Frame::default();
+            let mut data: Vec<&str> = vec![];
+            let text = String::from_utf8_lossy(&raw);
+            for line in text.lines() {
+                // Lines starting with ':' are comments, e.g. keep-alives
+                let (field, value) = match line.split_once(':') {
+                    Some(("", _)) | None => continue,
+                    Some((f, v)) => (f, v.strip_prefix(' ').unwrap_or(v)),
+                };
+                match field {
+                    "event" => frame.event = Some(value.to_string()),
+                    "data" => data.push(value),
+                    _ => {}
+                }
+            }
+            frame.data = data.join("\n");
+            if frame.event.is_some() || !frame.data.is_empty() {
+                frames.push(frame);
+            }
+        }
+        frames
+    }
+}
+
+/// Print recent fleet changes, then, with `follow`, live ones until
+/// interrupted.
+pub async fn run(c: &Client, follow: bool) -> Result<()> {
+    println!("{:<10} {:<12} MESSAGE", "WHEN", "EVENT");
+    let mut since = 0;
+    let mut connected = false;
+    loop {
+        match c.event_stream(&format!("/api/events?since={since}")).await {
+            Ok(resp) => {
+                connected = true;
+                match read(resp, &mut since, follow).await {
+                    Ok(()) if !follow => return Ok(()),
+                    Ok(()) => eprintln!("connection to the controller closed, reconnecting..."),
+                    Err(e) if !follow => return Err(e),
+                    Err(e) => eprintln!("{}, reconnecting...", e.get_top_msg()),
+                }
+            }
+            // Only the first connection has to succeed
+            Err(e) if !connected => return Err(e),
+            Err(e) => eprintln!("{}, retrying...", e.get_top_msg()),
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+    }
+}
+
+/// Print the events of a stream, keeping `since` at the last one seen,
+/// until it ends or, unless `follow`, until the backlog is out.
+async fn read(mut resp: reqwest::Response, since: &mut u64, follow: bool) -> Result<()> {
+    let mut parser = Parser::default();
+    while let Some(chunk) = resp.chunk().await.c(d!("event stream broken"))? {
+        for frame in parser.push(&chunk) {
+            match frame.event.as_deref() {
+                Some("ready") if !follow => return Ok(()),
+                Some("lagged") => {
+                    eprintln!("warning: fell behind, {} events were skipped", frame.data)
+                }
+                None => {
+                    let ev: FleetEvent =
+                        serde_json::from_str(&frame.data).c(d!("invalid event"))?;
+                    *since = ev.seq;
+                    print_event(&ev);
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_event(ev: &FleetEvent) {
+    let (kind, msg) = describe(&ev.change);
+    println!(
+        "{:<10} {kind:<12} {msg}",
+        format!("{} ago", fmt_duration(now().saturating_sub(ev.at)))
+    );
+}
+
+/// Short name and one-line description of a change.
+fn describe(change: &FleetChange) -> (&'static str, String) {
+    match change {
+        FleetChange::EnvCreated { env, owner } => ("env", format!("{env} created for {owner}")),
+        FleetChange::EnvDeleted { env, owner } => ("env", format!("{env} of {owner} deleted")),
+        FleetChange::EnvExpired { env, owner } => ("env", format!("{env} of {owner} expired")),
+        FleetChange::VmState {
+            vm, env, from, to, ..
+        } => ("vm", format!("{vm} of {env}: {from} -> {to}")),
+        FleetChange::HostState { host, from, to } => ("host", format!("{host}: {from} -> {to}")),
+        FleetChange::Job { job } => {
+            let done = job.steps.iter().filter(|s| s.state.is_finished()).count();
+            let mut msg = format!(
+                "{} {} by {} (job {}): {}, {done}/{} steps done",
+                job.kind,
+                job.target,
+                job.owner,
+                job.id,
+                job.state,
+                job.steps.len()
+            );
+            if let Some(e) = &job.error {
+                msg.push_str(&format!(" ({e})"));
+            }
+            ("job", msg)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parser_handles_split_chunks_comments_and_events() {
+        let mut p = Parser::default();
+        assert!(p.push(b"id: 1\ndata: {\"a\"").is_empty());
+        let frames = p.push(b":1}\n\n: keep-alive\n\nevent: ready\r\ndata: \r\n\r\n");
+        assert_eq!(
+            frames,
+            [
+                Frame {
+                    event: None,
+                    data: "{\"a\":1}".into(),
+                },
+                Frame {
+                    event: Some("ready".into()),
+                    data: String::new(),
+                },
+            ]
+        );
+
+        let frames = p.push(b"data: one\ndata:two\n\n");
+        assert_eq!(frames[0].data, "one\ntwo");
+    }
+}
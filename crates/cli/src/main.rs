@@ -1,8 +1,11 @@
 //! TTstack CLI — manage your private cloud from the command line.
 
 mod client;
+mod console;
 mod deploy;
+mod events;
 mod image_builder;
+mod spec;
 
 use clap::{Parser, Subcommand};
 use client::Client;
@@ -36,6 +39,14 @@ enum Cmd {
         #[arg(long, short = 'k')]
         api_key: Option<String>,
     },
+    /// Log in to the controller with an API token and save it.
+    Login {
+        /// API token; prompted for if omitted.
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Show the user you are authenticated as.
+    Whoami,
     /// Show fleet-wide status.
     Status,
     /// Manage physical hosts.
@@ -48,11 +59,76 @@ enum Cmd {
         #[command(subcommand)]
         action: EnvCmd,
     },
+    /// Access individual VMs.
+    Vm {
+        #[command(subcommand)]
+        action: VmCmd,
+    },
     /// Manage images.
     Image {
         #[command(subcommand)]
         action: ImageCmd,
     },
+    /// Show recent changes to envs, VMs, hosts and jobs.
+    Events {
+        /// Keep printing changes as they happen.
+        #[arg(long, short)]
+        follow: bool,
+    },
+    /// Follow background jobs: env creation and deletion, migrations.
+    Job {
+        #[command(subcommand)]
+        action: JobCmd,
+    },
+    /// Show the audit log of API calls that changed something: your own
+    /// (everyone's for admins), newest first.
+    Audit {
+        /// Only calls on this environment.
+        #[arg(long)]
+        env: Option<String>,
+        /// Only calls on this VM.
+        #[arg(long)]
+        vm: Option<String>,
+        /// Only calls on this host.
+        #[arg(long)]
+        host: Option<String>,
+        /// Only calls made by this user (admins only).
+        #[arg(long)]
+        user: Option<String>,
+        /// Only calls made within this long, e.g. 30m, 2h or 7d.
+        #[arg(long, value_parser = parse_duration)]
+        since: Option<u64>,
+        /// Show at most this many calls [default: 100, all with --jsonl].
+        #[arg(long)]
+        limit: Option<usize>,
+        /// Print the entries as JSON lines, oldest first, e.g. for export.
+        #[arg(long)]
+        jsonl: bool,
+    },
+    /// Show or set the log filter of the controller or of one host's
+    /// agent (admins only), e.g. `debug` or `warn,tt_agent=debug`.
+    LogLevel {
+        /// New filter; shows the current one if omitted.
+        filter: Option<String>,
+        /// The agent on this host instead of the controller.
+        #[arg(long)]
+        host: Option<String>,
+    },
+    /// Manage your API tokens.
+    Token {
+        #[command(subcommand)]
+        action: TokenCmd,
+    },
+    /// Manage controller users (admin only).
+    User {
+        #[command(subcommand)]
+        action: UserCmd,
+    },
+    /// View and set resource quotas.
+    Quota {
+        #[command(subcommand)]
+        action: QuotaCmd,
+    },
     /// Deploy TTstack to local or remote hosts.
     Deploy {
         #[command(subcommand)]
@@ -66,6 +142,10 @@ enum HostCmd {
     Add {
         /// Agent address, e.g. "10.0.0.2:9100".
         addr: String,
+        /// Host label as key=value (repeatable or comma-separated);
+        /// overrides labels the agent reports.
+        #[arg(long, value_delimiter = ',')]
+        label: Vec<String>,
     },
     /// List all hosts.
     List,
@@ -73,6 +153,30 @@ enum HostCmd {
     Show { id: String },
     /// Remove a host from the fleet.
     Remove { id: String },
+    /// Take a host out of service and move its VMs to other hosts (admin only).
+    ///
+    /// Running QEMU VMs are migrated live, others cold. The host enters
+    /// maintenance once it is empty.
+    Drain {
+        id: String,
+        /// Move VMs cold, even those that could be migrated live.
+        #[arg(long)]
+        cold: bool,
+        /// Rebuild VMs that cannot be migrated from their image on
+        /// another host; their disk contents are lost.
+        #[arg(long)]
+        recreate: bool,
+        /// Enter maintenance right away, leaving the VMs on the host.
+        #[arg(long, conflicts_with_all = ["cold", "recreate"])]
+        keep_vms: bool,
+    },
+    /// Show the progress of a host's last drain.
+    DrainStatus { id: String },
+    /// Put a draining or maintenance host back in service (admin only).
+    Undrain { id: String },
+    /// Print the key a host's agent sends its heartbeats with (admin
+    /// only); pass it as the agent's `--controller-key`.
+    Key { id: String },
 }
 
 #[derive(Subcommand)]
@@ -99,6 +203,14 @@ enum EnvCmd {
         /// Duplicate each image N times.
         #[arg(long, default_value_t = 1)]
         dup: u32,
+        /// Anti-affinity group of the VMs, kept on different hosts where
+        /// possible; defaults to the image name for --dup replicas.
+        #[arg(long)]
+        anti_affinity: Option<String>,
+        /// Host label the VMs require, as key=value (repeatable or
+        /// comma-separated).
+        #[arg(long, value_delimiter = ',')]
+        selector: Vec<String>,
         /// Port to expose (repeatable).
         #[arg(long, short)]
         port: Vec<u16>,
@@ -108,23 +220,229 @@ enum EnvCmd {
         /// Block outgoing network traffic from VMs.
         #[arg(long)]
         deny_outgoing: bool,
-        /// Owner identifier (defaults to $USER).
+        /// Connect the VMs through a private network spanning all their hosts.
+        #[arg(long)]
+        private_net: bool,
+        /// Keep the env with the VMs that could be created if some fail,
+        /// instead of rolling it back.
+        #[arg(long)]
+        allow_partial: bool,
+        /// Create the env on behalf of this user (admins only; defaults to you).
         #[arg(long)]
         owner: Option<String>,
         /// SSH public key for VM access (repeatable). Can also be a path to a .pub file.
         #[arg(long)]
         ssh_key: Vec<String>,
+        /// Follow the creation job until the env is ready, instead of
+        /// returning once it has started.
+        #[arg(long)]
+        wait: bool,
+    },
+    /// Add VMs to an existing environment.
+    AddVm {
+        /// Environment name.
+        name: String,
+        /// Image name (repeatable).
+        #[arg(long, short, required = true)]
+        image: Vec<String>,
+        /// Name of the new VM (default: the next free vmN).
+        #[arg(long)]
+        vm_name: Option<String>,
+        /// Engine type: qemu, firecracker, docker (Linux); bhyve, jail (FreeBSD).
+        #[arg(long, default_value = "qemu")]
+        engine: String,
+        /// CPU cores per VM.
+        #[arg(long)]
+        cpu: Option<u32>,
+        /// Memory per VM in MiB.
+        #[arg(long)]
+        mem: Option<u32>,
+        /// Disk per VM in MiB.
+        #[arg(long)]
+        disk: Option<u32>,
+        /// Duplicate each image N times.
+        #[arg(long, default_value_t = 1)]
+        dup: u32,
+        /// Anti-affinity group of the VMs, kept on different hosts where
+        /// possible; defaults to the image name for --dup replicas.
+        #[arg(long)]
+        anti_affinity: Option<String>,
+        /// Host label the VMs require, as key=value (repeatable or
+        /// comma-separated).
+        #[arg(long, value_delimiter = ',')]
+        selector: Vec<String>,
+        /// Port to expose (repeatable).
+        #[arg(long, short)]
+        port: Vec<u16>,
+        /// Block outgoing network traffic from the new VMs.
+        #[arg(long)]
+        deny_outgoing: bool,
+        /// SSH public key for the new VMs (repeatable). Can also be a path to a .pub file.
+        #[arg(long)]
+        ssh_key: Vec<String>,
+    },
+    /// Destroy one VM of an environment.
+    RmVm {
+        /// Environment name.
+        name: String,
+        /// VM name or ID.
+        vm: String,
+    },
+    /// Create an environment from a spec file, or converge an existing
+    /// one to it by creating, replacing and removing VMs.
+    Apply {
+        /// Spec file (TOML, or YAML for .yaml/.yml).
+        #[arg(long, short)]
+        file: String,
+        /// Show what would change without changing anything.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Print an environment as a spec file.
+    Export {
+        /// Environment name.
+        name: String,
+        /// Output format: toml or yaml.
+        #[arg(long, default_value = "toml")]
+        format: String,
     },
     /// List all environments.
     List,
     /// Show environment details.
     Show { name: String },
     /// Delete an environment.
-    Delete { name: String },
-    /// Stop all VMs in an environment.
+    Delete {
+        name: String,
+        /// Follow the deletion job until it finishes.
+        #[arg(long)]
+        wait: bool,
+    },
+    /// Push back an environment's expiry.
+    Extend {
+        /// Environment name.
+        name: String,
+        /// How much longer to keep it, e.g. 90m, 2h or 1d.
+        #[arg(long, value_parser = parse_duration, required_unless_present = "never")]
+        by: Option<u64>,
+        /// Never expire the environment (admin only).
+        #[arg(long, conflicts_with = "by")]
+        never: bool,
+    },
+    /// Show an environment's events (creation, extensions, expiry warnings).
+    Events { name: String },
+    /// Power off all VMs in an environment, freeing their CPU and memory.
     Stop { name: String },
-    /// Start all VMs in an environment.
+    /// Start all VMs in an environment, resuming suspended ones.
     Start { name: String },
+    /// Save the memory of all VMs in an environment to disk, freeing
+    /// their CPU and memory until it is resumed.
+    Hibernate { name: String },
+    /// Resume all suspended or hibernated VMs in an environment.
+    Resume { name: String },
+    /// Snapshot the disks of all VMs in an environment.
+    Snapshot {
+        /// Environment name.
+        name: String,
+        /// Snapshot name.
+        snapshot: String,
+        /// Delete the named snapshot instead of creating it.
+        #[arg(long)]
+        delete: bool,
+    },
+    /// List the snapshots of every VM in an environment.
+    Snapshots { name: String },
+    /// Roll all VMs in an environment back to a snapshot (VMs are rebooted).
+    Rollback {
+        /// Environment name.
+        name: String,
+        /// Snapshot name.
+        snapshot: String,
+        /// Delete the snapshots taken after it, which otherwise block
+        /// the rollback.
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum VmCmd {
+    /// Attach to a VM's serial console (a shell for containers and jails).
+    ///
+    /// Press Ctrl-] to detach.
+    Console {
+        /// VM ID.
+        id: String,
+    },
+    /// Serve a VM's VNC display locally for a VNC viewer (QEMU only).
+    Vnc {
+        /// VM ID.
+        id: String,
+        /// Local address to listen on.
+        #[arg(long, default_value = "127.0.0.1:5900")]
+        listen: String,
+    },
+    /// Show a VM's details.
+    Show {
+        /// VM ID.
+        id: String,
+    },
+    /// Power a VM off, freeing its CPU and memory; its disk is kept.
+    Stop {
+        /// VM ID.
+        id: String,
+    },
+    /// Start a stopped VM (cold boot), or resume a suspended one.
+    Start {
+        /// VM ID.
+        id: String,
+    },
+    /// Pause a running VM in place; it keeps its CPU and memory.
+    Suspend {
+        /// VM ID.
+        id: String,
+    },
+    /// Resume a suspended or hibernated VM.
+    Resume {
+        /// VM ID.
+        id: String,
+    },
+    /// Save a VM's memory to disk, freeing its CPU and memory.
+    Hibernate {
+        /// VM ID.
+        id: String,
+    },
+    /// Hard-reset a running VM, like pressing its reset button.
+    Reset {
+        /// VM ID.
+        id: String,
+    },
+    /// Reboot a running VM, letting its guest shut down first.
+    Reboot {
+        /// VM ID.
+        id: String,
+    },
+    /// Move a VM to another host (admin only).
+    ///
+    /// Running QEMU VMs move live; other VMs are stopped, copied and
+    /// booted on the target. The VM keeps its address and ports.
+    Migrate {
+        /// VM ID.
+        id: String,
+        /// Target host ID.
+        #[arg(long)]
+        to: String,
+        /// Stop the VM for the move even if it could move live.
+        #[arg(long)]
+        cold: bool,
+        /// Follow the migration job until it finishes.
+        #[arg(long)]
+        wait: bool,
+    },
+    /// Destroy a VM and drop it from its environment.
+    Destroy {
+        /// VM ID.
+        id: String,
+    },
 }
 
 #[derive(Subcommand)]
@@ -144,6 +462,131 @@ enum ImageCmd {
         #[arg(long)]
         engine: Option<String>,
     },
+    /// Copy an image from a host that has it to other hosts.
+    Push {
+        /// Image name.
+        image: String,
+        /// Target host ID, or "all".
+        #[arg(long)]
+        to: String,
+        /// Source host ID (default: any host that has the image).
+        #[arg(long)]
+        from: Option<String>,
+        /// Follow the push job until it finishes.
+        #[arg(long)]
+        wait: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum JobCmd {
+    /// List your jobs (everyone's for admins), newest first.
+    List,
+    /// Show a job and the progress of its steps.
+    Show {
+        /// Job ID.
+        id: String,
+        /// Follow the job until it finishes.
+        #[arg(long)]
+        wait: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum TokenCmd {
+    /// Issue a new API token. The secret is shown only once.
+    Create {
+        /// Issue the token for another user (admins only).
+        #[arg(long)]
+        user: Option<String>,
+        /// Note to tell the token apart, e.g. the machine it is used on.
+        #[arg(long, default_value = "")]
+        label: String,
+    },
+    /// List your tokens (all tokens for admins).
+    List,
+    /// Revoke a token.
+    Revoke {
+        /// Token ID.
+        id: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum UserCmd {
+    /// Create a user.
+    Add {
+        /// User name.
+        name: String,
+        /// Give the user the admin role.
+        #[arg(long)]
+        admin: bool,
+        /// Group whose quota the user also counts against.
+        #[arg(long)]
+        group: Option<String>,
+    },
+    /// Change a user's role or group.
+    Set {
+        /// User name.
+        name: String,
+        /// New role: admin or user.
+        #[arg(long)]
+        role: Option<String>,
+        /// New group ("" to remove the user from their group).
+        #[arg(long)]
+        group: Option<String>,
+    },
+    /// List users.
+    List,
+    /// Remove a user and revoke their tokens.
+    Remove {
+        /// User name.
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum QuotaCmd {
+    /// List all quotas with current usage (admin only).
+    List,
+    /// Show a quota and its usage (defaults to your own).
+    Show {
+        /// "user:<name>", "group:<name>", or a user name.
+        subject: Option<String>,
+    },
+    /// Set a quota, replacing the old one; limits not given are unlimited
+    /// (admin only).
+    Set {
+        /// "user:<name>", "group:<name>", or a user name.
+        subject: String,
+        /// Total vCPUs.
+        #[arg(long)]
+        cpu: Option<u32>,
+        /// Total memory in MiB.
+        #[arg(long)]
+        mem: Option<u32>,
+        /// Total disk in MiB.
+        #[arg(long)]
+        disk: Option<u32>,
+        /// Number of VMs.
+        #[arg(long)]
+        vms: Option<u32>,
+        /// Number of environments.
+        #[arg(long)]
+        envs: Option<u32>,
+        /// Maximum env lifetime in seconds.
+        #[arg(long)]
+        lifetime: Option<u64>,
+        /// Longest an env may live through extensions, from its creation,
+        /// in seconds.
+        #[arg(long)]
+        max_lifetime: Option<u64>,
+    },
+    /// Remove a quota (admin only).
+    Rm {
+        /// "user:<name>", "group:<name>", or a user name.
+        subject: String,
+    },
 }
 
 #[derive(Subcommand)]
@@ -248,14 +691,42 @@ async fn main() {
         std::process::exit(1);
     };
 
+    if let Cmd::Login { token } = cli.cmd {
+        if let Err(e) = cmd_login(&addr, token).await {
+            eprintln!("Error: {e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let c = Client::new(&addr, api_key.as_deref());
 
     let result = match cli.cmd {
-        Cmd::Config { .. } | Cmd::Deploy { .. } => unreachable!(),
+        Cmd::Config { .. } | Cmd::Deploy { .. } | Cmd::Login { .. } => unreachable!(),
+        Cmd::Whoami => cmd_whoami(&c).await,
         Cmd::Status => cmd_status(&c).await,
         Cmd::Host { action } => cmd_host(&c, action).await,
         Cmd::Env { action } => cmd_env(&c, action).await,
+        Cmd::Vm { action } => cmd_vm(&c, action).await,
         Cmd::Image { action } => cmd_image(&c, action).await,
+        Cmd::Events { follow } => events::run(&c, follow).await,
+        Cmd::Job { action } => cmd_job(&c, action).await,
+        Cmd::Audit {
+            env,
+            vm,
+            host,
+            user,
+            since,
+            limit,
+            jsonl,
+        } => {
+            let filters = [("env", env), ("vm", vm), ("host", host), ("user", user)];
+            cmd_audit(&c, &filters, since, limit, jsonl).await
+        }
+        Cmd::LogLevel { filter, host } => cmd_log_level(&c, filter, host).await,
+        Cmd::Token { action } => cmd_token(&c, action).await,
+        Cmd::User { action } => cmd_user(&c, action).await,
+        Cmd::Quota { action } => cmd_quota(&c, action).await,
     };
 
     if let Err(e) = result {
@@ -266,6 +737,28 @@ async fn main() {
 
 // ── Command Implementations ─────────────────────────────────────────
 
+async fn cmd_login(addr: &str, token: Option<String>) -> Result<()> {
+    let token = match token {
+        Some(t) => t,
+        None => client::read_secret("API token: ")?,
+    };
+    if token.is_empty() {
+        return Err(eg!("no token given"));
+    }
+
+    let c = Client::new(addr, Some(&token));
+    let me: User = c.get("/api/whoami").await?;
+    client::save_config(addr, Some(&token))?;
+    println!("Logged in to {addr} as {} ({}).", me.name, me.role);
+    Ok(())
+}
+
+async fn cmd_whoami(c: &Client) -> Result<()> {
+    let me: User = c.get("/api/whoami").await?;
+    println!("{} ({})", me.name, me.role);
+    Ok(())
+}
+
 async fn cmd_status(c: &Client) -> Result<()> {
     let s: FleetStatus = c.get("/api/status").await?;
     println!("Fleet Status");
@@ -280,8 +773,11 @@ async fn cmd_status(c: &Client) -> Result<()> {
 
 async fn cmd_host(c: &Client, action: HostCmd) -> Result<()> {
     match action {
-        HostCmd::Add { addr } => {
-            let host: Host = c.post("/api/hosts", &RegisterHostReq { addr }).await?;
+        HostCmd::Add { addr, label } => {
+            let labels = parse_labels(&label).map_err(|e| eg!(e))?;
+            let host: Host = c
+                .post("/api/hosts", &RegisterHostReq { addr, labels })
+                .await?;
             println!("Host registered: {} ({})", host.id, host.addr);
             println!("  Engines: {:?}", host.engines);
             println!("  Storage: {}", host.storage);
@@ -289,6 +785,9 @@ async fn cmd_host(c: &Client, action: HostCmd) -> Result<()> {
                 "  Resources: {} CPU, {} MB RAM, {} MB disk",
                 host.resource.cpu_total, host.resource.mem_total, host.resource.disk_total
             );
+            if !host.labels.is_empty() {
+                println!("  Labels: {}", format_labels(&host.labels));
+            }
         }
         HostCmd::List => {
             let hosts: Vec<Host> = c.get("/api/hosts").await?;
@@ -297,20 +796,21 @@ async fn cmd_host(c: &Client, action: HostCmd) -> Result<()> {
                 return Ok(());
             }
             println!(
-                "{:<12} {:<22} {:<8} {:>6} {:>8} {:>8}",
+                "{:<12} {:<22} {:<11} {:>6} {:>8} {:>8}  LABELS",
                 "ID", "ADDR", "STATE", "CPU", "MEM(MB)", "VMs"
             );
             for h in hosts {
                 println!(
-                    "{:<12} {:<22} {:<8} {:>3}/{:<3} {:>4}/{:<4} {:>4}",
+                    "{:<12} {:<22} {:<11} {:>3}/{:<3} {:>4}/{:<4} {:>4}  {}",
                     h.id,
                     h.addr,
-                    format!("{:?}", h.state).to_lowercase(),
+                    h.state.to_string(),
                     h.resource.cpu_used,
                     h.resource.cpu_total,
                     h.resource.mem_used,
                     h.resource.mem_total,
                     h.resource.vm_count,
+                    format_labels(&h.labels),
                 );
             }
         }
@@ -318,7 +818,7 @@ async fn cmd_host(c: &Client, action: HostCmd) -> Result<()> {
             let h: Host = c.get(&format!("/api/hosts/{id}")).await?;
             println!("Host: {}", h.id);
             println!("  Address:  {}", h.addr);
-            println!("  State:    {:?}", h.state);
+            println!("  State:    {}", h.state);
             println!("  Engines:  {:?}", h.engines);
             println!("  Storage:  {}", h.storage);
             println!(
@@ -334,15 +834,95 @@ async fn cmd_host(c: &Client, action: HostCmd) -> Result<()> {
                 h.resource.disk_used, h.resource.disk_total
             );
             println!("  VMs:      {}", h.resource.vm_count);
+            if !h.labels.is_empty() {
+                println!("  Labels:   {}", format_labels(&h.labels));
+            }
         }
         HostCmd::Remove { id } => {
             c.delete(&format!("/api/hosts/{id}")).await?;
             println!("Host removed: {id}");
         }
+        HostCmd::Drain {
+            id,
+            cold,
+            recreate,
+            keep_vms,
+        } => {
+            let req = DrainHostReq {
+                cold,
+                recreate,
+                keep_vms,
+            };
+            let host: Host = c.post(&format!("/api/hosts/{id}/drain"), &req).await?;
+            if host.state == HostState::Maintenance {
+                println!("Host {id} is in maintenance");
+                return Ok(());
+            }
+            println!("Draining host {id} (Ctrl-C stops following, not the drain)");
+            follow_drain(c, &id).await?;
+        }
+        HostCmd::DrainStatus { id } => {
+            let p: DrainProgress = c.get(&format!("/api/hosts/{id}/drain")).await?;
+            print_drain(&p);
+        }
+        HostCmd::Undrain { id } => {
+            c.post_action(&format!("/api/hosts/{id}/undrain")).await?;
+            println!("Host {id} is back in service");
+        }
+        HostCmd::Key { id } => {
+            let key: String = c.get(&format!("/api/hosts/{id}/key")).await?;
+            println!("{key}");
+        }
     }
     Ok(())
 }
 
+/// Print drain progress of host `id` as VMs move, until it finishes.
+async fn follow_drain(c: &Client, id: &str) -> Result<()> {
+    let (mut moved, mut failed) = (0, 0);
+    loop {
+        let p: DrainProgress = c.get(&format!("/api/hosts/{id}/drain")).await?;
+        for line in &p.moved[moved..] {
+            println!("  moved   {line}");
+        }
+        for line in &p.failed[failed..] {
+            println!("  FAILED  {line}");
+        }
+        (moved, failed) = (p.moved.len(), p.failed.len());
+        if p.finished {
+            let host: Host = c.get(&format!("/api/hosts/{id}")).await?;
+            println!(
+                "Drain finished: {moved}/{} VMs moved, {failed} failed; host is {}",
+                p.total, host.state
+            );
+            return Ok(());
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+    }
+}
+
+fn print_drain(p: &DrainProgress) {
+    let status = if p.finished { "finished" } else { "running" };
+    println!("Drain of host {} ({status})", p.host_id);
+    println!(
+        "  Moved:    {}/{}, {} failed",
+        p.moved.len(),
+        p.total,
+        p.failed.len()
+    );
+    if let Some(vm) = &p.current
+        && !p.finished
+    {
+        println!("  Moving:   {vm}");
+    }
+    for line in &p.moved {
+        println!("  moved   {line}");
+    }
+    for line in &p.failed {
+        println!("  FAILED  {line}");
+    }
+}
+
 async fn cmd_env(c: &Client, action: EnvCmd) -> Result<()> {
     match action {
         EnvCmd::Create {
@@ -353,40 +933,21 @@ async fn cmd_env(c: &Client, action: EnvCmd) -> Result<()> {
             mem,
             disk,
             dup,
+            anti_affinity,
+            selector,
             port,
             lifetime,
             deny_outgoing,
+            private_net,
+            allow_partial,
             owner,
             ssh_key,
+            wait,
         } => {
             let engine: Engine = engine
                 .parse()
                 .map_err(|e: Box<dyn std::error::Error>| eg!(e.to_string()))?;
-
-            let owner = owner
-                .or_else(|| std::env::var("USER").ok())
-                .unwrap_or_else(|| "default".to_string());
-
-            // Resolve SSH keys: if a value looks like a file path, read it
-            let ssh_keys: Vec<String> = ssh_key
-                .into_iter()
-                .map(|k| {
-                    if (k.ends_with(".pub") || k.starts_with('/') || k.starts_with("~/"))
-                        && !k.starts_with("ssh-")
-                    {
-                        let path = if k.starts_with("~/") {
-                            k.replacen("~", &std::env::var("HOME").unwrap_or_default(), 1)
-                        } else {
-                            k.clone()
-                        };
-                        std::fs::read_to_string(&path)
-                            .map(|s| s.trim().to_string())
-                            .unwrap_or(k)
-                    } else {
-                        k
-                    }
-                })
-                .collect();
+            let selector = parse_labels(&selector).map_err(|e| eg!(e))?;
 
             let mut vms = Vec::new();
             for img in &image {
@@ -400,31 +961,160 @@ async fn cmd_env(c: &Client, action: EnvCmd) -> Result<()> {
                         ports: port.clone(),
                         deny_outgoing,
                         ssh_keys: vec![],
+                        name: None,
+                        user_data: None,
+                        anti_affinity: replica_group(&anti_affinity, img, dup),
+                        node_selector: selector.clone(),
                     });
                 }
             }
 
             let req = CreateEnvReq {
                 id: name.clone(),
-                owner,
+                owner: owner.unwrap_or_default(),
                 vms,
                 lifetime,
-                ssh_keys,
+                ssh_keys: resolve_ssh_keys(ssh_key),
+                private_net,
+                all_or_nothing: !allow_partial,
             };
 
-            let detail: EnvDetail = c.post("/api/envs", &req).await?;
-            println!("Environment created: {name}");
-            println!("  VMs: {}", detail.vms.len());
-            for vm in &detail.vms {
-                println!(
-                    "    {} [{}] {} — {}  ports: {:?}",
-                    vm.id, vm.engine, vm.image, vm.ip, vm.port_map
-                );
+            let job: Job = c.post("/api/envs", &req).await?;
+            if wait {
+                create_and_wait(c, &name, job).await?;
+            } else {
+                println!("Creating environment: {name}");
+                print_job_hint(&job);
+            }
+        }
+        EnvCmd::AddVm {
+            name,
+            image,
+            vm_name,
+            engine,
+            cpu,
+            mem,
+            disk,
+            dup,
+            anti_affinity,
+            selector,
+            port,
+            deny_outgoing,
+            ssh_key,
+        } => {
+            let engine: Engine = engine
+                .parse()
+                .map_err(|e: Box<dyn std::error::Error>| eg!(e.to_string()))?;
+            let selector = parse_labels(&selector).map_err(|e| eg!(e))?;
+            if vm_name.is_some() && (image.len() > 1 || dup > 1) {
+                return Err(eg!(
+                    "--vm-name names a single VM; drop --dup or extra images"
+                ));
+            }
+
+            let mut vms = Vec::new();
+            for img in &image {
+                for _ in 0..dup {
+                    vms.push(VmSpec {
+                        image: img.clone(),
+                        engine,
+                        cpu,
+                        mem,
+                        disk,
+                        ports: port.clone(),
+                        deny_outgoing,
+                        ssh_keys: vec![],
+                        name: vm_name.clone(),
+                        user_data: None,
+                        anti_affinity: replica_group(&anti_affinity, img, dup),
+                        node_selector: selector.clone(),
+                    });
+                }
+            }
+            let req = AddVmsReq {
+                vms,
+                ssh_keys: resolve_ssh_keys(ssh_key),
+            };
+
+            let before: EnvDetail = c.get(&format!("/api/envs/{name}")).await?;
+            let detail: EnvDetail = c.post(&format!("/api/envs/{name}/vms"), &req).await?;
+            let added: Vec<&Vm> = detail
+                .vms
+                .iter()
+                .filter(|vm| !before.env.vm_ids.contains(&vm.id))
+                .collect();
+            println!("VMs added to {name}: {}", added.len());
+            for vm in added {
+                print_vm(vm);
             }
             for w in &detail.warnings {
                 eprintln!("  warning: {w}");
             }
         }
+        EnvCmd::RmVm { name, vm } => {
+            c.delete(&format!("/api/envs/{name}/vms/{vm}")).await?;
+            println!("VM removed from {name}: {vm}");
+        }
+        EnvCmd::Apply { file, dry_run } => {
+            let req = spec::load(&file)?;
+            let envs: Vec<Env> = c.get("/api/envs").await?;
+            if !envs.iter().any(|e| e.id == req.id) {
+                if dry_run {
+                    println!("Would create environment: {}", req.id);
+                    for vm in &req.vms {
+                        println!("  + {}", vm.name.as_deref().unwrap_or_default());
+                    }
+                    return Ok(());
+                }
+                let job: Job = c.post("/api/envs", &req).await?;
+                return create_and_wait(c, &req.id, job).await;
+            }
+
+            let path = if dry_run {
+                format!("/api/envs/{}/apply?dry_run=true", req.id)
+            } else {
+                format!("/api/envs/{}/apply", req.id)
+            };
+            let resp: ApplyResp = c.post(&path, &req).await?;
+            let plan = &resp.plan;
+            for (mark, names) in [
+                ("+", &plan.create),
+                ("~", &plan.replace),
+                ("-", &plan.remove),
+                ("=", &plan.keep),
+            ] {
+                for n in names {
+                    println!("  {mark} {n}");
+                }
+            }
+            let (create, replace, remove) = if dry_run {
+                ("to create", "to replace", "to remove")
+            } else {
+                ("created", "replaced", "removed")
+            };
+            let summary = format!(
+                "{} {create}, {} {replace}, {} {remove}, {} unchanged",
+                plan.create.len(),
+                plan.replace.len(),
+                plan.remove.len(),
+                plan.keep.len()
+            );
+            if dry_run {
+                println!("Dry run for {}: {summary}", req.id);
+            } else if plan.is_empty() {
+                println!("Environment {} is up to date", req.id);
+            } else {
+                println!("Environment applied: {} ({summary})", req.id);
+            }
+            for w in resp.detail.iter().flat_map(|d| &d.warnings) {
+                eprintln!("  warning: {w}");
+            }
+        }
+        EnvCmd::Export { name, format } => {
+            let format: spec::Format = format.parse().map_err(|e: String| eg!(e))?;
+            let detail: EnvDetail = c.get(&format!("/api/envs/{name}")).await?;
+            print!("{}", spec::render(&spec::export(&detail), format)?);
+        }
         EnvCmd::List => {
             let envs: Vec<Env> = c.get("/api/envs").await?;
             if envs.is_empty() {
@@ -447,12 +1137,16 @@ async fn cmd_env(c: &Client, action: EnvCmd) -> Result<()> {
             println!("Environment: {}", detail.env.id);
             println!("  Owner:   {}", detail.env.owner);
             println!("  State:   {:?}", detail.env.state);
+            println!("  Expires: {}", expires_in(detail.env.expires_at));
             println!("  VMs:     {}", detail.vms.len());
+            if let Some(net) = &detail.env.network {
+                println!("  Network: {} (vni {})", net.subnet, net.vni);
+            }
             println!();
             if !detail.vms.is_empty() {
                 println!(
-                    "  {:<14} {:<12} {:<10} {:<8} {:<16} PORTS",
-                    "ID", "IMAGE", "ENGINE", "STATE", "IP"
+                    "  {:<14} {:<12} {:<12} {:<10} {:<8} {:<16} PORTS",
+                    "ID", "NAME", "IMAGE", "ENGINE", "STATE", "IP"
                 );
                 for vm in &detail.vms {
                     let ports: String = vm
@@ -462,28 +1156,289 @@ async fn cmd_env(c: &Client, action: EnvCmd) -> Result<()> {
                         .collect::<Vec<_>>()
                         .join(", ");
                     println!(
-                        "  {:<14} {:<12} {:<10} {:<8} {:<16} {}",
-                        vm.id, vm.image, vm.engine, vm.state, vm.ip, ports
+                        "  {:<14} {:<12} {:<12} {:<10} {:<8} {:<16} {}",
+                        vm.id, vm.name, vm.image, vm.engine, vm.state, vm.ip, ports
                     );
                 }
             }
         }
-        EnvCmd::Delete { name } => {
-            c.delete(&format!("/api/envs/{name}")).await?;
-            println!("Environment deleted: {name}");
+        EnvCmd::Delete { name, wait } => {
+            let job: Job = c.delete_data(&format!("/api/envs/{name}")).await?;
+            if wait {
+                follow_job(c, &job.id).await?;
+                println!("Environment deleted: {name}");
+            } else {
+                println!("Deleting environment: {name}");
+                print_job_hint(&job);
+            }
+        }
+        EnvCmd::Extend { name, by, never } => {
+            let req = ExtendEnvReq {
+                by: by.unwrap_or(0),
+                never_expire: never,
+            };
+            let env: Env = c.post(&format!("/api/envs/{name}/extend"), &req).await?;
+            if env.expires_at == 0 {
+                println!("Environment {name} will never expire");
+            } else {
+                println!(
+                    "Environment extended: {name} (expires {})",
+                    expires_in(env.expires_at)
+                );
+            }
         }
-        EnvCmd::Stop { name } => {
-            c.post_action(&format!("/api/envs/{name}/stop")).await?;
-            println!("Environment stopped: {name}");
+        EnvCmd::Events { name } => {
+            let events: Vec<EnvEvent> = c.get(&format!("/api/envs/{name}/events")).await?;
+            if events.is_empty() {
+                println!("No events.");
+                return Ok(());
+            }
+            println!("{:<10} {:<16} MESSAGE", "WHEN", "EVENT");
+            for e in events {
+                println!(
+                    "{:<10} {:<16} {}",
+                    format!("{} ago", fmt_duration(now().saturating_sub(e.at))),
+                    e.kind.to_string(),
+                    e.message
+                );
+            }
         }
-        EnvCmd::Start { name } => {
-            c.post_action(&format!("/api/envs/{name}/start")).await?;
-            println!("Environment started: {name}");
+        EnvCmd::Stop { name } => env_action(c, &name, "stop", "stopped").await?,
+        EnvCmd::Start { name } => env_action(c, &name, "start", "started").await?,
+        EnvCmd::Hibernate { name } => env_action(c, &name, "hibernate", "hibernated").await?,
+        EnvCmd::Resume { name } => env_action(c, &name, "resume", "resumed").await?,
+        EnvCmd::Snapshot {
+            name,
+            snapshot,
+            delete,
+        } => {
+            let detail: EnvDetail = c.get(&format!("/api/envs/{name}")).await?;
+            for vm in snapshot_vms(&detail) {
+                if delete {
+                    c.delete(&format!("/api/vms/{}/snapshots/{snapshot}", vm.id))
+                        .await?;
+                } else {
+                    let req = SnapshotReq {
+                        name: snapshot.clone(),
+                    };
+                    c.post_action_with(&format!("/api/vms/{}/snapshots", vm.id), &req)
+                        .await?;
+                }
+                println!("  {} ok", vm.id);
+            }
+            if delete {
+                println!("Snapshot deleted: {name}@{snapshot}");
+            } else {
+                println!("Snapshot created: {name}@{snapshot}");
+            }
+        }
+        EnvCmd::Snapshots { name } => {
+            let detail: EnvDetail = c.get(&format!("/api/envs/{name}")).await?;
+            println!("{:<14} SNAPSHOTS", "VM");
+            for vm in snapshot_vms(&detail) {
+                let snaps: Vec<String> = c.get(&format!("/api/vms/{}/snapshots", vm.id)).await?;
+                println!("{:<14} {}", vm.id, snaps.join(", "));
+            }
+        }
+        EnvCmd::Rollback {
+            name,
+            snapshot,
+            force,
+        } => {
+            let detail: EnvDetail = c.get(&format!("/api/envs/{name}")).await?;
+            for vm in snapshot_vms(&detail) {
+                c.post_action(&format!(
+                    "/api/vms/{}/snapshots/{snapshot}/rollback?force={force}",
+                    vm.id
+                ))
+                .await?;
+                println!("  {} ok", vm.id);
+            }
+            println!("Environment rolled back: {name}@{snapshot}");
         }
     }
     Ok(())
 }
 
+/// Print a newly created environment.
+/// Resolve SSH keys: a value that looks like a file path is read.
+fn resolve_ssh_keys(keys: Vec<String>) -> Vec<String> {
+    keys.into_iter()
+        .map(|k| {
+            if spec::is_key_path(&k) {
+                std::fs::read_to_string(spec::expand_home(&k))
+                    .map(|s| s.trim().to_string())
+                    .unwrap_or(k)
+            } else {
+                k
+            }
+        })
+        .collect()
+}
+
+/// Anti-affinity group for VMs of image `img`: the one given, or the
+/// image name when it is duplicated.
+fn replica_group(group: &Option<String>, img: &str, dup: u32) -> Option<String> {
+    group.clone().or_else(|| (dup > 1).then(|| img.to_string()))
+}
+
+fn print_created(detail: &EnvDetail) {
+    println!("Environment created: {}", detail.env.id);
+    println!("  VMs: {}", detail.vms.len());
+    for vm in &detail.vms {
+        print_vm(vm);
+    }
+    for w in &detail.warnings {
+        eprintln!("  warning: {w}");
+    }
+}
+
+/// Follow the job creating env `name`, then show what was created.
+async fn create_and_wait(c: &Client, name: &str, job: Job) -> Result<()> {
+    let job = follow_job(c, &job.id).await?;
+    let mut detail: EnvDetail = c.get(&format!("/api/envs/{name}")).await?;
+    detail.warnings = job.warnings;
+    print_created(&detail);
+    Ok(())
+}
+
+fn print_job_hint(job: &Job) {
+    println!("  Job: {}", job.id);
+    println!("  Follow it with: tt job show {} --wait", job.id);
+}
+
+fn print_step(step: &JobStep) {
+    if step.detail.is_empty() {
+        println!("  [{}] {}", step.state, step.name);
+    } else {
+        println!("  [{}] {} — {}", step.state, step.name, step.detail);
+    }
+}
+
+/// Poll job `id` until it finishes, printing its steps as they change.
+/// Fails if the job does.
+async fn follow_job(c: &Client, id: &str) -> Result<Job> {
+    let mut seen: Vec<JobStep> = Vec::new();
+    loop {
+        let job: Job = c.get(&format!("/api/jobs/{id}")).await?;
+        for step in &job.steps {
+            if !seen.contains(step) {
+                print_step(step);
+            }
+        }
+        seen = job.steps.clone();
+        if job.state.is_finished() {
+            for w in &job.warnings {
+                eprintln!("  warning: {w}");
+            }
+            return match job.state {
+                JobState::Failed => Err(eg!(
+                    "job {id} failed: {}",
+                    job.error.as_deref().unwrap_or("unknown error")
+                )),
+                _ => Ok(job),
+            };
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+}
+
+fn print_vm(vm: &Vm) {
+    println!(
+        "    {} {} [{}] {} — {}  ports: {:?}",
+        vm.id, vm.name, vm.engine, vm.image, vm.ip, vm.port_map
+    );
+}
+
+/// VMs of an environment that support disk snapshots (everything but Docker).
+fn snapshot_vms(detail: &EnvDetail) -> impl Iterator<Item = &Vm> {
+    detail.vms.iter().filter(|vm| vm.engine != Engine::Docker)
+}
+
+async fn cmd_vm(c: &Client, action: VmCmd) -> Result<()> {
+    match action {
+        VmCmd::Console { id } => console::attach(c, &id).await,
+        VmCmd::Vnc { id, listen } => console::serve_vnc(c, &id, &listen).await,
+        VmCmd::Show { id } => {
+            let vm: Vm = c.get(&format!("/api/vms/{id}")).await?;
+            println!("VM: {}", vm.id);
+            println!("  Name:   {}", vm.name_or_id());
+            println!("  Env:    {}", vm.env_id);
+            println!("  Host:   {}", vm.host_id);
+            println!("  Image:  {} [{}]", vm.image, vm.engine);
+            println!(
+                "  Size:   {} CPU, {} MB RAM, {} MB disk",
+                vm.cpu, vm.mem, vm.disk
+            );
+            println!("  State:  {}", vm.state);
+            println!("  IP:     {}", vm.ip);
+            if let Some(o) = &vm.overlay {
+                println!("  Net IP: {}", o.ip);
+            }
+            for (g, h) in &vm.port_map {
+                println!("  Port:   {h}->{g}");
+            }
+            Ok(())
+        }
+        VmCmd::Stop { id } => vm_action(c, &id, "stop", "stopped").await,
+        VmCmd::Start { id } => vm_action(c, &id, "start", "started").await,
+        VmCmd::Suspend { id } => vm_action(c, &id, "suspend", "suspended").await,
+        VmCmd::Resume { id } => vm_action(c, &id, "resume", "resumed").await,
+        VmCmd::Hibernate { id } => vm_action(c, &id, "hibernate", "hibernated").await,
+        VmCmd::Reset { id } => vm_action(c, &id, "reset", "reset").await,
+        VmCmd::Reboot { id } => vm_action(c, &id, "reboot", "rebooted").await,
+        VmCmd::Migrate { id, to, cold, wait } => {
+            let job: Job = c
+                .post(
+                    &format!("/api/vms/{id}/migrate"),
+                    &MigrateVmReq {
+                        to: to.clone(),
+                        cold,
+                    },
+                )
+                .await?;
+            if wait {
+                // Copying a disk may take hours; the job keeps track of it
+                follow_job(c, &job.id).await?;
+                println!("VM migrated: {id} -> {to}");
+            } else {
+                println!("Migrating VM: {id} -> {to}");
+                print_job_hint(&job);
+            }
+            Ok(())
+        }
+        VmCmd::Destroy { id } => {
+            c.delete(&format!("/api/vms/{id}")).await?;
+            println!("VM destroyed: {id}");
+            Ok(())
+        }
+    }
+}
+
+async fn env_action(c: &Client, name: &str, action: &str, done: &str) -> Result<()> {
+    // Each host acts on its VMs one at a time, and guests may take a
+    // while to power down
+    c.post_action_timeout(
+        &format!("/api/envs/{name}/{action}"),
+        std::time::Duration::from_secs(3600),
+    )
+    .await?;
+    println!("Environment {done}: {name}");
+    Ok(())
+}
+
+async fn vm_action(c: &Client, id: &str, action: &str, done: &str) -> Result<()> {
+    // A guest may take a minute to power down, and up to ten to save
+    // or load its memory
+    c.post_action_timeout(
+        &format!("/api/vms/{id}/{action}"),
+        std::time::Duration::from_secs(700),
+    )
+    .await?;
+    println!("VM {done}: {id}");
+    Ok(())
+}
+
 async fn cmd_image(c: &Client, action: ImageCmd) -> Result<()> {
     match action {
         ImageCmd::List => {
@@ -497,9 +1452,433 @@ async fn cmd_image(c: &Client, action: ImageCmd) -> Result<()> {
                 println!("{:<30} {:<12}", img.name, img.host_id);
             }
         }
+        ImageCmd::Push {
+            image,
+            to,
+            from,
+            wait,
+        } => {
+            let req = PushImageReq { to, from };
+            let job: Job = c.post(&format!("/api/images/{image}/push"), &req).await?;
+            if wait {
+                // Each target host is a step, holding what was sent or why it failed
+                follow_job(c, &job.id).await?;
+                println!("Image pushed: {image}");
+            } else {
+                println!("Pushing image: {image}");
+                print_job_hint(&job);
+            }
+        }
         ImageCmd::Recipes | ImageCmd::Create { .. } => {
             unreachable!("handled before controller connection")
         }
     }
     Ok(())
 }
+
+async fn cmd_job(c: &Client, action: JobCmd) -> Result<()> {
+    match action {
+        JobCmd::List => {
+            let jobs: Vec<Job> = c.get("/api/jobs").await?;
+            if jobs.is_empty() {
+                println!("No jobs.");
+                return Ok(());
+            }
+            println!(
+                "{:<14} {:<12} {:<24} {:<10} {:<10}",
+                "ID", "KIND", "TARGET", "OWNER", "STATE"
+            );
+            for j in &jobs {
+                println!(
+                    "{:<14} {:<12} {:<24} {:<10} {:<10}",
+                    j.id,
+                    j.kind.to_string(),
+                    j.target,
+                    j.owner,
+                    j.state.to_string()
+                );
+            }
+        }
+        JobCmd::Show { id, wait } => {
+            if wait {
+                follow_job(c, &id).await?;
+                println!("Job {id} succeeded");
+                return Ok(());
+            }
+            let job: Job = c.get(&format!("/api/jobs/{id}")).await?;
+            println!("Job: {}", job.id);
+            println!("  Kind:   {}", job.kind);
+            println!("  Target: {}", job.target);
+            println!("  Owner:  {}", job.owner);
+            println!("  State:  {}", job.state);
+            for step in &job.steps {
+                print_step(step);
+            }
+            for w in &job.warnings {
+                println!("  warning: {w}");
+            }
+            if let Some(e) = &job.error {
+                println!("  error: {e}");
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn cmd_audit(
+    c: &Client,
+    filters: &[(&str, Option<String>)],
+    since: Option<u64>,
+    limit: Option<usize>,
+    jsonl: bool,
+) -> Result<()> {
+    let mut query = vec![];
+    for (k, v) in filters {
+        if let Some(v) = v {
+            query.push(format!("{k}={v}"));
+        }
+    }
+    if let Some(s) = since {
+        query.push(format!("since={}", now().saturating_sub(s)));
+    }
+    if let Some(n) = limit.or(jsonl.then_some(0)) {
+        query.push(format!("limit={n}"));
+    }
+    let entries: Vec<AuditEntry> = c.get(&format!("/api/audit?{}", query.join("&"))).await?;
+
+    if jsonl {
+        for e in entries.iter().rev() {
+            println!("{}", serde_json::to_string(e).c(d!())?);
+        }
+        return Ok(());
+    }
+    if entries.is_empty() {
+        println!("No audit entries.");
+        return Ok(());
+    }
+    println!(
+        "{:<10} {:<10} {:<15} {:<6} {:<40} {:<12} ERROR",
+        "WHEN", "USER", "SOURCE", "STATUS", "CALL", "REQUEST"
+    );
+    for e in &entries {
+        println!(
+            "{:<10} {:<10} {:<15} {:<6} {:<40} {:<12} {}",
+            format!("{} ago", fmt_duration(now().saturating_sub(e.at))),
+            e.user,
+            e.source,
+            e.status,
+            format!("{} {}", e.method, e.endpoint),
+            e.request_id.as_deref().unwrap_or("-"),
+            e.error.as_deref().unwrap_or("")
+        );
+    }
+    Ok(())
+}
+
+async fn cmd_log_level(c: &Client, filter: Option<String>, host: Option<String>) -> Result<()> {
+    let path = match &host {
+        Some(h) => format!("/api/hosts/{h}/log-level"),
+        None => "/api/log-level".to_string(),
+    };
+    let level: LogLevel = match filter {
+        Some(filter) => c.put(&path, &LogLevel { filter }).await?,
+        None => c.get(&path).await?,
+    };
+    println!("{}", level.filter);
+    Ok(())
+}
+
+async fn cmd_token(c: &Client, action: TokenCmd) -> Result<()> {
+    match action {
+        TokenCmd::Create { user, label } => {
+            let t: NewToken = c
+                .post("/api/tokens", &CreateTokenReq { user, label })
+                .await?;
+            println!("Token {} issued for {}.", t.token.id, t.token.user);
+            println!("  Secret: {}", t.secret);
+            println!("Store it now; it cannot be shown again. Use it with: tt login");
+        }
+        TokenCmd::List => {
+            let tokens: Vec<ApiToken> = c.get("/api/tokens").await?;
+            if tokens.is_empty() {
+                println!("No tokens.");
+                return Ok(());
+            }
+            println!("{:<14} {:<16} {:<12} LABEL", "ID", "USER", "CREATED");
+            for t in tokens {
+                println!(
+                    "{:<14} {:<16} {:<12} {}",
+                    t.id, t.user, t.created_at, t.label
+                );
+            }
+        }
+        TokenCmd::Revoke { id } => {
+            c.delete(&format!("/api/tokens/{id}")).await?;
+            println!("Token revoked: {id}");
+        }
+    }
+    Ok(())
+}
+
+async fn cmd_user(c: &Client, action: UserCmd) -> Result<()> {
+    match action {
+        UserCmd::Add { name, admin, group } => {
+            let role = if admin { Role::Admin } else { Role::User };
+            let req = CreateUserReq { name, role, group };
+            let u: User = c.post("/api/users", &req).await?;
+            println!("User created: {} ({})", u.name, u.role);
+            println!("Issue a token with: tt token create --user {}", u.name);
+        }
+        UserCmd::Set { name, role, group } => {
+            let role = match role.as_deref() {
+                None => None,
+                Some("admin") => Some(Role::Admin),
+                Some("user") => Some(Role::User),
+                Some(r) => return Err(eg!("unknown role: {}", r)),
+            };
+            let u: User = c
+                .put(
+                    &format!("/api/users/{name}"),
+                    &UpdateUserReq { role, group },
+                )
+                .await?;
+            println!(
+                "User updated: {} ({}, group: {})",
+                u.name,
+                u.role,
+                u.group.as_deref().unwrap_or("-")
+            );
+        }
+        UserCmd::List => {
+            let users: Vec<User> = c.get("/api/users").await?;
+            if users.is_empty() {
+                println!("No users.");
+                return Ok(());
+            }
+            println!("{:<16} {:<6} {:<12}", "NAME", "ROLE", "GROUP");
+            for u in users {
+                println!(
+                    "{:<16} {:<6} {:<12}",
+                    u.name,
+                    u.role,
+                    u.group.as_deref().unwrap_or("-")
+                );
+            }
+        }
+        UserCmd::Remove { name } => {
+            c.delete(&format!("/api/users/{name}")).await?;
+            println!("User removed: {name}");
+        }
+    }
+    Ok(())
+}
+
+async fn cmd_quota(c: &Client, action: QuotaCmd) -> Result<()> {
+    match action {
+        QuotaCmd::List => {
+            let quotas: Vec<QuotaStatus> = c.get("/api/quotas").await?;
+            if quotas.is_empty() {
+                println!("No quotas set.");
+                return Ok(());
+            }
+            println!(
+                "{:<20} {:>11} {:>15} {:>17} {:>9} {:>9} {:>9} {:>9}",
+                "SUBJECT", "CPU", "MEM(MB)", "DISK(MB)", "VMs", "ENVs", "LIFETIME", "MAX-LIFE"
+            );
+            for q in quotas {
+                println!(
+                    "{:<20} {:>11} {:>15} {:>17} {:>9} {:>9} {:>9} {:>9}",
+                    q.subject,
+                    used_of(q.usage.cpu, q.quota.cpu),
+                    used_of(q.usage.mem, q.quota.mem),
+                    used_of(q.usage.disk, q.quota.disk),
+                    used_of(q.usage.vms, q.quota.vms),
+                    used_of(q.usage.envs, q.quota.envs),
+                    q.quota
+                        .lifetime
+                        .map_or("-".to_string(), |l| format!("{l}s")),
+                    q.quota
+                        .max_lifetime
+                        .map_or("-".to_string(), |l| format!("{l}s")),
+                );
+            }
+        }
+        QuotaCmd::Show { subject } => {
+            let subject = match subject {
+                Some(s) => s,
+                None => c.get::<User>("/api/whoami").await?.name,
+            };
+            let q: QuotaStatus = c.get(&format!("/api/quotas/{subject}")).await?;
+            print_quota(&q);
+        }
+        QuotaCmd::Set {
+            subject,
+            cpu,
+            mem,
+            disk,
+            vms,
+            envs,
+            lifetime,
+            max_lifetime,
+        } => {
+            let quota = Quota {
+                cpu,
+                mem,
+                disk,
+                vms,
+                envs,
+                lifetime,
+                max_lifetime,
+            };
+            let q: QuotaStatus = c.put(&format!("/api/quotas/{subject}"), &quota).await?;
+            print_quota(&q);
+        }
+        QuotaCmd::Rm { subject } => {
+            c.delete(&format!("/api/quotas/{subject}")).await?;
+            println!("Quota removed: {subject}");
+        }
+    }
+    Ok(())
+}
+
+/// "used/limit", or just "used" when unlimited.
+fn used_of(used: u32, limit: Option<u32>) -> String {
+    match limit {
+        Some(l) => format!("{used}/{l}"),
+        None => used.to_string(),
+    }
+}
+
+fn print_quota(q: &QuotaStatus) {
+    let limit = |l: Option<u32>| l.map_or("unlimited".to_string(), |l| l.to_string());
+    println!("Quota: {}", q.subject);
+    println!("  {:<10} {:>10} {:>10}", "RESOURCE", "USED", "LIMIT");
+    println!(
+        "  {:<10} {:>10} {:>10}",
+        "cpu",
+        q.usage.cpu,
+        limit(q.quota.cpu)
+    );
+    println!(
+        "  {:<10} {:>10} {:>10}",
+        "mem (MB)",
+        q.usage.mem,
+        limit(q.quota.mem)
+    );
+    println!(
+        "  {:<10} {:>10} {:>10}",
+        "disk (MB)",
+        q.usage.disk,
+        limit(q.quota.disk)
+    );
+    println!(
+        "  {:<10} {:>10} {:>10}",
+        "vms",
+        q.usage.vms,
+        limit(q.quota.vms)
+    );
+    println!(
+        "  {:<10} {:>10} {:>10}",
+        "envs",
+        q.usage.envs,
+        limit(q.quota.envs)
+    );
+    println!(
+        "  {:<10} {:>10} {:>10}",
+        "lifetime",
+        "-",
+        q.quota
+            .lifetime
+            .map_or("unlimited".to_string(), |l| format!("{l}s"))
+    );
+    println!(
+        "  {:<10} {:>10} {:>10}",
+        "max-life",
+        "-",
+        q.quota
+            .max_lifetime
+            .map_or("default".to_string(), |l| format!("{l}s"))
+    );
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Parse a duration such as "90", "90s", "30m", "2h" or "1d" into seconds.
+fn parse_duration(s: &str) -> std::result::Result<u64, String> {
+    let s = s.trim();
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => {
+            return Err(format!(
+                "invalid duration '{s}': expected e.g. 90m, 2h or 1d"
+            ));
+        }
+    };
+    match num.parse::<u64>() {
+        Ok(n) if n > 0 => n
+            .checked_mul(scale)
+            .ok_or_else(|| format!("duration too long: {s}")),
+        _ => Err(format!(
+            "invalid duration '{s}': expected e.g. 90m, 2h or 1d"
+        )),
+    }
+}
+
+/// Render seconds as e.g. "2h 5m", "12m" or "40s".
+fn fmt_duration(secs: u64) -> String {
+    let (d, h, m) = (secs / 86400, secs % 86400 / 3600, secs % 3600 / 60);
+    match (d, h, m) {
+        (0, 0, 0) => format!("{secs}s"),
+        (0, 0, m) => format!("{m}m"),
+        (0, h, m) => format!("{h}h {m}m"),
+        (d, h, _) => format!("{d}d {h}h"),
+    }
+}
+
+/// "never", "in 2h 5m" or "now" for an env's `expires_at`.
+fn expires_in(expires_at: u64) -> String {
+    if expires_at == 0 {
+        return "never".to_string();
+    }
+    match expires_at.saturating_sub(now()) {
+        0 => "now".to_string(),
+        left => format!("in {}", fmt_duration(left)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("90").unwrap(), 90);
+        assert_eq!(parse_duration("90s").unwrap(), 90);
+        assert_eq!(parse_duration("30m").unwrap(), 1800);
+        assert_eq!(parse_duration("2h").unwrap(), 7200);
+        assert_eq!(parse_duration("1d").unwrap(), 86400);
+        for bad in ["", "0", "2w", "h", "1.5h", "-1h"] {
+            assert!(parse_duration(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn fmt_durations() {
+        assert_eq!(fmt_duration(40), "40s");
+        assert_eq!(fmt_duration(720), "12m");
+        assert_eq!(fmt_duration(7500), "2h 5m");
+        assert_eq!(fmt_duration(90000), "1d 1h");
+    }
+}
@@ -0,0 +1,424 @@
+//! Declarative environment spec files for `tt env apply` and
+//! `tt env export`.
+//!
+//! A spec describes an env and its VMs by name, in TOML or YAML
+//! (picked by file extension):
+//!
+//! ```toml
+//! name = "web"
+//! lifetime = 7200
+//! private_net = true
+//! ssh_keys = ["~/.ssh/id_ed25519.pub"]
+//!
+//! [vms.app]
+//! image = "alpine-cloud"
+//! cpu = 2
+//! ports = [80, 443]
+//! cloud_init_file = "app-init.yaml"
+//!
+//! [vms.db]
+//! image = "alpine-cloud"
+//! mem = 4096
+//! deny_outgoing = true
+//! ```
+//!
+//! SSH key entries may be public keys or paths to `.pub` files; paths
+//! and `cloud_init_file` are relative to the spec file.
+
+use ruc::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use ttcore::api::{CreateEnvReq, EnvDetail, VmSpec};
+use ttcore::model::{Engine, validate_name};
+
+/// An environment spec file.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnvFile {
+    pub name: String,
+    /// Lifetime in seconds; only used when the env is created.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lifetime: Option<u64>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub private_net: bool,
+    /// SSH keys for every VM.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ssh_keys: Vec<String>,
+    #[serde(default)]
+    pub vms: BTreeMap<String, VmFile>,
+}
+
+/// One named VM of a spec file.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VmFile {
+    pub image: String,
+    #[serde(default = "default_engine")]
+    pub engine: Engine,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mem: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disk: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ports: Vec<u16>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub deny_outgoing: bool,
+    /// SSH keys for this VM only.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ssh_keys: Vec<String>,
+    /// Cloud-init user-data, inline.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cloud_init: Option<String>,
+    /// Cloud-init user-data, read from a file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cloud_init_file: Option<String>,
+    /// Anti-affinity group: VMs sharing one go on different hosts
+    /// where possible.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub anti_affinity: Option<String>,
+    /// Labels its host must carry.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub node_selector: BTreeMap<String, String>,
+}
+
+fn default_engine() -> Engine {
+    Engine::Qemu
+}
+
+fn is_false(b: &bool) -> bool {
+    !b
+}
+
+/// Spec file syntax.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    /// YAML for `.yaml` / `.yml` files, TOML otherwise.
+    pub fn of_path(path: &str) -> Self {
+        if path.ends_with(".yaml") || path.ends_with(".yml") {
+            Format::Yaml
+        } else {
+            Format::Toml
+        }
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "toml" => Ok(Format::Toml),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            _ => Err(format!("unknown spec format: {s} (expected toml or yaml)")),
+        }
+    }
+}
+
+/// Whether an `ssh_keys` entry names a key file rather than a key.
+pub fn is_key_path(k: &str) -> bool {
+    (k.ends_with(".pub") || k.starts_with('/') || k.starts_with("~/")) && !k.starts_with("ssh-")
+}
+
+/// Expand a leading `~/` to the home directory.
+pub fn expand_home(p: &str) -> String {
+    match p.strip_prefix("~/") {
+        Some(rest) => format!("{}/{rest}", std::env::var("HOME").unwrap_or_default()),
+        None => p.to_string(),
+    }
+}
+
+/// Parse a spec file's contents.
+pub fn parse(content: &str, format: Format) -> Result<EnvFile> {
+    let file: EnvFile = match format {
+        Format::Toml => toml::from_str(content).map_err(|e| eg!(format!("parse spec: {e}")))?,
+        Format::Yaml => {
+            serde_yaml::from_str(content).map_err(|e| eg!(format!("parse spec: {e}")))?
+        }
+    };
+    validate_name(&file.name, "env name").map_err(|e| eg!(e))?;
+    for (name, vm) in &file.vms {
+        validate_name(name, "VM name").map_err(|e| eg!(e))?;
+        if vm.cloud_init.is_some() && vm.cloud_init_file.is_some() {
+            return Err(eg!(
+                "VM '{}': cloud_init and cloud_init_file are mutually exclusive",
+                name
+            ));
+        }
+    }
+    Ok(file)
+}
+
+/// Turn a spec into an env request, reading the key and cloud-init
+/// files it refers to relative to `base`.
+pub fn to_request(file: EnvFile, base: &Path) -> Result<CreateEnvReq> {
+    let read = |p: &str| -> Result<String> {
+        let path = base.join(expand_home(p));
+        std::fs::read_to_string(&path).c(d!("read {}", path.display()))
+    };
+    let keys = |entries: Vec<String>| -> Result<Vec<String>> {
+        entries
+            .into_iter()
+            .map(|k| {
+                if is_key_path(&k) {
+                    read(&k).map(|s| s.trim().to_string())
+                } else {
+                    Ok(k)
+                }
+            })
+            .collect()
+    };
+
+    let mut vms = Vec::with_capacity(file.vms.len());
+    for (name, vm) in file.vms {
+        let user_data = match vm.cloud_init_file {
+            Some(p) => Some(read(&p)?),
+            None => vm.cloud_init,
+        };
+        vms.push(VmSpec {
+            image: vm.image,
+            engine: vm.engine,
+            cpu: vm.cpu,
+            mem: vm.mem,
+            disk: vm.disk,
+            ports: vm.ports,
+            deny_outgoing: vm.deny_outgoing,
+            ssh_keys: keys(vm.ssh_keys)?,
+            name: Some(name),
+            user_data,
+            anti_affinity: vm.anti_affinity,
+            node_selector: vm.node_selector,
+        });
+    }
+
+    Ok(CreateEnvReq {
+        id: file.name,
+        owner: String::new(),
+        vms,
+        lifetime: file.lifetime,
+        ssh_keys: keys(file.ssh_keys)?,
+        private_net: file.private_net,
+        all_or_nothing: true,
+    })
+}
+
+/// Load a spec file as an env request.
+pub fn load(path: &str) -> Result<CreateEnvReq> {
+    let content = std::fs::read_to_string(path).c(d!("read {}", path))?;
+    let file = parse(&content, Format::of_path(path))?;
+    let base = Path::new(path).parent().unwrap_or(Path::new(""));
+    to_request(file, base)
+}
+
+/// The spec of a running env.
+///
+/// Keys shared by every VM are hoisted to the env; the lifetime is the
+/// one the env was created with.
+pub fn export(detail: &EnvDetail) -> EnvFile {
+    let env = &detail.env;
+    let common: Vec<String> = match detail.vms.split_first() {
+        Some((first, rest)) => first
+            .ssh_keys
+            .iter()
+            .filter(|k| rest.iter().all(|vm| vm.ssh_keys.contains(k)))
+            .cloned()
+            .collect(),
+        None => vec![],
+    };
+
+    let vms = detail
+        .vms
+        .iter()
+        .map(|vm| {
+            let spec = VmFile {
+                image: vm.image.clone(),
+                engine: vm.engine,
+                cpu: Some(vm.cpu),
+                mem: Some(vm.mem),
+                disk: Some(vm.disk),
+                // Port 22 is always forwarded
+                ports: vm.port_map.keys().copied().filter(|&p| p != 22).collect(),
+                deny_outgoing: vm.deny_outgoing,
+                ssh_keys: vm
+                    .ssh_keys
+                    .iter()
+                    .filter(|k| !common.contains(k))
+                    .cloned()
+                    .collect(),
+                cloud_init: vm.user_data.clone(),
+                cloud_init_file: None,
+                anti_affinity: vm.anti_affinity.clone(),
+                node_selector: vm.node_selector.clone(),
+            };
+            (vm.name_or_id().to_string(), spec)
+        })
+        .collect();
+
+    EnvFile {
+        name: env.id.clone(),
+        lifetime: (env.expires_at > env.created_at).then(|| env.expires_at - env.created_at),
+        private_net: env.network.is_some(),
+        ssh_keys: common,
+        vms,
+    }
+}
+
+/// Render a spec in the given syntax.
+pub fn render(file: &EnvFile, format: Format) -> Result<String> {
+    match format {
+        Format::Toml => toml::to_string(file).map_err(|e| eg!(format!("render spec: {e}"))),
+        Format::Yaml => serde_yaml::to_string(file).map_err(|e| eg!(format!("render spec: {e}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use ttcore::model::*;
+
+    const SPEC: &str = r##"
+name = "web"
+lifetime = 7200
+private_net = true
+ssh_keys = ["ssh-ed25519 AAAA alice"]
+
+[vms.app]
+image = "alpine-cloud"
+cpu = 2
+ports = [80]
+cloud_init = "#!/bin/sh\necho hi\n"
+
+[vms.db]
+image = "debian"
+engine = "firecracker"
+deny_outgoing = true
+"##;
+
+    fn vm(name: &str, keys: &[&str]) -> Vm {
+        Vm {
+            id: format!("id-{name}"),
+            env_id: "web".into(),
+            host_id: "h1".into(),
+            image: "alpine-cloud".into(),
+            cpu: 2,
+            mem: 1024,
+            disk: 4096,
+            ip: "10.10.0.2".into(),
+            port_map: BTreeMap::from([(22, 20000), (80, 20001)]),
+            state: VmState::Running,
+            name: name.into(),
+            ssh_keys: keys.iter().map(|k| k.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn toml_spec_to_request() {
+        let file = parse(SPEC, Format::Toml).unwrap();
+        let req = to_request(file, Path::new("")).unwrap();
+        assert_eq!(req.id, "web");
+        assert_eq!(req.lifetime, Some(7200));
+        assert!(req.private_net);
+        assert_eq!(req.ssh_keys, vec!["ssh-ed25519 AAAA alice"]);
+
+        assert_eq!(req.vms.len(), 2);
+        let app = &req.vms[0];
+        assert_eq!(app.name.as_deref(), Some("app"));
+        assert_eq!(app.engine, Engine::Qemu);
+        assert_eq!(app.user_data.as_deref(), Some("#!/bin/sh\necho hi\n"));
+        let db = &req.vms[1];
+        assert_eq!(db.engine, Engine::Firecracker);
+        assert!(db.deny_outgoing);
+        assert_eq!(db.cpu, None);
+    }
+
+    #[test]
+    fn yaml_and_toml_agree() {
+        let yaml = r##"
+name: web
+lifetime: 7200
+private_net: true
+ssh_keys: ["ssh-ed25519 AAAA alice"]
+vms:
+  app:
+    image: alpine-cloud
+    cpu: 2
+    ports: [80]
+    cloud_init: "#!/bin/sh\necho hi\n"
+  db:
+    image: debian
+    engine: firecracker
+    deny_outgoing: true
+"##;
+        assert_eq!(
+            parse(yaml, Format::Yaml).unwrap(),
+            parse(SPEC, Format::Toml).unwrap()
+        );
+        assert_eq!(Format::of_path("env.yml"), Format::Yaml);
+        assert_eq!(Format::of_path("env.toml"), Format::Toml);
+    }
+
+    #[test]
+    fn invalid_specs_are_rejected() {
+        assert!(parse("name = \"a b\"", Format::Toml).is_err());
+        assert!(parse("name = \"web\"\nsize = 3", Format::Toml).is_err());
+        let both =
+            "name = \"web\"\n[vms.a]\nimage = \"x\"\ncloud_init = \"\"\ncloud_init_file = \"f\"";
+        assert!(parse(both, Format::Toml).is_err());
+    }
+
+    #[test]
+    fn key_and_cloud_init_files_are_read_relative_to_spec() {
+        let dir = std::env::temp_dir().join(format!("tt-spec-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("alice.pub"), "ssh-ed25519 BBBB alice\n").unwrap();
+        std::fs::write(dir.join("init.yaml"), "#cloud-config\n").unwrap();
+
+        let spec = "name = \"web\"\nssh_keys = [\"alice.pub\"]\n\
+                    [vms.a]\nimage = \"x\"\ncloud_init_file = \"init.yaml\"";
+        let req = to_request(parse(spec, Format::Toml).unwrap(), &dir).unwrap();
+        assert_eq!(req.ssh_keys, vec!["ssh-ed25519 BBBB alice"]);
+        assert_eq!(req.vms[0].user_data.as_deref(), Some("#cloud-config\n"));
+
+        let missing = "name = \"web\"\nssh_keys = [\"bob.pub\"]";
+        assert!(to_request(parse(missing, Format::Toml).unwrap(), &dir).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn export_hoists_common_keys_and_round_trips() {
+        let detail = EnvDetail {
+            env: Env {
+                id: "web".into(),
+                owner: "alice".into(),
+                vm_ids: vec!["id-app".into(), "id-db".into()],
+                created_at: 100,
+                expires_at: 3700,
+                state: EnvState::Active,
+                network: None,
+                reserved: None,
+            },
+            vms: vec![vm("app", &["k1", "k2"]), vm("db", &["k1"])],
+            warnings: vec![],
+        };
+        let file = export(&detail);
+        assert_eq!(file.lifetime, Some(3600));
+        assert_eq!(file.ssh_keys, vec!["k1"]);
+        assert_eq!(file.vms["app"].ssh_keys, vec!["k2"]);
+        assert!(file.vms["db"].ssh_keys.is_empty());
+        assert_eq!(file.vms["app"].ports, vec![80]);
+
+        for format in [Format::Toml, Format::Yaml] {
+            let text = render(&file, format).unwrap();
+            assert_eq!(parse(&text, format).unwrap(), file, "{text}");
+        }
+    }
+}
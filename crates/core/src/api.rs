@@ -2,6 +2,7 @@
 
 use crate::model::*;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 // ── Agent API (controller → agent) ─────────────────────────────────
 
@@ -20,6 +21,38 @@ pub struct CreateVmReq {
     /// SSH public keys to inject into the VM for tenant access.
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub ssh_keys: Vec<String>,
+    /// NIC on the env's private network; the network must already be
+    /// set up on the agent (`PUT /api/networks/{vni}`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overlay: Option<OverlayNic>,
+    /// Name of the VM within its env.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    /// Custom cloud-init user-data, merged with the generated config.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_data: Option<String>,
+    /// Anti-affinity group within the env, kept on the VM.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub anti_affinity: Option<String>,
+    /// Host labels the VM was placed by, kept on the VM.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub node_selector: BTreeMap<String, String>,
+    /// Address to give the VM instead of allocating one; a VM moved to
+    /// another host keeps its address.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    /// Boot from a disk already transferred to this host (see
+    /// `/api/vms/{id}/disk/*`) instead of cloning the image.
+    #[serde(default)]
+    pub migrated: bool,
+}
+
+/// Request to set up (or update) an env network on an agent.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NetworkReq {
+    /// Underlay IPs of the other hosts carrying this network.
+    #[serde(default)]
+    pub peers: Vec<String>,
 }
 
 /// Response from agent after creating a VM.
@@ -28,6 +61,110 @@ pub struct CreateVmResp {
     pub vm: Vm,
 }
 
+/// Request to take a named snapshot of a VM's disk.
+///
+/// Accepted by both the agent and the controller
+/// (`POST /api/vms/{id}/snapshots`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotReq {
+    pub name: String,
+}
+
+/// Query of `POST /api/vms/{id}/snapshots/{name}/rollback`, on both the
+/// agent and the controller.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RollbackQuery {
+    /// Delete the snapshots taken after the one rolled back to, which
+    /// otherwise make the rollback fail.
+    #[serde(default)]
+    pub force: bool,
+}
+
+/// Size of one chunk in a file-backed image transfer.
+pub const IMAGE_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Header carrying the hex SHA-256 of an image chunk body.
+pub const CHUNK_SHA256_HEADER: &str = "x-tt-sha256";
+
+/// A regular file inside a file-backed image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageFile {
+    /// Path relative to the image root; empty for single-file images.
+    pub path: String,
+    pub size: u64,
+    /// Unix permission bits.
+    pub mode: u32,
+    /// Hex-encoded SHA-256 of the file contents.
+    pub sha256: String,
+}
+
+/// A symlink inside a file-backed image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageLink {
+    /// Path relative to the image root.
+    pub path: String,
+    /// What the link points to, as stored. It must stay within the image,
+    /// absolute targets counting from the image root as inside a jail.
+    pub target: String,
+}
+
+/// What an agent holds of a base image, used to plan a transfer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageManifest {
+    pub name: String,
+    pub storage: Storage,
+    /// zvol only: snapshot names, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub snapshots: Vec<String>,
+    /// file only: whether the image is a directory rather than a single file.
+    #[serde(default)]
+    pub is_dir: bool,
+    /// file only: every regular file of the image.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<ImageFile>,
+    /// file only: every symlink of the image.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub links: Vec<ImageLink>,
+}
+
+/// Header naming the zvol snapshot a VM disk stream was sent from.
+pub const DISK_SNAPSHOT_HEADER: &str = "x-tt-snapshot";
+
+/// Where an agent listens for an incoming live migration.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MigrationPorts {
+    /// IP address of the target host both ports are bound to.
+    pub addr: String,
+    /// Receives the VM's memory and device state.
+    pub memory: u16,
+    /// NBD export of the VM's new disk, which the source mirrors into.
+    pub disk: u16,
+}
+
+/// Request to prepare for an incoming live migration
+/// (`POST /api/vms/incoming`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IncomingVmReq {
+    /// The VM as it is to be created here; `migrated` is implied.
+    pub vm: CreateVmReq,
+    /// Virtual size of the VM's disk on the source host.
+    pub disk_bytes: u64,
+    /// IP address of this host as the controller reaches it; the
+    /// migration ports are bound to it.
+    pub listen: String,
+    /// IP address of the source host, the only one let in.
+    pub peer: String,
+}
+
+/// Request to live-migrate a VM to another agent
+/// (`POST /api/vms/{id}/migrate` on the source agent).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MigrateOutReq {
+    /// IP address of the target host.
+    pub host: String,
+    pub ports: MigrationPorts,
+}
+
 /// Information reported by an agent about itself.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AgentInfo {
@@ -36,6 +173,64 @@ pub struct AgentInfo {
     pub engines: Vec<Engine>,
     pub storage: Storage,
     pub images: Vec<String>,
+    /// Labels from the agent's config.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// What an agent started with `--controller` pushes to
+/// `POST /api/hosts/{id}/heartbeat`: it registers the host on the first
+/// one and keeps it online after.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Heartbeat {
+    /// Address the controller reaches the agent at; an unspecified IP
+    /// (`0.0.0.0:9100`) is replaced by the one the heartbeat came from.
+    pub addr: String,
+    /// Seconds until the next heartbeat.
+    pub interval: u64,
+    pub info: AgentInfo,
+    /// VMs whose state changed since the last heartbeat the controller
+    /// took; all of them in the first one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub vms: Vec<VmStatus>,
+}
+
+/// A VM's state, as reported in a [`Heartbeat`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmStatus {
+    pub id: String,
+    pub state: VmState,
+}
+
+/// A daemon's log filter, as read and set at `/api/log-level`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogLevel {
+    /// `RUST_LOG` directives, e.g. `info` or `warn,tt_agent=debug`.
+    pub filter: String,
+}
+
+/// What a VM, or all VMs of a host, actually used over the sampling
+/// interval ending at `at`. Fields the VM's engine cannot tell are
+/// left out.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UsageSample {
+    pub at: u64,
+    /// CPUs busy on average, e.g. 0.5 for half of one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<f64>,
+    /// Host memory in use at `at`, in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mem_bytes: Option<u64>,
+    /// Disk and network throughput, in bytes per second; the network
+    /// as seen by the guest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disk_read_bps: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disk_write_bps: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub net_rx_bps: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub net_tx_bps: Option<f64>,
 }
 
 // ── Controller API (CLI → controller) ──────────────────────────────
@@ -56,6 +251,20 @@ pub struct VmSpec {
     /// Per-VM SSH keys (merged with env-level keys).
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub ssh_keys: Vec<String>,
+    /// Name of the VM, unique within its env; generated if omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Cloud-init user-data (a `#cloud-config` document or a script)
+    /// run on first boot, in addition to the generated config.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_data: Option<String>,
+    /// Anti-affinity group: the scheduler keeps VMs of an env that
+    /// share a group on different hosts where it can.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub anti_affinity: Option<String>,
+    /// Labels a host must carry to run the VM.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub node_selector: BTreeMap<String, String>,
 }
 
 fn default_engine() -> Engine {
@@ -66,6 +275,9 @@ fn default_engine() -> Engine {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateEnvReq {
     pub id: String,
+    /// Owner of the env; only honoured for admins, everyone else
+    /// always owns the envs they create.
+    #[serde(default)]
     pub owner: String,
     pub vms: Vec<VmSpec>,
     /// Lifetime in seconds; `None` means use server default.
@@ -73,6 +285,27 @@ pub struct CreateEnvReq {
     /// SSH public keys applied to all VMs in this environment.
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub ssh_keys: Vec<String>,
+    /// Give the env a private network spanning all of its hosts.
+    #[serde(default)]
+    pub private_net: bool,
+    /// Roll the whole env back if any VM cannot be created, instead of
+    /// keeping the VMs that were.
+    #[serde(default = "default_all_or_nothing")]
+    pub all_or_nothing: bool,
+}
+
+fn default_all_or_nothing() -> bool {
+    true
+}
+
+/// Request to add VMs to an existing environment
+/// (`POST /api/envs/{id}/vms`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddVmsReq {
+    pub vms: Vec<VmSpec>,
+    /// SSH public keys applied to all of the new VMs.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ssh_keys: Vec<String>,
 }
 
 /// Full environment details returned to the CLI.
@@ -85,10 +318,102 @@ pub struct EnvDetail {
     pub warnings: Vec<String>,
 }
 
+/// Request to push back an environment's expiry
+/// (`POST /api/envs/{id}/extend`).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExtendEnvReq {
+    /// Seconds to add to the current expiry (or to now, if later).
+    #[serde(default)]
+    pub by: u64,
+    /// Never expire the env (admins only); `by` is ignored.
+    #[serde(default)]
+    pub never_expire: bool,
+}
+
+/// How applying a spec converges an env, by VM name.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EnvPlan {
+    /// VMs in the spec but not in the env.
+    pub create: Vec<String>,
+    /// VMs whose spec changed; they are destroyed and created anew.
+    pub replace: Vec<String>,
+    /// VMs in the env but not in the spec.
+    pub remove: Vec<String>,
+    /// VMs that already match the spec.
+    pub keep: Vec<String>,
+}
+
+impl EnvPlan {
+    /// Whether applying the plan would change nothing.
+    pub fn is_empty(&self) -> bool {
+        self.create.is_empty() && self.replace.is_empty() && self.remove.is_empty()
+    }
+}
+
+/// Response to `POST /api/envs/{id}/apply`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApplyResp {
+    pub plan: EnvPlan,
+    /// The env after the changes; absent for a dry run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<EnvDetail>,
+}
+
+/// Request to create a controller user (admin only).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateUserReq {
+    pub name: String,
+    #[serde(default)]
+    pub role: Role,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
+
+/// Request to change a user's role or group (admin only).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpdateUserReq {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<Role>,
+    /// New group; an empty string removes the user from their group.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
+
+/// A quota together with what its subject currently uses.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuotaStatus {
+    /// `user:<name>` or `group:<name>`.
+    pub subject: String,
+    pub quota: Quota,
+    pub usage: Usage,
+}
+
+/// Request to issue an API token.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CreateTokenReq {
+    /// User to issue the token for; defaults to the caller. Only admins
+    /// may issue tokens for other users.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub label: String,
+}
+
+/// A freshly issued API token, including its secret.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewToken {
+    pub token: ApiToken,
+    /// Bearer secret; it cannot be retrieved again later.
+    pub secret: String,
+}
+
 /// Host registration request from CLI or auto-discovery.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RegisterHostReq {
     pub addr: String,
+    /// Labels added to (or overriding) those the agent reports.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
 }
 
 /// Summary of available images across the fleet.
@@ -98,6 +423,59 @@ pub struct ImageInfo {
     pub host_id: String,
 }
 
+/// Request to copy an image from a host that has it to other hosts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushImageReq {
+    /// Target host ID, or `"all"` for every online host.
+    pub to: String,
+    /// Source host ID; `None` picks any host that has the image.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+}
+
+/// Request to move a VM to another host (`POST /api/vms/{id}/migrate`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MigrateVmReq {
+    /// Target host ID.
+    pub to: String,
+    /// Stop the VM and boot it on the target, even where it could be
+    /// live-migrated.
+    #[serde(default)]
+    pub cold: bool,
+}
+
+/// Request to take a host out of service (`POST /api/hosts/{id}/drain`).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DrainHostReq {
+    /// Move VMs cold, even those that could be live-migrated.
+    #[serde(default)]
+    pub cold: bool,
+    /// Rebuild VMs that cannot be migrated from their image on another
+    /// host, losing their disk contents.
+    #[serde(default)]
+    pub recreate: bool,
+    /// Put the host in maintenance right away and leave its VMs on it.
+    #[serde(default)]
+    pub keep_vms: bool,
+}
+
+/// Progress of evacuating a draining host.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DrainProgress {
+    pub host_id: String,
+    /// VMs on the host when the drain started.
+    pub total: u32,
+    /// One line per VM moved off the host.
+    pub moved: Vec<String>,
+    /// One line per VM left on the host, with the reason.
+    pub failed: Vec<String>,
+    /// ID of the VM being moved.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current: Option<String>,
+    pub finished: bool,
+    pub started_at: u64,
+}
+
 /// Global status of the fleet.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FleetStatus {
@@ -113,6 +491,64 @@ pub struct FleetStatus {
     pub disk_used: u32,
 }
 
+/// A change in the fleet, as streamed by `GET /api/events`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FleetEvent {
+    /// Position in the controller's stream; starts over when it restarts.
+    pub seq: u64,
+    pub at: u64,
+    #[serde(flatten)]
+    pub change: FleetChange,
+}
+
+/// What changed, tagged by `type`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FleetChange {
+    EnvCreated {
+        env: String,
+        owner: String,
+    },
+    EnvDeleted {
+        env: String,
+        owner: String,
+    },
+    EnvExpired {
+        env: String,
+        owner: String,
+    },
+    VmState {
+        vm: String,
+        env: String,
+        owner: String,
+        from: VmState,
+        to: VmState,
+    },
+    HostState {
+        host: String,
+        from: HostState,
+        to: HostState,
+    },
+    /// A job was started or made progress.
+    Job {
+        job: Job,
+    },
+}
+
+impl FleetChange {
+    /// User the change concerns; `None` for changes everyone may see.
+    pub fn owner(&self) -> Option<&str> {
+        match self {
+            Self::EnvCreated { owner, .. }
+            | Self::EnvDeleted { owner, .. }
+            | Self::EnvExpired { owner, .. }
+            | Self::VmState { owner, .. } => Some(owner),
+            Self::HostState { .. } => None,
+            Self::Job { job } => Some(&job.owner),
+        }
+    }
+}
+
 // ── Generic API Wrapper ────────────────────────────────────────────
 
 /// Standard API response envelope.
@@ -218,4 +654,41 @@ mod tests {
         assert!(spec.ports.is_empty());
         assert!(!spec.deny_outgoing);
     }
+
+    #[test]
+    fn create_env_req_is_all_or_nothing_by_default() {
+        let req: CreateEnvReq = serde_json::from_str(r#"{"id":"e1","vms":[]}"#).unwrap();
+        assert!(req.all_or_nothing);
+
+        let json = r#"{"id":"e1","vms":[],"all_or_nothing":false}"#;
+        let req: CreateEnvReq = serde_json::from_str(json).unwrap();
+        assert!(!req.all_or_nothing);
+    }
+
+    #[test]
+    fn create_user_req_defaults_to_user_role() {
+        let req: CreateUserReq = serde_json::from_str(r#"{"name":"alice"}"#).unwrap();
+        assert_eq!(req.role, Role::User);
+
+        let req: CreateUserReq = serde_json::from_str(r#"{"name":"bob","role":"admin"}"#).unwrap();
+        assert_eq!(req.role, Role::Admin);
+    }
+
+    #[test]
+    fn fleet_event_is_flat_and_tagged() {
+        let ev = FleetEvent {
+            seq: 7,
+            at: 100,
+            change: FleetChange::HostState {
+                host: "h1".into(),
+                from: HostState::Online,
+                to: HostState::Offline,
+            },
+        };
+        let json = serde_json::to_value(&ev).unwrap();
+        assert_eq!(json["type"], "host_state");
+        assert_eq!(json["to"], "offline");
+        assert_eq!(serde_json::from_value::<FleetEvent>(json).unwrap(), ev);
+        assert_eq!(ev.change.owner(), None);
+    }
 }
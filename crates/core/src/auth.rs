@@ -18,10 +18,49 @@ pub fn constant_time_eq(a: &str, b: &str) -> bool {
     diff == 0
 }
 
+/// Key host `host_id` sends its heartbeats with to a controller whose
+/// admin key is `admin_key`.
+///
+/// It only lets that host report in, and is derived from the admin key
+/// (HMAC-SHA256, RFC 2104) so that the controller needs to store none.
+pub fn host_key(admin_key: &str, host_id: &str) -> String {
+    use sha2::{Digest, Sha256};
+    const BLOCK: usize = 64;
+
+    let mut key = [0u8; BLOCK];
+    if admin_key.len() > BLOCK {
+        key[..32].copy_from_slice(&Sha256::digest(admin_key));
+    } else {
+        key[..admin_key.len()].copy_from_slice(admin_key.as_bytes());
+    }
+    let pad = |b: u8| key.map(|k| k ^ b);
+    let inner = Sha256::new()
+        .chain_update(pad(0x36))
+        .chain_update(host_id)
+        .finalize();
+    let mac = Sha256::new()
+        .chain_update(pad(0x5c))
+        .chain_update(inner)
+        .finalize();
+    let hex: String = mac.iter().map(|b| format!("{b:02x}")).collect();
+    format!("tth-{hex}")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn host_keys_are_hmac_sha256() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            host_key("Jefe", "what do ya want for nothing?"),
+            "tth-5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_ne!(host_key("k1", "h1"), host_key("k1", "h2"));
+        assert_ne!(host_key("k1", "h1"), host_key("k2", "h1"));
+    }
+
     #[test]
     fn equal_strings() {
         assert!(constant_time_eq("abc", "abc"));
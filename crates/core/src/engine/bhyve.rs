@@ -3,11 +3,12 @@
 //! Bhyve is the native hypervisor on FreeBSD. This module is only
 //! compiled on FreeBSD targets via `#[cfg(target_os = "freebsd")]`.
 
-use super::VmEngine;
+use super::{Console, VmEngine};
 use crate::model::{RUN_DIR, Vm, VmState};
 use crate::net;
 use ruc::*;
 use std::process::Command;
+use tracing::warn;
 
 pub struct BhyveEngine;
 
@@ -20,6 +21,12 @@ impl BhyveEngine {
         format!("{RUN_DIR}/bhyve-{}.pid", vm.id)
     }
 
+    /// One side of the null-modem pair carrying the VM's COM1 port:
+    /// bhyve holds `A`, console sessions open `B` (needs `nmdm.ko`).
+    fn nmdm_path(vm: &Vm, side: char) -> String {
+        format!("/dev/nmdm-{}{side}", vm.id)
+    }
+
     fn read_pid(vm: &Vm) -> Option<i32> {
         std::fs::read_to_string(Self::pid_path(vm))
             .ok()
@@ -53,15 +60,23 @@ impl VmEngine for BhyveEngine {
         let pid_path = format!("{RUN_DIR}/bhyve-{}.pid", vm.id);
         std::fs::create_dir_all(RUN_DIR).c(d!("create pid dir"))?;
 
-        let child = Command::new("bhyve")
-            .args(["-A", "-H", "-P"])
+        let mut cmd = Command::new("bhyve");
+        cmd.args(["-A", "-H", "-P"])
             .args(["-c", &vm.cpu.to_string()])
             .args(["-m", &format!("{}M", vm.mem)])
             .args(["-s", "0:0,hostbridge"])
             .args(["-s", &format!("3:0,virtio-blk,{image_path}")])
-            .args(["-s", &format!("4:0,virtio-net,{tap}")])
+            .args(["-s", &format!("4:0,virtio-net,{tap}")]);
+        if vm.overlay.is_some() {
+            let otap = net::overlay_tap_name(&vm.id);
+            cmd.args([
+                "-s",
+                &format!("5:0,virtio-net,{otap},mac={}", net::vm_mac(&vm.id, 1)),
+            ]);
+        }
+        let child = cmd
             .args(["-s", "31,lpc"])
-            .args(["-l", "com1,/dev/null"])
+            .args(["-l", &format!("com1,{}", Self::nmdm_path(vm, 'A'))])
             .arg(&vm.id)
             .stdin(std::process::Stdio::null())
             .stdout(std::process::Stdio::null())
@@ -75,12 +90,10 @@ impl VmEngine for BhyveEngine {
         Ok(())
     }
 
-    fn start(&self, _vm: &Vm) -> Result<()> {
-        // Bhyve doesn't support pause/resume natively;
-        // "start" after destroy requires re-create.
-        Err(eg!(
-            "bhyve does not support in-place restart; re-create the VM"
-        ))
+    fn start(&self, vm: &Vm, image_path: &str, disk_format: &str) -> Result<()> {
+        // Stopping tears the VM down, so starting is a cold boot
+        // from its disk
+        self.create(vm, image_path, disk_format, &[])
     }
 
     fn stop(&self, vm: &Vm) -> Result<()> {
@@ -102,10 +115,7 @@ impl VmEngine for BhyveEngine {
         if !output.status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr);
             // Not fatal — the VM device may already be gone
-            eprintln!(
-                "[bhyve] WARN: bhyvectl --destroy failed for {}: {}",
-                vm.id, stderr
-            );
+            warn!("bhyvectl --destroy failed for {}: {}", vm.id, stderr);
         }
 
         Ok(())
@@ -141,6 +151,12 @@ impl VmEngine for BhyveEngine {
         }
     }
 
+    fn console(&self, vm: &Vm) -> Result<Console> {
+        let mut cmd = Command::new("socat");
+        cmd.args(["-", &format!("{},raw,echo=0", Self::nmdm_path(vm, 'B'))]);
+        Ok(Console::Command(cmd))
+    }
+
     fn name(&self) -> &'static str {
         "bhyve"
     }
@@ -3,7 +3,7 @@
 //! Auto-detects whether `docker` or `podman` is available and uses
 //! whichever is found (preferring podman for rootless operation).
 
-use super::VmEngine;
+use super::{Console, VmEngine, VmStats, stats};
 use crate::model::{Vm, VmState};
 use ruc::*;
 use std::process::Command;
@@ -47,6 +47,37 @@ impl DockerEngine {
     fn container_name(vm: &Vm) -> String {
         format!("tt-{}", vm.id)
     }
+
+    /// Restart a container, killing it after `grace` seconds.
+    fn restart(vm: &Vm, grace: u32) -> Result<()> {
+        let output = Command::new(Self::runtime())
+            .args([
+                "restart",
+                "-t",
+                &grace.to_string(),
+                &Self::container_name(vm),
+            ])
+            .output()
+            .c(d!())?;
+
+        if !output.status.success() {
+            return Err(eg!("container restart failed"));
+        }
+        Ok(())
+    }
+
+    /// Freeze (`pause`) or thaw (`unpause`) a container's processes.
+    fn freeze(vm: &Vm, cmd: &str) -> Result<()> {
+        let output = Command::new(Self::runtime())
+            .args([cmd, &Self::container_name(vm)])
+            .output()
+            .c(d!())?;
+
+        if !output.status.success() {
+            return Err(eg!("container {} failed", cmd));
+        }
+        Ok(())
+    }
 }
 
 impl VmEngine for DockerEngine {
@@ -61,7 +92,8 @@ impl VmEngine for DockerEngine {
         let rt = Self::runtime();
 
         let mut cmd = Command::new(rt);
-        cmd.args(["run", "-d", "--name", &name])
+        // Keep stdin open so `attach` can be used as a console
+        cmd.args(["run", "-d", "-i", "--name", &name])
             .args(["--cpus", &vm.cpu.to_string()])
             .args(["--memory", &format!("{}m", vm.mem)]);
 
@@ -83,7 +115,7 @@ impl VmEngine for DockerEngine {
         Ok(())
     }
 
-    fn start(&self, vm: &Vm) -> Result<()> {
+    fn start(&self, vm: &Vm, _image_path: &str, _disk_format: &str) -> Result<()> {
         let name = Self::container_name(vm);
         let output = Command::new(Self::runtime())
             .args(["start", &name])
@@ -109,6 +141,22 @@ impl VmEngine for DockerEngine {
         Ok(())
     }
 
+    fn suspend(&self, vm: &Vm) -> Result<()> {
+        Self::freeze(vm, "pause")
+    }
+
+    fn resume(&self, vm: &Vm) -> Result<()> {
+        Self::freeze(vm, "unpause")
+    }
+
+    fn reset(&self, vm: &Vm) -> Result<()> {
+        Self::restart(vm, 0)
+    }
+
+    fn reboot(&self, vm: &Vm) -> Result<()> {
+        Self::restart(vm, 10)
+    }
+
     fn destroy(&self, vm: &Vm) -> Result<()> {
         let name = Self::container_name(vm);
         // Force remove the container
@@ -143,6 +191,46 @@ impl VmEngine for DockerEngine {
         }
     }
 
+    fn console(&self, vm: &Vm) -> Result<Console> {
+        let mut cmd = Command::new(Self::runtime());
+        cmd.args(["attach", "--sig-proxy=false", &Self::container_name(vm)]);
+        Ok(Console::Command(cmd))
+    }
+
+    fn stats(&self, vm: &Vm) -> Result<VmStats> {
+        let name = Self::container_name(vm);
+        let output = Command::new(Self::runtime())
+            .args(["inspect", "-f", "{{.State.Pid}}", &name])
+            .output()
+            .c(d!())?;
+        let pid: u32 = String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse()
+            .unwrap_or(0);
+        if !output.status.success() || pid == 0 {
+            return Err(eg!("container {} is not running", name));
+        }
+
+        // Reading the cgroup is instant, while `stats` samples for a
+        // second or two
+        if let Some(s) = stats::container(pid) {
+            return Ok(s);
+        }
+        let output = Command::new(Self::runtime())
+            .args(["stats", "--no-stream", "--format", "{{json .}}", &name])
+            .output()
+            .c(d!())?;
+        if !output.status.success() {
+            return Err(eg!(
+                "{} stats failed: {}",
+                Self::runtime(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        stats::parse_docker_stats(&String::from_utf8_lossy(&output.stdout))
+            .ok_or_else(|| eg!("unreadable {} stats output", Self::runtime()))
+    }
+
     fn name(&self) -> &'static str {
         "docker"
     }
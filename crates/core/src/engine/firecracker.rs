@@ -2,15 +2,21 @@
 //!
 //! Uses the Firecracker VMM for lightweight, fast-booting microVMs.
 //! Communicates with the Firecracker process via its REST API socket.
+//! The serial console is the process's stdio: input comes from a FIFO
+//! and output is appended to a log file, so both outlive the agent.
 
-use super::VmEngine;
+use super::{Console, VmEngine, VmStats, stats};
 use crate::model::{RUN_DIR, Vm, VmState};
 use ruc::*;
 use std::path::Path;
 use std::process::Command;
+use tracing::warn;
 
 pub struct FirecrackerEngine;
 
+/// How long a guest gets to shut down on stop before it is killed.
+const POWERDOWN_TIMEOUT_SECS: u64 = 60;
+
 impl Default for FirecrackerEngine {
     fn default() -> Self {
         Self::new()
@@ -34,18 +40,147 @@ impl FirecrackerEngine {
         format!("{RUN_DIR}/fc-{}.json", vm.id)
     }
 
+    fn console_in_path(vm: &Vm) -> String {
+        format!("{RUN_DIR}/fc-{}.in", vm.id)
+    }
+
+    fn console_log_path(vm: &Vm) -> String {
+        format!("{RUN_DIR}/fc-{}.log", vm.id)
+    }
+
     fn read_pid(vm: &Vm) -> Result<u32> {
         let path = Self::pid_path(vm);
         let content = std::fs::read_to_string(&path).c(d!("read fc pid"))?;
         content.trim().parse::<u32>().c(d!("invalid pid"))
     }
 
+    fn process_alive(pid: u32) -> bool {
+        Path::new(&format!("/proc/{pid}")).exists()
+    }
+
+    /// Kill the VMM and wait (briefly) for it to be gone, since its TAP
+    /// devices are only free afterwards.
+    fn kill(pid: u32) {
+        let _ = nix::sys::signal::kill(
+            nix::unistd::Pid::from_raw(pid as i32),
+            nix::sys::signal::Signal::SIGKILL,
+        );
+        for _ in 0..50 {
+            if !Self::process_alive(pid) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+    }
+
+    /// Remove the files of a VMM that is gone. The config, console log
+    /// and rootfs stay for the next boot.
+    fn remove_process_files(vm: &Vm) {
+        let _ = std::fs::remove_file(Self::socket_path(vm));
+        let _ = std::fs::remove_file(Self::pid_path(vm));
+    }
+
+    /// Wait (briefly) for a freshly spawned VMM to open its API socket.
+    fn wait_for_socket(vm: &Vm) -> Result<()> {
+        let sock = Self::socket_path(vm);
+        for _ in 0..50 {
+            if Path::new(&sock).exists() {
+                return Ok(());
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        Err(eg!(
+            "firecracker of VM {} did not open its API socket",
+            vm.id
+        ))
+    }
+
+    /// Call the Firecracker API of the VM.
+    fn api(vm: &Vm, method: &str, path: &str, body: &str) -> Result<()> {
+        let sock = Self::socket_path(vm);
+        if !Path::new(&sock).exists() {
+            return Err(eg!("firecracker socket not found for VM {}", vm.id));
+        }
+
+        let output = Command::new("curl")
+            .args(["--unix-socket", &sock, "-sSf", "-X", method])
+            .arg(format!("http://localhost{path}"))
+            .args(["-H", "Content-Type: application/json", "-d", body])
+            .output()
+            .c(d!("firecracker API {} {}", method, path))?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(eg!("firecracker {} {} failed: {}", method, path, stderr));
+        }
+        Ok(())
+    }
+
+    /// Spawn the VMM with `args` besides its API socket: its config
+    /// file to boot, or nothing to load a snapshot over the API.
+    fn launch(&self, vm: &Vm, args: &[&str]) -> Result<()> {
+        let sock = Self::socket_path(vm);
+        // Firecracker refuses to bind a stale API socket
+        let _ = std::fs::remove_file(&sock);
+
+        // Opened read-write so firecracker never sees EOF between console
+        // sessions, and opening it does not wait for a writer
+        let fifo = Self::console_in_path(vm);
+        let _ = std::fs::remove_file(&fifo);
+        let mode = nix::sys::stat::Mode::S_IRUSR | nix::sys::stat::Mode::S_IWUSR;
+        nix::unistd::mkfifo(fifo.as_str(), mode).c(d!("create console fifo"))?;
+        let stdin = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&fifo)
+            .c(d!("open console fifo"))?;
+        let stdout = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(Self::console_log_path(vm))
+            .c(d!("open console log"))?;
+
+        let mut child = Command::new("firecracker")
+            .args(["--api-sock", &sock])
+            .args(args)
+            .stdin(stdin)
+            .stdout(stdout)
+            .spawn()
+            .c(d!("spawn firecracker"))?;
+
+        let pid = child.id();
+        std::fs::write(Self::pid_path(vm), pid.to_string()).c(d!("write pid"))?;
+
+        // Spawn a reaper thread so the child process is wait()ed on,
+        // preventing zombie processes if the Firecracker VM exits.
+        std::thread::spawn(move || {
+            let _ = child.wait();
+        });
+
+        Ok(())
+    }
+
     fn write_config(&self, vm: &Vm, image_path: &str) -> Result<()> {
         let tap = crate::net::tap_name(&vm.id);
+        let mut boot_args = String::from("console=ttyS0 reboot=k panic=1 pci=off");
+        let mut nics = vec![serde_json::json!({
+            "iface_id": "eth0",
+            "host_dev_name": tap,
+        })];
+        // The env network NIC is configured by the kernel (ip= autoconfig)
+        // so it works without any support in the guest image.
+        if let Some(overlay) = &vm.overlay {
+            nics.push(serde_json::json!({
+                "iface_id": "eth1",
+                "host_dev_name": crate::net::overlay_tap_name(&vm.id),
+                "guest_mac": crate::net::vm_mac(&vm.id, 1),
+            }));
+            boot_args.push_str(&format!(" ip={}:::255.255.255.0::eth1:off", overlay.ip));
+        }
         let config = serde_json::json!({
             "boot-source": {
                 "kernel_image_path": format!("{image_path}/vmlinux"),
-                "boot_args": "console=ttyS0 reboot=k panic=1 pci=off"
+                "boot_args": boot_args
             },
             "drives": [{
                 "drive_id": "rootfs",
@@ -57,10 +192,7 @@ impl FirecrackerEngine {
                 "vcpu_count": vm.cpu,
                 "mem_size_mib": vm.mem,
             },
-            "network-interfaces": [{
-                "iface_id": "eth0",
-                "host_dev_name": tap,
-            }]
+            "network-interfaces": nics
         });
 
         let path = Self::config_path(vm);
@@ -80,84 +212,112 @@ impl VmEngine for FirecrackerEngine {
         std::fs::create_dir_all(RUN_DIR).c(d!("create runtime dir"))?;
 
         self.write_config(vm, image_path)?;
+        self.launch(vm, &["--config-file", &Self::config_path(vm)])
+    }
 
-        let sock = Self::socket_path(vm);
-        let config = Self::config_path(vm);
+    fn start(&self, vm: &Vm, image_path: &str, _disk_format: &str) -> Result<()> {
+        if Self::read_pid(vm).is_ok_and(Self::process_alive) {
+            return self.resume(vm);
+        }
+        // Powered off: cold-boot from the preserved rootfs
+        self.write_config(vm, image_path)?;
+        self.launch(vm, &["--config-file", &Self::config_path(vm)])
+    }
 
-        let mut child = Command::new("firecracker")
-            .args(["--api-sock", &sock])
-            .args(["--config-file", &config])
-            .spawn()
-            .c(d!("spawn firecracker"))?;
+    fn stop(&self, vm: &Vm) -> Result<()> {
+        if let Ok(pid) = Self::read_pid(vm)
+            && Self::process_alive(pid)
+        {
+            // Guests boot with reboot=k, so Ctrl+Alt+Del makes them shut
+            // down and the VMM exit; a paused guest must run to see it
+            if matches!(self.state(vm), Ok(VmState::Paused)) {
+                self.resume(vm)?;
+            }
+            match Self::api(
+                vm,
+                "PUT",
+                "/actions",
+                r#"{"action_type": "SendCtrlAltDel"}"#,
+            ) {
+                Ok(()) => {
+                    for _ in 0..POWERDOWN_TIMEOUT_SECS * 2 {
+                        if !Self::process_alive(pid) {
+                            break;
+                        }
+                        std::thread::sleep(std::time::Duration::from_millis(500));
+                    }
+                }
+                Err(e) => warn!("could not shut down {} cleanly: {e}", vm.id),
+            }
+            Self::kill(pid);
+        }
 
-        let pid = child.id();
-        std::fs::write(Self::pid_path(vm), pid.to_string()).c(d!("write pid"))?;
+        Self::remove_process_files(vm);
+        Ok(())
+    }
 
-        // Spawn a reaper thread so the child process is wait()ed on,
-        // preventing zombie processes if the Firecracker VM exits.
-        std::thread::spawn(move || {
-            let _ = child.wait();
-        });
+    fn suspend(&self, vm: &Vm) -> Result<()> {
+        Self::api(vm, "PATCH", "/vm", r#"{"state": "Paused"}"#)
+    }
 
-        Ok(())
+    fn resume(&self, vm: &Vm) -> Result<()> {
+        Self::api(vm, "PATCH", "/vm", r#"{"state": "Resumed"}"#)
     }
 
-    fn start(&self, vm: &Vm) -> Result<()> {
-        let sock = Self::socket_path(vm);
-        if !Path::new(&sock).exists() {
-            return Err(eg!("firecracker socket not found for VM {}", vm.id));
+    fn hibernate(&self, vm: &Vm, state_dir: &str) -> Result<()> {
+        let pid = Self::read_pid(vm)?;
+        if !Self::process_alive(pid) {
+            return Err(eg!("VM {} is not running", vm.id));
         }
 
-        // Resume a paused VM via the Firecracker API
-        let output = Command::new("curl")
-            .args([
-                "--unix-socket",
-                &sock,
-                "-s",
-                "-X",
-                "PATCH",
-                "http://localhost/vm",
-                "-H",
-                "Content-Type: application/json",
-                "-d",
-                r#"{"state": "Resumed"}"#,
-            ])
-            .output()
-            .c(d!("resume firecracker"))?;
-
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(eg!("firecracker resume failed: {}", stderr));
+        // Snapshots can only be taken of a paused microVM
+        self.suspend(vm)?;
+        let req = serde_json::json!({
+            "snapshot_type": "Full",
+            "snapshot_path": format!("{state_dir}/vmstate"),
+            "mem_file_path": format!("{state_dir}/memory"),
+        });
+        if let Err(e) = Self::api(vm, "PUT", "/snapshot/create", &req.to_string()) {
+            let _ = self.resume(vm);
+            return Err(e).c(d!("save memory of VM {}", vm.id));
         }
+
+        Self::kill(pid);
+        Self::remove_process_files(vm);
         Ok(())
     }
 
-    fn stop(&self, vm: &Vm) -> Result<()> {
-        let sock = Self::socket_path(vm);
-        if !Path::new(&sock).exists() {
-            return Ok(());
+    fn restore(
+        &self,
+        vm: &Vm,
+        _image_path: &str,
+        _disk_format: &str,
+        state_dir: &str,
+    ) -> Result<()> {
+        let vmstate = format!("{state_dir}/vmstate");
+        if !Path::new(&vmstate).exists() {
+            return Err(eg!("VM {} has no saved memory", vm.id));
         }
 
-        // Pause the VM via the Firecracker API (preserves the process)
-        let output = Command::new("curl")
-            .args([
-                "--unix-socket",
-                &sock,
-                "-s",
-                "-X",
-                "PATCH",
-                "http://localhost/vm",
-                "-H",
-                "Content-Type: application/json",
-                "-d",
-                r#"{"state": "Paused"}"#,
-            ])
-            .output()
-            .c(d!("pause firecracker"))?;
-
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(eg!("firecracker pause failed: {}", stderr));
+        // The snapshot records the rootfs and TAP devices, which are
+        // still in place
+        self.launch(vm, &[])?;
+        let req = serde_json::json!({
+            "snapshot_path": vmstate,
+            "mem_backend": {
+                "backend_type": "File",
+                "backend_path": format!("{state_dir}/memory"),
+            },
+            "resume_vm": true,
+        });
+        let loaded = Self::wait_for_socket(vm)
+            .and_then(|_| Self::api(vm, "PUT", "/snapshot/load", &req.to_string()));
+        if let Err(e) = loaded {
+            if let Ok(pid) = Self::read_pid(vm) {
+                Self::kill(pid);
+            }
+            Self::remove_process_files(vm);
+            return Err(e).c(d!("load memory of VM {}", vm.id));
         }
         Ok(())
     }
@@ -173,13 +333,15 @@ impl VmEngine for FirecrackerEngine {
         let _ = std::fs::remove_file(Self::socket_path(vm));
         let _ = std::fs::remove_file(Self::pid_path(vm));
         let _ = std::fs::remove_file(Self::config_path(vm));
+        let _ = std::fs::remove_file(Self::console_in_path(vm));
+        let _ = std::fs::remove_file(Self::console_log_path(vm));
 
         Ok(())
     }
 
     fn state(&self, vm: &Vm) -> Result<VmState> {
         match Self::read_pid(vm) {
-            Ok(pid) if Path::new(&format!("/proc/{pid}")).exists() => {
+            Ok(pid) if Self::process_alive(pid) => {
                 // Query Firecracker API to distinguish Running vs Paused
                 let sock = Self::socket_path(vm);
                 if Path::new(&sock).exists()
@@ -198,6 +360,30 @@ impl VmEngine for FirecrackerEngine {
         }
     }
 
+    fn console(&self, vm: &Vm) -> Result<Console> {
+        let input = Self::console_in_path(vm);
+        if !Path::new(&input).exists() {
+            return Err(eg!("VM {} has no serial console fifo", vm.id));
+        }
+        Ok(Console::Fifo {
+            input,
+            output: Self::console_log_path(vm),
+        })
+    }
+
+    fn stats(&self, vm: &Vm) -> Result<VmStats> {
+        let pid = Self::read_pid(vm)?;
+        if !Self::process_alive(pid) {
+            return Err(eg!("VM {} is not running", vm.id));
+        }
+        let mut s = stats::process(pid);
+        (s.net_rx_bytes, s.net_tx_bytes) = stats::tap_traffic(&[
+            crate::net::tap_name(&vm.id),
+            crate::net::overlay_tap_name(&vm.id),
+        ]);
+        Ok(s)
+    }
+
     fn name(&self) -> &'static str {
         "firecracker"
     }
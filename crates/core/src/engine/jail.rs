@@ -3,7 +3,7 @@
 //! Uses FreeBSD jails for lightweight OS-level virtualization.
 //! Each jail gets its own root filesystem, network stack, and process space.
 
-use super::VmEngine;
+use super::{Console, VmEngine};
 use crate::model::{Vm, VmState};
 use ruc::*;
 use std::path::Path;
@@ -64,13 +64,25 @@ impl VmEngine for JailEngine {
             }
         }
 
+        // Jails share the host stack, so the env network address is an
+        // alias on the env bridge that jail(8) adds and removes with the jail
+        let mut ip4 = vm.ip.clone();
+        if let Some(overlay) = &vm.overlay {
+            ip4.push_str(&format!(
+                ",{}|{}/{}",
+                crate::net::overlay_bridge_name(overlay.vni),
+                overlay.ip,
+                crate::net::OVERLAY_PREFIX
+            ));
+        }
+
         // Create the jail with the given root filesystem
         let output = Command::new("jail")
             .args(["-c"])
             .arg(format!("name={name}"))
             .arg(format!("path={}", abs_path.display()))
             .arg("host.hostname=ttstack")
-            .arg(format!("ip4.addr={}", vm.ip))
+            .arg(format!("ip4.addr={ip4}"))
             .arg("persist")
             .arg("mount.devfs")
             .output()
@@ -84,7 +96,7 @@ impl VmEngine for JailEngine {
         Ok(())
     }
 
-    fn start(&self, vm: &Vm) -> Result<()> {
+    fn start(&self, vm: &Vm, _image_path: &str, _disk_format: &str) -> Result<()> {
         // For jails that were stopped with 'persist' flag, we re-create
         let name = Self::jail_name(vm);
 
@@ -141,6 +153,13 @@ impl VmEngine for JailEngine {
         }
     }
 
+    fn console(&self, vm: &Vm) -> Result<Console> {
+        // script(1) gives the shell a pty, so it prompts and echoes
+        let mut cmd = Command::new("script");
+        cmd.args(["-q", "/dev/null", "jexec", &Self::jail_name(vm), "/bin/sh"]);
+        Ok(Console::Command(cmd))
+    }
+
     fn name(&self) -> &'static str {
         "jail"
     }
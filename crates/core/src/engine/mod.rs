@@ -16,9 +16,26 @@ pub mod firecracker;
 pub mod jail;
 #[cfg(target_os = "linux")]
 pub mod qemu;
+pub mod stats;
 
+use crate::api::MigrationPorts;
 use crate::model::{Engine, Vm, VmState};
 use ruc::*;
+use std::process::Command;
+
+pub use stats::VmStats;
+
+/// Where the interactive console of a running instance can be reached.
+#[derive(Debug)]
+pub enum Console {
+    /// Unix socket carrying the raw serial stream.
+    Socket(String),
+    /// FIFO feeding the guest's serial input, and the file its output
+    /// is appended to.
+    Fifo { input: String, output: String },
+    /// Process whose stdin/stdout/stderr are the console.
+    Command(Command),
+}
 
 /// Trait implemented by each hypervisor / container engine.
 pub trait VmEngine: Send + Sync {
@@ -35,17 +52,104 @@ pub trait VmEngine: Send + Sync {
     ) -> Result<()>;
 
     /// Start a previously stopped VM.
-    fn start(&self, vm: &Vm) -> Result<()>;
+    ///
+    /// Engines whose [`stop`](Self::stop) ends the VM process cold-boot
+    /// it again from its preserved disk at `image_path`.
+    fn start(&self, vm: &Vm, image_path: &str, disk_format: &str) -> Result<()>;
 
-    /// Gracefully stop a running VM.
+    /// Power a VM off, giving its guest the chance to shut down cleanly
+    /// first. Its host CPU and memory are free afterwards; its disk is
+    /// kept. May block while the guest shuts down.
     fn stop(&self, vm: &Vm) -> Result<()>;
 
+    /// Pause a running VM in place. It keeps its memory, and carries on
+    /// where it left off on [`resume`](Self::resume).
+    fn suspend(&self, _vm: &Vm) -> Result<()> {
+        Err(eg!("{} instances cannot be suspended", self.name()))
+    }
+
+    /// Resume a suspended VM.
+    fn resume(&self, _vm: &Vm) -> Result<()> {
+        Err(eg!("{} instances cannot be resumed", self.name()))
+    }
+
+    /// Save the memory of a running or suspended VM under `state_dir`
+    /// and end its process, freeing its host CPU and memory. May block
+    /// while the memory is written out.
+    fn hibernate(&self, _vm: &Vm, _state_dir: &str) -> Result<()> {
+        Err(eg!("{} instances cannot be hibernated", self.name()))
+    }
+
+    /// Bring a hibernated VM back from its disk at `image_path` and the
+    /// memory saved under `state_dir`.
+    fn restore(
+        &self,
+        _vm: &Vm,
+        _image_path: &str,
+        _disk_format: &str,
+        _state_dir: &str,
+    ) -> Result<()> {
+        Err(eg!("{} instances cannot be restored", self.name()))
+    }
+
+    /// Launch a VM that waits for a live migration from another host.
+    /// Its memory arrives on `ports.memory`; its disk is written into
+    /// the empty disk at `image_path` through an NBD export on
+    /// `ports.disk`.
+    fn migrate_in(
+        &self,
+        _vm: &Vm,
+        _image_path: &str,
+        _disk_format: &str,
+        _ports: &MigrationPorts,
+    ) -> Result<()> {
+        Err(eg!("{} instances cannot be live-migrated", self.name()))
+    }
+
+    /// Live-migrate a running VM, disk and memory, to `host`, where it
+    /// waits in [`migrate_in`](Self::migrate_in). May block for long.
+    ///
+    /// The VM is left paused here, so it can carry on with
+    /// [`resume`](Self::resume) should the target fail to take over.
+    fn migrate_out(&self, _vm: &Vm, _host: &str, _ports: &MigrationPorts) -> Result<()> {
+        Err(eg!("{} instances cannot be live-migrated", self.name()))
+    }
+
+    /// Run a VM whose incoming live migration has completed.
+    fn finish_migrate_in(&self, _vm: &Vm) -> Result<()> {
+        Err(eg!("{} instances cannot be live-migrated", self.name()))
+    }
+
+    /// Hard-reset a running VM, like pressing its reset button.
+    fn reset(&self, _vm: &Vm) -> Result<()> {
+        Err(eg!("{} instances cannot be reset", self.name()))
+    }
+
+    /// Reboot a running VM, giving its guest the chance to shut down
+    /// cleanly first. May block while the guest shuts down.
+    fn reboot(&self, _vm: &Vm) -> Result<()> {
+        Err(eg!("{} instances cannot be rebooted", self.name()))
+    }
+
     /// Destroy the VM and clean up all associated resources.
     fn destroy(&self, vm: &Vm) -> Result<()>;
 
     /// Query the current state of the VM.
     fn state(&self, vm: &Vm) -> Result<VmState>;
 
+    /// Locate the serial console (or shell, for containers) of a running VM.
+    fn console(&self, vm: &Vm) -> Result<Console>;
+
+    /// Unix socket of the VM's VNC display, for engines that have one.
+    fn vnc(&self, _vm: &Vm) -> Result<String> {
+        Err(eg!("{} instances have no graphical console", self.name()))
+    }
+
+    /// Resource usage of a running VM so far.
+    fn stats(&self, _vm: &Vm) -> Result<VmStats> {
+        Err(eg!("{} instances report no usage", self.name()))
+    }
+
     /// Human-readable engine name.
     fn name(&self) -> &'static str;
 }
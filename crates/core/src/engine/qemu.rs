@@ -3,11 +3,14 @@
 //! Launches VMs via `qemu-system-x86_64` with KVM acceleration.
 //! Each VM gets its own tap device connected to the host bridge.
 
-use super::VmEngine;
+use super::{Console, VmEngine, VmStats, stats};
+use crate::api::MigrationPorts;
 use crate::model::{RUN_DIR, Vm, VmState};
+use crate::net;
 use ruc::*;
 use std::path::Path;
 use std::process::Command;
+use tracing::warn;
 
 pub struct QemuEngine;
 
@@ -23,7 +26,7 @@ impl QemuEngine {
     }
 
     fn build_cmd(&self, vm: &Vm, disk_path: &str, disk_format: &str) -> Command {
-        let tap = crate::net::tap_name(&vm.id);
+        let tap = net::tap_name(&vm.id);
         let mut cmd = Command::new("qemu-system-x86_64");
         cmd.args(["-enable-kvm", "-daemonize"])
             .args(["-name", &vm.id])
@@ -37,13 +40,35 @@ impl QemuEngine {
                 "-netdev",
                 &format!("tap,id=net0,ifname={tap},script=no,downscript=no"),
             ])
-            .args(["-device", "virtio-net-pci,netdev=net0"])
+            .args([
+                "-device",
+                &format!("virtio-net-pci,netdev=net0,mac={}", net::vm_mac(&vm.id, 0)),
+            ])
             .args(["-pidfile", &self.pid_path(vm)])
             .args([
                 "-monitor",
                 &format!("unix:{},server,nowait", self.monitor_path(vm)),
             ])
-            .args(["-vnc", "none"]);
+            // Keep QEMU up when the guest powers off, so it can be reset
+            // into a fresh boot
+            .arg("-no-shutdown")
+            .args([
+                "-serial",
+                &format!("unix:{},server,nowait", self.serial_path(vm)),
+            ])
+            .args(["-vnc", &format!("unix:{}", self.vnc_path(vm))]);
+
+        if vm.overlay.is_some() {
+            let otap = net::overlay_tap_name(&vm.id);
+            cmd.args([
+                "-netdev",
+                &format!("tap,id=net1,ifname={otap},script=no,downscript=no"),
+            ])
+            .args([
+                "-device",
+                &format!("virtio-net-pci,netdev=net1,mac={}", net::vm_mac(&vm.id, 1)),
+            ]);
+        }
 
         // Attach cloud-init seed ISO if it exists (for cloud images)
         let seed = self.seed_path(vm);
@@ -69,13 +94,14 @@ impl QemuEngine {
         let meta_data = format!("instance-id: {}\nlocal-hostname: {}\n", vm.id, vm.id);
         std::fs::write(format!("{seed_dir}/meta-data"), meta_data).c(d!("write meta-data"))?;
 
-        // network-config (v2) — static IP on the virtio NIC
-        let network_config = format!(
+        // network-config (v2) — static IP on the primary NIC, plus the
+        // env network NIC if the VM has one
+        let mut network_config = format!(
             r#"version: 2
 ethernets:
   id0:
     match:
-      driver: virtio_net
+      macaddress: "{mac}"
     addresses:
       - {ip}/16
     routes:
@@ -87,31 +113,29 @@ ethernets:
         - 1.1.1.1
 "#,
             ip = vm.ip,
+            mac = net::vm_mac(&vm.id, 0),
         );
+        if let Some(overlay) = &vm.overlay {
+            network_config.push_str(&format!(
+                r#"  id1:
+    match:
+      macaddress: "{mac}"
+    mtu: {mtu}
+    addresses:
+      - {ip}/{prefix}
+"#,
+                mac = net::vm_mac(&vm.id, 1),
+                mtu = net::OVERLAY_MTU,
+                ip = overlay.ip,
+                prefix = net::OVERLAY_PREFIX,
+            ));
+        }
         std::fs::write(format!("{seed_dir}/network-config"), network_config)
             .c(d!("write network-config"))?;
 
-        // user-data — inject SSH keys, disable password login
-        let mut user_data = String::from(
-            "#cloud-config\n\
-             disable_root: false\n\
-             ssh_pwauth: false\n",
-        );
-
-        if !ssh_keys.is_empty() {
-            user_data.push_str("ssh_authorized_keys:\n");
-            for key in ssh_keys {
-                user_data.push_str(&format!("  - {key}\n"));
-            }
-        }
-
-        user_data.push_str(
-            "runcmd:\n  \
-             - sed -i 's/^#*PermitRootLogin.*/PermitRootLogin prohibit-password/' /etc/ssh/sshd_config\n  \
-             - sed -i 's/^#*PasswordAuthentication.*/PasswordAuthentication no/' /etc/ssh/sshd_config\n  \
-             - systemctl restart sshd 2>/dev/null || service sshd restart 2>/dev/null || rc-service sshd restart 2>/dev/null || true\n",
-        );
-
+        // user-data — inject SSH keys, disable password login, then run
+        // the tenant's own user-data
+        let user_data = user_data(ssh_keys, vm.user_data.as_deref());
         std::fs::write(format!("{seed_dir}/user-data"), user_data).c(d!("write user-data"))?;
 
         // Generate ISO using genisoimage or mkisofs
@@ -155,6 +179,14 @@ ethernets:
         format!("{RUN_DIR}/qemu-{}.sock", vm.id)
     }
 
+    fn serial_path(&self, vm: &Vm) -> String {
+        format!("{RUN_DIR}/qemu-{}.serial", vm.id)
+    }
+
+    fn vnc_path(&self, vm: &Vm) -> String {
+        format!("{RUN_DIR}/qemu-{}.vnc", vm.id)
+    }
+
     fn seed_path(&self, vm: &Vm) -> String {
         format!("{RUN_DIR}/seed-{}.iso", vm.id)
     }
@@ -168,6 +200,213 @@ ethernets:
     fn process_alive(pid: u32) -> bool {
         Path::new(&format!("/proc/{pid}")).exists()
     }
+
+    /// Run a command on the VM's QEMU monitor and return its output.
+    fn monitor(&self, vm: &Vm, cmd: &str) -> Result<String> {
+        let sock = self.monitor_path(vm);
+        if !Path::new(&sock).exists() {
+            // Monitor socket gone means QEMU exited
+            return Err(eg!(
+                "VM {} has no monitor socket; stop and start it again",
+                vm.id
+            ));
+        }
+        let output = Command::new("sh")
+            .args([
+                "-c",
+                &format!(r#"echo "{cmd}" | socat - UNIX-CONNECT:{sock}"#),
+            ])
+            .output()
+            .c(d!("qemu monitor {}", cmd))?;
+        if !output.status.success() {
+            return Err(eg!("QEMU monitor command '{}' failed", cmd));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Run a command built by [`build_cmd`](Self::build_cmd), which
+    /// daemonizes QEMU.
+    fn launch(mut cmd: Command) -> Result<()> {
+        let output = cmd.output().c(d!("spawn qemu"))?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(eg!("qemu launch failed: {}", stderr));
+        }
+
+        Ok(())
+    }
+
+    /// Like [`monitor`](Self::monitor), but fail if the command reports
+    /// an error.
+    fn monitor_ok(&self, vm: &Vm, cmd: &str) -> Result<()> {
+        let out = self.monitor(vm, cmd)?;
+        match out.lines().find(|l| l.contains("Error:")) {
+            Some(err) => Err(eg!("QEMU monitor command '{}' failed: {}", cmd, err.trim())),
+            None => Ok(()),
+        }
+    }
+
+    /// Run a monitor command every 500ms until `done` holds for its
+    /// output, for at most `timeout_secs`.
+    fn poll_monitor(
+        &self,
+        vm: &Vm,
+        cmd: &str,
+        timeout_secs: u64,
+        done: impl Fn(&str) -> bool,
+    ) -> Result<String> {
+        for _ in 0..timeout_secs * 2 {
+            if !self.read_pid(vm).is_ok_and(Self::process_alive) {
+                return Err(eg!("QEMU of VM {} exited", vm.id));
+            }
+            let out = self.monitor(vm, cmd)?;
+            if done(&out) {
+                return Ok(out);
+            }
+            std::thread::sleep(std::time::Duration::from_millis(500));
+        }
+        Err(eg!("timed out waiting on '{}' for VM {}", cmd, vm.id))
+    }
+
+    /// Kill a QEMU process and wait (briefly) for it to be gone, since
+    /// its TAP devices are only free afterwards.
+    fn kill(pid: u32) {
+        let _ = nix::sys::signal::kill(
+            nix::unistd::Pid::from_raw(pid as i32),
+            nix::sys::signal::Signal::SIGKILL,
+        );
+        for _ in 0..50 {
+            if !Self::process_alive(pid) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+    }
+
+    /// Remove the files of a QEMU process that is gone. The disk and
+    /// seed ISO stay for the next boot.
+    fn remove_process_files(&self, vm: &Vm) {
+        let _ = std::fs::remove_file(self.pid_path(vm));
+        let _ = std::fs::remove_file(self.monitor_path(vm));
+        let _ = std::fs::remove_file(self.serial_path(vm));
+        let _ = std::fs::remove_file(self.vnc_path(vm));
+    }
+
+    /// Press the guest's ACPI power button and wait up to
+    /// [`POWERDOWN_TIMEOUT_SECS`] for it to power off.
+    ///
+    /// Returns whether the QEMU process is still up, which it is unless
+    /// it exited meanwhile; a guest that ignored the button is left
+    /// running.
+    fn power_down(&self, vm: &Vm) -> Result<bool> {
+        self.monitor(vm, "system_powerdown")?;
+        for _ in 0..POWERDOWN_TIMEOUT_SECS * 2 {
+            std::thread::sleep(std::time::Duration::from_millis(500));
+            if !self.read_pid(vm).is_ok_and(Self::process_alive) {
+                return Ok(false);
+            }
+            if self.monitor(vm, "info status")?.contains("shutdown") {
+                break;
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// How long a guest gets to power down on stop or reboot before it is
+/// forced off.
+const POWERDOWN_TIMEOUT_SECS: u64 = 60;
+
+/// How long saving or loading the memory of a hibernated VM may take.
+const STATE_TRANSFER_TIMEOUT_SECS: u64 = 600;
+
+/// How long each of mirroring the disk and moving the memory of a
+/// live-migrated VM may take.
+const MIGRATION_TIMEOUT_SECS: u64 = 4 * 3600;
+
+/// Block device ID QEMU gives the VM's disk, the first `if=virtio`
+/// drive.
+const DISK_DRIVE: &str = "virtio0";
+
+/// Whether `info migrate` output shows the migration has ended.
+fn migration_done(out: &str) -> bool {
+    ["completed", "failed", "cancelled"]
+        .iter()
+        .any(|s| out.contains(&format!("status: {s}")))
+}
+
+/// Bytes done and in total of the block job in `info block-jobs`
+/// output; `None` when no job is running.
+fn mirror_progress(out: &str) -> Option<(u64, u64)> {
+    let mut words = out.split("Completed ").nth(1)?.split_whitespace();
+    let done = words.next()?.parse().ok()?;
+    if words.next()? != "of" {
+        return None;
+    }
+    let total = words.next()?.parse().ok()?;
+    Some((done, total))
+}
+
+/// Boundary of the multipart user-data built by [`user_data`].
+const USER_DATA_BOUNDARY: &str = "==ttstack-user-data==";
+
+/// Cloud-init user-data: SSH keys and sshd hardening, followed by the
+/// tenant's own user-data, if any.
+///
+/// Custom user-data is combined with the generated config as a MIME
+/// multipart archive, so it may be a `#cloud-config` document, a script
+/// (`#!...`) or a boothook. Its lists (e.g. `runcmd`) are appended to
+/// the generated ones rather than replacing them.
+fn user_data(ssh_keys: &[String], custom: Option<&str>) -> String {
+    // Inject SSH keys, disable password login
+    let mut base = String::from(
+        "#cloud-config\n\
+         disable_root: false\n\
+         ssh_pwauth: false\n",
+    );
+
+    if !ssh_keys.is_empty() {
+        base.push_str("ssh_authorized_keys:\n");
+        for key in ssh_keys {
+            base.push_str(&format!("  - {key}\n"));
+        }
+    }
+
+    base.push_str(
+        "runcmd:\n  \
+         - sed -i 's/^#*PermitRootLogin.*/PermitRootLogin prohibit-password/' /etc/ssh/sshd_config\n  \
+         - sed -i 's/^#*PasswordAuthentication.*/PasswordAuthentication no/' /etc/ssh/sshd_config\n  \
+         - systemctl restart sshd 2>/dev/null || service sshd restart 2>/dev/null || rc-service sshd restart 2>/dev/null || true\n",
+    );
+
+    let Some(custom) = custom.filter(|c| !c.trim().is_empty()) else {
+        return base;
+    };
+    let content_type = if custom.starts_with("#!") {
+        "text/x-shellscript"
+    } else if custom.starts_with("#cloud-boothook") {
+        "text/cloud-boothook"
+    } else {
+        "text/cloud-config"
+    };
+
+    let b = USER_DATA_BOUNDARY;
+    let mut out = format!("Content-Type: multipart/mixed; boundary=\"{b}\"\nMIME-Version: 1.0\n\n");
+    out.push_str(&format!(
+        "--{b}\nContent-Type: text/cloud-config; charset=\"utf-8\"\n\
+         Content-Disposition: attachment; filename=\"ttstack.cfg\"\n\n{base}\n"
+    ));
+    out.push_str(&format!(
+        "--{b}\nContent-Type: {content_type}; charset=\"utf-8\"\n\
+         Content-Disposition: attachment; filename=\"user-data\"\n\
+         Merge-Type: list(append)+dict(no_replace,recurse_list)+str()\n\n{custom}"
+    ));
+    if !custom.ends_with('\n') {
+        out.push('\n');
+    }
+    out.push_str(&format!("--{b}--\n"));
+    out
 }
 
 impl VmEngine for QemuEngine {
@@ -182,66 +421,224 @@ impl VmEngine for QemuEngine {
 
         // Generate cloud-init seed ISO (best-effort; non-cloud images ignore it)
         if let Err(e) = self.generate_seed_iso(vm, ssh_keys) {
-            eprintln!(
-                "[qemu] WARN: could not create seed ISO for {}: {e} (cloud-init may not work)",
+            warn!(
+                "could not create seed ISO for {}: {e} (cloud-init may not work)",
                 vm.id
             );
         }
 
-        let output = self
-            .build_cmd(vm, image_path, disk_format)
-            .output()
-            .c(d!("spawn qemu"))?;
+        Self::launch(self.build_cmd(vm, image_path, disk_format))
+    }
 
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(eg!("qemu launch failed: {}", stderr));
+    fn start(&self, vm: &Vm, image_path: &str, disk_format: &str) -> Result<()> {
+        if !self.read_pid(vm).is_ok_and(Self::process_alive) {
+            // Powered off: cold-boot from the preserved disk
+            return Self::launch(self.build_cmd(vm, image_path, disk_format));
         }
+        // Still up: a guest that powered itself off must be reset
+        // before it runs again
+        if self.monitor(vm, "info status")?.contains("shutdown") {
+            self.monitor(vm, "system_reset")?;
+        }
+        self.monitor(vm, "cont").map(|_| ())
+    }
 
+    fn stop(&self, vm: &Vm) -> Result<()> {
+        if let Ok(pid) = self.read_pid(vm)
+            && Self::process_alive(pid)
+        {
+            // A paused guest cannot see the power button
+            if self.monitor(vm, "info status")?.contains("paused") {
+                self.monitor(vm, "cont")?;
+            }
+            if self.power_down(vm)? {
+                Self::kill(pid);
+            }
+        }
+
+        self.remove_process_files(vm);
         Ok(())
     }
 
-    fn start(&self, vm: &Vm) -> Result<()> {
-        let sock = self.monitor_path(vm);
-        if !Path::new(&sock).exists() {
-            // Monitor socket gone means QEMU exited; re-create the VM.
-            return Err(eg!(
-                "VM {} has no monitor socket; it must be re-created",
-                vm.id
-            ));
+    fn suspend(&self, vm: &Vm) -> Result<()> {
+        // "stop" freezes the vCPUs without killing the QEMU process
+        self.monitor(vm, "stop").map(|_| ())
+    }
+
+    fn resume(&self, vm: &Vm) -> Result<()> {
+        self.monitor(vm, "cont").map(|_| ())
+    }
+
+    fn hibernate(&self, vm: &Vm, state_dir: &str) -> Result<()> {
+        let pid = self.read_pid(vm)?;
+        if !Self::process_alive(pid) {
+            return Err(eg!("VM {} is not running", vm.id));
         }
 
-        // Send "cont" command to the QEMU monitor to resume execution
-        let output = Command::new("sh")
-            .args([
-                "-c",
-                &format!(r#"echo "cont" | socat - UNIX-CONNECT:{sock}"#),
-            ])
-            .output()
-            .c(d!("qemu monitor cont"))?;
+        // Freeze the guest so its memory stops changing, then stream
+        // the whole machine state out through a migration
+        self.monitor(vm, "stop")?;
+        let memory = format!("{state_dir}/memory");
+        let saved = self
+            .monitor(vm, &format!("migrate exec:cat>{memory}"))
+            .and_then(|_| {
+                self.poll_monitor(
+                    vm,
+                    "info migrate",
+                    STATE_TRANSFER_TIMEOUT_SECS,
+                    migration_done,
+                )
+            })
+            .and_then(|out| {
+                if out.contains("status: completed") {
+                    Ok(())
+                } else {
+                    Err(eg!("QEMU migration did not complete"))
+                }
+            });
+        if let Err(e) = saved {
+            let _ = self.monitor(vm, "migrate_cancel");
+            let _ = self.monitor(vm, "cont");
+            let _ = std::fs::remove_file(&memory);
+            return Err(e).c(d!("save memory of VM {}", vm.id));
+        }
 
-        if !output.status.success() {
-            return Err(eg!("failed to resume VM via QEMU monitor"));
+        let _ = self.monitor(vm, "quit");
+        Self::kill(pid);
+        self.remove_process_files(vm);
+        Ok(())
+    }
+
+    fn restore(&self, vm: &Vm, image_path: &str, disk_format: &str, state_dir: &str) -> Result<()> {
+        let memory = format!("{state_dir}/memory");
+        if !Path::new(&memory).exists() {
+            return Err(eg!("VM {} has no saved memory", vm.id));
+        }
+
+        let mut cmd = self.build_cmd(vm, image_path, disk_format);
+        cmd.args(["-incoming", &format!("exec:cat {memory}")]);
+        Self::launch(cmd)?;
+
+        // The guest was frozen when it was saved, so it comes back paused
+        if let Err(e) = self.poll_monitor(vm, "info status", STATE_TRANSFER_TIMEOUT_SECS, |out| {
+            !out.contains("inmigrate")
+        }) {
+            if let Ok(pid) = self.read_pid(vm) {
+                Self::kill(pid);
+            }
+            self.remove_process_files(vm);
+            return Err(e).c(d!("load memory of VM {}", vm.id));
+        }
+        self.monitor(vm, "cont").map(|_| ())
+    }
+
+    fn migrate_in(
+        &self,
+        vm: &Vm,
+        image_path: &str,
+        disk_format: &str,
+        ports: &MigrationPorts,
+    ) -> Result<()> {
+        std::fs::create_dir_all(RUN_DIR).c(d!("create runtime dir"))?;
+
+        // The target must present the same devices as the source,
+        // seed ISO included
+        if let Err(e) = self.generate_seed_iso(vm, &vm.ssh_keys) {
+            warn!(
+                "could not create seed ISO for {}: {e} (migration may fail)",
+                vm.id
+            );
+        }
+
+        // Stay paused once the memory is in, until the source has let
+        // go of the disk
+        let mut cmd = self.build_cmd(vm, image_path, disk_format);
+        cmd.arg("-S")
+            .args(["-incoming", &format!("tcp:{}:{}", ports.addr, ports.memory)]);
+        Self::launch(cmd)?;
+
+        let exported = self
+            .monitor_ok(
+                vm,
+                &format!("nbd_server_start {}:{}", ports.addr, ports.disk),
+            )
+            .and_then(|_| self.monitor_ok(vm, &format!("nbd_server_add -w {DISK_DRIVE}")));
+        if let Err(e) = exported {
+            if let Ok(pid) = self.read_pid(vm) {
+                Self::kill(pid);
+            }
+            self.remove_process_files(vm);
+            return Err(e).c(d!("export disk of VM {}", vm.id));
         }
         Ok(())
     }
 
-    fn stop(&self, vm: &Vm) -> Result<()> {
-        // Pause the VM via QEMU monitor "stop" command.
-        // This freezes the vCPU without killing the QEMU process,
-        // allowing later resume via "cont".
-        let sock = self.monitor_path(vm);
-        if Path::new(&sock).exists() {
-            let _ = Command::new("sh")
-                .args([
-                    "-c",
-                    &format!(r#"echo "stop" | socat - UNIX-CONNECT:{sock}"#),
-                ])
-                .output();
+    fn migrate_out(&self, vm: &Vm, host: &str, ports: &MigrationPorts) -> Result<()> {
+        let target = format!("nbd:{host}:{}:exportname={DISK_DRIVE}", ports.disk);
+        let moved = self
+            .monitor_ok(vm, &format!("drive_mirror -n -f {DISK_DRIVE} {target} raw"))
+            .and_then(|_| {
+                // Copy the disk while the guest runs; once in sync, the
+                // mirror keeps up with its writes
+                let out =
+                    self.poll_monitor(vm, "info block-jobs", MIGRATION_TIMEOUT_SECS, |out| {
+                        mirror_progress(out).is_none_or(|(done, total)| total > 0 && done == total)
+                    })?;
+                if mirror_progress(&out).is_none() {
+                    return Err(eg!("disk mirror of VM {} ended early", vm.id));
+                }
+
+                self.monitor_ok(vm, &format!("migrate -d tcp:{host}:{}", ports.memory))?;
+                let out =
+                    self.poll_monitor(vm, "info migrate", MIGRATION_TIMEOUT_SECS, migration_done)?;
+                if !out.contains("status: completed") {
+                    return Err(eg!("QEMU migration did not complete"));
+                }
+
+                // The guest is paused now, so cancelling the synced
+                // mirror leaves the target with a complete disk
+                self.monitor_ok(vm, &format!("block_job_cancel {DISK_DRIVE}"))?;
+                self.poll_monitor(vm, "info block-jobs", POWERDOWN_TIMEOUT_SECS, |out| {
+                    mirror_progress(out).is_none()
+                })
+                .map(|_| ())
+            });
+        if let Err(e) = moved {
+            let _ = self.monitor(vm, "migrate_cancel");
+            let _ = self.monitor(vm, &format!("block_job_cancel -f {DISK_DRIVE}"));
+            let _ = self.monitor(vm, "cont");
+            return Err(e).c(d!("live-migrate VM {}", vm.id));
         }
         Ok(())
     }
 
+    fn finish_migrate_in(&self, vm: &Vm) -> Result<()> {
+        self.poll_monitor(vm, "info status", STATE_TRANSFER_TIMEOUT_SECS, |out| {
+            !out.contains("inmigrate")
+        })
+        .c(d!("receive VM {}", vm.id))?;
+        self.monitor_ok(vm, "nbd_server_stop")?;
+        self.monitor(vm, "cont").map(|_| ())
+    }
+
+    fn reset(&self, vm: &Vm) -> Result<()> {
+        self.monitor(vm, "system_reset").map(|_| ())
+    }
+
+    fn reboot(&self, vm: &Vm) -> Result<()> {
+        // With -no-shutdown QEMU waits for a reset once the guest has
+        // powered off
+        if !self.power_down(vm)? {
+            return Err(eg!(
+                "VM {} exited on power-down; stop and start it again",
+                vm.id
+            ));
+        }
+        // A guest that ignored the power button is reset the hard way
+        self.monitor(vm, "system_reset")?;
+        self.monitor(vm, "cont").map(|_| ())
+    }
+
     fn destroy(&self, vm: &Vm) -> Result<()> {
         if let Ok(pid) = self.read_pid(vm)
             && Self::process_alive(pid)
@@ -254,6 +651,8 @@ impl VmEngine for QemuEngine {
 
         let _ = std::fs::remove_file(self.pid_path(vm));
         let _ = std::fs::remove_file(self.monitor_path(vm));
+        let _ = std::fs::remove_file(self.serial_path(vm));
+        let _ = std::fs::remove_file(self.vnc_path(vm));
         let _ = std::fs::remove_file(self.seed_path(vm));
 
         Ok(())
@@ -263,31 +662,61 @@ impl VmEngine for QemuEngine {
         match self.read_pid(vm) {
             Ok(pid) if Self::process_alive(pid) => {
                 // Query QEMU monitor to distinguish Running vs Paused
-                let sock = self.monitor_path(vm);
-                if Path::new(&sock).exists()
-                    && let Ok(output) = Command::new("sh")
-                        .args([
-                            "-c",
-                            &format!(r#"echo "info status" | socat - UNIX-CONNECT:{sock}"#),
-                        ])
-                        .output()
-                {
-                    let body = String::from_utf8_lossy(&output.stdout);
-                    if body.contains("paused") {
-                        return Ok(VmState::Paused);
-                    }
+                match self.monitor(vm, "info status") {
+                    Ok(body) => Ok(parse_status(&body)),
+                    Err(_) => Ok(VmState::Running),
                 }
-                Ok(VmState::Running)
             }
             _ => Ok(VmState::Stopped),
         }
     }
 
+    fn console(&self, vm: &Vm) -> Result<Console> {
+        let sock = self.serial_path(vm);
+        if !Path::new(&sock).exists() {
+            return Err(eg!("VM {} has no serial console socket", vm.id));
+        }
+        Ok(Console::Socket(sock))
+    }
+
+    fn vnc(&self, vm: &Vm) -> Result<String> {
+        let sock = self.vnc_path(vm);
+        if !Path::new(&sock).exists() {
+            return Err(eg!("VM {} has no VNC socket", vm.id));
+        }
+        Ok(sock)
+    }
+
+    fn stats(&self, vm: &Vm) -> Result<VmStats> {
+        let pid = self.read_pid(vm)?;
+        if !Self::process_alive(pid) {
+            return Err(eg!("VM {} is not running", vm.id));
+        }
+        let mut s = stats::process(pid);
+        (s.net_rx_bytes, s.net_tx_bytes) =
+            stats::tap_traffic(&[net::tap_name(&vm.id), net::overlay_tap_name(&vm.id)]);
+        Ok(s)
+    }
+
     fn name(&self) -> &'static str {
         "qemu"
     }
 }
 
+/// The state of a live QEMU process from its `info status` answer.
+///
+/// With `-no-shutdown` a guest that powered itself off leaves QEMU
+/// alive, reporting `paused (shutdown)`: the VM is stopped, not paused.
+fn parse_status(body: &str) -> VmState {
+    if body.contains("shutdown") {
+        VmState::Stopped
+    } else if body.contains("paused") {
+        VmState::Paused
+    } else {
+        VmState::Running
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -301,14 +730,11 @@ mod tests {
             env_id: "e1".into(),
             host_id: "h1".into(),
             image: "img".into(),
-            engine: crate::model::Engine::Qemu,
             cpu: 2,
             mem: 1024,
             disk: 10240,
             ip: "10.10.0.2".into(),
-            port_map: Default::default(),
-            state: VmState::Creating,
-            created_at: 0,
+            ..Default::default()
         };
 
         let cmd = eng.build_cmd(&vm, "/dev/zvol/tank/clone-1", "raw");
@@ -328,4 +754,109 @@ mod tests {
         let drive_arg2 = args2.iter().find(|a| a.starts_with("file=")).unwrap();
         assert!(drive_arg2.contains("format=qcow2"));
     }
+
+    #[test]
+    fn build_cmd_adds_overlay_nic() {
+        let eng = QemuEngine::new();
+        let mut vm = Vm {
+            id: "test-vm".into(),
+            env_id: "e1".into(),
+            host_id: "h1".into(),
+            image: "img".into(),
+            cpu: 1,
+            mem: 512,
+            disk: 1024,
+            ip: "10.10.0.2".into(),
+            ..Default::default()
+        };
+        let count_nics = |vm: &Vm| {
+            eng.build_cmd(vm, "/tmp/disk.qcow2", "qcow2")
+                .get_args()
+                .filter(|a| a.to_string_lossy().starts_with("virtio-net-pci"))
+                .count()
+        };
+        assert_eq!(count_nics(&vm), 1);
+
+        vm.overlay = Some(crate::model::OverlayNic {
+            vni: net::OVERLAY_VNI_BASE,
+            ip: "172.16.0.2".into(),
+        });
+        assert_eq!(count_nics(&vm), 2);
+    }
+
+    #[test]
+    fn build_cmd_exposes_serial_and_vnc_sockets() {
+        let eng = QemuEngine::new();
+        let vm = Vm {
+            id: "test-vm".into(),
+            env_id: "e1".into(),
+            host_id: "h1".into(),
+            image: "img".into(),
+            cpu: 1,
+            mem: 512,
+            disk: 1024,
+            ip: "10.10.0.2".into(),
+            ..Default::default()
+        };
+        let args: Vec<_> = eng
+            .build_cmd(&vm, "/tmp/disk.qcow2", "qcow2")
+            .get_args()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect();
+        let value_of = |flag: &str| {
+            let i = args.iter().position(|a| a == flag).unwrap();
+            args[i + 1].clone()
+        };
+        assert_eq!(
+            value_of("-serial"),
+            format!("unix:{RUN_DIR}/qemu-test-vm.serial,server,nowait")
+        );
+        assert_eq!(value_of("-vnc"), format!("unix:{RUN_DIR}/qemu-test-vm.vnc"));
+        // Guest power-offs leave QEMU up for reboots
+        assert!(args.iter().any(|a| a == "-no-shutdown"));
+    }
+
+    #[test]
+    fn status_of_a_powered_off_guest_is_stopped() {
+        let status = |s: &str| parse_status(&format!("(qemu) info status\r\nVM status: {s}\r\n"));
+        assert_eq!(status("running"), VmState::Running);
+        assert_eq!(status("paused"), VmState::Paused);
+        assert_eq!(status("paused (shutdown)"), VmState::Stopped);
+        assert_eq!(status("shutdown"), VmState::Stopped);
+    }
+
+    #[test]
+    fn mirror_progress_parses_block_jobs() {
+        let out = "(qemu) info block-jobs\r\n\
+                   Type mirror, device virtio0: Completed 1048576 of 4194304 bytes, \
+                   speed limit 0 bytes/s\r\n(qemu) ";
+        assert_eq!(mirror_progress(out), Some((1048576, 4194304)));
+        assert_eq!(
+            mirror_progress("(qemu) info block-jobs\r\nNo active jobs\r\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn user_data_without_custom_part_is_plain_cloud_config() {
+        let ud = user_data(&["ssh-ed25519 AAAA k".into()], None);
+        assert!(ud.starts_with("#cloud-config\n"));
+        assert!(ud.contains("  - ssh-ed25519 AAAA k\n"));
+        assert_eq!(user_data(&[], Some("  \n")), user_data(&[], None));
+    }
+
+    #[test]
+    fn user_data_appends_custom_part_as_multipart() {
+        let ud = user_data(&[], Some("#!/bin/sh\necho hi"));
+        assert!(ud.starts_with("Content-Type: multipart/mixed"));
+        assert!(ud.contains("Content-Type: text/x-shellscript"));
+        assert!(ud.contains("\n\n#!/bin/sh\necho hi\n--"));
+        assert!(ud.ends_with(&format!("--{USER_DATA_BOUNDARY}--\n")));
+        // The generated config comes first
+        let generated = ud.find("ssh_pwauth").unwrap();
+        assert!(generated < ud.find("echo hi").unwrap());
+
+        let ud = user_data(&[], Some("#cloud-config\npackages: [git]\n"));
+        assert_eq!(ud.matches("Content-Type: text/cloud-config").count(), 2);
+    }
 }
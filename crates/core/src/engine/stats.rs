@@ -0,0 +1,275 @@
+//! Resource usage of running instances, as the host sees it.
+//!
+//! QEMU and Firecracker guests are single processes, so their CPU time,
+//! memory and disk I/O come from `/proc/<pid>`, and their traffic from
+//! the counters of their tap devices. Containers are read from their
+//! cgroup (v2) and network namespace, or failing that from the output
+//! of `docker stats`.
+
+use serde::Deserialize;
+
+/// What an instance has used so far. Fields the engine cannot tell
+/// are `None`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VmStats {
+    /// CPU time, in seconds.
+    pub cpu_seconds: Option<f64>,
+    /// Memory in use, in bytes.
+    pub mem_bytes: Option<u64>,
+    pub disk_read_bytes: Option<u64>,
+    pub disk_written_bytes: Option<u64>,
+    /// Bytes the guest received over its NICs.
+    pub net_rx_bytes: Option<u64>,
+    /// Bytes the guest sent over its NICs.
+    pub net_tx_bytes: Option<u64>,
+}
+
+/// Clock ticks per second of the CPU times in `/proc/<pid>/stat`;
+/// `USER_HZ` is 100 on every architecture we run on.
+const USER_HZ: f64 = 100.0;
+
+/// Usage of process `pid`, without network traffic.
+pub fn process(pid: u32) -> VmStats {
+    let read = |f: &str| std::fs::read_to_string(format!("/proc/{pid}/{f}")).ok();
+    let (disk_read_bytes, disk_written_bytes) = read("io").map(|t| parse_proc_io(&t)).unzip();
+    VmStats {
+        cpu_seconds: read("stat").and_then(|t| parse_proc_cpu(&t)),
+        mem_bytes: read("status").and_then(|t| parse_status_rss(&t)),
+        disk_read_bytes: disk_read_bytes.flatten(),
+        disk_written_bytes: disk_written_bytes.flatten(),
+        ..Default::default()
+    }
+}
+
+/// Traffic of a guest through the tap devices `taps`, as (received,
+/// sent) by the guest: what the host side of a tap sends, the guest
+/// receives. Taps that do not exist are skipped.
+pub fn tap_traffic(taps: &[String]) -> (Option<u64>, Option<u64>) {
+    let read = |tap: &str, counter: &str| {
+        std::fs::read_to_string(format!("/sys/class/net/{tap}/statistics/{counter}"))
+            .ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+    };
+    let (mut rx, mut tx) = (None, None);
+    for tap in taps {
+        if let Some(n) = read(tap, "tx_bytes") {
+            *rx.get_or_insert(0) += n;
+        }
+        if let Some(n) = read(tap, "rx_bytes") {
+            *tx.get_or_insert(0) += n;
+        }
+    }
+    (rx, tx)
+}
+
+/// Usage of the container whose init process is `pid`, from its
+/// cgroup v2 and its network namespace. `None` if the cgroup cannot be
+/// read, e.g. on a cgroup v1 host.
+pub fn container(pid: u32) -> Option<VmStats> {
+    let cgroup = std::fs::read_to_string(format!("/proc/{pid}/cgroup")).ok()?;
+    let dir = format!("/sys/fs/cgroup{}", parse_cgroup_path(&cgroup)?);
+    let read = |f: &str| std::fs::read_to_string(format!("{dir}/{f}")).ok();
+    let cpu_seconds = parse_cpu_stat(&read("cpu.stat")?);
+    let (disk_read_bytes, disk_written_bytes) = read("io.stat").map(|t| parse_io_stat(&t)).unzip();
+    let (net_rx_bytes, net_tx_bytes) = std::fs::read_to_string(format!("/proc/{pid}/net/dev"))
+        .ok()
+        .map(|t| parse_net_dev(&t))
+        .unzip();
+    Some(VmStats {
+        cpu_seconds,
+        mem_bytes: read("memory.current").and_then(|v| v.trim().parse().ok()),
+        disk_read_bytes,
+        disk_written_bytes,
+        net_rx_bytes,
+        net_tx_bytes,
+    })
+}
+
+/// User plus system CPU time in `/proc/<pid>/stat`, in seconds.
+fn parse_proc_cpu(stat: &str) -> Option<f64> {
+    // The command name may hold spaces and parentheses; fields after it
+    // start with the state, field 3, so utime (14) and stime (15) are
+    // the 12th and 13th
+    let rest = &stat[stat.rfind(')')? + 1..];
+    let mut fields = rest.split_whitespace().skip(11);
+    let utime: u64 = fields.next()?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    Some((utime + stime) as f64 / USER_HZ)
+}
+
+/// Resident memory in `/proc/<pid>/status`, in bytes.
+fn parse_status_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// Bytes read from and written to storage in `/proc/<pid>/io`.
+fn parse_proc_io(io: &str) -> (Option<u64>, Option<u64>) {
+    let field = |name: &str| {
+        io.lines()
+            .find_map(|l| l.strip_prefix(name))
+            .and_then(|v| v.trim().parse().ok())
+    };
+    (field("read_bytes:"), field("write_bytes:"))
+}
+
+/// Bytes received and sent over all interfaces but loopback in
+/// `/proc/<pid>/net/dev`.
+fn parse_net_dev(dev: &str) -> (u64, u64) {
+    let (mut rx, mut tx) = (0, 0);
+    for line in dev.lines().skip(2) {
+        let Some((iface, counters)) = line.split_once(':') else {
+            continue;
+        };
+        if iface.trim() == "lo" {
+            continue;
+        }
+        let counters: Vec<u64> = counters
+            .split_whitespace()
+            .filter_map(|v| v.parse().ok())
+            .collect();
+        if counters.len() >= 9 {
+            rx += counters[0];
+            tx += counters[8];
+        }
+    }
+    (rx, tx)
+}
+
+/// Path of a process's cgroup v2 in `/proc/<pid>/cgroup`.
+fn parse_cgroup_path(cgroup: &str) -> Option<&str> {
+    cgroup.lines().find_map(|l| l.strip_prefix("0::"))
+}
+
+/// CPU time in a cgroup's `cpu.stat`, in seconds.
+fn parse_cpu_stat(stat: &str) -> Option<f64> {
+    let usec: u64 = stat
+        .lines()
+        .find_map(|l| l.strip_prefix("usage_usec "))?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(usec as f64 / 1e6)
+}
+
+/// Bytes read and written over all devices in a cgroup's `io.stat`.
+fn parse_io_stat(stat: &str) -> (u64, u64) {
+    let (mut read, mut written) = (0, 0);
+    for field in stat.split_whitespace() {
+        if let Some(n) = field.strip_prefix("rbytes=") {
+            read += n.parse::<u64>().unwrap_or(0);
+        } else if let Some(n) = field.strip_prefix("wbytes=") {
+            written += n.parse::<u64>().unwrap_or(0);
+        }
+    }
+    (read, written)
+}
+
+/// One line of `docker stats --format '{{json .}}'`.
+#[derive(Deserialize)]
+struct DockerStatsLine {
+    #[serde(rename = "MemUsage", default)]
+    mem: String,
+    #[serde(rename = "NetIO", default)]
+    net: String,
+    #[serde(rename = "BlockIO", default)]
+    block: String,
+}
+
+/// Usage in a line of `docker stats --format '{{json .}}'`. It only
+/// has CPU time as a percentage, so that is left out.
+pub fn parse_docker_stats(line: &str) -> Option<VmStats> {
+    let s: DockerStatsLine = serde_json::from_str(line.trim()).ok()?;
+    // Each is "<used> / <limit>" or "<in> / <out>"
+    let pair = |v: &str| {
+        let (a, b) = v.split_once(" / ")?;
+        Some((parse_size(a)?, parse_size(b)?))
+    };
+    let (net_rx_bytes, net_tx_bytes) = pair(&s.net).unzip();
+    let (disk_read_bytes, disk_written_bytes) = pair(&s.block).unzip();
+    Some(VmStats {
+        cpu_seconds: None,
+        mem_bytes: pair(&s.mem).map(|(used, _)| used),
+        disk_read_bytes,
+        disk_written_bytes,
+        net_rx_bytes,
+        net_tx_bytes,
+    })
+}
+
+/// A size as `docker stats` prints it, e.g. "1.5MiB" or "12kB".
+fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: f64 = num.trim().parse().ok()?;
+    let scale: f64 = match unit {
+        "" | "B" => 1.0,
+        "kB" | "KB" => 1e3,
+        "MB" => 1e6,
+        "GB" => 1e9,
+        "TB" => 1e12,
+        "KiB" => 1024.0,
+        "MiB" => 1024.0 * 1024.0,
+        "GiB" => 1024.0 * 1024.0 * 1024.0,
+        "TiB" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        _ => return None,
+    };
+    Some((num * scale).round() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proc_files_parse() {
+        let stat = "4242 (qemu (x) ) S 1 4242 4242 0 -1 4194560 1 0 0 0 250 130 0 0 20 0 5";
+        assert_eq!(parse_proc_cpu(stat), Some(3.8));
+
+        let status = "Name:\tqemu\nVmPeak:\t 9000 kB\nVmRSS:\t  2048 kB\n";
+        assert_eq!(parse_status_rss(status), Some(2048 * 1024));
+
+        let io = "rchar: 10\nwchar: 20\nread_bytes: 4096\nwrite_bytes: 8192\n";
+        assert_eq!(parse_proc_io(io), (Some(4096), Some(8192)));
+
+        let dev = "Inter-|   Receive |  Transmit\n face |bytes packets|bytes\n\
+                   \x20   lo: 500 5 0 0 0 0 0 0 500 5 0 0 0 0 0 0\n\
+                   \x20 eth0: 1000 10 0 0 0 0 0 0 2000 20 0 0 0 0 0 0\n";
+        assert_eq!(parse_net_dev(dev), (1000, 2000));
+    }
+
+    #[test]
+    fn cgroup_files_parse() {
+        let cgroup = "0::/system.slice/docker-abc.scope\n";
+        assert_eq!(
+            parse_cgroup_path(cgroup),
+            Some("/system.slice/docker-abc.scope")
+        );
+        assert_eq!(parse_cgroup_path("12:cpu:/docker/abc\n"), None);
+
+        let cpu = "usage_usec 2500000\nuser_usec 2000000\nsystem_usec 500000\n";
+        assert_eq!(parse_cpu_stat(cpu), Some(2.5));
+
+        let io = "8:0 rbytes=100 wbytes=200 rios=1 wios=2\n253:1 rbytes=1 wbytes=2\n";
+        assert_eq!(parse_io_stat(io), (101, 202));
+    }
+
+    #[test]
+    fn docker_stats_parse() {
+        let line = r#"{"CPUPerc":"0.50%","MemUsage":"1.5MiB / 2GiB","NetIO":"12kB / 3.4MB","BlockIO":"0B / 8.19kB"}"#;
+        let s = parse_docker_stats(line).unwrap();
+        assert_eq!(s.cpu_seconds, None);
+        assert_eq!(s.mem_bytes, Some(1_572_864));
+        assert_eq!(
+            (s.net_rx_bytes, s.net_tx_bytes),
+            (Some(12_000), Some(3_400_000))
+        );
+        assert_eq!(
+            (s.disk_read_bytes, s.disk_written_bytes),
+            (Some(0), Some(8190))
+        );
+        assert!(parse_docker_stats("not json").is_none());
+    }
+}
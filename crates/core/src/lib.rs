@@ -4,13 +4,16 @@
 //! network utilities used by both the host agent and central controller.
 //!
 //! The [`api`] and [`model`] modules are platform-independent and used
-//! by all components (CLI, controller, agent).
+//! by all components (CLI, controller, agent); [`log`] and [`metrics`]
+//! by the agent and controller.
 //!
 //! The [`engine`], [`net`], and [`storage`] modules are only available
 //! on Linux and FreeBSD where the agent daemon runs.
 
 pub mod api;
 pub mod auth;
+pub mod log;
+pub mod metrics;
 pub mod model;
 
 pub mod engine;
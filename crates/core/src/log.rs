@@ -0,0 +1,120 @@
+//! Logging for the agent and the controller, on top of `tracing`.
+//!
+//! [`init`] installs a subscriber that writes to stderr, either as
+//! readable lines or as JSON objects, through a filter that can be
+//! changed while the daemon runs ([`set_filter`]).
+//!
+//! Each call the controller serves gets a request ID, which it passes on
+//! to agents in [`REQUEST_ID_HEADER`]; both sides log the work done for
+//! the call in a `request` span carrying it, so one operation can be
+//! followed from the controller to the engine commands on its host.
+
+use ruc::*;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{EnvFilter, Registry, reload};
+
+/// Header carrying the controller's request ID on calls to agents.
+pub const REQUEST_ID_HEADER: &str = "x-tt-request-id";
+
+/// Filter used unless one is configured.
+pub const DEFAULT_FILTER: &str = "info";
+
+/// How log lines are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// One readable line per event, with its spans and their fields.
+    #[default]
+    Pretty,
+    /// One JSON object per event, for log collectors.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "pretty" => Ok(Self::Pretty),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown log format: {s} (expected pretty or json)")),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pretty => write!(f, "pretty"),
+            Self::Json => write!(f, "json"),
+        }
+    }
+}
+
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Install the process-wide subscriber. `filter` takes `RUST_LOG`
+/// directives, e.g. `info` or `warn,tt_agent=debug`.
+pub fn init(format: LogFormat, filter: &str) -> Result<()> {
+    let (filter, handle) = reload::Layer::new(parse_filter(filter)?);
+    let registry = tracing_subscriber::registry().with(filter);
+    let lines = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    match format {
+        LogFormat::Pretty => {
+            let ansi = std::io::IsTerminal::is_terminal(&std::io::stderr());
+            registry.with(lines.with_ansi(ansi)).try_init()
+        }
+        LogFormat::Json => registry.with(lines.json().flatten_event(true)).try_init(),
+    }
+    .c(d!("install log subscriber"))?;
+    let _ = FILTER.set(handle);
+    Ok(())
+}
+
+/// The filter in effect, if [`init`] was called.
+pub fn filter() -> Option<String> {
+    FILTER.get()?.with_current(|f| f.to_string()).ok()
+}
+
+/// Replace the filter, e.g. to turn on debug logs without a restart.
+pub fn set_filter(filter: &str) -> Result<()> {
+    let new = parse_filter(filter)?;
+    FILTER
+        .get()
+        .ok_or_else(|| eg!("logging is not initialized"))?
+        .reload(new)
+        .c(d!("reload log filter"))
+}
+
+fn parse_filter(filter: &str) -> Result<EnvFilter> {
+    if filter.trim().is_empty() {
+        return Err(eg!("empty log filter"));
+    }
+    EnvFilter::builder()
+        .parse(filter)
+        .c(d!("invalid log filter: {}", filter))
+}
+
+/// A new request ID.
+pub fn new_request_id() -> String {
+    uuid::Uuid::new_v4().to_string()[..12].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_and_filters_parse() {
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert_eq!(LogFormat::default().to_string(), "pretty");
+        assert!("xml".parse::<LogFormat>().is_err());
+
+        assert!(parse_filter("warn,tt_agent=debug").is_ok());
+        assert!(parse_filter("tt_agent=loud").is_err());
+        assert!(parse_filter(" ").is_err());
+        assert_ne!(new_request_id(), new_request_id());
+    }
+}
@@ -0,0 +1,257 @@
+//! Prometheus text exposition for the `/metrics` endpoints of the agent
+//! and the controller.
+//!
+//! Only what the two need: gauges, which are read when scraped, and
+//! counters and histograms, which are kept in [`CounterVec`] and
+//! [`HistogramVec`] statics as things happen.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::sync::{Mutex, MutexGuard};
+
+/// Content type of the text exposition format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Histogram buckets, in seconds, for operations that take from a few
+/// milliseconds (pausing a VM) to many minutes (cloning a large image).
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0,
+];
+
+#[derive(Debug, Clone, Copy)]
+pub enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Counter => write!(f, "counter"),
+            Self::Gauge => write!(f, "gauge"),
+            Self::Histogram => write!(f, "histogram"),
+        }
+    }
+}
+
+/// A scrape response being written.
+#[derive(Debug, Default)]
+pub struct Text {
+    out: String,
+}
+
+impl Text {
+    /// Start a metric family; its samples follow.
+    pub fn family(&mut self, name: &str, kind: Kind, help: &str) {
+        let _ = writeln!(self.out, "# HELP {name} {help}");
+        let _ = writeln!(self.out, "# TYPE {name} {kind}");
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            self.out.push('{');
+            for (i, (k, v)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.out.push(',');
+                }
+                let _ = write!(self.out, "{k}=\"{}\"", escape(v));
+            }
+            self.out.push('}');
+        }
+        let _ = writeln!(self.out, " {value}");
+    }
+
+    /// A family with a single unlabelled gauge sample.
+    pub fn gauge(&mut self, name: &str, help: &str, value: f64) {
+        self.family(name, Kind::Gauge, help);
+        self.sample(name, &[], value);
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+fn escape(v: &str) -> String {
+    v.replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Pair label names with one set of values.
+fn pairs<'a>(names: &[&'a str], values: &'a [String]) -> Vec<(&'a str, &'a str)> {
+    names
+        .iter()
+        .copied()
+        .zip(values.iter().map(String::as_str))
+        .collect()
+}
+
+/// Counters of one family, one per set of label values.
+pub struct CounterVec {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl CounterVec {
+    pub const fn new(
+        name: &'static str,
+        help: &'static str,
+        labels: &'static [&'static str],
+    ) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Add one to the counter of `values`, given in label order.
+    pub fn inc(&self, values: &[&str]) {
+        debug_assert_eq!(values.len(), self.labels.len());
+        let key = values.iter().map(|v| v.to_string()).collect();
+        *lock(&self.values).entry(key).or_default() += 1;
+    }
+
+    pub fn get(&self, values: &[&str]) -> u64 {
+        let key: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        lock(&self.values).get(&key).copied().unwrap_or(0)
+    }
+
+    pub fn write(&self, t: &mut Text) {
+        t.family(self.name, Kind::Counter, self.help);
+        let values = lock(&self.values);
+        // Without labels there is one counter, there from the start
+        if self.labels.is_empty() && values.is_empty() {
+            t.sample(self.name, &[], 0.0);
+        }
+        for (values, n) in values.iter() {
+            t.sample(self.name, &pairs(self.labels, values), *n as f64);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative; the last one is `+Inf`.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+/// Histograms of one family, one per set of label values.
+pub struct HistogramVec {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    buckets: &'static [f64],
+    values: Mutex<BTreeMap<Vec<String>, Histogram>>,
+}
+
+impl HistogramVec {
+    pub const fn new(
+        name: &'static str,
+        help: &'static str,
+        labels: &'static [&'static str],
+        buckets: &'static [f64],
+    ) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            buckets,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Record `value` in the histogram of `values`, given in label order.
+    pub fn observe(&self, values: &[&str], value: f64) {
+        debug_assert_eq!(values.len(), self.labels.len());
+        let key = values.iter().map(|v| v.to_string()).collect();
+        let mut all = lock(&self.values);
+        let h = all.entry(key).or_default();
+        h.counts.resize(self.buckets.len() + 1, 0);
+        let i = self
+            .buckets
+            .iter()
+            .position(|b| value <= *b)
+            .unwrap_or(self.buckets.len());
+        h.counts[i] += 1;
+        h.sum += value;
+    }
+
+    pub fn write(&self, t: &mut Text) {
+        t.family(self.name, Kind::Histogram, self.help);
+        let bucket = format!("{}_bucket", self.name);
+        for (values, h) in lock(&self.values).iter() {
+            let labels = pairs(self.labels, values);
+            let mut seen = 0;
+            let bounds = self.buckets.iter().map(f64::to_string);
+            for (le, n) in bounds.chain(["+Inf".to_string()]).zip(&h.counts) {
+                seen += n;
+                let mut with_le = labels.clone();
+                with_le.push(("le", &le));
+                t.sample(&bucket, &with_le, seen as f64);
+            }
+            t.sample(&format!("{}_sum", self.name), &labels, h.sum);
+            t.sample(&format!("{}_count", self.name), &labels, seen as f64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_escape_label_values() {
+        let mut t = Text::default();
+        t.gauge("up", "Whether it is up.", 1.0);
+        t.sample("vms", &[("name", "a\"b\\c\nd"), ("engine", "qemu")], 2.5);
+        assert_eq!(
+            t.finish(),
+            "# HELP up Whether it is up.\n# TYPE up gauge\nup 1\n\
+             vms{name=\"a\\\"b\\\\c\\nd\",engine=\"qemu\"} 2.5\n"
+        );
+    }
+
+    #[test]
+    fn counters_and_histograms_accumulate_per_label_set() {
+        let c = CounterVec::new("errors_total", "Errors.", &["host"]);
+        c.inc(&["h1"]);
+        c.inc(&["h1"]);
+        c.inc(&["h2"]);
+        assert_eq!(c.get(&["h1"]), 2);
+        let mut t = Text::default();
+        c.write(&mut t);
+        assert!(
+            t.finish()
+                .ends_with("errors_total{host=\"h1\"} 2\nerrors_total{host=\"h2\"} 1\n")
+        );
+
+        let h = HistogramVec::new("op_seconds", "Op time.", &["op"], &[0.1, 1.0]);
+        h.observe(&["start"], 0.05);
+        h.observe(&["start"], 0.5);
+        h.observe(&["start"], 7.0);
+        let mut t = Text::default();
+        h.write(&mut t);
+        let text = t.finish();
+        for line in [
+            "op_seconds_bucket{op=\"start\",le=\"0.1\"} 1\n",
+            "op_seconds_bucket{op=\"start\",le=\"1\"} 2\n",
+            "op_seconds_bucket{op=\"start\",le=\"+Inf\"} 3\n",
+            "op_seconds_sum{op=\"start\"} 7.55\n",
+            "op_seconds_count{op=\"start\"} 3\n",
+        ] {
+            assert!(text.contains(line), "missing {line:?} in\n{text}");
+        }
+    }
+}
@@ -14,9 +14,10 @@ use std::fmt;
 /// Platform availability:
 /// - **Linux**: Qemu, Firecracker, Docker
 /// - **FreeBSD**: Bhyve, Jail
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Engine {
+    #[default]
     Qemu,
     Firecracker,
     Bhyve,
@@ -51,10 +52,11 @@ impl std::str::FromStr for Engine {
 }
 
 /// Storage backend for VM / container images.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Storage {
     /// Plain qcow2 file copies — works on any filesystem.
+    #[default]
     File,
     /// ZFS zvol — raw block devices backed by ZFS volumes.
     Zvol,
@@ -83,12 +85,16 @@ impl std::str::FromStr for Storage {
 // ── State Enums ─────────────────────────────────────────────────────
 
 /// Runtime state of a VM or container.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum VmState {
     Running,
     Stopped,
     Paused,
+    /// Memory saved to disk and the process gone; its CPU and memory
+    /// are free until it is resumed.
+    Hibernated,
+    #[default]
     Creating,
     Failed,
 }
@@ -99,6 +105,7 @@ impl fmt::Display for VmState {
             Self::Running => write!(f, "running"),
             Self::Stopped => write!(f, "stopped"),
             Self::Paused => write!(f, "paused"),
+            Self::Hibernated => write!(f, "hibernated"),
             Self::Creating => write!(f, "creating"),
             Self::Failed => write!(f, "failed"),
         }
@@ -111,14 +118,55 @@ impl fmt::Display for VmState {
 pub enum EnvState {
     Active,
     Stopped,
+    Hibernated,
 }
 
-/// Online status of a physical host.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+/// Status of a physical host.
+///
+/// Only `Online` hosts take new VMs. `Draining` and `Maintenance` are
+/// set by an admin and kept until the host is made available again.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum HostState {
+    #[default]
     Online,
     Offline,
+    /// Its VMs are being moved to other hosts.
+    Draining,
+    /// Out of service; VMs it still runs keep running.
+    Maintenance,
+}
+
+impl fmt::Display for HostState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Online => write!(f, "online"),
+            Self::Offline => write!(f, "offline"),
+            Self::Draining => write!(f, "draining"),
+            Self::Maintenance => write!(f, "maintenance"),
+        }
+    }
+}
+
+/// Permission level of a controller user.
+///
+/// Admins manage hosts, users and every environment; users only manage
+/// the environments they own.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    #[default]
+    User,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Admin => write!(f, "admin"),
+            Self::User => write!(f, "user"),
+        }
+    }
 }
 
 // ── Resource Tracking ───────────────────────────────────────────────
@@ -162,7 +210,7 @@ impl Resource {
 // ── Core Entities ───────────────────────────────────────────────────
 
 /// A physical host in the fleet.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Host {
     pub id: String,
     /// Agent listen address, e.g. "10.0.0.1:9100".
@@ -174,10 +222,17 @@ pub struct Host {
     /// Storage backend used on this host.
     pub storage: Storage,
     pub registered_at: u64,
+    /// Free-form `key=value` labels that VMs can select hosts by.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// Whether the host joined through its own heartbeats rather than
+    /// `tt host add`; only such hosts follow the address they advertise.
+    #[serde(default)]
+    pub self_registered: bool,
 }
 
 /// A VM or container instance managed by an agent.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Vm {
     pub id: String,
     pub env_id: String,
@@ -196,6 +251,58 @@ pub struct Vm {
     pub port_map: BTreeMap<u16, u16>,
     pub state: VmState,
     pub created_at: u64,
+    /// Second NIC on the env's cross-host private network, if it has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overlay: Option<OverlayNic>,
+    /// Name of the VM within its env; empty for VMs created unnamed.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    /// Whether outbound traffic from the VM is blocked.
+    #[serde(default)]
+    pub deny_outgoing: bool,
+    /// SSH public keys injected at creation.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ssh_keys: Vec<String>,
+    /// Custom cloud-init user-data given at creation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_data: Option<String>,
+    /// Anti-affinity group within the env.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub anti_affinity: Option<String>,
+    /// Labels its host must carry.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub node_selector: BTreeMap<String, String>,
+}
+
+impl Vm {
+    /// The VM's name within its env, falling back to its ID.
+    pub fn name_or_id(&self) -> &str {
+        if self.name.is_empty() {
+            &self.id
+        } else {
+            &self.name
+        }
+    }
+}
+
+/// A VM's attachment to its environment's private network.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OverlayNic {
+    /// VXLAN network identifier of the env network.
+    pub vni: u32,
+    /// Address on the env subnet (prefix length is `net::OVERLAY_PREFIX`).
+    pub ip: String,
+}
+
+/// A cross-host private network owned by one environment.
+///
+/// Allocated by the controller so subnets and VNIs never collide
+/// across the fleet.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EnvNetwork {
+    pub vni: u32,
+    /// Subnet in CIDR notation, e.g. "172.16.3.0/24".
+    pub subnet: String,
 }
 
 /// An environment — a logical group of related VMs.
@@ -208,6 +315,228 @@ pub struct Env {
     /// Unix timestamp after which the env auto-expires (0 = never).
     pub expires_at: u64,
     pub state: EnvState,
+    /// Private network spanning the env's hosts, if requested.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<EnvNetwork>,
+    /// What the env counts against its owner's quotas while its VMs are
+    /// still being created, in place of the VMs themselves.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reserved: Option<Usage>,
+}
+
+/// What happened to an environment, as recorded in its events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EnvEventKind {
+    Created,
+    Extended,
+    /// The env is about to expire.
+    ExpiryWarning,
+    VmAdded,
+    VmRemoved,
+    /// A VM moved to another host.
+    VmMigrated,
+    /// A VM was rebuilt from its image on another host.
+    VmRecreated,
+}
+
+impl fmt::Display for EnvEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Created => write!(f, "created"),
+            Self::Extended => write!(f, "extended"),
+            Self::ExpiryWarning => write!(f, "expiry_warning"),
+            Self::VmAdded => write!(f, "vm_added"),
+            Self::VmRemoved => write!(f, "vm_removed"),
+            Self::VmMigrated => write!(f, "vm_migrated"),
+            Self::VmRecreated => write!(f, "vm_recreated"),
+        }
+    }
+}
+
+/// An entry in an environment's event log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EnvEvent {
+    pub env_id: String,
+    pub at: u64,
+    pub kind: EnvEventKind,
+    pub message: String,
+}
+
+/// What a background job does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    CreateEnv,
+    DeleteEnv,
+    MigrateVm,
+    /// Copy an image to other hosts; the job's target is the image.
+    PushImage,
+}
+
+impl fmt::Display for JobKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CreateEnv => write!(f, "create_env"),
+            Self::DeleteEnv => write!(f, "delete_env"),
+            Self::MigrateVm => write!(f, "migrate_vm"),
+            Self::PushImage => write!(f, "push_image"),
+        }
+    }
+}
+
+/// Where a job, or one of its steps, stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl JobState {
+    pub fn is_finished(self) -> bool {
+        matches!(self, Self::Succeeded | Self::Failed)
+    }
+}
+
+impl fmt::Display for JobState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pending => write!(f, "pending"),
+            Self::Running => write!(f, "running"),
+            Self::Succeeded => write!(f, "succeeded"),
+            Self::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// One step of a job, e.g. creating one VM of an env.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobStep {
+    pub name: String,
+    pub state: JobState,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub detail: String,
+}
+
+/// A long-running controller operation, carried out in the background.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub kind: JobKind,
+    /// Env or VM the job acts on.
+    pub target: String,
+    /// User who started the job.
+    pub owner: String,
+    pub state: JobState,
+    pub steps: Vec<JobStep>,
+    /// Problems that did not fail the job.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub created_at: u64,
+    /// 0 until the job is finished.
+    #[serde(default)]
+    pub finished_at: u64,
+}
+
+/// A controller user account.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub name: String,
+    pub role: Role,
+    /// Group whose quota the user also counts against.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    pub created_at: u64,
+}
+
+/// Resource limits for a user or a group; `None` means unlimited.
+///
+/// Sizes are in the same units as VM specs: vCPUs and MiB.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mem: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disk: Option<u32>,
+    /// Maximum number of VMs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vms: Option<u32>,
+    /// Maximum number of environments.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub envs: Option<u32>,
+    /// Maximum env lifetime in seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lifetime: Option<u64>,
+    /// How long an env may live, counted from its creation, once
+    /// extended; in seconds (default: [`MAX_EXTENDED_LIFETIME`]).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_lifetime: Option<u64>,
+}
+
+/// Resources held by a user or group, in the units of [`Quota`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub cpu: u32,
+    pub mem: u32,
+    pub disk: u32,
+    pub vms: u32,
+    pub envs: u32,
+}
+
+/// An API token issued to a user.
+///
+/// The secret itself is only shown once, at issuance; the controller
+/// keeps a SHA-256 hash of it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: String,
+    /// Name of the user the token authenticates as.
+    pub user: String,
+    /// Free-form note, e.g. the machine the token is used on.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub label: String,
+    pub created_at: u64,
+}
+
+/// A mutating API call, as recorded in the controller's audit log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Position in the log: later calls have higher IDs.
+    pub id: u64,
+    pub at: u64,
+    pub user: String,
+    /// ID of the API token used; none for the bootstrap key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// IP address the call came from.
+    pub source: String,
+    pub method: String,
+    /// Path and query string of the call.
+    pub endpoint: String,
+    /// Env, VM and host the call acted on, as far as they are known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vm: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// The request body, shortened.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub summary: String,
+    /// HTTP status of the response.
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// ID the call was logged under, on the controller and its agents.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
 }
 
 // ── Default VM Sizing ───────────────────────────────────────────────
@@ -220,6 +549,11 @@ pub const VM_MEM_DEFAULT: u32 = 1024;
 pub const VM_DISK_DEFAULT: u32 = 40 * 1024;
 /// Maximum environment lifetime in seconds (6 hours).
 pub const MAX_LIFETIME: u64 = 6 * 3600;
+/// Longest an environment may live through extensions, counted from its
+/// creation, unless a quota says otherwise (7 days).
+pub const MAX_EXTENDED_LIFETIME: u64 = 7 * 24 * 3600;
+/// How long before expiry an environment records a warning event.
+pub const EXPIRY_WARNING: u64 = 15 * 60;
 /// Maximum hosts in the fleet.
 pub const MAX_HOSTS: usize = 50;
 /// Maximum total VM instances across the fleet.
@@ -256,6 +590,40 @@ pub fn validate_name(name: &str, label: &str) -> std::result::Result<(), String>
     Ok(())
 }
 
+/// Parse `key=value` labels, as given to `--label`.
+pub fn parse_labels<S: AsRef<str>>(
+    items: &[S],
+) -> std::result::Result<BTreeMap<String, String>, String> {
+    let mut labels = BTreeMap::new();
+    for item in items {
+        let item = item.as_ref();
+        let (k, v) = item
+            .split_once('=')
+            .ok_or_else(|| format!("label '{item}' is not key=value"))?;
+        labels.insert(k.to_string(), v.to_string());
+    }
+    validate_labels(&labels)?;
+    Ok(labels)
+}
+
+/// Validate label keys and values like names.
+pub fn validate_labels(labels: &BTreeMap<String, String>) -> std::result::Result<(), String> {
+    for (k, v) in labels {
+        validate_name(k, "label key")?;
+        validate_name(v, "label value")?;
+    }
+    Ok(())
+}
+
+/// Render labels as `key=value,key=value`.
+pub fn format_labels(labels: &BTreeMap<String, String>) -> String {
+    labels
+        .iter()
+        .map(|(k, v)| format!("{k}={v}"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -322,6 +690,7 @@ mod tests {
         assert_eq!(VmState::Running.to_string(), "running");
         assert_eq!(VmState::Stopped.to_string(), "stopped");
         assert_eq!(VmState::Paused.to_string(), "paused");
+        assert_eq!(VmState::Hibernated.to_string(), "hibernated");
         assert_eq!(VmState::Creating.to_string(), "creating");
         assert_eq!(VmState::Failed.to_string(), "failed");
     }
@@ -383,11 +752,14 @@ mod tests {
     // ── Constants ───────────────────────────────────────────────────
 
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn constants_sane() {
         assert!(VM_CPU_DEFAULT > 0);
         assert!(VM_MEM_DEFAULT > 0);
         assert!(VM_DISK_DEFAULT > 0);
         assert!(MAX_LIFETIME > 0);
+        assert!(MAX_EXTENDED_LIFETIME >= MAX_LIFETIME);
+        assert!(EXPIRY_WARNING < MAX_LIFETIME);
         assert!(MAX_HOSTS > 0 && MAX_HOSTS <= 100);
         assert!(MAX_VMS > 0 && MAX_VMS <= 10_000);
     }
@@ -427,4 +799,13 @@ mod tests {
         assert!(validate_name("bad@name", "env").is_err());
         assert!(validate_name(".hidden", "env").is_err());
     }
+
+    #[test]
+    fn parse_labels_checks_syntax() {
+        let labels = parse_labels(&["disk=nvme", "rack=b"]).unwrap();
+        assert_eq!(format_labels(&labels), "disk=nvme,rack=b");
+        assert!(parse_labels(&["nvme"]).is_err());
+        assert!(parse_labels(&["disk="]).is_err());
+        assert!(parse_labels(&["disk=a b"]).is_err());
+    }
 }
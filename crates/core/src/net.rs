@@ -4,9 +4,20 @@
 //! - A bridge device for VM connectivity
 //! - TAP devices for individual VMs
 //! - Firewall NAT rules for port forwarding
+//! - Firewall rules letting only the source host into the ports of an
+//!   incoming live migration
+//! - Per-environment private networks that span hosts: a bridge per env
+//!   joined to the other hosts by a VXLAN tunnel, with a second NIC in
+//!   each VM
 //!
-//! **Linux**: uses `ip`, `nftables`
-//! **FreeBSD**: uses `ifconfig`, `pf`
+//! **Linux**: uses `ip`, `bridge`, `nftables`; VXLAN floods to an explicit
+//! peer list (unicast), so no multicast is needed on the underlay
+//! **FreeBSD**: uses `ifconfig`, `pf`; vxlan(4) joins a multicast group
+//! derived from the VNI
+//!
+//! Each operation runs in a `tracing` span named after it, with its
+//! arguments; failures are logged at debug level, callers decide what
+//! is worth a warning.
 
 #[cfg(any(target_os = "linux", target_os = "freebsd"))]
 use ruc::*;
@@ -23,6 +34,47 @@ pub const BRIDGE_CIDR: &str = "10.10.0.1/16";
 #[cfg(target_os = "linux")]
 pub const NFT_TABLE: &str = "tt-nat";
 
+/// UDP port for VXLAN traffic between agents (IANA default).
+pub const VXLAN_PORT: u16 = 4789;
+/// VNI of the first env network; env network `n` uses `OVERLAY_VNI_BASE + n`.
+pub const OVERLAY_VNI_BASE: u32 = 10000;
+/// Number of env networks: 172.16.0.0/12 split into /24 subnets.
+pub const OVERLAY_MAX_NETS: u32 = 4096;
+/// Prefix length of every env subnet.
+pub const OVERLAY_PREFIX: u8 = 24;
+/// Maximum VMs attached to one env network (.2 ... .254).
+pub const OVERLAY_MAX_VMS: u32 = 253;
+/// MTU for overlay NICs, leaving room for the 50-byte VXLAN header.
+pub const OVERLAY_MTU: u32 = 1450;
+
+/// Env network number `index` (0..OVERLAY_MAX_NETS) as a VNI and subnet.
+pub fn overlay_network(index: u32) -> crate::model::EnvNetwork {
+    crate::model::EnvNetwork {
+        vni: OVERLAY_VNI_BASE + index,
+        subnet: format!(
+            "172.{}.{}.0/{OVERLAY_PREFIX}",
+            16 + index / 256,
+            index % 256
+        ),
+    }
+}
+
+/// Address of the `slot`-th VM (0..OVERLAY_MAX_VMS) on env network `vni`.
+pub fn overlay_ip(vni: u32, slot: u32) -> String {
+    let index = vni - OVERLAY_VNI_BASE;
+    format!("172.{}.{}.{}", 16 + index / 256, index % 256, slot + 2)
+}
+
+/// Bridge joining the local VMs of an env network to its VXLAN tunnel.
+pub fn overlay_bridge_name(vni: u32) -> String {
+    format!("ttb{vni}")
+}
+
+/// VXLAN interface of an env network.
+pub fn vxlan_name(vni: u32) -> String {
+    format!("ttx{vni}")
+}
+
 /// Derive an IP address for a VM from a sequential index (0..65534).
 ///
 /// Produces addresses in the 10.10.x.y range, skipping .0 and .255.
@@ -38,13 +90,34 @@ pub fn vm_ip(index: u32) -> String {
 /// Uses a hash of the VM ID to guarantee uniqueness even for long IDs.
 /// Result is always <= 15 chars (IFNAMSIZ).
 pub fn tap_name(vm_id: &str) -> String {
+    // "tt-" + 12 hex chars = 15 chars exactly
+    format!("tt-{:012x}", id_hash(vm_id) & 0xFFFF_FFFF_FFFF)
+}
+
+/// TAP device name for a VM's NIC on its env network.
+pub fn overlay_tap_name(vm_id: &str) -> String {
+    format!("to-{:012x}", id_hash(vm_id) & 0xFFFF_FFFF_FFFF)
+}
+
+/// Stable MAC address for a VM's `nic`-th interface.
+///
+/// Lets guest network config match NICs by address instead of driver.
+pub fn vm_mac(vm_id: &str, nic: u8) -> String {
+    let h = id_hash(vm_id);
+    format!(
+        "52:54:{nic:02x}:{:02x}:{:02x}:{:02x}",
+        (h >> 16) & 0xFF,
+        (h >> 8) & 0xFF,
+        h & 0xFF
+    )
+}
+
+fn id_hash(vm_id: &str) -> u64 {
     use std::collections::hash_map::DefaultHasher;
     use std::hash::{Hash, Hasher};
     let mut h = DefaultHasher::new();
     vm_id.hash(&mut h);
-    let hash = h.finish();
-    // "tt-" + 12 hex chars = 15 chars exactly
-    format!("tt-{:012x}", hash & 0xFFFF_FFFF_FFFF)
+    h.finish()
 }
 
 // ═══════════════════════════════════════════════════════════════════
@@ -71,26 +144,112 @@ mod platform {
     }
 
     pub fn bridge_exists() -> Result<bool> {
+        link_exists(BRIDGE_NAME)
+    }
+
+    fn link_exists(name: &str) -> Result<bool> {
         let output = Command::new("ip")
-            .args(["link", "show", BRIDGE_NAME])
+            .args(["link", "show", name])
             .output()
             .c(d!())?;
         Ok(output.status.success())
     }
 
     pub fn create_tap(vm_id: &str) -> Result<()> {
-        let tap = tap_name(vm_id);
+        attach_tap(&tap_name(vm_id), BRIDGE_NAME)
+    }
+
+    pub fn create_overlay_tap(vm_id: &str, vni: u32) -> Result<()> {
+        attach_tap(&overlay_tap_name(vm_id), &overlay_bridge_name(vni))
+    }
 
-        run(&["ip", "tuntap", "add", "dev", &tap, "mode", "tap"])?;
-        run(&["ip", "link", "set", &tap, "master", BRIDGE_NAME])?;
-        run(&["ip", "link", "set", &tap, "up"])?;
+    fn attach_tap(tap: &str, bridge: &str) -> Result<()> {
+        run(&["ip", "tuntap", "add", "dev", tap, "mode", "tap"])?;
+        run(&["ip", "link", "set", tap, "master", bridge])?;
+        run(&["ip", "link", "set", tap, "up"])?;
 
         Ok(())
     }
 
     pub fn destroy_tap(vm_id: &str) -> Result<()> {
-        let tap = tap_name(vm_id);
-        let _ = run(&["ip", "link", "del", &tap]);
+        let _ = run(&["ip", "link", "del", &tap_name(vm_id)]);
+        let _ = run(&["ip", "link", "del", &overlay_tap_name(vm_id)]);
+        Ok(())
+    }
+
+    pub fn ensure_overlay(vni: u32, peers: &[String]) -> Result<()> {
+        let br = overlay_bridge_name(vni);
+        let vx = vxlan_name(vni);
+
+        if !link_exists(&br)? {
+            run(&["ip", "link", "add", &br, "type", "bridge"])?;
+            run(&["ip", "link", "set", &br, "up"])?;
+        }
+        if !link_exists(&vx)? {
+            run(&[
+                "ip",
+                "link",
+                "add",
+                &vx,
+                "type",
+                "vxlan",
+                "id",
+                &vni.to_string(),
+                "dstport",
+                &VXLAN_PORT.to_string(),
+            ])?;
+            run(&["ip", "link", "set", &vx, "master", &br])?;
+            run(&["ip", "link", "set", &vx, "up"])?;
+        }
+
+        // Broadcast/unknown traffic is replicated to every peer through
+        // all-zero FDB entries; keep them in sync with the peer list.
+        let current = flood_peers(&vx)?;
+        for peer in peers.iter().filter(|p| !current.contains(p)) {
+            run(&[
+                "bridge",
+                "fdb",
+                "append",
+                "00:00:00:00:00:00",
+                "dev",
+                &vx,
+                "dst",
+                peer,
+            ])?;
+        }
+        for peer in current.iter().filter(|p| !peers.contains(p)) {
+            let _ = run(&[
+                "bridge",
+                "fdb",
+                "del",
+                "00:00:00:00:00:00",
+                "dev",
+                &vx,
+                "dst",
+                peer,
+            ]);
+        }
+
+        Ok(())
+    }
+
+    fn flood_peers(vx: &str) -> Result<Vec<String>> {
+        let output = Command::new("bridge")
+            .args(["fdb", "show", "dev", vx])
+            .output()
+            .c(d!("bridge fdb show"))?;
+        let listing = String::from_utf8_lossy(&output.stdout);
+        Ok(listing
+            .lines()
+            .filter_map(|l| l.strip_prefix("00:00:00:00:00:00 dst "))
+            .filter_map(|rest| rest.split_whitespace().next())
+            .map(String::from)
+            .collect())
+    }
+
+    pub fn destroy_overlay(vni: u32) -> Result<()> {
+        let _ = run(&["ip", "link", "del", &vxlan_name(vni)]);
+        let _ = run(&["ip", "link", "del", &overlay_bridge_name(vni)]);
         Ok(())
     }
 
@@ -111,6 +270,13 @@ mod platform {
         let _ = nft(&format!("flush chain ip {NFT_TABLE} postrouting"));
         let _ = nft(&format!("flush chain ip {NFT_TABLE} prerouting"));
 
+        // Incoming migrations do not survive a restart, nor do the
+        // rules guarding their ports
+        nft(&format!(
+            "add chain ip {NFT_TABLE} input {{ type filter hook input priority 0; policy accept; }}"
+        ))?;
+        let _ = nft(&format!("flush chain ip {NFT_TABLE} input"));
+
         nft(&format!(
             "add rule ip {NFT_TABLE} postrouting ip saddr 10.10.0.0/16 masquerade"
         ))?;
@@ -174,6 +340,43 @@ mod platform {
         Ok(())
     }
 
+    pub fn restrict_migration(vm_id: &str, peer: &str, ports: &[u16]) -> Result<()> {
+        let ports = ports
+            .iter()
+            .map(u16::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        nft(&format!(
+            "add rule ip {NFT_TABLE} input tcp dport {{ {ports} }} ip saddr != {peer} drop comment \"migrate-{vm_id}\""
+        ))
+    }
+
+    pub fn remove_migration_rules(vm_id: &str) -> Result<()> {
+        let output = Command::new("nft")
+            .args(["-a", "list", "chain", "ip", NFT_TABLE, "input"])
+            .output()
+            .c(d!())?;
+
+        if !output.status.success() {
+            return Ok(());
+        }
+
+        let tag = format!("\"migrate-{vm_id}\"");
+        let listing = String::from_utf8_lossy(&output.stdout);
+        for line in listing.lines() {
+            if line.contains(&tag)
+                && let Some(handle) = line
+                    .rsplit("handle ")
+                    .next()
+                    .and_then(|h| h.trim().parse::<u64>().ok())
+            {
+                let _ = nft(&format!("delete rule ip {NFT_TABLE} input handle {handle}"));
+            }
+        }
+
+        Ok(())
+    }
+
     fn nft(rule: &str) -> Result<()> {
         use std::io::Write;
         let mut child = Command::new("nft")
@@ -239,23 +442,87 @@ mod platform {
     }
 
     pub fn bridge_exists() -> Result<bool> {
-        let output = Command::new("ifconfig").arg(BRIDGE_NAME).output().c(d!())?;
+        iface_exists(BRIDGE_NAME)
+    }
+
+    fn iface_exists(name: &str) -> Result<bool> {
+        let output = Command::new("ifconfig").arg(name).output().c(d!())?;
         Ok(output.status.success())
     }
 
     pub fn create_tap(vm_id: &str) -> Result<()> {
-        let tap = tap_name(vm_id);
+        attach_tap(&tap_name(vm_id), BRIDGE_NAME)
+    }
+
+    pub fn create_overlay_tap(vm_id: &str, vni: u32) -> Result<()> {
+        attach_tap(&overlay_tap_name(vm_id), &overlay_bridge_name(vni))
+    }
 
-        run(&["ifconfig", "tap", "create", "name", &tap])?;
-        run(&["ifconfig", BRIDGE_NAME, "addm", &tap])?;
-        run(&["ifconfig", &tap, "up"])?;
+    fn attach_tap(tap: &str, bridge: &str) -> Result<()> {
+        run(&["ifconfig", "tap", "create", "name", tap])?;
+        run(&["ifconfig", bridge, "addm", tap])?;
+        run(&["ifconfig", tap, "up"])?;
 
         Ok(())
     }
 
     pub fn destroy_tap(vm_id: &str) -> Result<()> {
-        let tap = tap_name(vm_id);
-        let _ = run(&["ifconfig", &tap, "destroy"]);
+        let _ = run(&["ifconfig", &tap_name(vm_id), "destroy"]);
+        let _ = run(&["ifconfig", &overlay_tap_name(vm_id), "destroy"]);
+        Ok(())
+    }
+
+    /// vxlan(4) cannot flood to a list of unicast peers, so every env
+    /// network uses a multicast group derived from its VNI instead and
+    /// `_peers` is not needed.
+    pub fn ensure_overlay(vni: u32, _peers: &[String]) -> Result<()> {
+        let br = overlay_bridge_name(vni);
+        let vx = vxlan_name(vni);
+
+        if !iface_exists(&br)? {
+            run(&["ifconfig", "bridge", "create", "name", &br])?;
+            run(&["ifconfig", &br, "up"])?;
+        }
+        if !iface_exists(&vx)? {
+            let group = format!("239.1.{}.{}", (vni >> 8) & 0xFF, vni & 0xFF);
+            let dev = default_iface()?;
+            run(&[
+                "ifconfig",
+                "vxlan",
+                "create",
+                "name",
+                &vx,
+                "vxlanid",
+                &vni.to_string(),
+                "vxlangroup",
+                &group,
+                "vxlandev",
+                &dev,
+                "vxlanport",
+                &VXLAN_PORT.to_string(),
+            ])?;
+            run(&["ifconfig", &br, "addm", &vx])?;
+            run(&["ifconfig", &vx, "up"])?;
+        }
+
+        Ok(())
+    }
+
+    fn default_iface() -> Result<String> {
+        let output = Command::new("route")
+            .args(["-n", "get", "default"])
+            .output()
+            .c(d!("route get default"))?;
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .find_map(|l| l.trim().strip_prefix("interface:"))
+            .map(|i| i.trim().to_string())
+            .ok_or_else(|| eg!("no default route interface for vxlan"))
+    }
+
+    pub fn destroy_overlay(vni: u32) -> Result<()> {
+        let _ = run(&["ifconfig", &vxlan_name(vni), "destroy"]);
+        let _ = run(&["ifconfig", &overlay_bridge_name(vni), "destroy"]);
         Ok(())
     }
 
@@ -360,6 +627,39 @@ mod platform {
         Ok(())
     }
 
+    pub fn restrict_migration(vm_id: &str, peer: &str, ports: &[u16]) -> Result<()> {
+        let ports = ports
+            .iter()
+            .map(u16::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        let rule = format!("block in quick proto tcp from ! {peer} to any port {{ {ports} }}");
+        let output = Command::new("sh")
+            .args([
+                "-c",
+                &format!(r#"echo '{rule}' | pfctl -a ttstack/migrate-{vm_id} -f -"#),
+            ])
+            .output()
+            .c(d!("pfctl migrate"))?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(eg!("pfctl migrate failed: {}", stderr));
+        }
+        Ok(())
+    }
+
+    pub fn remove_migration_rules(vm_id: &str) -> Result<()> {
+        let _ = run(&[
+            "pfctl",
+            "-a",
+            &format!("ttstack/migrate-{vm_id}"),
+            "-F",
+            "rules",
+        ]);
+        Ok(())
+    }
+
     fn run(args: &[&str]) -> Result<()> {
         let output = Command::new(args[0])
             .args(&args[1..])
@@ -380,45 +680,90 @@ mod platform {
 // ═══════════════════════════════════════════════════════════════════
 
 #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+#[tracing::instrument(err(level = "debug"))]
 pub fn setup_bridge() -> Result<()> {
     platform::setup_bridge()
 }
 
 #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+#[tracing::instrument(err(level = "debug"))]
 pub fn setup_nat() -> Result<()> {
     platform::setup_nat()
 }
 
 #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+#[tracing::instrument(err(level = "debug"))]
 pub fn create_tap(vm_id: &str, _vm_ip_addr: &str) -> Result<()> {
     platform::create_tap(vm_id)
 }
 
 #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+#[tracing::instrument(err(level = "debug"))]
 pub fn destroy_tap(vm_id: &str) -> Result<()> {
     platform::destroy_tap(vm_id)
 }
 
+/// Attach a VM's second TAP device to its env network bridge.
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+#[tracing::instrument(err(level = "debug"))]
+pub fn create_overlay_tap(vm_id: &str, vni: u32) -> Result<()> {
+    platform::create_overlay_tap(vm_id, vni)
+}
+
+/// Create (if needed) an env network's bridge and VXLAN tunnel and
+/// point the tunnel at `peers` (underlay IPs of the other hosts).
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+#[tracing::instrument(err(level = "debug"))]
+pub fn ensure_overlay(vni: u32, peers: &[String]) -> Result<()> {
+    platform::ensure_overlay(vni, peers)
+}
+
+/// Remove an env network's bridge and VXLAN tunnel from this host.
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+#[tracing::instrument(err(level = "debug"))]
+pub fn destroy_overlay(vni: u32) -> Result<()> {
+    platform::destroy_overlay(vni)
+}
+
 #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+#[tracing::instrument(err(level = "debug"))]
 pub fn add_port_forward(host_port: u16, vm_ip_addr: &str, guest_port: u16) -> Result<()> {
     platform::add_port_forward(host_port, vm_ip_addr, guest_port)
 }
 
 #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+#[tracing::instrument(err(level = "debug"))]
 pub fn remove_port_forwards(vm_ip_addr: &str) -> Result<()> {
     platform::remove_port_forwards(vm_ip_addr)
 }
 
 #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+#[tracing::instrument(err(level = "debug"))]
 pub fn deny_outgoing(vm_ip_addr: &str) -> Result<()> {
     platform::deny_outgoing(vm_ip_addr)
 }
 
 #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+#[tracing::instrument(err(level = "debug"))]
 pub fn allow_outgoing(vm_ip_addr: &str) -> Result<()> {
     platform::allow_outgoing(vm_ip_addr)
 }
 
+/// Drop connections to the ports of VM `vm_id`'s incoming live
+/// migration from anywhere but `peer`, the source host (IPv4 only).
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+#[tracing::instrument(err(level = "debug"))]
+pub fn restrict_migration(vm_id: &str, peer: &str, ports: &[u16]) -> Result<()> {
+    platform::restrict_migration(vm_id, peer, ports)
+}
+
+/// Remove the rules set by [`restrict_migration`] for VM `vm_id`.
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+#[tracing::instrument(err(level = "debug"))]
+pub fn remove_migration_rules(vm_id: &str) -> Result<()> {
+    platform::remove_migration_rules(vm_id)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -443,6 +788,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::manual_range_contains)]
     fn vm_ip_unique_and_valid() {
         use std::collections::HashSet;
         let mut seen = HashSet::new();
@@ -455,6 +801,35 @@ mod tests {
         }
     }
 
+    #[test]
+    fn overlay_networks_do_not_collide() {
+        assert_eq!(overlay_network(0).subnet, "172.16.0.0/24");
+        assert_eq!(overlay_network(0).vni, OVERLAY_VNI_BASE);
+        assert_eq!(overlay_network(257).subnet, "172.17.1.0/24");
+        let last = overlay_network(OVERLAY_MAX_NETS - 1);
+        assert_eq!(last.subnet, "172.31.255.0/24");
+
+        let vni = overlay_network(3).vni;
+        assert_eq!(overlay_ip(vni, 0), "172.16.3.2");
+        assert_eq!(overlay_ip(vni, OVERLAY_MAX_VMS - 1), "172.16.3.254");
+    }
+
+    #[test]
+    fn overlay_names_fit_ifnamsiz() {
+        let vni = OVERLAY_VNI_BASE + OVERLAY_MAX_NETS;
+        assert!(overlay_bridge_name(vni).len() <= 15);
+        assert!(vxlan_name(vni).len() <= 15);
+        assert!(overlay_tap_name("vm1").len() <= 15);
+        assert_ne!(overlay_tap_name("vm1"), tap_name("vm1"));
+    }
+
+    #[test]
+    fn vm_mac_per_nic() {
+        assert_eq!(vm_mac("vm1", 0), vm_mac("vm1", 0));
+        assert_ne!(vm_mac("vm1", 0), vm_mac("vm1", 1));
+        assert!(vm_mac("vm1", 1).starts_with("52:54:01:"));
+    }
+
     #[test]
     fn tap_name_fits_ifnamsiz() {
         assert!(tap_name("abc").len() <= 15);
@@ -3,20 +3,57 @@
 //! Uses plain file/directory copies for image provisioning. Works on
 //! any filesystem. On Linux with CoW filesystems, `cp --reflink=auto`
 //! makes copies near-instant.
+//!
+//! Snapshots are external: each one is a full (reflinked where the
+//! filesystem allows) copy of the clone kept under `<clone>.snapshots/`,
+//! whose `.order` file lists them oldest first. This works the same for
+//! qcow2 disks, raw Firecracker rootfs files, and jail root directories.
+//!
+//! Base images are distributed between hosts file by file: the sender
+//! publishes a manifest of SHA-256 checksums, chunks are written into a
+//! hidden staging path on the receiver, and [`FileStore::install_image`]
+//! verifies everything before moving the image into place.
 
-use super::ImageStore;
+use super::{ImageStore, newer_snapshots, to_hex};
+use crate::api::{ImageFile, ImageLink};
 use ruc::*;
-use std::path::Path;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
 
 pub struct FileStore;
 
-impl ImageStore for FileStore {
-    fn clone_image(&self, base: &str, target: &str) -> Result<()> {
+impl FileStore {
+    /// Directory holding the snapshots of a clone.
+    fn snapshot_dir(path: &str) -> String {
+        format!("{path}.snapshots")
+    }
+
+    fn snapshot_path(path: &str, name: &str) -> String {
+        format!("{}/{name}", Self::snapshot_dir(path))
+    }
+
+    /// File listing a clone's snapshots in the order they were taken;
+    /// snapshot names cannot start with a dot.
+    fn order_path(path: &str) -> String {
+        format!("{}/.order", Self::snapshot_dir(path))
+    }
+
+    fn write_order(path: &str, snaps: &[String]) -> Result<()> {
+        let mut text = snaps.join("\n");
+        text.push('\n');
+        std::fs::write(Self::order_path(path), text).c(d!("write snapshot order"))
+    }
+
+    fn copy(src: &str, dst: &str) -> Result<()> {
         let mut cmd = std::process::Command::new("cp");
         #[cfg(target_os = "linux")]
-        cmd.args(["--reflink=auto", "-a", base, target]);
+        cmd.args(["--reflink=auto", "-a", src, dst]);
         #[cfg(not(target_os = "linux"))]
-        cmd.args(["-a", base, target]);
+        cmd.args(["-a", src, dst]);
         let output = cmd.output().c(d!("cp image"))?;
 
         if !output.status.success() {
@@ -27,7 +64,7 @@ impl ImageStore for FileStore {
         Ok(())
     }
 
-    fn remove_image(&self, path: &str) -> Result<()> {
+    fn remove_path(path: &str) -> Result<()> {
         let p = Path::new(path);
         if p.is_dir() {
             std::fs::remove_dir_all(p).c(d!("remove dir"))?;
@@ -36,6 +73,224 @@ impl ImageStore for FileStore {
         }
         Ok(())
     }
+}
+
+// ── Image distribution ──────────────────────────────────────────────
+
+impl FileStore {
+    /// List every regular file of an image with its size, mode and
+    /// checksum, and every symlink with its target.
+    ///
+    /// Returns whether the image is a directory along with its files and
+    /// links, in path order. Special files, and symlinks pointing outside
+    /// the image, are rejected because they cannot be transferred.
+    pub fn manifest(&self, path: &str) -> Result<(bool, Vec<ImageFile>, Vec<ImageLink>)> {
+        let root = Path::new(path);
+        let meta = std::fs::symlink_metadata(root).c(d!("stat image"))?;
+        if meta.is_file() {
+            let file = Self::describe(root, String::new())?;
+            return Ok((false, vec![file], vec![]));
+        }
+        if !meta.is_dir() {
+            return Err(eg!("image {} is neither a file nor a directory", path));
+        }
+
+        let mut files = Vec::new();
+        let mut links = Vec::new();
+        let mut pending = vec![root.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            for entry in std::fs::read_dir(&dir).c(d!("read image dir"))? {
+                let entry = entry.c(d!("read dir entry"))?;
+                let p = entry.path();
+                let ft = entry.file_type().c(d!("stat dir entry"))?;
+                let rel = || -> Result<String> {
+                    Ok(p.strip_prefix(root).c(d!())?.to_string_lossy().into_owned())
+                };
+                if ft.is_dir() {
+                    pending.push(p);
+                } else if ft.is_file() {
+                    files.push(Self::describe(&p, rel()?)?);
+                } else if ft.is_symlink() {
+                    let target = std::fs::read_link(&p).c(d!("read symlink"))?;
+                    let link = ImageLink {
+                        path: rel()?,
+                        target: target.to_string_lossy().into_owned(),
+                    };
+                    Self::check_link(&link)?;
+                    links.push(link);
+                } else {
+                    return Err(eg!("unsupported file type in image: {}", p.display()));
+                }
+            }
+        }
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        links.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok((true, files, links))
+    }
+
+    /// Read up to `len` bytes of an image member starting at `offset`.
+    pub fn read_chunk(&self, image: &str, rel: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let mut f = File::open(Self::member(image, rel)?).c(d!("open image file"))?;
+        f.seek(SeekFrom::Start(offset)).c(d!("seek image file"))?;
+        let mut buf = Vec::new();
+        f.take(len).read_to_end(&mut buf).c(d!("read image file"))?;
+        Ok(buf)
+    }
+
+    /// Write one chunk of an incoming image member under `staging`.
+    ///
+    /// Parent directories are created as needed; a chunk at offset 0
+    /// truncates the file so a retried transfer starts clean.
+    pub fn write_chunk(&self, staging: &str, rel: &str, offset: u64, data: &[u8]) -> Result<()> {
+        let target = Self::member(staging, rel)?;
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent).c(d!("create staging dir"))?;
+        }
+        let mut f = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(offset == 0)
+            .open(&target)
+            .c(d!("open staging file"))?;
+        f.seek(SeekFrom::Start(offset)).c(d!("seek staging file"))?;
+        f.write_all(data).c(d!("write staging file"))
+    }
+
+    /// Verify a staged image against its manifest, create its symlinks
+    /// and move it into place.
+    ///
+    /// Any existing image at `image` is replaced. The staging path is
+    /// removed when verification fails.
+    pub fn install_image(
+        &self,
+        staging: &str,
+        image: &str,
+        is_dir: bool,
+        files: &[ImageFile],
+        links: &[ImageLink],
+    ) -> Result<()> {
+        let verified = (|| {
+            if is_dir {
+                std::fs::create_dir_all(staging).c(d!("create staging dir"))?;
+            } else if files.len() != 1 || !files[0].path.is_empty() || !links.is_empty() {
+                return Err(eg!("single-file image must have exactly one unnamed file"));
+            }
+            for f in files {
+                let p = Self::member(staging, &f.path)?;
+                let got = Self::describe(&p, f.path.clone()).c(d!("missing file '{}'", f.path))?;
+                if got.size != f.size || got.sha256 != f.sha256 {
+                    return Err(eg!("checksum mismatch for '{}'", f.path));
+                }
+                std::fs::set_permissions(&p, std::fs::Permissions::from_mode(f.mode))
+                    .c(d!("set file mode"))?;
+            }
+
+            // Nothing may be reached through a link, or installing it
+            // could write wherever the link points on this host
+            let link_paths: HashSet<&Path> = links.iter().map(|l| Path::new(&l.path)).collect();
+            let paths = files
+                .iter()
+                .map(|f| &f.path)
+                .chain(links.iter().map(|l| &l.path));
+            for rel in paths {
+                if let Some(under) = Path::new(rel)
+                    .ancestors()
+                    .skip(1)
+                    .find(|a| link_paths.contains(a))
+                {
+                    return Err(eg!("'{}' lies under symlink '{}'", rel, under.display()));
+                }
+            }
+            for link in links {
+                Self::check_link(link)?;
+                let p = Self::member(staging, &link.path)?;
+                if let Some(parent) = p.parent() {
+                    std::fs::create_dir_all(parent).c(d!("create staging dir"))?;
+                }
+                std::os::unix::fs::symlink(&link.target, &p)
+                    .c(d!("create symlink '{}'", link.path))?;
+            }
+            Ok(())
+        })();
+        if let Err(e) = verified {
+            let _ = Self::remove_path(staging);
+            return Err(e);
+        }
+
+        Self::remove_path(image)?;
+        std::fs::rename(staging, image).c(d!("move image into place"))
+    }
+
+    fn describe(path: &Path, rel: String) -> Result<ImageFile> {
+        let mut f = File::open(path).c(d!("open image file"))?;
+        let meta = f.metadata().c(d!("stat image file"))?;
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut f, &mut hasher).c(d!("hash image file"))?;
+        Ok(ImageFile {
+            path: rel,
+            size: meta.len(),
+            mode: meta.permissions().mode() & 0o7777,
+            sha256: to_hex(&hasher.finalize()),
+        })
+    }
+
+    /// Refuse a symlink whose path could escape the image, or whose target
+    /// resolves outside it. Absolute targets count from the image root, as
+    /// they do inside a jail.
+    fn check_link(link: &ImageLink) -> Result<()> {
+        if link.path.is_empty() {
+            return Err(eg!("invalid image file path: ''"));
+        }
+        let rel = Self::member("", &link.path)?;
+        let mut at: Vec<Component> = rel.parent().map_or(vec![], |p| p.components().collect());
+        let target = Path::new(&link.target);
+        if link.target.is_empty() {
+            return Err(eg!("symlink '{}' has no target", link.path));
+        }
+        for c in target.components() {
+            match c {
+                Component::RootDir => at.clear(),
+                Component::CurDir => {}
+                Component::Normal(_) => at.push(c),
+                Component::ParentDir if at.pop().is_some() => {}
+                _ => {
+                    return Err(eg!(
+                        "symlink '{}' points outside the image: '{}'",
+                        link.path,
+                        link.target
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolve a manifest path under `root`, refusing anything that could
+    /// escape it. An empty path names `root` itself.
+    fn member(root: &str, rel: &str) -> Result<PathBuf> {
+        if rel.is_empty() {
+            return Ok(PathBuf::from(root));
+        }
+        let rel_path = Path::new(rel);
+        if !rel_path
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(eg!("invalid image file path: '{}'", rel));
+        }
+        Ok(Path::new(root).join(rel_path))
+    }
+}
+
+impl ImageStore for FileStore {
+    fn clone_image(&self, base: &str, target: &str) -> Result<()> {
+        Self::copy(base, target)
+    }
+
+    fn remove_image(&self, path: &str) -> Result<()> {
+        Self::remove_path(path)?;
+        Self::remove_path(&Self::snapshot_dir(path))
+    }
 
     fn list_images(&self, base_dir: &str) -> Result<Vec<String>> {
         let dir = Path::new(base_dir);
@@ -89,6 +344,128 @@ impl ImageStore for FileStore {
         "qcow2"
     }
 
+    fn disk_size(&self, clone_path: &str) -> Result<u64> {
+        let disk = self.resolve_disk(clone_path);
+        let output = std::process::Command::new("qemu-img")
+            .args(["info", "--output=json", &disk])
+            .output()
+            .c(d!("qemu-img info"))?;
+        if !output.status.success() {
+            let err = String::from_utf8_lossy(&output.stderr);
+            return Err(eg!("qemu-img info failed: {}", err.trim()));
+        }
+        let info: serde_json::Value =
+            serde_json::from_slice(&output.stdout).c(d!("parse qemu-img info"))?;
+        info["virtual-size"]
+            .as_u64()
+            .ok_or_else(|| eg!("qemu-img reported no size for {}", disk))
+    }
+
+    fn create_disk(&self, clone_path: &str, bytes: u64) -> Result<()> {
+        let output = std::process::Command::new("qemu-img")
+            .args([
+                "create",
+                "-q",
+                "-f",
+                "qcow2",
+                clone_path,
+                &bytes.to_string(),
+            ])
+            .output()
+            .c(d!("qemu-img create"))?;
+        if !output.status.success() {
+            let err = String::from_utf8_lossy(&output.stderr);
+            return Err(eg!("qemu-img create failed: {}", err.trim()));
+        }
+        Ok(())
+    }
+
+    fn snapshot_image(&self, path: &str, name: &str) -> Result<()> {
+        if !Path::new(path).exists() {
+            return Err(eg!("image not found: {}", path));
+        }
+        let snap = Self::snapshot_path(path, name);
+        if Path::new(&snap).exists() {
+            return Err(eg!("snapshot '{}' already exists", name));
+        }
+        std::fs::create_dir_all(Self::snapshot_dir(path)).c(d!("create snapshot dir"))?;
+        let mut snaps = self.list_image_snapshots(path)?;
+        Self::copy(path, &snap)?;
+        snaps.push(name.to_string());
+        Self::write_order(path, &snaps)
+    }
+
+    fn list_image_snapshots(&self, path: &str) -> Result<Vec<String>> {
+        let dir = Self::snapshot_dir(path);
+        if !Path::new(&dir).is_dir() {
+            return Ok(vec![]);
+        }
+
+        let mut present = Vec::new();
+        for entry in std::fs::read_dir(&dir).c(d!("read snapshot dir"))? {
+            let entry = entry.c(d!("read dir entry"))?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with('.') {
+                present.push(name);
+            }
+        }
+        // Snapshots missing from the order file are older than it
+        present.sort();
+        let order = std::fs::read_to_string(Self::order_path(path)).unwrap_or_default();
+        let ordered: Vec<String> = order
+            .lines()
+            .filter(|s| present.iter().any(|p| p == s))
+            .map(String::from)
+            .collect();
+        present.retain(|p| !ordered.contains(p));
+        present.extend(ordered);
+        Ok(present)
+    }
+
+    fn rollback_image(&self, path: &str, name: &str, force: bool) -> Result<()> {
+        let snap = Self::snapshot_path(path, name);
+        if !Path::new(&snap).exists() {
+            return Err(eg!("snapshot '{}' not found", name));
+        }
+        let newer = newer_snapshots(&self.list_image_snapshots(path)?, name, force)?;
+
+        // Copy next to the clone and swap it in, so that a failed copy
+        // leaves the clone as it was
+        let dir = Self::snapshot_dir(path);
+        let restored = format!("{dir}/.restored");
+        let replaced = format!("{dir}/.replaced");
+        Self::remove_path(&restored)?;
+        Self::remove_path(&replaced)?;
+        if let Err(e) = Self::copy(&snap, &restored) {
+            let _ = Self::remove_path(&restored);
+            return Err(e);
+        }
+        if Path::new(path).exists() {
+            std::fs::rename(path, &replaced).c(d!("move clone aside"))?;
+        }
+        if let Err(e) = std::fs::rename(&restored, path) {
+            let _ = std::fs::rename(&replaced, path);
+            return Err(e).c(d!("swap in restored clone"));
+        }
+        Self::remove_path(&replaced)?;
+
+        for newer in newer {
+            self.remove_image_snapshot(path, &newer)?;
+        }
+        Ok(())
+    }
+
+    fn remove_image_snapshot(&self, path: &str, name: &str) -> Result<()> {
+        let snap = Self::snapshot_path(path, name);
+        if !Path::new(&snap).exists() {
+            return Err(eg!("snapshot '{}' not found", name));
+        }
+        Self::remove_path(&snap)?;
+        let mut snaps = self.list_image_snapshots(path)?;
+        snaps.retain(|s| s != name);
+        Self::write_order(path, &snaps)
+    }
+
     fn name(&self) -> &'static str {
         "file"
     }
@@ -178,6 +555,202 @@ mod tests {
         store.remove_image("/no/such/file").unwrap();
     }
 
+    #[test]
+    fn snapshot_rollback_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let clone = dir.path().join("clone-vm1");
+        std::fs::create_dir(&clone).unwrap();
+        std::fs::write(clone.join("disk.qcow2"), b"good").unwrap();
+        let path = clone.to_str().unwrap();
+
+        let store = FileStore;
+        store.snapshot_image(path, "base").unwrap();
+        assert!(store.snapshot_image(path, "base").is_err()); // duplicate
+        assert_eq!(store.list_image_snapshots(path).unwrap(), vec!["base"]);
+
+        std::fs::write(clone.join("disk.qcow2"), b"broken").unwrap();
+        store.rollback_image(path, "base", false).unwrap();
+        assert_eq!(std::fs::read(clone.join("disk.qcow2")).unwrap(), b"good");
+
+        // Rolling back does not consume the snapshot
+        assert_eq!(store.list_image_snapshots(path).unwrap(), vec!["base"]);
+        store.remove_image_snapshot(path, "base").unwrap();
+        assert!(store.list_image_snapshots(path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rollback_past_newer_snapshots_drops_them_when_forced() {
+        let dir = tempfile::tempdir().unwrap();
+        let clone = dir.path().join("clone.img");
+        let path = clone.to_str().unwrap();
+        let store = FileStore;
+        // Taken out of name order, listed in the order taken
+        for (snap, data) in [("zz", "one"), ("aa", "two"), ("mm", "three")] {
+            std::fs::write(&clone, data).unwrap();
+            store.snapshot_image(path, snap).unwrap();
+        }
+        assert_eq!(
+            store.list_image_snapshots(path).unwrap(),
+            ["zz", "aa", "mm"]
+        );
+
+        assert!(store.rollback_image(path, "zz", false).is_err());
+        assert_eq!(std::fs::read(&clone).unwrap(), b"three");
+        store.rollback_image(path, "aa", true).unwrap();
+        assert_eq!(std::fs::read(&clone).unwrap(), b"two");
+        assert_eq!(store.list_image_snapshots(path).unwrap(), ["zz", "aa"]);
+    }
+
+    #[test]
+    fn snapshot_missing_is_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let clone = dir.path().join("clone.img");
+        std::fs::write(&clone, b"data").unwrap();
+        let path = clone.to_str().unwrap();
+
+        let store = FileStore;
+        assert!(store.rollback_image(path, "nope", true).is_err());
+        assert!(store.remove_image_snapshot(path, "nope").is_err());
+        assert!(store.snapshot_image("/no/such/clone", "s1").is_err());
+    }
+
+    #[test]
+    fn remove_image_drops_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let clone = dir.path().join("clone.img");
+        std::fs::write(&clone, b"data").unwrap();
+        let path = clone.to_str().unwrap();
+
+        let store = FileStore;
+        store.snapshot_image(path, "s1").unwrap();
+        store.remove_image(path).unwrap();
+        assert!(!clone.exists());
+        assert!(!dir.path().join("clone.img.snapshots").exists());
+    }
+
+    #[test]
+    fn transfer_directory_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("fc-alpine");
+        std::fs::create_dir_all(src.join("boot")).unwrap();
+        std::fs::write(src.join("rootfs.ext4"), b"rootfs-bytes").unwrap();
+        std::fs::write(src.join("boot/vmlinux"), b"kernel").unwrap();
+        std::fs::write(src.join("empty"), b"").unwrap();
+        // Jail roots are full of links, some absolute
+        std::os::unix::fs::symlink("boot", src.join("kernel")).unwrap();
+        std::os::unix::fs::symlink("/boot/vmlinux", src.join("boot/current")).unwrap();
+
+        let store = FileStore;
+        let (is_dir, files, links) = store.manifest(src.to_str().unwrap()).unwrap();
+        assert!(is_dir);
+        let paths: Vec<_> = files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, vec!["boot/vmlinux", "empty", "rootfs.ext4"]);
+        let paths: Vec<_> = links.iter().map(|l| l.path.as_str()).collect();
+        assert_eq!(paths, vec!["boot/current", "kernel"]);
+
+        // Copy in 5-byte chunks, as the controller would over HTTP
+        let staging = dir.path().join(".incoming-fc-alpine");
+        let staging = staging.to_str().unwrap();
+        for f in &files {
+            let mut offset = 0;
+            loop {
+                let chunk = store
+                    .read_chunk(src.to_str().unwrap(), &f.path, offset, 5)
+                    .unwrap();
+                store.write_chunk(staging, &f.path, offset, &chunk).unwrap();
+                offset += chunk.len() as u64;
+                if offset >= f.size {
+                    break;
+                }
+            }
+        }
+
+        let dst = dir.path().join("copy");
+        store
+            .install_image(staging, dst.to_str().unwrap(), true, &files, &links)
+            .unwrap();
+        assert_eq!(std::fs::read(dst.join("boot/vmlinux")).unwrap(), b"kernel");
+        assert_eq!(
+            std::fs::read(dst.join("kernel/vmlinux")).unwrap(),
+            b"kernel"
+        );
+        let copied = store.manifest(dst.to_str().unwrap()).unwrap();
+        assert_eq!((copied.1, copied.2), (files, links));
+        assert!(!Path::new(staging).exists());
+    }
+
+    #[test]
+    fn install_rejects_checksum_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("img.qcow2");
+        std::fs::write(&src, b"original").unwrap();
+
+        let store = FileStore;
+        let (is_dir, files, _) = store.manifest(src.to_str().unwrap()).unwrap();
+        assert!(!is_dir);
+
+        let staging = dir.path().join(".incoming-img");
+        let staging = staging.to_str().unwrap();
+        store.write_chunk(staging, "", 0, b"tampered").unwrap();
+        let dst = dir.path().join("dst.qcow2");
+        assert!(
+            store
+                .install_image(staging, dst.to_str().unwrap(), false, &files, &[])
+                .is_err()
+        );
+        assert!(!dst.exists());
+        assert!(!Path::new(staging).exists());
+    }
+
+    #[test]
+    fn transfer_paths_cannot_escape() {
+        let store = FileStore;
+        assert!(
+            store
+                .write_chunk("/tmp/x", "../etc/passwd", 0, b"")
+                .is_err()
+        );
+        assert!(store.write_chunk("/tmp/x", "/etc/passwd", 0, b"").is_err());
+        assert!(store.read_chunk("/tmp/x", "a/../../b", 0, 1).is_err());
+
+        let link = |path: &str, target: &str| {
+            FileStore::check_link(&ImageLink {
+                path: path.into(),
+                target: target.into(),
+            })
+        };
+        assert!(link("usr/lib64", "lib").is_ok());
+        assert!(link("etc/localtime", "../usr/share/zoneinfo/UTC").is_ok());
+        assert!(link("etc/localtime", "/usr/share/zoneinfo/UTC").is_ok());
+        assert!(link("etc/up", "../..").is_err());
+        assert!(link("etc/up", "/../etc").is_err());
+        assert!(link("../x", "y").is_err());
+        assert!(link("x", "").is_err());
+    }
+
+    #[test]
+    fn install_refuses_files_under_links() {
+        let dir = tempfile::tempdir().unwrap();
+        let staging = dir.path().join(".incoming-img");
+        let staging = staging.to_str().unwrap();
+        FileStore
+            .write_chunk(staging, "etc/passwd", 0, b"x")
+            .unwrap();
+        let files = vec![
+            FileStore::describe(&Path::new(staging).join("etc/passwd"), "etc/passwd".into())
+                .unwrap(),
+        ];
+        let links = vec![ImageLink {
+            path: "etc".into(),
+            target: "/".into(),
+        }];
+
+        let dst = dir.path().join("img");
+        let result = FileStore.install_image(staging, dst.to_str().unwrap(), true, &files, &links);
+        assert!(result.is_err());
+        assert!(!dst.exists());
+    }
+
     #[test]
     fn name_is_file() {
         assert_eq!(FileStore.name(), "file");
@@ -37,6 +37,31 @@ pub trait ImageStore: Send + Sync {
     /// Disk format string for the engine (e.g. `"qcow2"` or `"raw"`).
     fn disk_format(&self) -> &'static str;
 
+    /// Virtual size in bytes of the disk of a VM's image clone.
+    fn disk_size(&self, clone_path: &str) -> Result<u64>;
+
+    /// Create an empty disk of `bytes` as a VM's image clone, for a
+    /// live migration to fill in.
+    fn create_disk(&self, clone_path: &str, bytes: u64) -> Result<()>;
+
+    /// Take a named point-in-time snapshot of a VM's image clone.
+    fn snapshot_image(&self, path: &str, name: &str) -> Result<()>;
+
+    /// List snapshot names of a VM's image clone, oldest first.
+    fn list_image_snapshots(&self, path: &str) -> Result<Vec<String>>;
+
+    /// Restore a VM's image clone to the given snapshot, which is kept.
+    ///
+    /// Snapshots taken after it do not survive a rollback (ZFS cannot
+    /// keep them), so while there are any the rollback is refused unless
+    /// `force` is set, and then they are deleted, on every backend.
+    ///
+    /// The VM must not be using the image while it is rolled back.
+    fn rollback_image(&self, path: &str, name: &str, force: bool) -> Result<()>;
+
+    /// Delete a single snapshot of a VM's image clone.
+    fn remove_image_snapshot(&self, path: &str, name: &str) -> Result<()>;
+
     /// Backend name for logging.
     fn name(&self) -> &'static str;
 }
@@ -49,13 +74,58 @@ pub fn create_store(kind: Storage) -> Box<dyn ImageStore> {
     }
 }
 
+/// The snapshots in `snaps` (oldest first) taken after `name`, or an
+/// error if there are any and the rollback is not forced.
+pub fn newer_snapshots(snaps: &[String], name: &str, force: bool) -> Result<Vec<String>> {
+    let pos = snaps
+        .iter()
+        .position(|s| s == name)
+        .ok_or_else(|| eg!("snapshot '{}' not found", name))?;
+    let newer = snaps[pos + 1..].to_vec();
+    if !newer.is_empty() && !force {
+        return Err(eg!(
+            "rolling back to '{}' would delete the newer snapshots {}; force it to do so",
+            name,
+            newer.join(", ")
+        ));
+    }
+    Ok(newer)
+}
+
+/// Hex-encoded SHA-256 of `data`, as used for image transfer checksums.
+pub fn sha256_hex(data: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    to_hex(&Sha256::digest(data))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn rollback_past_newer_snapshots_needs_force() {
+        let snaps: Vec<String> = ["a", "b", "c"].map(String::from).into();
+        assert!(newer_snapshots(&snaps, "c", false).unwrap().is_empty());
+        assert!(newer_snapshots(&snaps, "a", false).is_err());
+        assert_eq!(newer_snapshots(&snaps, "a", true).unwrap(), ["b", "c"]);
+        assert!(newer_snapshots(&snaps, "x", true).is_err());
+    }
+
     #[test]
     fn create_store_names() {
         assert_eq!(create_store(Storage::File).name(), "file");
         assert_eq!(create_store(Storage::Zvol).name(), "zvol");
     }
+
+    #[test]
+    fn sha256_hex_known_value() {
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
 }
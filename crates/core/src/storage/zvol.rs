@@ -7,9 +7,11 @@
 //! - **Snapshots**: create, list, destroy, rollback
 //! - **zfs send/recv**: full and incremental streams for backup and
 //!   cross-host migration
+//! - **Image distribution**: base images are pushed between hosts as
+//!   full or incremental streams (see [`ZvolStore::ensure_send_snapshot`])
 //! - **Property queries**: volsize, used, compressratio, etc.
 
-use super::ImageStore;
+use super::{ImageStore, newer_snapshots};
 use ruc::*;
 use std::io::{Read, Write};
 use std::process::{Command, Stdio};
@@ -82,6 +84,31 @@ impl ZvolStore {
         Ok(())
     }
 
+    /// List the short snapshot names of a dataset, oldest first.
+    pub fn snapshot_names(&self, dataset: &str) -> Result<Vec<String>> {
+        let out = zfs_cmd(&[
+            "list", "-H", "-o", "name", "-t", "snapshot", "-s", "creation", "-d", "1", dataset,
+        ])?;
+        Ok(out
+            .lines()
+            .filter_map(|l| l.split_once('@'))
+            .map(|(_, snap)| snap.to_string())
+            .collect())
+    }
+
+    /// Pick the snapshot to send a base image from.
+    ///
+    /// Uses the newest existing snapshot so repeated pushes line up with
+    /// what targets already received; creates `@ttsnap` if there is none.
+    /// Returns the short snapshot name.
+    pub fn ensure_send_snapshot(&self, dataset: &str) -> Result<String> {
+        if let Some(last) = self.snapshot_names(dataset)?.pop() {
+            return Ok(last);
+        }
+        Self::ensure_clone_snap(dataset)?;
+        Ok(CLONE_SNAP.to_string())
+    }
+
     /// Ensure the clone snapshot (`@ttsnap`) exists on `dataset`.
     fn ensure_clone_snap(dataset: &str) -> Result<()> {
         let snap = format!("{dataset}@{CLONE_SNAP}");
@@ -265,6 +292,39 @@ impl ImageStore for ZvolStore {
         "raw"
     }
 
+    fn disk_size(&self, clone_path: &str) -> Result<u64> {
+        zfs_cmd(&["get", "-Hp", "-o", "value", "volsize", clone_path])?
+            .parse()
+            .c(d!("invalid volsize of {}", clone_path))
+    }
+
+    fn create_disk(&self, clone_path: &str, bytes: u64) -> Result<()> {
+        zfs_cmd(&["create", "-s", "-V", &bytes.to_string(), clone_path])?;
+        Ok(())
+    }
+
+    fn snapshot_image(&self, path: &str, name: &str) -> Result<()> {
+        self.create_snapshot(path, name).map(|_| ())
+    }
+
+    fn list_image_snapshots(&self, path: &str) -> Result<Vec<String>> {
+        Ok(self
+            .snapshot_names(path)?
+            .into_iter()
+            .filter(|snap| snap != CLONE_SNAP)
+            .collect())
+    }
+
+    fn rollback_image(&self, path: &str, name: &str, force: bool) -> Result<()> {
+        // `zfs rollback -r` destroys the newer snapshots
+        newer_snapshots(&self.list_image_snapshots(path)?, name, force)?;
+        self.rollback(path, name)
+    }
+
+    fn remove_image_snapshot(&self, path: &str, name: &str) -> Result<()> {
+        self.destroy_snapshot(&format!("{path}@{name}"))
+    }
+
     fn name(&self) -> &'static str {
         "zvol"
     }
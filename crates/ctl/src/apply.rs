@@ -0,0 +1,405 @@
+//! Converging an existing environment to a declarative spec.
+//!
+//! A spec is a [`CreateEnvReq`] whose VMs are matched to the env's VMs
+//! by name. VMs missing from the env are created, VMs missing from the
+//! spec are destroyed, and VMs whose image, engine, size, ports, keys,
+//! cloud-init, anti-affinity group or node selector changed are
+//! replaced, since those are fixed at creation.
+
+use crate::auth::Caller;
+use crate::handler::{
+    CtlState, agent_client, create_vms, destroy_vms, fetch_host_images, name_specs, net_host_ids,
+    refresh_all_hosts, repeer_network, validate_specs, vm_ssh_keys,
+};
+use crate::{quota, scheduler};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use tracing::warn;
+use ttcore::api::*;
+use ttcore::model::*;
+use ttcore::net;
+
+/// Query parameters of `POST /api/envs/:id/apply`.
+#[derive(Debug, Default, Deserialize)]
+pub struct ApplyQuery {
+    /// Only compute the plan.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Whether a VM was created from `spec` (with env-level `env_keys`).
+pub fn vm_matches(vm: &Vm, spec: &VmSpec, env_keys: &[String]) -> bool {
+    // Port 22 is always forwarded
+    let mut ports: BTreeSet<u16> = spec.ports.iter().copied().collect();
+    ports.insert(22);
+
+    vm.image == spec.image
+        && vm.engine == spec.engine
+        && vm.cpu == spec.cpu.unwrap_or(VM_CPU_DEFAULT)
+        && vm.mem == spec.mem.unwrap_or(VM_MEM_DEFAULT)
+        && vm.disk == spec.disk.unwrap_or(VM_DISK_DEFAULT)
+        && vm.port_map.keys().copied().collect::<BTreeSet<_>>() == ports
+        && vm.deny_outgoing == spec.deny_outgoing
+        && same_keys(&vm.ssh_keys, &vm_ssh_keys(env_keys, spec))
+        && vm.user_data == spec.user_data
+        && vm.anti_affinity == spec.anti_affinity
+        && vm.node_selector == spec.node_selector
+}
+
+/// Whether two key lists hold the same keys, in any order.
+fn same_keys(a: &[String], b: &[String]) -> bool {
+    a.iter().collect::<BTreeSet<_>>() == b.iter().collect::<BTreeSet<_>>()
+}
+
+/// Diff an env's VMs against named specs.
+///
+/// VMs created without a name are matched by ID.
+pub fn plan(vms: &[Vm], specs: &[VmSpec], env_keys: &[String]) -> EnvPlan {
+    let mut plan = EnvPlan::default();
+    for spec in specs {
+        let name = spec.name.clone().unwrap_or_default();
+        match vms.iter().find(|vm| vm.name_or_id() == name) {
+            None => plan.create.push(name),
+            Some(vm) if vm_matches(vm, spec, env_keys) => plan.keep.push(name),
+            Some(_) => plan.replace.push(name),
+        }
+    }
+    for vm in vms {
+        if !specs
+            .iter()
+            .any(|s| s.name.as_deref() == Some(vm.name_or_id()))
+        {
+            plan.remove.push(vm.name_or_id().to_string());
+        }
+    }
+    plan
+}
+
+/// Resources held by `vms`, in quota units.
+fn held(vms: &[&Vm]) -> Usage {
+    let mut u = Usage {
+        vms: vms.len() as u32,
+        ..Default::default()
+    };
+    for vm in vms {
+        u.cpu += vm.cpu;
+        u.mem += vm.mem;
+        u.disk += vm.disk;
+    }
+    u
+}
+
+/// POST /api/envs/:id/apply — converge an env to a spec.
+///
+/// The body is the same as for `POST /api/envs`; its `owner` and
+/// `lifetime` are ignored, and `private_net` must match the env.
+/// `?dry_run=true` only returns the plan.
+pub async fn apply_env(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+    Query(query): Query<ApplyQuery>,
+    Json(mut req): Json<CreateEnvReq>,
+) -> impl IntoResponse {
+    if !req.id.is_empty() && req.id != id {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ApiResp::<ApplyResp>::err(format!(
+                "spec is for environment '{}', not '{id}'",
+                req.id
+            ))),
+        );
+    }
+    if let Err(e) = validate_specs(&req.vms) {
+        return (StatusCode::BAD_REQUEST, Json(ApiResp::<ApplyResp>::err(e)));
+    }
+    name_specs(&mut req.vms, &[]);
+
+    let (mut env, vms, hosts) = {
+        let db = state.lock_db();
+        let env = match db.get_env(&id) {
+            Ok(Some(e)) => e,
+            Ok(None) => {
+                return (
+                    StatusCode::NOT_FOUND,
+                    Json(ApiResp::<ApplyResp>::err(format!(
+                        "environment not found: {id}"
+                    ))),
+                );
+            }
+            Err(e) => {
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(ApiResp::<ApplyResp>::err(e.to_string())),
+                );
+            }
+        };
+        if let Err((code, msg)) = caller.require_owner(&env) {
+            return (code, Json(ApiResp::<ApplyResp>::err(msg)));
+        }
+        let vms = db.vms_by_env(&id).unwrap_or_default();
+        let hosts = db.list_hosts().unwrap_or_default();
+        (env, vms, hosts)
+    };
+
+    if req.private_net != env.network.is_some() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ApiResp::<ApplyResp>::err(
+                "private_net cannot be changed on an existing environment",
+            )),
+        );
+    }
+    let overlay_vms = req
+        .vms
+        .iter()
+        .filter(|s| s.engine != Engine::Docker)
+        .count();
+    if req.private_net && overlay_vms > net::OVERLAY_MAX_VMS as usize {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ApiResp::<ApplyResp>::err(format!(
+                "a private network holds at most {} VMs",
+                net::OVERLAY_MAX_VMS
+            ))),
+        );
+    }
+
+    let plan = plan(&vms, &req.vms, &req.ssh_keys);
+    if query.dry_run || plan.is_empty() {
+        let detail = (!query.dry_run).then(|| EnvDetail {
+            env,
+            vms,
+            warnings: vec![],
+        });
+        return (
+            StatusCode::OK,
+            Json(ApiResp::success(ApplyResp { plan, detail })),
+        );
+    }
+
+    let is_new = |name: &str| plan.create.iter().chain(&plan.replace).any(|n| n == name);
+    let new_specs: Vec<VmSpec> = req
+        .vms
+        .iter()
+        .filter(|s| is_new(s.name.as_deref().unwrap_or_default()))
+        .cloned()
+        .collect();
+    let (gone, kept): (Vec<&Vm>, Vec<&Vm>) = vms.iter().partition(|vm| {
+        let name = vm.name_or_id();
+        plan.remove.iter().any(|n| n == name) || plan.replace.iter().any(|n| n == name)
+    });
+    let freed = held(&gone);
+
+    if let Err((code, msg)) = quota::check_change(
+        &state.lock_db(),
+        &env.owner,
+        &Usage {
+            envs: 0,
+            ..quota::demand(&new_specs)
+        },
+        &freed,
+        None,
+    ) {
+        return (code, Json(ApiResp::<ApplyResp>::err(msg)));
+    }
+
+    // Schedule as if the outgoing VMs were already gone, so a replaced
+    // VM may land where it was
+    let mut shadow = hosts.clone();
+    for vm in &gone {
+        if let Some(h) = shadow.iter_mut().find(|h| h.id == vm.host_id) {
+            h.resource.cpu_used = h.resource.cpu_used.saturating_sub(vm.cpu);
+            h.resource.mem_used = h.resource.mem_used.saturating_sub(vm.mem);
+            h.resource.disk_used = h.resource.disk_used.saturating_sub(vm.disk);
+            h.resource.vm_count = h.resource.vm_count.saturating_sub(1);
+        }
+    }
+    let client = agent_client(state.api_key.as_deref(), 30);
+    let host_images = fetch_host_images(&hosts, &client).await;
+    let kept: Vec<Vm> = kept.into_iter().cloned().collect();
+    let placements = match scheduler::schedule_env(
+        &shadow,
+        &new_specs,
+        &host_images,
+        &*state.placement,
+        &kept,
+    ) {
+        Ok(p) => p,
+        Err(e) => {
+            return (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(ApiResp::<ApplyResp>::err(e.to_string())),
+            );
+        }
+    };
+
+    destroy_vms(&client, &hosts, gone.iter().copied()).await;
+    {
+        let db = state.lock_db();
+        for vm in &gone {
+            let _ = db.remove_vm(&vm.id);
+        }
+        env.vm_ids = kept.iter().map(|vm| vm.id.clone()).collect();
+        let _ = db.put_env(&env);
+    }
+
+    // Re-peer the env network over the hosts it spans after the change
+    let mut warnings = Vec::new();
+    if let Some(n) = &env.network {
+        let mut after = net_host_ids(&kept);
+        after.extend(
+            placements
+                .iter()
+                .filter(|(s, _)| s.engine != Engine::Docker)
+                .map(|(_, p)| p.host_id.clone()),
+        );
+        warnings.extend(repeer_network(&client, n.vni, &hosts, &net_host_ids(&vms), &after).await);
+    }
+
+    let (created, failures) = create_vms(
+        &client,
+        &id,
+        &req.ssh_keys,
+        env.network.as_ref(),
+        &kept,
+        &placements,
+    )
+    .await;
+    if !failures.is_empty() {
+        warn!(
+            "applying env '{id}': {}/{} VMs failed: {}",
+            failures.len(),
+            placements.len(),
+            failures.join("; ")
+        );
+    }
+    warnings.extend(failures);
+
+    let mut all = kept;
+    all.extend(created);
+    env.vm_ids = all.iter().map(|vm| vm.id.clone()).collect();
+    {
+        let db = state.lock_db();
+        let _ = db.put_env(&env);
+        for vm in &all {
+            let _ = db.put_vm(vm);
+        }
+    }
+
+    refresh_all_hosts(&state, &client).await;
+
+    let detail = EnvDetail {
+        env,
+        vms: all,
+        warnings,
+    };
+    (
+        StatusCode::OK,
+        Json(ApiResp::success(ApplyResp {
+            plan,
+            detail: Some(detail),
+        })),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn spec(name: &str, image: &str) -> VmSpec {
+        VmSpec {
+            image: image.into(),
+            engine: Engine::Qemu,
+            cpu: None,
+            mem: None,
+            disk: None,
+            ports: vec![80],
+            deny_outgoing: false,
+            ssh_keys: vec![],
+            name: Some(name.into()),
+            user_data: None,
+            anti_affinity: None,
+            node_selector: Default::default(),
+        }
+    }
+
+    fn vm(id: &str, name: &str, image: &str, keys: &[&str]) -> Vm {
+        Vm {
+            id: id.into(),
+            env_id: "e1".into(),
+            host_id: "h1".into(),
+            image: image.into(),
+            cpu: VM_CPU_DEFAULT,
+            mem: VM_MEM_DEFAULT,
+            disk: VM_DISK_DEFAULT,
+            ip: "10.10.0.2".into(),
+            port_map: BTreeMap::from([(22, 20000), (80, 20001)]),
+            state: VmState::Running,
+            name: name.into(),
+            ssh_keys: keys.iter().map(|k| k.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn matching_vm_is_kept() {
+        let keys = vec!["ssh-ed25519 A".to_string()];
+        let v = vm("1", "web", "alpine", &["ssh-ed25519 A"]);
+        assert!(vm_matches(&v, &spec("web", "alpine"), &keys));
+
+        // Anything fixed at creation forces a replacement
+        let mut s = spec("web", "alpine");
+        s.ports.push(443);
+        assert!(!vm_matches(&v, &s, &keys));
+        let mut s = spec("web", "alpine");
+        s.mem = Some(VM_MEM_DEFAULT * 2);
+        assert!(!vm_matches(&v, &s, &keys));
+        let mut s = spec("web", "alpine");
+        s.user_data = Some("#!/bin/sh\n".into());
+        assert!(!vm_matches(&v, &s, &keys));
+        let mut s = spec("web", "alpine");
+        s.anti_affinity = Some("web".into());
+        assert!(!vm_matches(&v, &s, &keys));
+        let mut s = spec("web", "alpine");
+        s.node_selector.insert("disk".into(), "nvme".into());
+        assert!(!vm_matches(&v, &s, &keys));
+        assert!(!vm_matches(&v, &spec("web", "alpine"), &[]));
+    }
+
+    #[test]
+    fn plan_diffs_by_name() {
+        let vms = vec![
+            vm("1", "web", "alpine", &[]),
+            vm("2", "db", "alpine", &[]),
+            vm("3", "old", "alpine", &[]),
+        ];
+        let specs = vec![
+            spec("web", "alpine"),
+            spec("db", "debian"),
+            spec("cache", "alpine"),
+        ];
+        let p = plan(&vms, &specs, &[]);
+        assert_eq!(p.keep, vec!["web"]);
+        assert_eq!(p.replace, vec!["db"]);
+        assert_eq!(p.create, vec!["cache"]);
+        assert_eq!(p.remove, vec!["old"]);
+        assert!(!p.is_empty());
+
+        assert!(plan(&vms[..1], &specs[..1], &[]).is_empty());
+    }
+
+    #[test]
+    fn unnamed_vms_match_by_id() {
+        let vms = vec![vm("abc123", "", "alpine", &[])];
+        let p = plan(&vms, &[spec("abc123", "alpine")], &[]);
+        assert_eq!(p.keep, vec!["abc123"]);
+        let p = plan(&vms, &[spec("web", "alpine")], &[]);
+        assert_eq!(p.remove, vec!["abc123"]);
+    }
+}
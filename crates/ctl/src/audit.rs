@@ -0,0 +1,307 @@
+//! Audit log of mutating API calls.
+//!
+//! Every POST, PUT and DELETE that gets past authentication is recorded
+//! by [`record`]: who made it (user and token) and from where, what it
+//! acted on, a summary of the request and how it ended. Agents'
+//! heartbeats are left out. Entries are only ever appended, and dropped
+//! once older than `--audit-retention-days`.
+
+use crate::auth::Caller;
+use crate::db::{AuditFilter, Db};
+use crate::handler::{CtlState, now};
+use crate::trace;
+use axum::body::{Body, to_bytes};
+use axum::extract::{ConnectInfo, Query, State};
+use axum::http::{Method, Request, StatusCode, header};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use tracing::warn;
+use ttcore::api::{ApiResp, ApiRespEmpty};
+use ttcore::model::AuditEntry;
+
+/// Largest request body accepted, as for axum's `Json` extractor.
+const BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+/// Longest request summary or error kept, in characters.
+const SUMMARY_LEN: usize = 512;
+
+/// Entries listed when the query sets no `limit`.
+const DEFAULT_LIMIT: usize = 100;
+
+/// Content type of the JSON lines export.
+const JSONL: &str = "application/x-ndjson";
+
+/// Middleware that records mutating calls in the audit log.
+///
+/// Runs inside [`crate::auth::authenticate`], so calls without valid
+/// credentials are turned away before they get here.
+pub async fn record(State(state): State<CtlState>, req: Request<Body>, next: Next) -> Response {
+    if !matches!(
+        *req.method(),
+        Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+    ) || is_heartbeat(req.uri().path())
+    {
+        return next.run(req).await;
+    }
+
+    let (parts, body) = req.into_parts();
+    let caller = parts.extensions.get::<Caller>().cloned();
+    let source = parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|c| c.0.ip().to_string())
+        .unwrap_or_default();
+    let endpoint = parts
+        .uri
+        .path_and_query()
+        .map_or_else(|| parts.uri.path().to_string(), |p| p.to_string());
+    let mut entry = AuditEntry {
+        id: 0,
+        at: now(),
+        user: caller.as_ref().map(|c| c.user.clone()).unwrap_or_default(),
+        token: caller.and_then(|c| c.token),
+        source,
+        method: parts.method.to_string(),
+        endpoint,
+        env: None,
+        vm: None,
+        host: None,
+        summary: String::new(),
+        status: 0,
+        error: None,
+        request_id: trace::request_id(),
+    };
+
+    let resp = match to_bytes(body, BODY_LIMIT).await {
+        Ok(bytes) => {
+            // Look the VM up now: it may be gone once the call is done
+            (entry.env, entry.vm, entry.host) = targets(&state.lock_db(), parts.uri.path(), &bytes);
+            entry.summary = summarize(&bytes);
+            next.run(Request::from_parts(parts, Body::from(bytes)))
+                .await
+        }
+        Err(e) => (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            Json(ApiRespEmpty::err(format!(
+                "failed to read request body: {e}"
+            ))),
+        )
+            .into_response(),
+    };
+
+    entry.status = resp.status().as_u16();
+    let resp = if resp.status().is_success() {
+        resp
+    } else {
+        let (parts, body) = resp.into_parts();
+        let bytes = to_bytes(body, BODY_LIMIT).await.unwrap_or_default();
+        entry.error = Some(error_message(&bytes));
+        Response::from_parts(parts, Body::from(bytes))
+    };
+
+    if let Err(e) = state.lock_db().add_audit(&entry) {
+        warn!(
+            "failed to record {} {} in the audit log: {e}",
+            entry.method, entry.endpoint
+        );
+    }
+    resp
+}
+
+/// Whether `path` is where agents send heartbeats, several a minute.
+fn is_heartbeat(path: &str) -> bool {
+    path.starts_with("/api/hosts/") && path.ends_with("/heartbeat")
+}
+
+/// The env, VM and host a call to `path` acts on, as far as the path,
+/// the body and the fleet tell.
+fn targets(db: &Db, path: &str, body: &[u8]) -> (Option<String>, Option<String>, Option<String>) {
+    let segments: Vec<&str> = match path.strip_prefix("/api/") {
+        Some(p) => p.split('/').collect(),
+        None => return (None, None, None),
+    };
+    match segments.as_slice() {
+        ["envs"] => (body_field(body, "id"), None, None),
+        ["envs", env, ..] => (Some(env.to_string()), None, None),
+        ["vms", vm, ..] => match db.get_vm(vm) {
+            Ok(Some(v)) => (Some(v.env_id), Some(v.id), Some(v.host_id)),
+            _ => (None, Some(vm.to_string()), None),
+        },
+        ["hosts", host, ..] => (None, None, Some(host.to_string())),
+        _ => (None, None, None),
+    }
+}
+
+fn body_field(body: &[u8], field: &str) -> Option<String> {
+    let v: serde_json::Value = serde_json::from_slice(body).ok()?;
+    v.get(field)?.as_str().map(str::to_string)
+}
+
+/// A request body as kept in the log: compact JSON, cut short.
+fn summarize(body: &[u8]) -> String {
+    if body.is_empty() {
+        return String::new();
+    }
+    let text = match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(v) => v.to_string(),
+        Err(_) => String::from_utf8_lossy(body).into_owned(),
+    };
+    shorten(text.trim())
+}
+
+/// The error of a failed call: the envelope's message, or the body as
+/// is for responses that are not an envelope (e.g. axum rejections).
+fn error_message(body: &[u8]) -> String {
+    serde_json::from_slice::<ApiRespEmpty>(body)
+        .ok()
+        .and_then(|r| r.error)
+        .unwrap_or_else(|| shorten(String::from_utf8_lossy(body).trim()))
+}
+
+fn shorten(s: &str) -> String {
+    match s.char_indices().nth(SUMMARY_LEN) {
+        Some((i, _)) => format!("{}…", &s[..i]),
+        None => s.to_string(),
+    }
+}
+
+/// Drop entries older than `days`; 0 keeps them forever.
+pub fn prune(db: &Db, days: u64) {
+    if days == 0 {
+        return;
+    }
+    if let Err(e) = db.prune_audit(now().saturating_sub(days * 86400)) {
+        warn!("failed to prune the audit log: {e}");
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    user: Option<String>,
+    env: Option<String>,
+    vm: Option<String>,
+    host: Option<String>,
+    /// Only entries recorded at or after this Unix time.
+    #[serde(default)]
+    since: u64,
+    /// Only entries recorded at or before this Unix time.
+    #[serde(default)]
+    until: u64,
+    /// At most this many entries; 0 for all.
+    limit: Option<usize>,
+    /// `jsonl` to export the entries as JSON lines, oldest first.
+    format: Option<String>,
+}
+
+/// GET /api/audit — the caller's calls (everyone's for admins), newest
+/// first.
+pub async fn list_audit(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Query(q): Query<AuditQuery>,
+) -> Response {
+    let jsonl = match q.format.as_deref() {
+        None | Some("json") => false,
+        Some("jsonl") => true,
+        Some(f) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(ApiResp::<Vec<AuditEntry>>::err(format!(
+                    "unknown format: {f} (expected json or jsonl)"
+                ))),
+            )
+                .into_response();
+        }
+    };
+    let filter = AuditFilter {
+        user: if caller.is_admin() {
+            q.user
+        } else {
+            Some(caller.user.clone())
+        },
+        env: q.env,
+        vm: q.vm,
+        host: q.host,
+        since: q.since,
+        until: q.until,
+        // An export is complete unless asked otherwise
+        limit: q.limit.unwrap_or(if jsonl { 0 } else { DEFAULT_LIMIT }),
+        oldest_first: jsonl,
+    };
+
+    let entries = match state.lock_db().list_audit(&filter) {
+        Ok(entries) => entries,
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiResp::<Vec<AuditEntry>>::err(e.to_string())),
+            )
+                .into_response();
+        }
+    };
+    if !jsonl {
+        return (StatusCode::OK, Json(ApiResp::success(entries))).into_response();
+    }
+
+    let mut out = String::new();
+    for entry in &entries {
+        if let Ok(line) = serde_json::to_string(entry) {
+            out.push_str(&line);
+            out.push('\n');
+        }
+    }
+    ([(header::CONTENT_TYPE, JSONL)], out).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn targets_come_from_the_path_or_the_body() {
+        let db = Db::open(":memory:").unwrap();
+        let create = br#"{"id":"demo","vms":[]}"#;
+        assert_eq!(
+            targets(&db, "/api/envs", create),
+            (Some("demo".into()), None, None)
+        );
+        assert_eq!(
+            targets(&db, "/api/envs/demo/extend", b""),
+            (Some("demo".into()), None, None)
+        );
+        // A VM the controller does not know keeps only its ID
+        assert_eq!(
+            targets(&db, "/api/vms/abc/stop", b""),
+            (None, Some("abc".into()), None)
+        );
+        assert_eq!(
+            targets(&db, "/api/hosts/h1/drain", b""),
+            (None, None, Some("h1".into()))
+        );
+        assert_eq!(targets(&db, "/api/users", b"{}"), (None, None, None));
+    }
+
+    #[test]
+    fn summaries_and_errors_are_compact_and_bounded() {
+        assert_eq!(summarize(b""), "");
+        assert_eq!(
+            summarize(b"{ \"lifetime\" : 3600 }\n"),
+            r#"{"lifetime":3600}"#
+        );
+        let long = summarize(format!("\"{}\"", "x".repeat(2000)).as_bytes());
+        assert_eq!(long.chars().count(), SUMMARY_LEN + 1);
+        assert!(long.ends_with('…'));
+
+        assert_eq!(
+            error_message(br#"{"ok":false,"error":"quota exceeded"}"#),
+            "quota exceeded"
+        );
+        assert_eq!(
+            error_message(b"Failed to parse the request body as JSON"),
+            "Failed to parse the request body as JSON"
+        );
+    }
+}
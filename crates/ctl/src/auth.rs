@@ -1,47 +1,255 @@
-//! API key authentication middleware.
+//! Request authentication and the caller identity it yields.
+//!
+//! A request is authenticated either by the controller's bootstrap key
+//! (`--api-key`), which acts as an admin, or by a per-user API token.
+//! Without `--api-key` the controller runs open: requests without a
+//! token are treated as coming from an admin.
+//!
+//! Agents send their heartbeats with a key of their own
+//! ([`ttcore::auth::host_key`]), good for nothing but the heartbeats of
+//! their host.
 
+use crate::handler::CtlState;
 use axum::body::Body;
+use axum::extract::{Query, State};
 use axum::http::{Request, StatusCode};
 use axum::middleware::Next;
 use axum::response::{IntoResponse, Response};
+use std::collections::HashMap;
+use ttcore::api::ApiRespEmpty;
+use ttcore::model::{Env, Role};
+use ttcore::storage::sha256_hex;
 
-/// Create an auth middleware function that validates Bearer tokens.
+/// User name recorded for requests made with the bootstrap key (or with
+/// no key on an open controller).
+pub const BOOTSTRAP_USER: &str = "admin";
+
+/// The authenticated user behind a request.
+///
+/// Inserted as a request extension by [`authenticate`]; handlers take it
+/// with `Extension<Caller>`.
+#[derive(Debug, Clone)]
+pub struct Caller {
+    pub user: String,
+    pub role: Role,
+    /// ID of the API token the request was made with; none for the
+    /// bootstrap key.
+    pub token: Option<String>,
+    /// Host whose key the request was made with, if any.
+    pub host: Option<String>,
+}
+
+impl Caller {
+    fn bootstrap() -> Self {
+        Self {
+            user: BOOTSTRAP_USER.to_string(),
+            role: Role::Admin,
+            token: None,
+            host: None,
+        }
+    }
+
+    /// A host's agent, authenticated by its host key.
+    fn host(id: &str) -> Self {
+        Self {
+            user: format!("host:{id}"),
+            role: Role::User,
+            token: None,
+            host: Some(id.to_string()),
+        }
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.role == Role::Admin
+    }
+
+    /// Whether the caller may modify (stop, delete, ...) an environment.
+    pub fn can_manage(&self, env: &Env) -> bool {
+        self.is_admin() || env.owner == self.user
+    }
+
+    /// Fail with 403 unless the caller is an admin.
+    pub fn require_admin(&self) -> std::result::Result<(), (StatusCode, String)> {
+        if self.is_admin() {
+            Ok(())
+        } else {
+            Err((
+                StatusCode::FORBIDDEN,
+                format!("permission denied: {} is not an admin", self.user),
+            ))
+        }
+    }
+
+    /// Fail with 403 unless the caller is an admin or the agent of host
+    /// `id`.
+    pub fn require_host(&self, id: &str) -> std::result::Result<(), (StatusCode, String)> {
+        if self.is_admin() || self.host.as_deref() == Some(id) {
+            Ok(())
+        } else {
+            Err((
+                StatusCode::FORBIDDEN,
+                format!("permission denied: {} may not act as host {id}", self.user),
+            ))
+        }
+    }
+
+    /// Fail with 403 unless the caller may modify the environment.
+    pub fn require_owner(&self, env: &Env) -> std::result::Result<(), (StatusCode, String)> {
+        if self.can_manage(env) {
+            Ok(())
+        } else {
+            Err((
+                StatusCode::FORBIDDEN,
+                format!(
+                    "permission denied: environment '{}' belongs to {}",
+                    env.id, env.owner
+                ),
+            ))
+        }
+    }
+}
+
+/// Middleware that resolves the Bearer token to a [`Caller`].
 ///
-/// Returns a closure suitable for `axum::middleware::from_fn`.
-pub fn make_auth_layer(
-    expected_key: String,
-) -> impl Fn(
-    Request<Body>,
-    Next,
-) -> std::pin::Pin<Box<dyn std::future::Future<Output = Response> + Send>>
-+ Clone
-+ Send
-+ Sync
-+ 'static {
-    move |req: Request<Body>, next: Next| {
-        let expected = expected_key.clone();
-        Box::pin(async move {
-            let auth_header = req
-                .headers()
-                .get("authorization")
-                .and_then(|v| v.to_str().ok());
-
-            match auth_header {
-                Some(value)
-                    if value
-                        .strip_prefix("Bearer ")
-                        .is_some_and(|t| ttcore::auth::constant_time_eq(t, &expected)) =>
-                {
-                    next.run(req).await
-                }
-                _ => (
-                    StatusCode::UNAUTHORIZED,
-                    axum::Json(ttcore::api::ApiResp::<()>::err(
-                        "invalid or missing API key",
-                    )),
-                )
-                    .into_response(),
-            }
-        })
+/// WebSocket upgrades and event streams may pass the token as an
+/// `access_token` query parameter instead, since browsers cannot set
+/// headers on them.
+pub async fn authenticate(
+    State(state): State<CtlState>,
+    mut req: Request<Body>,
+    next: Next,
+) -> Response {
+    let token = req
+        .headers()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::to_string)
+        .or_else(|| query_token(&req));
+
+    let caller = match token {
+        Some(t) => resolve(&state, &t, req.uri().path()),
+        None if state.api_key.is_none() => Some(Caller::bootstrap()),
+        None => None,
+    };
+
+    match caller {
+        Some(c) => {
+            req.extensions_mut().insert(c);
+            next.run(req).await
+        }
+        None => (
+            StatusCode::UNAUTHORIZED,
+            axum::Json(ApiRespEmpty::err("invalid or missing API key")),
+        )
+            .into_response(),
+    }
+}
+
+/// Look up who a token sent to `path` belongs to.
+fn resolve(state: &CtlState, token: &str, path: &str) -> Option<Caller> {
+    if let Some(key) = &state.api_key {
+        if ttcore::auth::constant_time_eq(token, key) {
+            return Some(Caller::bootstrap());
+        }
+        // Host keys are only good for their own heartbeats
+        if let Some(id) = heartbeat_host(path)
+            && ttcore::auth::constant_time_eq(token, &ttcore::auth::host_key(key, id))
+        {
+            return Some(Caller::host(id));
+        }
+    }
+
+    // Tokens are stored hashed, so the lookup itself leaks nothing
+    let db = state.lock_db();
+    let tok = db.token_by_hash(&sha256_hex(token.as_bytes())).ok()??;
+    let user = db.get_user(&tok.user).ok()??;
+    Some(Caller {
+        user: user.name,
+        role: user.role,
+        token: Some(tok.id),
+        host: None,
+    })
+}
+
+/// ID of the host whose heartbeats `path` receives, if it does.
+fn heartbeat_host(path: &str) -> Option<&str> {
+    path.strip_prefix("/api/hosts/")?
+        .strip_suffix("/heartbeat")
+        .filter(|id| !id.is_empty() && !id.contains('/'))
+}
+
+/// The `access_token` query parameter of a WebSocket upgrade or
+/// event stream request.
+fn query_token(req: &Request<Body>) -> Option<String> {
+    let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+    let is_upgrade = header("upgrade").is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+    let is_stream = header("accept").is_some_and(|v| v.contains("text/event-stream"));
+    if !is_upgrade && !is_stream {
+        return None;
+    }
+    Query::<HashMap<String, String>>::try_from_uri(req.uri())
+        .ok()?
+        .0
+        .remove("access_token")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ttcore::model::EnvState;
+
+    fn env_owned_by(owner: &str) -> Env {
+        Env {
+            id: "e1".into(),
+            owner: owner.into(),
+            vm_ids: vec![],
+            created_at: 0,
+            expires_at: 0,
+            state: EnvState::Active,
+            network: None,
+            reserved: None,
+        }
+    }
+
+    fn caller(user: &str, role: Role) -> Caller {
+        Caller {
+            user: user.into(),
+            role,
+            token: None,
+            host: None,
+        }
+    }
+
+    #[test]
+    fn owner_and_admin_can_manage() {
+        let env = env_owned_by("alice");
+        assert!(caller("alice", Role::User).can_manage(&env));
+        assert!(caller("root", Role::Admin).can_manage(&env));
+        assert!(!caller("bob", Role::User).can_manage(&env));
+
+        let (code, _) = caller("bob", Role::User).require_owner(&env).unwrap_err();
+        assert_eq!(code, StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn only_admins_pass_require_admin() {
+        assert!(caller("root", Role::Admin).require_admin().is_ok());
+        assert!(caller("alice", Role::User).require_admin().is_err());
+    }
+
+    #[test]
+    fn host_keys_only_act_for_their_host() {
+        assert_eq!(heartbeat_host("/api/hosts/h1/heartbeat"), Some("h1"));
+        assert_eq!(heartbeat_host("/api/hosts/h1/drain"), None);
+        assert_eq!(heartbeat_host("/api/hosts/a/b/heartbeat"), None);
+        assert_eq!(heartbeat_host("/api/envs"), None);
+
+        let host = Caller::host("h1");
+        assert!(!host.is_admin());
+        assert!(host.require_host("h1").is_ok());
+        assert!(host.require_host("h2").is_err());
+        assert!(caller("root", Role::Admin).require_host("h2").is_ok());
+        assert!(caller("alice", Role::User).require_host("h1").is_err());
     }
 }
@@ -1,6 +1,8 @@
 //! Controller configuration.
 
-use clap::Parser;
+use crate::scheduler::{BestFit, PlacementPolicy, Spread, Weighted};
+use clap::{Parser, ValueEnum};
+use ttcore::log::{DEFAULT_FILTER, LogFormat};
 
 /// TTstack central controller — fleet management and VM scheduling.
 #[derive(Parser, Debug)]
@@ -14,8 +16,64 @@ pub struct Config {
     #[arg(long, default_value = "/home/ttstack/ctl")]
     pub data_dir: String,
 
+    /// Directory holding the files the web dashboard does not build in:
+    /// `xterm.js` and `xterm.css` for the VM console.
+    #[arg(long, default_value = "/opt/ttstack/web")]
+    pub web_dir: String,
+
     /// API key for authentication. If set, all API requests must include
     /// `Authorization: Bearer <key>`. Can also be provided via TT_API_KEY env var.
     #[arg(long, env = "TT_API_KEY")]
     pub api_key: Option<String>,
+
+    /// How to pick among the hosts that can run a VM.
+    #[arg(long, value_enum, default_value_t = Placement::BestFit)]
+    pub placement: Placement,
+
+    /// Weight of CPU against memory (weight 1) for `--placement weighted`.
+    #[arg(long, default_value_t = 1.0)]
+    pub cpu_weight: f64,
+
+    /// Days to keep audit log entries; 0 keeps them forever.
+    #[arg(long, default_value_t = 90)]
+    pub audit_retention_days: u64,
+
+    /// Heartbeats a host started with `--controller` may miss in a row
+    /// before it is marked offline.
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u64).range(1..))]
+    pub heartbeat_misses: u64,
+
+    /// Log output: `pretty` (readable lines) or `json`.
+    #[arg(long, env = "TT_LOG_FORMAT", default_value_t = LogFormat::Pretty)]
+    pub log_format: LogFormat,
+
+    /// Log filter in `RUST_LOG` syntax, e.g. `info` or `warn,tt_ctl=debug`;
+    /// it can be changed while running through `/api/log-level`.
+    #[arg(long, env = "TT_LOG", default_value = DEFAULT_FILTER)]
+    pub log_level: String,
+}
+
+/// Built-in placement policies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Placement {
+    /// Fill the host with the least free memory first.
+    BestFit,
+    /// Use the host with the most free memory (worst-fit).
+    Spread,
+    /// Fill hosts by their weighted share of used CPU and memory.
+    Weighted,
+}
+
+impl Config {
+    /// The placement policy selected by `--placement`.
+    pub fn placement_policy(&self) -> Box<dyn PlacementPolicy> {
+        match self.placement {
+            Placement::BestFit => Box::new(BestFit),
+            Placement::Spread => Box::new(Spread),
+            Placement::Weighted => Box::new(Weighted {
+                cpu: self.cpu_weight,
+                mem: 1.0,
+            }),
+        }
+    }
 }
@@ -0,0 +1,128 @@
+//! WebSocket proxy for VM consoles and VNC displays.
+//!
+//! The controller first connects to the agent's WebSocket, so problems
+//! such as a stopped VM come back to the client as ordinary JSON errors,
+//! and only then upgrades the client connection and relays frames
+//! between the two sockets unchanged.
+
+use crate::auth::Caller;
+use crate::handler::{CtlState, locate_vm};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::{self, Message as AgentMessage};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use ttcore::api::*;
+
+type AgentSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// GET /api/vms/:id/console — attach to a VM's console on its host.
+pub async fn console(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    match connect(&state, &caller, &id, "console").await {
+        Ok(agent) => ws.on_upgrade(move |socket| relay(socket, agent)),
+        Err((code, msg)) => fail(code, msg),
+    }
+}
+
+/// GET /api/vms/:id/vnc — relay a VM's VNC display from its host.
+pub async fn vnc(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    match connect(&state, &caller, &id, "vnc").await {
+        Ok(agent) => ws
+            .protocols(["binary"])
+            .on_upgrade(move |socket| relay(socket, agent)),
+        Err((code, msg)) => fail(code, msg),
+    }
+}
+
+/// Open the agent side of the proxy for `/api/vms/{id}/{kind}`.
+async fn connect(
+    state: &CtlState,
+    caller: &Caller,
+    id: &str,
+    kind: &str,
+) -> std::result::Result<AgentSocket, (StatusCode, String)> {
+    let (_, host) = locate_vm(state, caller, id)?;
+    let url = format!("ws://{}/api/vms/{id}/{kind}", host.addr);
+    let mut req = url
+        .into_client_request()
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if let Some(key) = &state.api_key
+        && let Ok(val) = HeaderValue::from_str(&format!("Bearer {key}"))
+    {
+        req.headers_mut().insert("authorization", val);
+    }
+
+    match tokio_tungstenite::connect_async(req).await {
+        Ok((socket, _)) => Ok(socket),
+        // The agent refused the upgrade; pass its error through
+        Err(tungstenite::Error::Http(resp)) => {
+            let status =
+                StatusCode::from_u16(resp.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+            let msg = resp
+                .body()
+                .as_deref()
+                .and_then(|b| serde_json::from_slice::<ApiRespEmpty>(b).ok())
+                .and_then(|r| r.error)
+                .unwrap_or_else(|| format!("agent {} returned {status}", host.addr));
+            Err((status, msg))
+        }
+        Err(e) => Err((
+            StatusCode::BAD_GATEWAY,
+            format!("failed to reach agent {}: {e}", host.addr),
+        )),
+    }
+}
+
+/// Forward frames between the client and the agent until either closes.
+async fn relay(client: WebSocket, agent: AgentSocket) {
+    let (mut client_tx, mut client_rx) = client.split();
+    let (mut agent_tx, mut agent_rx) = agent.split();
+    loop {
+        tokio::select! {
+            msg = client_rx.next() => {
+                let msg = match msg {
+                    Some(Ok(Message::Binary(data))) => AgentMessage::Binary(data),
+                    Some(Ok(Message::Text(text))) => AgentMessage::text(text.as_str()),
+                    Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                    _ => break,
+                };
+                if agent_tx.send(msg).await.is_err() {
+                    break;
+                }
+            }
+            msg = agent_rx.next() => {
+                let msg = match msg {
+                    Some(Ok(AgentMessage::Binary(data))) => Message::Binary(data),
+                    Some(Ok(AgentMessage::Text(text))) => Message::Text(text.as_str().into()),
+                    Some(Ok(AgentMessage::Ping(_) | AgentMessage::Pong(_))) => continue,
+                    _ => break,
+                };
+                if client_tx.send(msg).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    let _ = client_tx.send(Message::Close(None)).await;
+    let _ = agent_tx.send(AgentMessage::Close(None)).await;
+}
+
+fn fail(code: StatusCode, msg: impl ToString) -> Response {
+    (code, Json(ApiRespEmpty::err(msg.to_string()))).into_response()
+}
@@ -1,15 +1,16 @@
 //! Persistent state management backed by SQLite.
 //!
-//! All fleet state (hosts, environments, VMs) is stored in a single
-//! SQLite database. Data survives controller restarts.
+//! All fleet state (hosts, environments, VMs) and user accounts are
+//! stored in a single SQLite database. Data survives controller restarts.
 
 use ruc::*;
 use rusqlite::Connection;
+use tracing::info;
 use ttcore::api::FleetStatus;
 use ttcore::model::*;
 
 /// Current schema version. Bump this when schema changes.
-const SCHEMA_VERSION: u32 = 1;
+const SCHEMA_VERSION: u32 = 6;
 
 /// Fleet database — the single source of truth for the controller.
 pub struct Db {
@@ -80,13 +81,86 @@ impl Db {
             .c(d!("migration v1"))?;
         }
 
+        if current < 2 {
+            // v1 → v2: user accounts and API tokens
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS users (
+                     name TEXT PRIMARY KEY,
+                     data TEXT NOT NULL
+                 );
+                 CREATE TABLE IF NOT EXISTS tokens (
+                     id   TEXT PRIMARY KEY,
+                     user TEXT NOT NULL,
+                     hash TEXT NOT NULL UNIQUE,
+                     data TEXT NOT NULL
+                 );
+                 CREATE INDEX IF NOT EXISTS idx_tokens_user ON tokens(user);",
+            )
+            .c(d!("migration v2"))?;
+        }
+
+        if current < 3 {
+            // v2 → v3: per-user and per-group quotas
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS quotas (
+                     subject TEXT PRIMARY KEY,
+                     data    TEXT NOT NULL
+                 );",
+            )
+            .c(d!("migration v3"))?;
+        }
+
+        if current < 4 {
+            // v3 → v4: environment event log
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS events (
+                     id     INTEGER PRIMARY KEY AUTOINCREMENT,
+                     env_id TEXT NOT NULL,
+                     data   TEXT NOT NULL
+                 );
+                 CREATE INDEX IF NOT EXISTS idx_events_env ON events(env_id);",
+            )
+            .c(d!("migration v4"))?;
+        }
+
+        if current < 5 {
+            // v4 → v5: background jobs
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS jobs (
+                     id         TEXT PRIMARY KEY,
+                     created_at INTEGER NOT NULL,
+                     data       TEXT NOT NULL
+                 );
+                 CREATE INDEX IF NOT EXISTS idx_jobs_created ON jobs(created_at);",
+            )
+            .c(d!("migration v5"))?;
+        }
+
+        if current < 6 {
+            // v5 → v6: audit log of mutating API calls
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS audit (
+                     id   INTEGER PRIMARY KEY AUTOINCREMENT,
+                     at   INTEGER NOT NULL,
+                     user TEXT NOT NULL,
+                     env  TEXT,
+                     vm   TEXT,
+                     host TEXT,
+                     data TEXT NOT NULL
+                 );
+                 CREATE INDEX IF NOT EXISTS idx_audit_at  ON audit(at);
+                 CREATE INDEX IF NOT EXISTS idx_audit_env ON audit(env);",
+            )
+            .c(d!("migration v6"))?;
+        }
+
         // Future migrations go here:
-        // if current < 2 { ... }
+        // if current < 7 { ... }
 
         Self::set_schema_version(conn, SCHEMA_VERSION)?;
 
         if current < SCHEMA_VERSION {
-            eprintln!("database migrated: v{current} → v{SCHEMA_VERSION}");
+            info!("database migrated: v{current} → v{SCHEMA_VERSION}");
         }
 
         Ok(())
@@ -169,10 +243,14 @@ impl Db {
         query_one(&self.conn, "SELECT data FROM envs WHERE id = ?1", [id])
     }
 
+    /// Remove an env along with its events.
     pub fn remove_env(&self, id: &str) -> Result<()> {
         self.conn
             .execute("DELETE FROM envs WHERE id = ?1", [id])
             .c(d!("remove env"))?;
+        self.conn
+            .execute("DELETE FROM events WHERE env_id = ?1", [id])
+            .c(d!("remove env events"))?;
         Ok(())
     }
 
@@ -188,6 +266,141 @@ impl Db {
         Ok(count as usize)
     }
 
+    // ── Env Events ──────────────────────────────────────────────────
+
+    pub fn add_event(&self, event: &EnvEvent) -> Result<()> {
+        let data = serde_json::to_string(event).c(d!("serialize event"))?;
+        self.conn
+            .execute(
+                "INSERT INTO events (env_id, data) VALUES (?1, ?2)",
+                rusqlite::params![event.env_id, data],
+            )
+            .c(d!("add event"))?;
+        Ok(())
+    }
+
+    /// Events of an env, oldest first.
+    pub fn events_by_env(&self, env_id: &str) -> Result<Vec<EnvEvent>> {
+        query_all(
+            &self.conn,
+            "SELECT data FROM events WHERE env_id = ?1 ORDER BY id",
+            [env_id],
+        )
+    }
+
+    // ── Jobs ────────────────────────────────────────────────────────
+
+    pub fn put_job(&self, job: &Job) -> Result<()> {
+        let data = serde_json::to_string(job).c(d!("serialize job"))?;
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO jobs (id, created_at, data) VALUES (?1, ?2, ?3)",
+                rusqlite::params![job.id, job.created_at as i64, data],
+            )
+            .c(d!("put job"))?;
+        Ok(())
+    }
+
+    pub fn get_job(&self, id: &str) -> Result<Option<Job>> {
+        query_one(&self.conn, "SELECT data FROM jobs WHERE id = ?1", [id])
+    }
+
+    /// All jobs, newest first.
+    pub fn list_jobs(&self) -> Result<Vec<Job>> {
+        query_all(
+            &self.conn,
+            "SELECT data FROM jobs ORDER BY created_at DESC, rowid DESC",
+            [],
+        )
+    }
+
+    /// Forget finished jobs that started before `before`.
+    pub fn prune_jobs(&self, before: u64) -> Result<()> {
+        for job in self.list_jobs()? {
+            if job.state.is_finished() && job.created_at < before {
+                self.conn
+                    .execute("DELETE FROM jobs WHERE id = ?1", [&job.id])
+                    .c(d!("remove job"))?;
+            }
+        }
+        Ok(())
+    }
+
+    // ── Audit Log ───────────────────────────────────────────────────
+
+    /// Append an entry to the audit log; its `id` is assigned here.
+    pub fn add_audit(&self, entry: &AuditEntry) -> Result<u64> {
+        let data = serde_json::to_string(entry).c(d!("serialize audit entry"))?;
+        self.conn
+            .execute(
+                "INSERT INTO audit (at, user, env, vm, host, data) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                rusqlite::params![
+                    entry.at as i64,
+                    entry.user,
+                    entry.env,
+                    entry.vm,
+                    entry.host,
+                    data
+                ],
+            )
+            .c(d!("add audit entry"))?;
+        Ok(self.conn.last_insert_rowid() as u64)
+    }
+
+    /// Audit entries matching `filter`, newest first unless
+    /// `filter.oldest_first`.
+    pub fn list_audit(&self, filter: &AuditFilter) -> Result<Vec<AuditEntry>> {
+        use rusqlite::types::Value;
+
+        let mut sql = "SELECT id, data FROM audit WHERE at >= ?1".to_string();
+        let mut params = vec![Value::Integer(filter.since as i64)];
+        if filter.until > 0 {
+            params.push(Value::Integer(filter.until as i64));
+            sql += &format!(" AND at <= ?{}", params.len());
+        }
+        for (col, val) in [
+            ("user", &filter.user),
+            ("env", &filter.env),
+            ("vm", &filter.vm),
+            ("host", &filter.host),
+        ] {
+            if let Some(v) = val {
+                params.push(Value::Text(v.clone()));
+                sql += &format!(" AND {col} = ?{}", params.len());
+            }
+        }
+        sql += if filter.oldest_first {
+            " ORDER BY id"
+        } else {
+            " ORDER BY id DESC"
+        };
+        if filter.limit > 0 {
+            sql += &format!(" LIMIT {}", filter.limit);
+        }
+
+        let mut stmt = self.conn.prepare(&sql).c(d!("prepare"))?;
+        let rows = stmt
+            .query_map(rusqlite::params_from_iter(params), |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })
+            .c(d!("query audit"))?;
+        let mut result = Vec::new();
+        for row in rows {
+            let (id, data) = row.c(d!("read row"))?;
+            let mut entry: AuditEntry = serde_json::from_str(&data).c(d!("deserialize"))?;
+            entry.id = id as u64;
+            result.push(entry);
+        }
+        Ok(result)
+    }
+
+    /// Drop audit entries recorded before `before`; returns how many.
+    pub fn prune_audit(&self, before: u64) -> Result<usize> {
+        self.conn
+            .execute("DELETE FROM audit WHERE at < ?1", [before as i64])
+            .c(d!("prune audit log"))
+    }
+
     // ── VMs ─────────────────────────────────────────────────────────
 
     pub fn put_vm(&self, vm: &Vm) -> Result<()> {
@@ -221,6 +434,10 @@ impl Db {
         Ok(count as usize)
     }
 
+    pub fn list_vms(&self) -> Result<Vec<Vm>> {
+        query_all(&self.conn, "SELECT data FROM vms", [])
+    }
+
     pub fn vms_by_env(&self, env_id: &str) -> Result<Vec<Vm>> {
         query_all(
             &self.conn,
@@ -237,6 +454,132 @@ impl Db {
         )
     }
 
+    // ── Users & Tokens ──────────────────────────────────────────────
+
+    pub fn put_user(&self, user: &User) -> Result<()> {
+        let data = serde_json::to_string(user).c(d!("serialize user"))?;
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO users (name, data) VALUES (?1, ?2)",
+                rusqlite::params![user.name, data],
+            )
+            .c(d!("put user"))?;
+        Ok(())
+    }
+
+    pub fn get_user(&self, name: &str) -> Result<Option<User>> {
+        query_one(&self.conn, "SELECT data FROM users WHERE name = ?1", [name])
+    }
+
+    /// Remove a user together with all of their tokens.
+    pub fn remove_user(&self, name: &str) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM tokens WHERE user = ?1", [name])
+            .c(d!("remove user tokens"))?;
+        self.conn
+            .execute("DELETE FROM users WHERE name = ?1", [name])
+            .c(d!("remove user"))?;
+        Ok(())
+    }
+
+    pub fn list_users(&self) -> Result<Vec<User>> {
+        query_all(&self.conn, "SELECT data FROM users ORDER BY name", [])
+    }
+
+    /// Store a token; `hash` is the SHA-256 of its secret.
+    pub fn put_token(&self, token: &ApiToken, hash: &str) -> Result<()> {
+        let data = serde_json::to_string(token).c(d!("serialize token"))?;
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO tokens (id, user, hash, data)
+                 VALUES (?1, ?2, ?3, ?4)",
+                rusqlite::params![token.id, token.user, hash, data],
+            )
+            .c(d!("put token"))?;
+        Ok(())
+    }
+
+    pub fn get_token(&self, id: &str) -> Result<Option<ApiToken>> {
+        query_one(&self.conn, "SELECT data FROM tokens WHERE id = ?1", [id])
+    }
+
+    /// Find the token whose secret hashes to `hash`.
+    pub fn token_by_hash(&self, hash: &str) -> Result<Option<ApiToken>> {
+        query_one(
+            &self.conn,
+            "SELECT data FROM tokens WHERE hash = ?1",
+            [hash],
+        )
+    }
+
+    pub fn remove_token(&self, id: &str) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM tokens WHERE id = ?1", [id])
+            .c(d!("remove token"))?;
+        Ok(())
+    }
+
+    pub fn list_tokens(&self) -> Result<Vec<ApiToken>> {
+        query_all(&self.conn, "SELECT data FROM tokens", [])
+    }
+
+    pub fn tokens_by_user(&self, user: &str) -> Result<Vec<ApiToken>> {
+        query_all(
+            &self.conn,
+            "SELECT data FROM tokens WHERE user = ?1",
+            [user],
+        )
+    }
+
+    // ── Quotas ──────────────────────────────────────────────────────
+
+    /// Set the quota of a subject (`user:<name>` or `group:<name>`).
+    pub fn put_quota(&self, subject: &str, quota: &Quota) -> Result<()> {
+        let data = serde_json::to_string(quota).c(d!("serialize quota"))?;
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO quotas (subject, data) VALUES (?1, ?2)",
+                rusqlite::params![subject, data],
+            )
+            .c(d!("put quota"))?;
+        Ok(())
+    }
+
+    pub fn get_quota(&self, subject: &str) -> Result<Option<Quota>> {
+        query_one(
+            &self.conn,
+            "SELECT data FROM quotas WHERE subject = ?1",
+            [subject],
+        )
+    }
+
+    pub fn remove_quota(&self, subject: &str) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM quotas WHERE subject = ?1", [subject])
+            .c(d!("remove quota"))?;
+        Ok(())
+    }
+
+    /// All quotas as `(subject, quota)` pairs, ordered by subject.
+    pub fn list_quotas(&self) -> Result<Vec<(String, Quota)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT subject, data FROM quotas ORDER BY subject")
+            .c(d!("prepare"))?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })
+            .c(d!("query"))?;
+        let mut result = Vec::new();
+        for row in rows {
+            let (subject, data) = row.c(d!("read row"))?;
+            let quota: Quota = serde_json::from_str(&data).c(d!("deserialize"))?;
+            result.push((subject, quota));
+        }
+        Ok(result)
+    }
+
     // ── Aggregate Status ────────────────────────────────────────────
 
     pub fn fleet_status(&self) -> Result<FleetStatus> {
@@ -273,6 +616,22 @@ impl Db {
     }
 }
 
+/// Which audit entries [`Db::list_audit`] returns.
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    pub user: Option<String>,
+    pub env: Option<String>,
+    pub vm: Option<String>,
+    pub host: Option<String>,
+    /// Only entries recorded at or after this Unix time.
+    pub since: u64,
+    /// Only entries recorded at or before this Unix time; 0 for no bound.
+    pub until: u64,
+    /// At most this many entries; 0 for all.
+    pub limit: usize,
+    pub oldest_first: bool,
+}
+
 // ── Generic Query Helpers ───────────────────────────────────────────
 
 fn query_one<T: serde::de::DeserializeOwned, P: rusqlite::Params>(
@@ -313,7 +672,6 @@ fn query_all<T: serde::de::DeserializeOwned, P: rusqlite::Params>(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::BTreeMap;
 
     fn test_db() -> Db {
         Db::open(":memory:").unwrap()
@@ -329,10 +687,9 @@ mod tests {
                 disk_total: 500_000,
                 ..Default::default()
             },
-            state: HostState::Online,
             engines: vec![Engine::Qemu],
-            storage: Storage::File,
             registered_at: 1000,
+            ..Default::default()
         }
     }
 
@@ -344,6 +701,8 @@ mod tests {
             created_at: 1000,
             expires_at: 2000,
             state: EnvState::Active,
+            network: None,
+            reserved: None,
         }
     }
 
@@ -353,14 +712,13 @@ mod tests {
             env_id: env_id.into(),
             host_id: host_id.into(),
             image: "ubuntu".into(),
-            engine: Engine::Qemu,
             cpu: 2,
             mem: 1024,
             disk: 40960,
             ip: "10.10.0.1".into(),
-            port_map: BTreeMap::new(),
             state: VmState::Running,
             created_at: 1000,
+            ..Default::default()
         }
     }
 
@@ -430,6 +788,123 @@ mod tests {
         assert_eq!(db.list_envs().unwrap().len(), 3);
     }
 
+    #[test]
+    fn env_events() {
+        let db = test_db();
+        db.put_env(&make_env("e1")).unwrap();
+        for (at, kind) in [
+            (1000, EnvEventKind::Created),
+            (1500, EnvEventKind::Extended),
+        ] {
+            db.add_event(&EnvEvent {
+                env_id: "e1".into(),
+                at,
+                kind,
+                message: String::new(),
+            })
+            .unwrap();
+        }
+        let events = db.events_by_env("e1").unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].kind, EnvEventKind::Created);
+        assert_eq!(events[1].at, 1500);
+        assert!(db.events_by_env("e2").unwrap().is_empty());
+
+        // Events go with their env
+        db.remove_env("e1").unwrap();
+        assert!(db.events_by_env("e1").unwrap().is_empty());
+    }
+
+    #[test]
+    fn jobs_newest_first_and_pruned_once_finished() {
+        let db = test_db();
+        let job = |id: &str, at: u64, state: JobState| Job {
+            id: id.into(),
+            kind: JobKind::CreateEnv,
+            target: "e1".into(),
+            owner: "alice".into(),
+            state,
+            steps: vec![],
+            warnings: vec![],
+            error: None,
+            created_at: at,
+            finished_at: 0,
+        };
+        db.put_job(&job("j1", 1000, JobState::Succeeded)).unwrap();
+        db.put_job(&job("j2", 1100, JobState::Running)).unwrap();
+        db.put_job(&job("j3", 2000, JobState::Failed)).unwrap();
+        let ids: Vec<_> = db.list_jobs().unwrap().into_iter().map(|j| j.id).collect();
+        assert_eq!(ids, ["j3", "j2", "j1"]);
+
+        // Running jobs are kept however old they are
+        db.prune_jobs(1500).unwrap();
+        assert!(db.get_job("j1").unwrap().is_none());
+        assert_eq!(db.get_job("j2").unwrap().unwrap().state, JobState::Running);
+        assert!(db.get_job("j3").unwrap().is_some());
+    }
+
+    #[test]
+    fn audit_log_filters_and_prunes() {
+        let db = test_db();
+        let entry = |at: u64, user: &str, env: Option<&str>| AuditEntry {
+            id: 0,
+            at,
+            user: user.into(),
+            token: None,
+            source: "10.0.0.9".into(),
+            method: "POST".into(),
+            endpoint: "/api/envs".into(),
+            env: env.map(str::to_string),
+            vm: None,
+            host: None,
+            summary: String::new(),
+            status: 202,
+            error: None,
+            request_id: None,
+        };
+        let first = db.add_audit(&entry(1000, "alice", Some("demo"))).unwrap();
+        db.add_audit(&entry(2000, "bob", Some("other"))).unwrap();
+        db.add_audit(&entry(3000, "alice", Some("demo"))).unwrap();
+        db.add_audit(&entry(4000, "alice", None)).unwrap();
+
+        let all = db.list_audit(&AuditFilter::default()).unwrap();
+        assert_eq!(all.len(), 4);
+        assert_eq!(all[3].id, first);
+        assert_eq!(all[0].at, 4000);
+
+        let demo = db
+            .list_audit(&AuditFilter {
+                env: Some("demo".into()),
+                oldest_first: true,
+                ..Default::default()
+            })
+            .unwrap();
+        let ats: Vec<_> = demo.iter().map(|e| e.at).collect();
+        assert_eq!(ats, [1000, 3000]);
+
+        let window = db
+            .list_audit(&AuditFilter {
+                user: Some("alice".into()),
+                since: 2000,
+                until: 3500,
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(window.len(), 1);
+        assert_eq!(window[0].at, 3000);
+
+        let latest = db
+            .list_audit(&AuditFilter {
+                limit: 1,
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(latest[0].at, 4000);
+
+        assert_eq!(db.prune_audit(2500).unwrap(), 2);
+        assert_eq!(db.list_audit(&AuditFilter::default()).unwrap().len(), 2);
+    }
+
     // ── VM CRUD ─────────────────────────────────────────────────────
 
     #[test]
@@ -474,6 +949,92 @@ mod tests {
         assert!(db.vms_by_host("h3").unwrap().is_empty());
     }
 
+    // ── Users & Tokens ──────────────────────────────────────────────
+
+    fn make_user(name: &str, role: Role) -> User {
+        User {
+            name: name.into(),
+            role,
+            group: None,
+            created_at: 1000,
+        }
+    }
+
+    fn make_token(id: &str, user: &str) -> ApiToken {
+        ApiToken {
+            id: id.into(),
+            user: user.into(),
+            label: String::new(),
+            created_at: 1000,
+        }
+    }
+
+    #[test]
+    fn user_crud() {
+        let db = test_db();
+        db.put_user(&make_user("bob", Role::User)).unwrap();
+        db.put_user(&make_user("alice", Role::Admin)).unwrap();
+
+        let users = db.list_users().unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[0].name, "alice");
+        assert_eq!(db.get_user("alice").unwrap().unwrap().role, Role::Admin);
+
+        db.remove_user("bob").unwrap();
+        assert!(db.get_user("bob").unwrap().is_none());
+    }
+
+    #[test]
+    fn token_lookup_by_hash() {
+        let db = test_db();
+        db.put_user(&make_user("alice", Role::User)).unwrap();
+        db.put_token(&make_token("t1", "alice"), "hash1").unwrap();
+        db.put_token(&make_token("t2", "alice"), "hash2").unwrap();
+
+        assert_eq!(db.token_by_hash("hash2").unwrap().unwrap().id, "t2");
+        assert!(db.token_by_hash("nope").unwrap().is_none());
+        assert_eq!(db.tokens_by_user("alice").unwrap().len(), 2);
+
+        db.remove_token("t1").unwrap();
+        assert!(db.token_by_hash("hash1").unwrap().is_none());
+        assert_eq!(db.list_tokens().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn removing_user_revokes_tokens() {
+        let db = test_db();
+        db.put_user(&make_user("alice", Role::User)).unwrap();
+        db.put_user(&make_user("bob", Role::User)).unwrap();
+        db.put_token(&make_token("t1", "alice"), "hash1").unwrap();
+        db.put_token(&make_token("t2", "bob"), "hash2").unwrap();
+
+        db.remove_user("alice").unwrap();
+        assert!(db.token_by_hash("hash1").unwrap().is_none());
+        assert!(db.get_token("t2").unwrap().is_some());
+    }
+
+    // ── Quotas ──────────────────────────────────────────────────────
+
+    #[test]
+    fn quota_crud() {
+        let db = test_db();
+        let q = Quota {
+            cpu: Some(8),
+            envs: Some(2),
+            ..Default::default()
+        };
+        db.put_quota("user:alice", &q).unwrap();
+        db.put_quota("group:dev", &Quota::default()).unwrap();
+
+        assert_eq!(db.get_quota("user:alice").unwrap(), Some(q));
+        let all = db.list_quotas().unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].0, "group:dev");
+
+        db.remove_quota("user:alice").unwrap();
+        assert!(db.get_quota("user:alice").unwrap().is_none());
+    }
+
     // ── Fleet Status ────────────────────────────────────────────────
 
     #[test]
@@ -0,0 +1,378 @@
+//! Taking hosts out of service.
+//!
+//! Draining a host stops the scheduler from placing VMs on it and moves
+//! the VMs it runs to other hosts, one at a time, in the background:
+//! by migration where their disk can follow them, or (if asked) by
+//! recreating them from their image elsewhere. Once the host is empty
+//! it enters maintenance. Each move is a `migrate_vm` job; the drain's
+//! progress is kept in memory and served until the next drain of the
+//! same host.
+
+use crate::auth::Caller;
+use crate::handler::{
+    CtlState, agent_client, announce_host_state, fetch_host_images, now, refresh_all_hosts,
+};
+use crate::migrate::{Move, check_target, move_vm, start_move};
+use crate::scheduler;
+use crate::trace;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use std::collections::{HashMap, HashSet};
+use tracing::warn;
+use ttcore::api::*;
+use ttcore::model::*;
+
+/// POST /api/hosts/:id/drain — take a host out of service (admin only).
+///
+/// Unless `keep_vms` is set, the host becomes `draining` and its VMs
+/// are moved off in the background; follow them with `GET
+/// /api/hosts/:id/drain`.
+pub async fn drain_host(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+    Json(req): Json<DrainHostReq>,
+) -> impl IntoResponse {
+    if let Err((code, msg)) = caller.require_admin() {
+        return (code, Json(ApiResp::<Host>::err(msg)));
+    }
+    let busy = state.lock_drains().get(&id).is_some_and(|p| !p.finished);
+    if busy {
+        return (
+            StatusCode::CONFLICT,
+            Json(ApiResp::err(format!("host {id} is already draining"))),
+        );
+    }
+
+    let (host, vms) = {
+        let db = state.lock_db();
+        let mut host = match db.get_host(&id) {
+            Ok(Some(h)) => h,
+            Ok(None) => {
+                return (
+                    StatusCode::NOT_FOUND,
+                    Json(ApiResp::err(format!("host not found: {id}"))),
+                );
+            }
+            Err(e) => {
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(ApiResp::err(e.to_string())),
+                );
+            }
+        };
+        let vms = db.vms_by_host(&id).unwrap_or_default();
+        let from = host.state;
+        host.state = if req.keep_vms || vms.is_empty() {
+            HostState::Maintenance
+        } else {
+            HostState::Draining
+        };
+        if let Err(e) = db.put_host(&host) {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiResp::err(e.to_string())),
+            );
+        }
+        announce_host_state(&state, &host, from);
+        (host, vms)
+    };
+
+    if host.state == HostState::Maintenance {
+        return (StatusCode::OK, Json(ApiResp::success(host)));
+    }
+
+    state.lock_drains().insert(
+        id.clone(),
+        DrainProgress {
+            host_id: id.clone(),
+            total: vms.len() as u32,
+            moved: vec![],
+            failed: vec![],
+            current: None,
+            finished: false,
+            started_at: now(),
+        },
+    );
+    trace::spawn(evacuate(state.clone(), id, vms, req, caller.user));
+
+    (StatusCode::ACCEPTED, Json(ApiResp::success(host)))
+}
+
+/// GET /api/hosts/:id/drain — progress of the last drain of a host.
+pub async fn drain_progress(
+    State(state): State<CtlState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match state.lock_drains().get(&id) {
+        Some(p) => (StatusCode::OK, Json(ApiResp::success(p.clone()))),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(ApiResp::<DrainProgress>::err(format!(
+                "host {id} has not been drained"
+            ))),
+        ),
+    }
+}
+
+/// POST /api/hosts/:id/undrain — put a host back in service (admin only).
+///
+/// A drain in progress stops before its next VM.
+pub async fn undrain_host(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    if let Err((code, msg)) = caller.require_admin() {
+        return (code, Json(ApiRespEmpty::err(msg)));
+    }
+    let db = state.lock_db();
+    let mut host = match db.get_host(&id) {
+        Ok(Some(h)) => h,
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(ApiRespEmpty::err(format!("host not found: {id}"))),
+            );
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiRespEmpty::err(e.to_string())),
+            );
+        }
+    };
+    if !matches!(host.state, HostState::Draining | HostState::Maintenance) {
+        return (
+            StatusCode::CONFLICT,
+            Json(ApiRespEmpty::err(format!("host {id} is {}", host.state))),
+        );
+    }
+    // The next health check takes it offline if it is down
+    let from = host.state;
+    host.state = HostState::Online;
+    if let Err(e) = db.put_host(&host) {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiRespEmpty::err(e.to_string())),
+        );
+    }
+    announce_host_state(&state, &host, from);
+    (StatusCode::OK, Json(ApiRespEmpty::ok()))
+}
+
+/// Move `vms` off host `host_id`, then put it in maintenance if none is
+/// left on it.
+async fn evacuate(state: CtlState, host_id: String, vms: Vec<Vm>, req: DrainHostReq, by: String) {
+    let client = agent_client(state.api_key.as_deref(), 10);
+    let hosts = state.lock_db().list_hosts().unwrap_or_default();
+    let images = fetch_host_images(&hosts, &client).await;
+    let update = |f: &dyn Fn(&mut DrainProgress)| {
+        if let Some(p) = state.lock_drains().get_mut(&host_id) {
+            f(p);
+        }
+    };
+
+    let step = format!("move off {host_id}");
+    for vm in vms {
+        let (src, vm, hosts, started) = {
+            let db = state.lock_db();
+            let src = match db.get_host(&host_id) {
+                Ok(Some(h)) if h.state == HostState::Draining => h,
+                _ => break,
+            };
+            // Skip VMs destroyed or moved meanwhile
+            let vm = match db.get_vm(&vm.id) {
+                Ok(Some(v)) if v.host_id == host_id => v,
+                _ => continue,
+            };
+            // Each move is a job, so that it and a manual migration
+            // exclude each other
+            let started = start_move(&state, &db, &vm, &host_id, &by, &step)
+                .map(|(_, tracker)| tracker)
+                .map_err(|(_, e)| e);
+            (src, vm, db.list_hosts().unwrap_or_default(), started)
+        };
+
+        update(&|p| p.current = Some(vm.id.clone()));
+        let outcome = match &started {
+            Ok(tracker) => {
+                tracker.step(&step, JobState::Running, "");
+                evacuate_vm(&state, &client, &vm, &src, &hosts, &images, &req, &by).await
+            }
+            Err(e) => Err(e.clone()),
+        };
+        let name = vm.name_or_id();
+        match outcome {
+            Ok(line) => {
+                if let Ok(tracker) = &started {
+                    tracker.step(&step, JobState::Succeeded, &line);
+                    tracker.succeed(vec![]);
+                }
+                update(&|p| p.moved.push(format!("{name} ({}): {line}", vm.id)));
+                refresh_all_hosts(&state, &client).await;
+            }
+            Err(e) => {
+                if let Ok(tracker) = &started {
+                    tracker.fail(&e);
+                }
+                warn!("could not move VM {} off host {host_id}: {e}", vm.id);
+                update(&|p| p.failed.push(format!("{name} ({}): {e}", vm.id)));
+            }
+        }
+    }
+
+    {
+        let db = state.lock_db();
+        if let Ok(Some(mut host)) = db.get_host(&host_id)
+            && host.state == HostState::Draining
+            && db.vms_by_host(&host_id).is_ok_and(|v| v.is_empty())
+        {
+            host.state = HostState::Maintenance;
+            let _ = db.put_host(&host);
+            announce_host_state(&state, &host, HostState::Draining);
+        }
+    }
+    update(&|p| {
+        p.current = None;
+        p.finished = true;
+    });
+}
+
+/// Move `vm` off `src` to the best other host. Migration failures leave
+/// it running on `src`; VMs that cannot be migrated at all are
+/// recreated elsewhere if `req.recreate` allows it.
+#[allow(clippy::too_many_arguments)]
+async fn evacuate_vm(
+    state: &CtlState,
+    client: &reqwest::Client,
+    vm: &Vm,
+    src: &Host,
+    hosts: &[Host],
+    images: &HashMap<String, HashSet<String>>,
+    req: &DrainHostReq,
+    by: &str,
+) -> std::result::Result<String, String> {
+    let url = format!("http://{}/api/info", src.addr);
+    let reachable = client
+        .get(&url)
+        .send()
+        .await
+        .is_ok_and(|r| r.status().is_success());
+
+    // Keep apart from the rest of the VM's anti-affinity group
+    let peers: Vec<Vm> = state
+        .lock_db()
+        .vms_by_env(&vm.env_id)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|v| v.id != vm.id)
+        .collect();
+    let avoid = vm
+        .anti_affinity
+        .as_ref()
+        .and_then(|g| scheduler::group_hosts(&peers).remove(g))
+        .unwrap_or_default();
+    let spec = vm_spec(vm);
+    let place = |candidates: &[Host]| {
+        scheduler::place_vm(candidates, &spec, images, &*state.placement, &avoid)
+    };
+
+    let reason = if reachable {
+        match place(&migration_candidates(vm, src, hosts)) {
+            Ok(p) => {
+                let dst = hosts.iter().find(|h| h.id == p.host_id).unwrap();
+                let how = Move::for_vm(vm, req.cold);
+                let result = match check_target(vm, src, dst) {
+                    Ok(()) => move_vm(state, vm, src, dst, how, by).await,
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(_) => {
+                        let kind = if how == Move::Live { "live" } else { "cold" };
+                        return Ok(format!("migrated {kind} to {}", dst.id));
+                    }
+                    // The VM is still intact on the source; don't throw its disk away
+                    Err((StatusCode::BAD_GATEWAY, e)) => return Err(e),
+                    Err((_, e)) => e,
+                }
+            }
+            Err(e) => format!("no host to migrate to: {e}"),
+        }
+    } else {
+        format!("host {} is unreachable", src.id)
+    };
+    if !req.recreate {
+        return Err(reason);
+    }
+
+    let others: Vec<Host> = hosts.iter().filter(|h| h.id != src.id).cloned().collect();
+    let p = place(&others).map_err(|e| format!("{reason}; no host to recreate it on: {e}"))?;
+    let dst = hosts.iter().find(|h| h.id == p.host_id).unwrap();
+    move_vm(state, vm, src, dst, Move::Recreate, by)
+        .await
+        .map_err(|(_, e)| format!("{reason}; {e}"))?;
+    Ok(format!("recreated on {} ({reason})", dst.id))
+}
+
+/// Hosts other than `src` that `vm`'s disk can be copied to.
+fn migration_candidates(vm: &Vm, src: &Host, hosts: &[Host]) -> Vec<Host> {
+    hosts
+        .iter()
+        .filter(|h| h.id != src.id && (vm.engine == Engine::Docker || h.storage == src.storage))
+        .cloned()
+        .collect()
+}
+
+/// What the scheduler needs to place `vm` again.
+fn vm_spec(vm: &Vm) -> VmSpec {
+    VmSpec {
+        image: vm.image.clone(),
+        engine: vm.engine,
+        cpu: Some(vm.cpu),
+        mem: Some(vm.mem),
+        disk: Some(vm.disk),
+        ports: vec![],
+        deny_outgoing: vm.deny_outgoing,
+        ssh_keys: vec![],
+        name: Some(vm.name.clone()),
+        user_data: None,
+        anti_affinity: vm.anti_affinity.clone(),
+        node_selector: vm.node_selector.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrate::tests::{host, vm};
+
+    #[test]
+    fn candidates_share_storage_unless_container() {
+        let hosts = [
+            host("h1", Storage::Zvol),
+            host("h2", Storage::Zvol),
+            host("h3", Storage::File),
+        ];
+        let ids = |vm: &Vm| -> Vec<String> {
+            migration_candidates(vm, &hosts[0], &hosts)
+                .into_iter()
+                .map(|h| h.id)
+                .collect()
+        };
+        assert_eq!(ids(&vm(Engine::Qemu, VmState::Running)), ["h2"]);
+        assert_eq!(ids(&vm(Engine::Docker, VmState::Running)), ["h2", "h3"]);
+    }
+
+    #[test]
+    fn vm_spec_asks_for_same_size() {
+        let spec = vm_spec(&vm(Engine::Qemu, VmState::Running));
+        assert_eq!(
+            (spec.cpu, spec.mem, spec.disk),
+            (Some(2), Some(1024), Some(10240))
+        );
+        assert_eq!(spec.image, "alpine");
+    }
+}
@@ -0,0 +1,219 @@
+//! Live stream of fleet changes.
+//!
+//! Whatever creates, deletes or expires an env, notices a VM or host
+//! change state, or moves a job along publishes a [`FleetChange`] on
+//! the controller's [`EventBus`]. `GET /api/events` streams them as
+//! server-sent events to the dashboard and `tt events`.
+//!
+//! The bus keeps the last [`RECENT_EVENTS`] events, so a client that
+//! reconnects with `Last-Event-ID` gets what it missed, and one asking
+//! with `?since=0` gets recent history. Each stream sends a `ready`
+//! event once that backlog is out, and a `lagged` event if it fell so
+//! far behind that events were dropped.
+
+use crate::auth::Caller;
+use crate::handler::{CtlState, now};
+use axum::Extension;
+use axum::extract::{Query, State};
+use axum::http::HeaderMap;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures_util::stream::{self, Stream, StreamExt};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::Mutex;
+use tokio::sync::broadcast::{self, error::RecvError};
+use ttcore::api::*;
+
+/// How many past events the bus keeps for replay.
+pub const RECENT_EVENTS: usize = 256;
+
+/// Fans fleet changes out to every open event stream.
+pub struct EventBus {
+    tx: broadcast::Sender<FleetEvent>,
+    recent: Mutex<VecDeque<FleetEvent>>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self {
+            tx: broadcast::channel(RECENT_EVENTS).0,
+            recent: Mutex::new(VecDeque::with_capacity(RECENT_EVENTS)),
+        }
+    }
+}
+
+impl EventBus {
+    fn lock_recent(&self) -> std::sync::MutexGuard<'_, VecDeque<FleetEvent>> {
+        self.recent.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Number a change and send it to all subscribers.
+    pub fn publish(&self, change: FleetChange) {
+        let mut recent = self.lock_recent();
+        let ev = FleetEvent {
+            seq: recent.back().map_or(1, |e| e.seq + 1),
+            at: now(),
+            change,
+        };
+        if recent.len() == RECENT_EVENTS {
+            recent.pop_front();
+        }
+        recent.push_back(ev.clone());
+        // Sending under the lock keeps subscribers in sequence order;
+        // having no subscribers is fine
+        let _ = self.tx.send(ev);
+    }
+
+    /// Subscribe to new events, along with the kept ones after `since`.
+    ///
+    /// A `since` beyond the last event comes from before a controller
+    /// restart, so everything kept since the restart is returned.
+    pub fn subscribe(
+        &self,
+        since: Option<u64>,
+    ) -> (Vec<FleetEvent>, broadcast::Receiver<FleetEvent>) {
+        let recent = self.lock_recent();
+        let rx = self.tx.subscribe();
+        let backlog = match since {
+            Some(s) => {
+                let last = recent.back().map_or(0, |e| e.seq);
+                let s = if s > last { 0 } else { s };
+                recent.iter().filter(|e| e.seq > s).cloned().collect()
+            }
+            None => vec![],
+        };
+        (backlog, rx)
+    }
+}
+
+/// Whether the caller may see an event: admins see all, users the
+/// changes to their own envs and jobs, and everyone host changes.
+fn visible(caller: &Caller, ev: &FleetEvent) -> bool {
+    caller.is_admin() || ev.change.owner().is_none_or(|o| o == caller.user)
+}
+
+fn sse_event(ev: &FleetEvent) -> Event {
+    Event::default()
+        .id(ev.seq.to_string())
+        .json_data(ev)
+        .unwrap_or_default()
+}
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    /// Replay the kept events after this sequence number.
+    since: Option<u64>,
+}
+
+/// GET /api/events — stream fleet changes the caller may see.
+pub async fn stream_events(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    headers: HeaderMap,
+    Query(q): Query<EventsQuery>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    // Browsers resend the last ID they saw when they reconnect
+    let last_id = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok());
+    let (backlog, rx) = state.events.subscribe(last_id.or(q.since));
+
+    let backlog: Vec<_> = backlog
+        .iter()
+        .filter(|e| visible(&caller, e))
+        .map(|e| Ok(sse_event(e)))
+        .collect();
+    let ready = Ok(Event::default().event("ready").data(""));
+    let live = stream::unfold((rx, caller), |(mut rx, caller)| async move {
+        loop {
+            let ev = match rx.recv().await {
+                Ok(e) if visible(&caller, &e) => sse_event(&e),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(n)) => Event::default().event("lagged").data(n.to_string()),
+                Err(RecvError::Closed) => return None,
+            };
+            return Some((Ok(ev), (rx, caller)));
+        }
+    });
+
+    Sse::new(
+        stream::iter(backlog)
+            .chain(stream::once(async { ready }))
+            .chain(live),
+    )
+    .keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ttcore::model::*;
+
+    fn host_change(host: &str) -> FleetChange {
+        FleetChange::HostState {
+            host: host.into(),
+            from: HostState::Online,
+            to: HostState::Offline,
+        }
+    }
+
+    #[test]
+    fn subscribe_replays_kept_events_after_since() {
+        let bus = EventBus::default();
+        for i in 0..RECENT_EVENTS + 10 {
+            bus.publish(host_change(&format!("h{i}")));
+        }
+
+        let (backlog, _) = bus.subscribe(None);
+        assert!(backlog.is_empty());
+
+        // The oldest events were dropped
+        let (backlog, _) = bus.subscribe(Some(0));
+        assert_eq!(backlog.len(), RECENT_EVENTS);
+        assert_eq!(backlog[0].seq, 11);
+
+        let last = (RECENT_EVENTS + 10) as u64;
+        let (backlog, mut rx) = bus.subscribe(Some(last - 2));
+        let seqs: Vec<_> = backlog.iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, [last - 1, last]);
+
+        bus.publish(host_change("h-new"));
+        assert_eq!(rx.try_recv().unwrap().seq, last + 1);
+
+        // An ID from before a restart replays everything kept
+        let (backlog, _) = bus.subscribe(Some(last + 100));
+        assert_eq!(backlog.len(), RECENT_EVENTS);
+    }
+
+    #[test]
+    fn users_only_see_their_own_changes() {
+        let user = Caller {
+            user: "alice".into(),
+            role: Role::User,
+            token: None,
+            host: None,
+        };
+        let ev = |change| FleetEvent {
+            seq: 1,
+            at: 0,
+            change,
+        };
+        let env = |owner: &str| FleetChange::EnvCreated {
+            env: "e1".into(),
+            owner: owner.into(),
+        };
+        assert!(visible(&user, &ev(env("alice"))));
+        assert!(!visible(&user, &ev(env("bob"))));
+        assert!(visible(&user, &ev(host_change("h1"))));
+
+        let admin = Caller {
+            user: "root".into(),
+            role: Role::Admin,
+            token: None,
+            host: None,
+        };
+        assert!(visible(&admin, &ev(env("bob"))));
+    }
+}
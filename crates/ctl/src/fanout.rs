@@ -0,0 +1,137 @@
+//! Bounded-concurrency fan-out of agent calls.
+//!
+//! Calls to different hosts run concurrently, at most [`MAX_IN_FLIGHT`]
+//! at a time, so a request touching many hosts costs about as long as
+//! its slowest host rather than the sum of all of them. Calls about
+//! several VMs of one host still reach that host one by one, so a
+//! single request never has an agent clone several images or boot
+//! several guests at once. Results always come back in the order of
+//! the input.
+
+use futures_util::future::join_all;
+use std::future::Future;
+use tokio::sync::Semaphore;
+
+/// Most agent calls a single fan-out keeps in flight.
+pub const MAX_IN_FLIGHT: usize = 16;
+
+/// Run `f` on each item, at most [`MAX_IN_FLIGHT`] at a time.
+pub async fn map<T, R, F, Fut>(items: impl IntoIterator<Item = T>, f: F) -> Vec<R>
+where
+    F: FnMut(T) -> Fut,
+    Fut: Future<Output = R>,
+{
+    let slots = Semaphore::new(MAX_IN_FLIGHT);
+    let slots = &slots;
+    // Futures are lazy, so calls only start once they hold a slot
+    let calls: Vec<_> = items
+        .into_iter()
+        .map(f)
+        .map(|call| async move {
+            let _slot = slots.acquire().await;
+            call.await
+        })
+        .collect();
+    join_all(calls).await
+}
+
+/// Run `f` on each item, one at a time for items of the same `key` (a
+/// host ID) and concurrently across keys, as [`map`] does.
+pub async fn map_by_key<T, K, R, F, Fut>(
+    items: impl IntoIterator<Item = T>,
+    key: impl Fn(&T) -> K,
+    f: F,
+) -> Vec<R>
+where
+    K: Eq,
+    F: Fn(T) -> Fut,
+    Fut: Future<Output = R>,
+{
+    let mut keys: Vec<K> = Vec::new();
+    let mut groups: Vec<Vec<(usize, T)>> = Vec::new();
+    for (i, item) in items.into_iter().enumerate() {
+        let k = key(&item);
+        match keys.iter().position(|x| *x == k) {
+            Some(g) => groups[g].push((i, item)),
+            None => {
+                keys.push(k);
+                groups.push(vec![(i, item)]);
+            }
+        }
+    }
+
+    let f = &f;
+    let done = map(groups, |group| async move {
+        let mut out = Vec::with_capacity(group.len());
+        for (i, item) in group {
+            out.push((i, f(item).await));
+        }
+        out
+    })
+    .await;
+
+    let mut all: Vec<(usize, R)> = done.into_iter().flatten().collect();
+    all.sort_by_key(|(i, _)| *i);
+    all.into_iter().map(|(_, r)| r).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn map_keeps_order_and_bounds_concurrency() {
+        let live = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        let out = map(0..40u64, |i| {
+            let (live, peak) = (&live, &peak);
+            async move {
+                let n = live.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(n, Ordering::SeqCst);
+                // Later items finish first
+                tokio::time::sleep(Duration::from_millis(40 - i)).await;
+                live.fetch_sub(1, Ordering::SeqCst);
+                i * 2
+            }
+        })
+        .await;
+        assert_eq!(out, (0..40).map(|i| i * 2).collect::<Vec<_>>());
+        assert!(peak.load(Ordering::SeqCst) > 1);
+        assert!(peak.load(Ordering::SeqCst) <= MAX_IN_FLIGHT);
+    }
+
+    #[tokio::test]
+    async fn map_by_key_serializes_each_key() {
+        let items = vec![("h1", 1), ("h2", 2), ("h1", 3), ("h2", 4), ("h1", 5)];
+        let busy = Mutex::new(Vec::<&str>::new());
+        let seen = Mutex::new(Vec::new());
+        let out = map_by_key(
+            items,
+            |(host, _)| *host,
+            |(host, n)| {
+                let (busy, seen) = (&busy, &seen);
+                async move {
+                    {
+                        let mut b = busy.lock().unwrap();
+                        assert!(!b.contains(&host), "two calls to {host} at once");
+                        b.push(host);
+                    }
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    busy.lock().unwrap().retain(|h| *h != host);
+                    seen.lock().unwrap().push(n);
+                    n * 10
+                }
+            },
+        )
+        .await;
+        assert_eq!(out, [10, 20, 30, 40, 50]);
+
+        // Each host got its items in input order
+        let seen = seen.into_inner().unwrap();
+        let h1: Vec<_> = seen.iter().filter(|n| *n % 2 == 1).collect();
+        assert_eq!(h1, [&1, &3, &5]);
+    }
+}
@@ -2,36 +2,72 @@
 //!
 //! Handles requests from the CLI and coordinates with host agents.
 
+use crate::auth::Caller;
 use crate::db::Db;
+use crate::events::EventBus;
+use crate::fanout;
+use crate::heartbeat::Heartbeats;
+use crate::jobs::{self, Tracker};
+use crate::lifetime;
+use crate::metrics;
+use crate::quota;
 use crate::scheduler;
-use axum::Json;
-use axum::extract::{Path, State};
+use crate::trace;
+use crate::transfer;
+use axum::extract::{Path, Query, State};
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
-use std::collections::{HashMap, HashSet};
+use axum::{Extension, Json};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::sync::{Arc, Mutex, MutexGuard};
+use tracing::{info, warn};
 use ttcore::api::*;
 use ttcore::model::*;
+use ttcore::net;
 
 /// Shared controller state.
 pub struct CtlShared {
     pub(crate) db: Mutex<Db>,
     /// API key used for controller→agent communication.
     pub api_key: Option<String>,
+    /// Progress of host drains, by host ID.
+    pub(crate) drains: Mutex<HashMap<String, DrainProgress>>,
+    /// Ranks the hosts a VM may be placed on.
+    pub(crate) placement: Box<dyn scheduler::PlacementPolicy>,
+    /// Fleet changes, streamed to `GET /api/events`.
+    pub(crate) events: EventBus,
+    /// Last heartbeat of each host that sends them.
+    pub(crate) heartbeats: Heartbeats,
 }
 
 impl CtlShared {
-    pub fn new(db: Db, api_key: Option<String>) -> Self {
+    pub fn new(
+        db: Db,
+        api_key: Option<String>,
+        placement: Box<dyn scheduler::PlacementPolicy>,
+    ) -> Self {
         Self {
             db: Mutex::new(db),
             api_key,
+            drains: Mutex::new(HashMap::new()),
+            placement,
+            events: EventBus::default(),
+            heartbeats: Heartbeats::default(),
         }
     }
 
     /// Lock the DB mutex, recovering from poisoning.
     pub fn lock_db(&self) -> MutexGuard<'_, Db> {
         self.db.lock().unwrap_or_else(|e| {
-            eprintln!("[ctl] WARN: db mutex was poisoned, recovering");
+            warn!("db mutex was poisoned, recovering");
+            e.into_inner()
+        })
+    }
+
+    /// Lock the drain progress table, recovering from poisoning.
+    pub(crate) fn lock_drains(&self) -> MutexGuard<'_, HashMap<String, DrainProgress>> {
+        self.drains.lock().unwrap_or_else(|e| {
+            warn!("drain mutex was poisoned, recovering");
             e.into_inner()
         })
     }
@@ -39,18 +75,34 @@ impl CtlShared {
 
 pub type CtlState = Arc<CtlShared>;
 
-/// Build an HTTP client for agent communication, with optional Bearer auth.
+/// Timeout of VM creation on an agent, which may clone a large image.
+const VM_CREATE_TIMEOUT_SECS: u64 = 600;
+
+/// Timeout of lifecycle actions relayed to agents: a guest may take a
+/// minute to power down, and up to ten to save or load its memory.
+const VM_ACTION_TIMEOUT_SECS: u64 = 660;
+
+/// Timeout of each host's answer to a probe for its info or images, so
+/// one dead host does not hold up a fan-out over the fleet.
+const PROBE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Build an HTTP client for agent communication, with optional Bearer auth
+/// and the ID of the request being served.
 pub fn agent_client(api_key: Option<&str>, timeout_secs: u64) -> reqwest::Client {
-    let mut builder =
-        reqwest::Client::builder().timeout(std::time::Duration::from_secs(timeout_secs));
-    if let Some(key) = api_key {
-        let mut headers = reqwest::header::HeaderMap::new();
-        if let Ok(val) = reqwest::header::HeaderValue::from_str(&format!("Bearer {key}")) {
-            headers.insert(reqwest::header::AUTHORIZATION, val);
-        }
-        builder = builder.default_headers(headers);
+    let builder = reqwest::Client::builder().timeout(std::time::Duration::from_secs(timeout_secs));
+    let mut headers = reqwest::header::HeaderMap::new();
+    if let Some(key) = api_key
+        && let Ok(val) = reqwest::header::HeaderValue::from_str(&format!("Bearer {key}"))
+    {
+        headers.insert(reqwest::header::AUTHORIZATION, val);
+    }
+    // Agents log the calls under the request that caused them
+    if let Some(id) = trace::request_id()
+        && let Ok(val) = reqwest::header::HeaderValue::from_str(&id)
+    {
+        headers.insert(ttcore::log::REQUEST_ID_HEADER, val);
     }
-    builder.build().unwrap()
+    builder.default_headers(headers).build().unwrap()
 }
 
 // ── Host Management ─────────────────────────────────────────────────
@@ -58,8 +110,15 @@ pub fn agent_client(api_key: Option<&str>, timeout_secs: u64) -> reqwest::Client
 /// POST /api/hosts — register a new host by its agent address.
 pub async fn register_host(
     State(db): State<CtlState>,
+    Extension(caller): Extension<Caller>,
     Json(req): Json<RegisterHostReq>,
 ) -> impl IntoResponse {
+    if let Err((code, msg)) = caller.require_admin() {
+        return (code, Json(ApiResp::<Host>::err(msg)));
+    }
+    if let Err(e) = validate_labels(&req.labels) {
+        return (StatusCode::BAD_REQUEST, Json(ApiResp::<Host>::err(e)));
+    }
     let client = agent_client(db.api_key.as_deref(), 30);
     let url = format!("http://{}/api/info", req.addr);
 
@@ -115,6 +174,8 @@ pub async fn register_host(
         engines: info.engines,
         storage: info.storage,
         registered_at: now(),
+        labels: info.labels.into_iter().chain(req.labels).collect(),
+        self_registered: false,
     };
 
     if let Err(e) = db.put_host(&host) {
@@ -153,7 +214,14 @@ pub async fn get_host(State(db): State<CtlState>, Path(id): Path<String>) -> imp
 }
 
 /// DELETE /api/hosts/:id
-pub async fn remove_host(State(db): State<CtlState>, Path(id): Path<String>) -> impl IntoResponse {
+pub async fn remove_host(
+    State(db): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    if let Err((code, msg)) = caller.require_admin() {
+        return (code, Json(ApiRespEmpty::err(msg)));
+    }
     let db = db.lock_db();
 
     let vms = db.vms_by_host(&id).unwrap_or_default();
@@ -161,7 +229,7 @@ pub async fn remove_host(State(db): State<CtlState>, Path(id): Path<String>) ->
         return (
             StatusCode::CONFLICT,
             Json(ApiRespEmpty::err(format!(
-                "host {id} still has {} VMs; destroy them first",
+                "host {id} still has {} VMs; drain it or destroy them first",
                 vms.len()
             ))),
         );
@@ -180,66 +248,107 @@ pub async fn remove_host(State(db): State<CtlState>, Path(id): Path<String>) ->
 // ── Environment Management ──────────────────────────────────────────
 
 /// POST /api/envs — create an environment with VMs.
+///
+/// The env is owned by the caller; admins may name another owner. The
+/// request is checked and the VMs scheduled right away; they are then
+/// created by a background job, which is returned.
 pub async fn create_env(
     State(db): State<CtlState>,
-    Json(req): Json<CreateEnvReq>,
+    Extension(caller): Extension<Caller>,
+    Json(mut req): Json<CreateEnvReq>,
 ) -> impl IntoResponse {
     // Input validation
-    if let Err(e) = validate_name(&req.id, "env name") {
-        return (StatusCode::BAD_REQUEST, Json(ApiResp::<EnvDetail>::err(e)));
+    if let Err(e) = validate_name(&req.id, "env name").and_then(|_| validate_specs(&req.vms)) {
+        return (StatusCode::BAD_REQUEST, Json(ApiResp::<Job>::err(e)));
     }
-    for spec in &req.vms {
-        if let Err(e) = validate_name(&spec.image, "image") {
-            return (StatusCode::BAD_REQUEST, Json(ApiResp::<EnvDetail>::err(e)));
-        }
-        if spec.cpu == Some(0) || spec.mem == Some(0) || spec.disk == Some(0) {
-            return (
-                StatusCode::BAD_REQUEST,
-                Json(ApiResp::<EnvDetail>::err(
-                    "cpu, mem, and disk must be > 0 if specified",
-                )),
-            );
-        }
+    name_specs(&mut req.vms, &[]);
+    let overlay_vms = req
+        .vms
+        .iter()
+        .filter(|s| s.engine != Engine::Docker)
+        .count();
+    if req.private_net && overlay_vms > net::OVERLAY_MAX_VMS as usize {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ApiResp::<Job>::err(format!(
+                "a private network holds at most {} VMs",
+                net::OVERLAY_MAX_VMS
+            ))),
+        );
     }
 
+    let owner = if caller.is_admin() && !req.owner.is_empty() {
+        req.owner.clone()
+    } else {
+        caller.user.clone()
+    };
+
     // Reserve the environment name under lock to prevent races
-    let hosts = {
+    let (hosts, network, lifetime_cap) = {
         let db = db.lock_db();
 
         if let Ok(Some(_)) = db.get_env(&req.id) {
             return (
                 StatusCode::CONFLICT,
-                Json(ApiResp::<EnvDetail>::err(format!(
+                Json(ApiResp::<Job>::err(format!(
                     "environment '{}' already exists",
                     req.id
                 ))),
             );
         }
 
+        // Quotas are checked before any scheduling work, and what the
+        // env asks for is held by its placeholder under the same lock
+        let lifetime_cap =
+            match quota::check(&db, &owner, &req.vms, req.lifetime.filter(|&lt| lt > 0)) {
+                Ok(cap) => cap,
+                Err((code, msg)) => return (code, Json(ApiResp::<Job>::err(msg))),
+            };
+
+        // The placeholder also reserves the env network, if any
+        let network = if req.private_net {
+            match db
+                .list_envs()
+                .and_then(|envs| scheduler::allocate_network(&envs))
+            {
+                Ok(n) => Some(n),
+                Err(e) => {
+                    return (
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        Json(ApiResp::<Job>::err(e.to_string())),
+                    );
+                }
+            }
+        } else {
+            None
+        };
+
         // Insert a placeholder env to reserve the name while we create VMs.
         // This prevents concurrent requests from creating the same env.
         let placeholder = Env {
             id: req.id.clone(),
-            owner: req.owner.clone(),
+            owner: owner.clone(),
             vm_ids: vec![],
             created_at: now(),
             expires_at: 0,
             state: EnvState::Active,
+            network: network.clone(),
+            reserved: Some(quota::demand(&req.vms)),
         };
         if let Err(e) = db.put_env(&placeholder) {
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ApiResp::<EnvDetail>::err(e.to_string())),
+                Json(ApiResp::<Job>::err(e.to_string())),
             );
         }
 
         match db.list_hosts() {
-            Ok(h) => h,
+            Ok(h) => (h, network, lifetime_cap),
             Err(e) => {
                 let _ = db.remove_env(&req.id);
                 return (
                     StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(ApiResp::<EnvDetail>::err(e.to_string())),
+                    Json(ApiResp::<Job>::err(e.to_string())),
                 );
             }
         }
@@ -249,129 +358,144 @@ pub async fn create_env(
     let client = agent_client(db.api_key.as_deref(), 30);
     let host_images = fetch_host_images(&hosts, &client).await;
 
-    let placements = match scheduler::schedule_env(&hosts, &req.vms, &host_images) {
-        Ok(p) => p,
-        Err(e) => {
-            // Clean up the placeholder
-            let db = db.lock_db();
-            let _ = db.remove_env(&req.id);
-            return (
-                StatusCode::UNPROCESSABLE_ENTITY,
-                Json(ApiResp::<EnvDetail>::err(e.to_string())),
-            );
-        }
-    };
+    let placements =
+        match scheduler::schedule_env(&hosts, &req.vms, &host_images, &*db.placement, &[]) {
+            Ok(p) => p,
+            Err(e) => {
+                // Clean up the placeholder
+                let db = db.lock_db();
+                let _ = db.remove_env(&req.id);
+                return (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Json(ApiResp::<Job>::err(e.to_string())),
+                );
+            }
+        };
 
     let created_at = now();
+    let max_lifetime = lifetime_cap.map_or(MAX_LIFETIME, |cap| cap.min(MAX_LIFETIME));
     let expires_at = req
         .lifetime
         .filter(|&lt| lt > 0)
-        .map(|lt| created_at + lt.min(MAX_LIFETIME))
-        .unwrap_or(created_at + MAX_LIFETIME);
-
-    let mut vm_ids = Vec::new();
-    let mut created_vms = Vec::new();
-    let mut warnings = Vec::new();
-
-    // Create VMs on agents (no lock held during HTTP calls)
-    for (spec, placement) in &placements {
-        let vm_id = uuid::Uuid::new_v4().to_string()[..12].to_string();
-        let cpu = spec.cpu.unwrap_or(VM_CPU_DEFAULT);
-        let mem = spec.mem.unwrap_or(VM_MEM_DEFAULT);
-        let disk = spec.disk.unwrap_or(VM_DISK_DEFAULT);
-
-        let agent_req = CreateVmReq {
-            vm_id: vm_id.clone(),
-            env_id: req.id.clone(),
-            image: spec.image.clone(),
-            engine: spec.engine,
-            cpu,
-            mem,
-            disk,
-            ports: spec.ports.clone(),
-            deny_outgoing: spec.deny_outgoing,
-            ssh_keys: {
-                let mut keys = req.ssh_keys.clone();
-                keys.extend(spec.ssh_keys.iter().cloned());
-                keys.dedup();
-                keys
-            },
-        };
+        .map(|lt| created_at + lt.min(max_lifetime))
+        .unwrap_or(created_at + max_lifetime);
+    let env = Env {
+        id: req.id.clone(),
+        owner,
+        vm_ids: vec![],
+        created_at,
+        expires_at,
+        state: EnvState::Active,
+        network,
+        reserved: None,
+    };
 
-        let url = format!("http://{}/api/vms", placement.host_addr);
-        match client.post(&url).json(&agent_req).send().await {
-            Ok(r) if r.status().is_success() => {
-                if let Ok(body) = r.json::<ApiResp<CreateVmResp>>().await
-                    && let Some(data) = body.data
-                {
-                    vm_ids.push(vm_id);
-                    created_vms.push(data.vm);
-                    continue;
-                }
-                warnings.push(format!("unparseable response from {}", placement.host_addr));
-            }
-            Ok(r) => {
-                warnings.push(format!(
-                    "agent {} returned {}",
-                    placement.host_addr,
-                    r.status()
-                ));
-            }
-            Err(e) => {
-                warnings.push(format!("failed to reach {}: {e}", placement.host_addr));
-            }
+    let steps = placements
+        .iter()
+        .map(|(s, _)| format!("create {}", s.name.as_deref().unwrap_or_default()))
+        .collect();
+    let job = match Tracker::start(&db, JobKind::CreateEnv, &req.id, &caller.user, steps) {
+        Ok(j) => j,
+        Err(e) => {
+            let _ = db.lock_db().remove_env(&req.id);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiResp::<Job>::err(e.to_string())),
+            );
         }
-    }
+    };
+    let (job, tracker) = job;
+    trace::spawn(build_env(
+        db.clone(),
+        tracker,
+        req,
+        env,
+        caller.user,
+        hosts,
+        host_images,
+        placements,
+    ));
+
+    (StatusCode::ACCEPTED, Json(ApiResp::success(job)))
+}
 
-    if !warnings.is_empty() {
-        eprintln!(
-            "[ctl] WARN: partial env '{}' creation: {}/{} VMs failed: {}",
+/// Create the VMs of a new env and write it out, or roll it back.
+#[allow(clippy::too_many_arguments)]
+async fn build_env(
+    state: CtlState,
+    job: Tracker,
+    req: CreateEnvReq,
+    mut env: Env,
+    by: String,
+    hosts: Vec<Host>,
+    host_images: HashMap<String, HashSet<String>>,
+    placements: Vec<(VmSpec, scheduler::Placement)>,
+) {
+    // Agents may take a while to clone an image and boot a guest
+    let client = agent_client(state.api_key.as_deref(), VM_CREATE_TIMEOUT_SECS);
+    let (created_vms, failures, mut warnings) = create_env_vms(
+        &state,
+        &job,
+        &client,
+        &hosts,
+        &host_images,
+        &req.id,
+        &req.ssh_keys,
+        env.network.as_ref(),
+        placements,
+    )
+    .await;
+
+    if !failures.is_empty() {
+        warn!(
+            "partial env '{}' creation: {}/{} VMs failed: {}",
             req.id,
-            warnings.len(),
+            failures.len(),
             req.vms.len(),
-            warnings.join("; ")
+            failures.join("; ")
         );
     }
 
-    if created_vms.is_empty() && !req.vms.is_empty() {
-        // Clean up the placeholder
-        let db = db.lock_db();
-        let _ = db.remove_env(&req.id);
-        return (
-            StatusCode::BAD_GATEWAY,
-            Json(ApiResp::<EnvDetail>::err(
-                "all VM creation attempts failed; check agent connectivity",
-            )),
-        );
+    if !failures.is_empty() && (req.all_or_nothing || created_vms.is_empty()) {
+        teardown_env(&state, &job, &req.id).await;
+        let msg = if created_vms.is_empty() {
+            format!("all VM creation attempts failed: {}", failures.join("; "))
+        } else {
+            format!(
+                "{}/{} VMs failed, so the env was rolled back: {}",
+                failures.len(),
+                req.vms.len(),
+                failures.join("; ")
+            )
+        };
+        job.fail(msg);
+        return;
     }
+    warnings.extend(failures);
 
     // Update the placeholder with the real environment data
-    let env = Env {
-        id: req.id.clone(),
-        owner: req.owner.clone(),
-        vm_ids: vm_ids.clone(),
-        created_at,
-        expires_at,
-        state: EnvState::Active,
-    };
-
+    env.vm_ids = created_vms.iter().map(|vm| vm.id.clone()).collect();
     {
-        let db = db.lock_db();
+        let db = state.lock_db();
         let _ = db.put_env(&env);
-        for vm in &created_vms {
-            let _ = db.put_vm(vm);
-        }
+        lifetime::record(
+            &db,
+            &env.id,
+            EnvEventKind::Created,
+            format!(
+                "created by {by} with {} VMs, expires in {}s",
+                created_vms.len(),
+                env.expires_at - env.created_at
+            ),
+        );
     }
 
-    refresh_all_hosts(&db, &client).await;
-
-    let detail = EnvDetail {
-        env,
-        vms: created_vms,
-        warnings,
-    };
-
-    (StatusCode::CREATED, Json(ApiResp::success(detail)))
+    state.events.publish(FleetChange::EnvCreated {
+        env: env.id,
+        owner: env.owner,
+    });
+    refresh_all_hosts(&state, &client).await;
+    job.succeed(warnings);
 }
 
 /// GET /api/envs
@@ -411,70 +535,173 @@ pub async fn get_env(State(db): State<CtlState>, Path(id): Path<String>) -> impl
     }
 }
 
-/// DELETE /api/envs/:id
-pub async fn delete_env(State(db): State<CtlState>, Path(id): Path<String>) -> impl IntoResponse {
-    let (vms, hosts) = {
-        let db = db.lock_db();
-        match db.get_env(&id) {
-            Ok(Some(_)) => {}
-            Ok(None) => {
-                return (
-                    StatusCode::NOT_FOUND,
-                    Json(ApiRespEmpty::err(format!("environment not found: {id}"))),
-                );
-            }
-            Err(e) => {
-                return (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(ApiRespEmpty::err(e.to_string())),
-                );
-            }
-        };
-        let vms = db.vms_by_env(&id).unwrap_or_default();
-        let hosts = db.list_hosts().unwrap_or_default();
-        (vms, hosts)
+/// DELETE /api/envs/:id — destroy an env in a background job, which is
+/// returned.
+pub async fn delete_env(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let db = state.lock_db();
+    let env = match db.get_env(&id) {
+        Ok(Some(e)) => e,
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(ApiResp::<Job>::err(format!("environment not found: {id}"))),
+            );
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiResp::<Job>::err(e.to_string())),
+            );
+        }
     };
+    if let Err((code, msg)) = caller.require_owner(&env) {
+        return (code, Json(ApiResp::<Job>::err(msg)));
+    }
+    if let Some(job) = jobs::busy(&db, &id) {
+        return (
+            StatusCode::CONFLICT,
+            Json(ApiResp::<Job>::err(format!(
+                "environment '{id}' is busy with {} job {}",
+                job.kind, job.id
+            ))),
+        );
+    }
+    let steps = db
+        .vms_by_env(&id)
+        .unwrap_or_default()
+        .iter()
+        .map(|vm| format!("destroy {}", vm.name_or_id()))
+        .collect();
+    drop(db);
 
-    let client = agent_client(db.api_key.as_deref(), 30);
-    for vm in &vms {
-        if let Some(host) = hosts.iter().find(|h| h.id == vm.host_id) {
-            let url = format!("http://{}/api/vms/{}", host.addr, vm.id);
-            match client.delete(&url).send().await {
-                Ok(r) if !r.status().is_success() => {
-                    eprintln!(
-                        "[ctl] WARN: agent {} returned {} when deleting VM {}",
-                        host.addr,
-                        r.status(),
-                        vm.id
-                    );
-                }
-                Err(e) => {
-                    eprintln!(
-                        "[ctl] WARN: failed to contact agent {} to delete VM {}: {e}",
-                        host.addr, vm.id
-                    );
-                }
-                _ => {}
-            }
+    let (job, tracker) = match Tracker::start(&state, JobKind::DeleteEnv, &id, &caller.user, steps)
+    {
+        Ok(j) => j,
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiResp::<Job>::err(e.to_string())),
+            );
         }
+    };
+    let state = state.clone();
+    trace::spawn(async move {
+        let warnings = teardown_env(&state, &tracker, &id).await;
+        state.events.publish(FleetChange::EnvDeleted {
+            env: id,
+            owner: env.owner,
+        });
+        tracker.succeed(warnings);
+    });
+
+    (StatusCode::ACCEPTED, Json(ApiResp::success(job)))
+}
+
+/// Destroy env `id` with its VMs and network, recording each VM as a
+/// step of `job`. VMs whose agent fails to destroy them are dropped all
+/// the same; the failures are returned as warnings.
+pub(crate) async fn teardown_env(state: &CtlState, job: &Tracker, id: &str) -> Vec<String> {
+    let (env, vms, hosts) = {
+        let db = state.lock_db();
+        (
+            db.get_env(id).ok().flatten(),
+            db.vms_by_env(id).unwrap_or_default(),
+            db.list_hosts().unwrap_or_default(),
+        )
+    };
+
+    let client = agent_client(state.api_key.as_deref(), 30);
+    let (client, hosts) = (&client, &hosts);
+    let results = fanout::map_by_key(
+        &vms,
+        |vm| vm.host_id.clone(),
+        |vm| async move {
+            let step = format!("destroy {}", vm.name_or_id());
+            job.step(&step, JobState::Running, "");
+            let r = destroy_vm(client, hosts, vm).await;
+            match &r {
+                Ok(()) => job.step(&step, JobState::Succeeded, ""),
+                Err(e) => job.step(&step, JobState::Failed, e.clone()),
+            }
+            r
+        },
+    )
+    .await;
+
+    if let Some(n) = env.and_then(|e| e.network) {
+        teardown_network(client, n.vni, &env_hosts(hosts, &vms)).await;
     }
 
     {
-        let db = db.lock_db();
+        let db = state.lock_db();
         for vm in &vms {
             let _ = db.remove_vm(&vm.id);
         }
-        let _ = db.remove_env(&id);
+        let _ = db.remove_env(id);
     }
 
-    (StatusCode::OK, Json(ApiRespEmpty::ok()))
+    refresh_all_hosts(state, client).await;
+    results.into_iter().filter_map(|r| r.err()).collect()
 }
 
-/// POST /api/envs/:id/stop
-pub async fn stop_env(State(db): State<CtlState>, Path(id): Path<String>) -> impl IntoResponse {
+/// POST /api/envs/:id/stop — power off every VM of an env.
+pub async fn stop_env(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    env_action(&state, &caller, &id, "stop", EnvState::Stopped).await
+}
+
+/// POST /api/envs/:id/start — start (or resume) every VM of an env.
+pub async fn start_env(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    env_action(&state, &caller, &id, "start", EnvState::Active).await
+}
+
+/// POST /api/envs/:id/hibernate — save the memory of every VM of an env
+/// to disk, freeing their CPU and memory.
+pub async fn hibernate_env(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    env_action(&state, &caller, &id, "hibernate", EnvState::Hibernated).await
+}
+
+/// POST /api/envs/:id/resume — resume every suspended or hibernated VM
+/// of an env.
+pub async fn resume_env(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    env_action(&state, &caller, &id, "resume", EnvState::Active).await
+}
+
+/// Relay a lifecycle action to the agents of every VM of an env the
+/// caller may act on, then move the env to `new_state`.
+///
+/// Failures on single VMs are logged rather than returned, so one
+/// unreachable host does not leave the rest of the env half-done; only
+/// an env none of whose VMs made it fails as a whole.
+async fn env_action(
+    state: &CtlState,
+    caller: &Caller,
+    id: &str,
+    action: &str,
+    new_state: EnvState,
+) -> (StatusCode, Json<ApiRespEmpty>) {
     let (mut env, vms, hosts) = {
-        let db = db.lock_db();
-        let env = match db.get_env(&id) {
+        let db = state.lock_db();
+        let env = match db.get_env(id) {
             Ok(Some(e)) => e,
             _ => {
                 return (
@@ -483,91 +710,389 @@ pub async fn stop_env(State(db): State<CtlState>, Path(id): Path<String>) -> imp
                 );
             }
         };
-        let vms = db.vms_by_env(&id).unwrap_or_default();
+        if let Err((code, msg)) = caller.require_owner(&env) {
+            return (code, Json(ApiRespEmpty::err(msg)));
+        }
+        let vms = db.vms_by_env(id).unwrap_or_default();
         let hosts = db.list_hosts().unwrap_or_default();
         (env, vms, hosts)
     };
 
-    let client = agent_client(db.api_key.as_deref(), 30);
-    for vm in &vms {
-        if let Some(host) = hosts.iter().find(|h| h.id == vm.host_id) {
-            let url = format!("http://{}/api/vms/{}/stop", host.addr, vm.id);
-            match client.post(&url).send().await {
-                Ok(r) if r.status().is_success() => {
-                    refresh_vm(&db, &client, host, &vm.id).await;
-                }
-                Ok(r) => {
-                    eprintln!(
-                        "[ctl] WARN: agent {} returned {} when stopping VM {}",
-                        host.addr,
-                        r.status(),
-                        vm.id
-                    );
-                }
-                Err(e) => {
-                    eprintln!(
-                        "[ctl] WARN: failed to contact agent {} to stop VM {}: {e}",
-                        host.addr, vm.id
-                    );
-                }
+    // Guests may take a while to power down or write out their memory
+    let client = agent_client(state.api_key.as_deref(), VM_ACTION_TIMEOUT_SECS);
+    let (client, hosts) = (&client, &hosts);
+    let results = fanout::map_by_key(
+        &vms,
+        |vm| vm.host_id.clone(),
+        |vm| async move {
+            let host = hosts.iter().find(|h| h.id == vm.host_id)?;
+            let url = format!("http://{}/api/vms/{}/{action}", host.addr, vm.id);
+            let (code, Json(body)) = relay::<()>(client.post(&url).send().await, &host.addr).await;
+            if code.is_success() {
+                refresh_vm(state, client, host, &vm.id).await;
+                return None;
             }
-        }
+            let err = body.error.unwrap_or_else(|| code.to_string());
+            warn!("failed to {action} VM {}: {err}", vm.id);
+            Some(format!("{}: {err}", vm.id))
+        },
+    )
+    .await;
+    let errors: Vec<String> = results.into_iter().flatten().collect();
+    // The env only changes state if at least one of its VMs did
+    if !vms.is_empty() && errors.len() == vms.len() {
+        return (
+            StatusCode::BAD_GATEWAY,
+            Json(ApiRespEmpty::err(format!(
+                "failed to {action} any VM of environment '{id}': {}",
+                errors.join("; ")
+            ))),
+        );
     }
 
-    env.state = EnvState::Stopped;
-    let db = db.lock_db();
+    env.state = new_state;
+    let db = state.lock_db();
     let _ = db.put_env(&env);
 
     (StatusCode::OK, Json(ApiRespEmpty::ok()))
 }
 
-/// POST /api/envs/:id/start
-pub async fn start_env(State(db): State<CtlState>, Path(id): Path<String>) -> impl IntoResponse {
+/// POST /api/envs/:id/vms — schedule more VMs into an existing env.
+///
+/// Unnamed specs are named after the env's existing VMs (`vm3`, `vm4`,
+/// ... when `vm1` and `vm2` exist). Returns the whole env.
+pub async fn add_vms(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+    Json(mut req): Json<AddVmsReq>,
+) -> impl IntoResponse {
+    if req.vms.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ApiResp::<EnvDetail>::err("no VMs to add")),
+        );
+    }
+    if let Err(e) = validate_specs(&req.vms) {
+        return (StatusCode::BAD_REQUEST, Json(ApiResp::<EnvDetail>::err(e)));
+    }
+
     let (mut env, vms, hosts) = {
-        let db = db.lock_db();
+        let db = state.lock_db();
         let env = match db.get_env(&id) {
             Ok(Some(e)) => e,
-            _ => {
+            Ok(None) => {
                 return (
                     StatusCode::NOT_FOUND,
-                    Json(ApiRespEmpty::err(format!("environment not found: {id}"))),
+                    Json(ApiResp::<EnvDetail>::err(format!(
+                        "environment not found: {id}"
+                    ))),
+                );
+            }
+            Err(e) => {
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(ApiResp::<EnvDetail>::err(e.to_string())),
                 );
             }
         };
+        if let Err((code, msg)) = caller.require_owner(&env) {
+            return (code, Json(ApiResp::<EnvDetail>::err(msg)));
+        }
+        if let Some(msg) = env_busy(&db, &env) {
+            return (StatusCode::CONFLICT, Json(ApiResp::<EnvDetail>::err(msg)));
+        }
         let vms = db.vms_by_env(&id).unwrap_or_default();
         let hosts = db.list_hosts().unwrap_or_default();
         (env, vms, hosts)
     };
 
-    let client = agent_client(db.api_key.as_deref(), 30);
-    for vm in &vms {
-        if let Some(host) = hosts.iter().find(|h| h.id == vm.host_id) {
-            let url = format!("http://{}/api/vms/{}/start", host.addr, vm.id);
-            match client.post(&url).send().await {
-                Ok(r) if r.status().is_success() => {
-                    refresh_vm(&db, &client, host, &vm.id).await;
-                }
-                Ok(r) => {
-                    eprintln!(
-                        "[ctl] WARN: agent {} returned {} when starting VM {}",
-                        host.addr,
-                        r.status(),
-                        vm.id
-                    );
-                }
-                Err(e) => {
-                    eprintln!(
-                        "[ctl] WARN: failed to contact agent {} to start VM {}: {e}",
-                        host.addr, vm.id
-                    );
-                }
+    if let Some(name) = req
+        .vms
+        .iter()
+        .filter_map(|s| s.name.as_deref())
+        .find(|name| vms.iter().any(|vm| vm.name_or_id() == *name))
+    {
+        return (
+            StatusCode::CONFLICT,
+            Json(ApiResp::<EnvDetail>::err(format!(
+                "environment '{id}' already has a VM named '{name}'"
+            ))),
+        );
+    }
+    let existing: Vec<&str> = vms.iter().map(|vm| vm.name_or_id()).collect();
+    name_specs(&mut req.vms, &existing);
+
+    let overlay_vms = vms.iter().filter(|vm| vm.engine != Engine::Docker).count()
+        + req
+            .vms
+            .iter()
+            .filter(|s| s.engine != Engine::Docker)
+            .count();
+    if env.network.is_some() && overlay_vms > net::OVERLAY_MAX_VMS as usize {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ApiResp::<EnvDetail>::err(format!(
+                "a private network holds at most {} VMs",
+                net::OVERLAY_MAX_VMS
+            ))),
+        );
+    }
+
+    // Like the placeholder of a new env, the env holds what it has and
+    // what it is getting until the VMs are in; this also keeps other
+    // changes out meanwhile
+    {
+        let db = state.lock_db();
+        env = match db.get_env(&id) {
+            Ok(Some(e)) => e,
+            _ => {
+                return (
+                    StatusCode::NOT_FOUND,
+                    Json(ApiResp::<EnvDetail>::err(format!(
+                        "environment not found: {id}"
+                    ))),
+                );
             }
+        };
+        if let Some(msg) = env_busy(&db, &env) {
+            return (StatusCode::CONFLICT, Json(ApiResp::<EnvDetail>::err(msg)));
+        }
+        let want = Usage {
+            envs: 0,
+            ..quota::demand(&req.vms)
+        };
+        if let Err((code, msg)) =
+            quota::check_change(&db, &env.owner, &want, &Usage::default(), None)
+        {
+            return (code, Json(ApiResp::<EnvDetail>::err(msg)));
+        }
+        let current = db.vms_by_env(&id).unwrap_or_default();
+        let held = quota::usage(std::slice::from_ref(&env), &current, |_| true);
+        env.reserved = Some(Usage {
+            envs: 1,
+            cpu: held.cpu + want.cpu,
+            mem: held.mem + want.mem,
+            disk: held.disk + want.disk,
+            vms: held.vms + want.vms,
+        });
+        if let Err(e) = db.put_env(&env) {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiResp::<EnvDetail>::err(e.to_string())),
+            );
         }
     }
 
-    env.state = EnvState::Active;
-    let db = db.lock_db();
-    let _ = db.put_env(&env);
+    let client = agent_client(state.api_key.as_deref(), 30);
+    let host_images = fetch_host_images(&hosts, &client).await;
+    let placements =
+        match scheduler::schedule_env(&hosts, &req.vms, &host_images, &*state.placement, &vms) {
+            Ok(p) => p,
+            Err(e) => {
+                release_reservation(&state, &id);
+                return (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Json(ApiResp::<EnvDetail>::err(e.to_string())),
+                );
+            }
+        };
+
+    let mut warnings = Vec::new();
+    if let Some(n) = &env.network {
+        let mut after = net_host_ids(&vms);
+        after.extend(
+            placements
+                .iter()
+                .filter(|(s, _)| s.engine != Engine::Docker)
+                .map(|(_, p)| p.host_id.clone()),
+        );
+        warnings.extend(repeer_network(&client, n.vni, &hosts, &net_host_ids(&vms), &after).await);
+    }
+
+    let (created, failures) = create_vms(
+        &client,
+        &id,
+        &req.ssh_keys,
+        env.network.as_ref(),
+        &vms,
+        &placements,
+    )
+    .await;
+    if !failures.is_empty() {
+        warn!(
+            "adding VMs to env '{id}': {}/{} VMs failed: {}",
+            failures.len(),
+            placements.len(),
+            failures.join("; ")
+        );
+    }
+    if created.is_empty() {
+        release_reservation(&state, &id);
+        return (
+            StatusCode::BAD_GATEWAY,
+            Json(ApiResp::<EnvDetail>::err(format!(
+                "all VM creation attempts failed: {}",
+                failures.join("; ")
+            ))),
+        );
+    }
+    warnings.extend(failures);
+
+    // Re-read the env: it may have been extended, or have expired,
+    // meanwhile
+    let all = {
+        let db = state.lock_db();
+        match db.get_env(&id) {
+            Ok(Some(e)) => {
+                env = Env {
+                    reserved: None,
+                    ..e
+                };
+                for vm in &created {
+                    let _ = db.put_vm(vm);
+                }
+                env.vm_ids.extend(created.iter().map(|vm| vm.id.clone()));
+                let _ = db.put_env(&env);
+                let names: Vec<&str> = created.iter().map(|vm| vm.name_or_id()).collect();
+                lifetime::record(
+                    &db,
+                    &id,
+                    EnvEventKind::VmAdded,
+                    format!("{} added by {}", names.join(", "), caller.user),
+                );
+                Some(db.vms_by_env(&id).unwrap_or_default())
+            }
+            _ => None,
+        }
+    };
+    let Some(all) = all else {
+        destroy_vms(&client, &hosts, &created).await;
+        return (
+            StatusCode::CONFLICT,
+            Json(ApiResp::<EnvDetail>::err(format!(
+                "environment '{id}' was deleted while VMs were added to it"
+            ))),
+        );
+    };
+
+    refresh_all_hosts(&state, &client).await;
+
+    let detail = EnvDetail {
+        env,
+        vms: all,
+        warnings,
+    };
+    (StatusCode::OK, Json(ApiResp::success(detail)))
+}
+
+/// Why `env` cannot take more VMs right now, if it cannot: a job is
+/// acting on it, or it is still being created or getting other VMs.
+fn env_busy(db: &Db, env: &Env) -> Option<String> {
+    if let Some(job) = jobs::busy(db, &env.id) {
+        return Some(format!(
+            "environment '{}' is busy with {} job {}",
+            env.id, job.kind, job.id
+        ));
+    }
+    env.reserved
+        .is_some()
+        .then(|| format!("environment '{}' is still getting its VMs", env.id))
+}
+
+/// Drop what env `id` reserved for VMs being added, if it still exists.
+fn release_reservation(state: &CtlState, id: &str) {
+    let db = state.lock_db();
+    if let Ok(Some(mut env)) = db.get_env(id) {
+        env.reserved = None;
+        let _ = db.put_env(&env);
+    }
+}
+
+/// DELETE /api/envs/:id/vms/:vm — destroy one VM of an env, by name or ID.
+pub async fn remove_env_vm(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path((id, vm_key)): Path<(String, String)>,
+) -> impl IntoResponse {
+    remove_vm(&state, &caller, &id, &vm_key).await
+}
+
+/// Destroy VM `vm_key` (a name or ID) of env `id` and drop it from the
+/// env, shrinking the env network if the VM's host is left without
+/// any of its VMs. A VM its agent fails to destroy is kept.
+async fn remove_vm(
+    state: &CtlState,
+    caller: &Caller,
+    id: &str,
+    vm_key: &str,
+) -> (StatusCode, Json<ApiRespEmpty>) {
+    let (vm, vms, hosts) = {
+        let db = state.lock_db();
+        let env = match db.get_env(id) {
+            Ok(Some(e)) => e,
+            Ok(None) => {
+                return (
+                    StatusCode::NOT_FOUND,
+                    Json(ApiRespEmpty::err(format!("environment not found: {id}"))),
+                );
+            }
+            Err(e) => {
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(ApiRespEmpty::err(e.to_string())),
+                );
+            }
+        };
+        if let Err((code, msg)) = caller.require_owner(&env) {
+            return (code, Json(ApiRespEmpty::err(msg)));
+        }
+        let vms = db.vms_by_env(id).unwrap_or_default();
+        let Some(vm) = find_env_vm(&vms, vm_key).cloned() else {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(ApiRespEmpty::err(format!(
+                    "environment '{id}' has no VM '{vm_key}'"
+                ))),
+            );
+        };
+        let hosts = db.list_hosts().unwrap_or_default();
+        (vm, vms, hosts)
+    };
+
+    // A VM the agent may still run stays on record, and on the quota
+    let client = agent_client(state.api_key.as_deref(), 30);
+    if let Err(e) = destroy_vm(&client, &hosts, &vm).await {
+        return (StatusCode::BAD_GATEWAY, Json(ApiRespEmpty::err(e)));
+    }
+
+    let network = {
+        let db = state.lock_db();
+        let _ = db.remove_vm(&vm.id);
+        match db.get_env(id) {
+            Ok(Some(mut env)) => {
+                env.vm_ids.retain(|v| *v != vm.id);
+                let _ = db.put_env(&env);
+                lifetime::record(
+                    &db,
+                    id,
+                    EnvEventKind::VmRemoved,
+                    format!("{} removed by {}", vm.name_or_id(), caller.user),
+                );
+                env.network
+            }
+            _ => None,
+        }
+    };
+
+    if let Some(n) = &network {
+        let after = net_host_ids(vms.iter().filter(|v| v.id != vm.id));
+        for w in repeer_network(&client, n.vni, &hosts, &net_host_ids(&vms), &after).await {
+            warn!("{w}");
+        }
+    }
+
+    refresh_all_hosts(state, &client).await;
 
     (StatusCode::OK, Json(ApiRespEmpty::ok()))
 }
@@ -583,28 +1108,36 @@ pub async fn list_images(State(db): State<CtlState>) -> impl IntoResponse {
 
     let client = agent_client(db.api_key.as_deref(), 30);
     let mut images = Vec::new();
-
-    for host in &hosts {
-        if host.state != HostState::Online {
-            continue;
-        }
-        let url = format!("http://{}/api/images", host.addr);
-        if let Ok(resp) = client.get(&url).send().await
-            && let Ok(body) = resp.json::<ApiResp<Vec<String>>>().await
-            && let Some(names) = body.data
-        {
-            for name in names {
-                images.push(ImageInfo {
-                    name,
-                    host_id: host.id.clone(),
-                });
-            }
+    for (host_id, names) in fetch_host_images(&hosts, &client).await {
+        for name in names {
+            images.push(ImageInfo {
+                name,
+                host_id: host_id.clone(),
+            });
         }
     }
+    images.sort_by(|a, b| (&a.host_id, &a.name).cmp(&(&b.host_id, &b.name)));
 
     Json(ApiResp::success(images))
 }
 
+/// POST /api/images/:name/push — copy an image to other hosts in a
+/// background job, which is returned.
+pub async fn push_image(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(name): Path<String>,
+    Json(req): Json<PushImageReq>,
+) -> impl IntoResponse {
+    if let Err((code, msg)) = caller.require_admin() {
+        return (code, Json(ApiResp::<Job>::err(msg)));
+    }
+    match transfer::start_push(&state, &caller.user, &name, req) {
+        Ok(job) => (StatusCode::ACCEPTED, Json(ApiResp::success(job))),
+        Err((code, msg)) => (code, Json(ApiResp::<Job>::err(msg))),
+    }
+}
+
 // ── Status ──────────────────────────────────────────────────────────
 
 /// GET /api/status
@@ -637,34 +1170,911 @@ pub async fn get_vm(State(db): State<CtlState>, Path(id): Path<String>) -> impl
     }
 }
 
+/// DELETE /api/vms/:id — destroy a VM, dropping it from its env.
+pub async fn delete_vm(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let env_id = match state.lock_db().get_vm(&id) {
+        Ok(Some(vm)) => vm.env_id,
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(ApiRespEmpty::err(format!("VM not found: {id}"))),
+            );
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiRespEmpty::err(e.to_string())),
+            );
+        }
+    };
+    remove_vm(&state, &caller, &env_id, &id).await
+}
+
+// ── VM Lifecycle ────────────────────────────────────────────────────
+
+/// POST /api/vms/:id/stop — power off, releasing the VM's CPU and memory.
+pub async fn stop_vm(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    vm_action(&state, &caller, &id, "stop").await
+}
+
+/// POST /api/vms/:id/start
+pub async fn start_vm(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    vm_action(&state, &caller, &id, "start").await
+}
+
+/// POST /api/vms/:id/suspend — pause in place, keeping CPU and memory.
+pub async fn suspend_vm(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    vm_action(&state, &caller, &id, "suspend").await
+}
+
+/// POST /api/vms/:id/resume — resume a suspended or hibernated VM.
+pub async fn resume_vm(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    vm_action(&state, &caller, &id, "resume").await
+}
+
+/// POST /api/vms/:id/hibernate — save memory to disk, freeing CPU and memory.
+pub async fn hibernate_vm(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    vm_action(&state, &caller, &id, "hibernate").await
+}
+
+/// POST /api/vms/:id/reset — hard reset, like pressing the reset button.
+pub async fn reset_vm(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    vm_action(&state, &caller, &id, "reset").await
+}
+
+/// POST /api/vms/:id/reboot — soft reboot through the guest.
+pub async fn reboot_vm(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    vm_action(&state, &caller, &id, "reboot").await
+}
+
+/// Relay a lifecycle action to the agent of a VM the caller may act
+/// on, then refresh the VM's state.
+async fn vm_action(
+    state: &CtlState,
+    caller: &Caller,
+    id: &str,
+    action: &str,
+) -> (StatusCode, Json<ApiRespEmpty>) {
+    let host = match locate_vm(state, caller, id) {
+        Ok((_, h)) => h,
+        Err((code, msg)) => return (code, Json(ApiRespEmpty::err(msg))),
+    };
+
+    let client = agent_client(state.api_key.as_deref(), VM_ACTION_TIMEOUT_SECS);
+    let url = format!("http://{}/api/vms/{id}/{action}", host.addr);
+    let resp = relay(client.post(&url).send().await, &host.addr).await;
+    refresh_vm(state, &client, &host, id).await;
+    resp
+}
+
+// ── VM Snapshots ────────────────────────────────────────────────────
+
+/// POST /api/vms/:id/snapshots — take a disk snapshot on the VM's host.
+pub async fn create_snapshot(
+    State(db): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+    Json(req): Json<SnapshotReq>,
+) -> impl IntoResponse {
+    if let Err(e) = validate_name(&req.name, "snapshot name") {
+        return (StatusCode::BAD_REQUEST, Json(ApiRespEmpty::err(e)));
+    }
+    let host = match locate_vm(&db, &caller, &id) {
+        Ok((_, h)) => h,
+        Err((code, msg)) => return (code, Json(ApiRespEmpty::err(msg))),
+    };
+
+    let client = agent_client(db.api_key.as_deref(), 300);
+    let url = format!("http://{}/api/vms/{}/snapshots", host.addr, id);
+    relay(client.post(&url).json(&req).send().await, &host.addr).await
+}
+
+/// GET /api/vms/:id/snapshots
+pub async fn list_snapshots(
+    State(db): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let host = match locate_vm(&db, &caller, &id) {
+        Ok((_, h)) => h,
+        Err((code, msg)) => return (code, Json(ApiResp::<Vec<String>>::err(msg))),
+    };
+
+    let client = agent_client(db.api_key.as_deref(), 30);
+    let url = format!("http://{}/api/vms/{}/snapshots", host.addr, id);
+    relay(client.get(&url).send().await, &host.addr).await
+}
+
+/// POST /api/vms/:id/snapshots/:name/rollback
+pub async fn rollback_snapshot(
+    State(db): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path((id, name)): Path<(String, String)>,
+    Query(q): Query<RollbackQuery>,
+) -> impl IntoResponse {
+    if let Err(e) = validate_name(&name, "snapshot name") {
+        return (StatusCode::BAD_REQUEST, Json(ApiRespEmpty::err(e)));
+    }
+    let host = match locate_vm(&db, &caller, &id) {
+        Ok((_, h)) => h,
+        Err((code, msg)) => return (code, Json(ApiRespEmpty::err(msg))),
+    };
+
+    let client = agent_client(db.api_key.as_deref(), 300);
+    let url = format!(
+        "http://{}/api/vms/{}/snapshots/{}/rollback?force={}",
+        host.addr, id, name, q.force
+    );
+    let resp = relay(client.post(&url).send().await, &host.addr).await;
+    refresh_vm(&db, &client, &host, &id).await;
+    resp
+}
+
+/// DELETE /api/vms/:id/snapshots/:name
+pub async fn delete_snapshot(
+    State(db): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path((id, name)): Path<(String, String)>,
+) -> impl IntoResponse {
+    if let Err(e) = validate_name(&name, "snapshot name") {
+        return (StatusCode::BAD_REQUEST, Json(ApiRespEmpty::err(e)));
+    }
+    let host = match locate_vm(&db, &caller, &id) {
+        Ok((_, h)) => h,
+        Err((code, msg)) => return (code, Json(ApiRespEmpty::err(msg))),
+    };
+
+    let client = agent_client(db.api_key.as_deref(), 60);
+    let url = format!("http://{}/api/vms/{}/snapshots/{}", host.addr, id, name);
+    relay(client.delete(&url).send().await, &host.addr).await
+}
+
+// ── Usage ───────────────────────────────────────────────────────────
+
+#[derive(serde::Deserialize)]
+pub struct UsageQuery {
+    /// Only samples taken after this Unix time.
+    #[serde(default)]
+    since: u64,
+}
+
+/// GET /api/vms/:id/usage — the VM's usage history, from its host.
+pub async fn vm_usage(
+    State(db): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+    Query(q): Query<UsageQuery>,
+) -> impl IntoResponse {
+    let host = match locate_vm(&db, &caller, &id) {
+        Ok((_, h)) => h,
+        Err((code, msg)) => return (code, Json(ApiResp::<Vec<UsageSample>>::err(msg))),
+    };
+
+    let client = agent_client(db.api_key.as_deref(), 30);
+    let url = format!(
+        "http://{}/api/vms/{}/usage?since={}",
+        host.addr, id, q.since
+    );
+    relay(client.get(&url).send().await, &host.addr).await
+}
+
+/// GET /api/hosts/:id/usage — the combined usage history of the host's VMs.
+pub async fn host_usage(
+    State(db): State<CtlState>,
+    Path(id): Path<String>,
+    Query(q): Query<UsageQuery>,
+) -> impl IntoResponse {
+    let host = match db.lock_db().get_host(&id) {
+        Ok(Some(h)) => h,
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(ApiResp::<Vec<UsageSample>>::err(format!(
+                    "host not found: {id}"
+                ))),
+            );
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiResp::err(e.to_string())),
+            );
+        }
+    };
+
+    let client = agent_client(db.api_key.as_deref(), 30);
+    let url = format!("http://{}/api/usage?since={}", host.addr, q.since);
+    relay(client.get(&url).send().await, &host.addr).await
+}
+
 // ── Helpers ─────────────────────────────────────────────────────────
 
-fn now() -> u64 {
+/// Look up a VM the caller may act on, and the host it lives on.
+pub(crate) fn locate_vm(
+    state: &CtlState,
+    caller: &Caller,
+    vm_id: &str,
+) -> std::result::Result<(Vm, Host), (StatusCode, String)> {
+    let db = state.lock_db();
+    let vm = match db.get_vm(vm_id) {
+        Ok(Some(vm)) => vm,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, format!("VM not found: {vm_id}"))),
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    };
+    match db.get_env(&vm.env_id) {
+        Ok(Some(env)) => caller.require_owner(&env)?,
+        // A VM without an env is an orphan only admins may touch
+        Ok(None) => caller.require_admin()?,
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+    match db.get_host(&vm.host_id) {
+        Ok(Some(host)) => Ok((vm, host)),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            format!("host {} of VM {vm_id} is not registered", vm.host_id),
+        )),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+/// Pass an agent's response (status and envelope) through to the caller.
+pub(crate) async fn relay<T>(
+    resp: reqwest::Result<reqwest::Response>,
+    addr: &str,
+) -> (StatusCode, Json<ApiResp<T>>)
+where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+{
+    match resp {
+        Ok(r) => {
+            let status = r.status();
+            match r.json::<ApiResp<T>>().await {
+                Ok(body) => {
+                    if status.is_server_error() {
+                        metrics::agent_error(addr, "vm_op");
+                    }
+                    (status, Json(body))
+                }
+                Err(e) => {
+                    metrics::agent_error(addr, "vm_op");
+                    (
+                        StatusCode::BAD_GATEWAY,
+                        Json(ApiResp::err(format!(
+                            "invalid response from agent {addr}: {e}"
+                        ))),
+                    )
+                }
+            }
+        }
+        Err(e) => {
+            metrics::agent_error(addr, "vm_op");
+            (
+                StatusCode::BAD_GATEWAY,
+                Json(ApiResp::err(format!("failed to reach agent {addr}: {e}"))),
+            )
+        }
+    }
+}
+
+pub(crate) fn now() -> u64 {
     std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap_or_default()
         .as_secs()
 }
 
+/// Check the VM specs of a request before anything is scheduled.
+pub(crate) fn validate_specs(specs: &[VmSpec]) -> std::result::Result<(), String> {
+    let mut names = HashSet::new();
+    for spec in specs {
+        validate_name(&spec.image, "image")?;
+        if spec.cpu == Some(0) || spec.mem == Some(0) || spec.disk == Some(0) {
+            return Err("cpu, mem, and disk must be > 0 if specified".into());
+        }
+        validate_labels(&spec.node_selector)?;
+        if let Some(name) = &spec.name {
+            validate_name(name, "VM name")?;
+            if !names.insert(name) {
+                return Err(format!("duplicate VM name '{name}'"));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Name every unnamed spec `vm1`, `vm2`, ... by position, skipping
+/// names given explicitly and `reserved` ones.
+pub(crate) fn name_specs(specs: &mut [VmSpec], reserved: &[&str]) {
+    let mut taken: HashSet<String> = specs.iter().filter_map(|s| s.name.clone()).collect();
+    taken.extend(reserved.iter().map(|n| n.to_string()));
+    let mut n = 0;
+    for spec in specs.iter_mut().filter(|s| s.name.is_none()) {
+        let name = loop {
+            n += 1;
+            let name = format!("vm{n}");
+            if !taken.contains(&name) {
+                break name;
+            }
+        };
+        spec.name = Some(name);
+    }
+}
+
+/// The VM of an env named `key`, or with ID `key`.
+pub(crate) fn find_env_vm<'a>(vms: &'a [Vm], key: &str) -> Option<&'a Vm> {
+    vms.iter()
+        .find(|vm| vm.name_or_id() == key)
+        .or_else(|| vms.iter().find(|vm| vm.id == key))
+}
+
+/// SSH keys of a VM: the env's keys followed by the VM's own.
+pub(crate) fn vm_ssh_keys(env_keys: &[String], spec: &VmSpec) -> Vec<String> {
+    let mut keys: Vec<String> = Vec::new();
+    for key in env_keys.iter().chain(&spec.ssh_keys) {
+        if !keys.contains(key) {
+            keys.push(key.clone());
+        }
+    }
+    keys
+}
+
+/// Create scheduled VMs of env `env_id` on their agents.
+///
+/// VMs joining the env network take the lowest overlay addresses not
+/// held by `existing`. Returns the created VMs and a warning for each
+/// VM that could not be created.
+pub(crate) async fn create_vms(
+    client: &reqwest::Client,
+    env_id: &str,
+    env_keys: &[String],
+    network: Option<&EnvNetwork>,
+    existing: &[Vm],
+    placements: &[(VmSpec, scheduler::Placement)],
+) -> (Vec<Vm>, Vec<String>) {
+    let (created, failures) = try_create_vms(
+        client, env_id, env_keys, network, existing, placements, None,
+    )
+    .await;
+    (created, failures.into_iter().map(|f| f.msg).collect())
+}
+
+/// A VM that its agent failed to create.
+pub(crate) struct CreateFailure {
+    pub spec: VmSpec,
+    pub host_id: String,
+    pub msg: String,
+    /// The agent could not be reached or failed on its side, so another
+    /// host may well succeed.
+    pub transient: bool,
+}
+
+/// Hosts `create_env` tries for a VM before giving up on it.
+const CREATE_ATTEMPTS: usize = 3;
+
+/// Create the VMs of a new env, retrying those that fail transiently on
+/// other hosts picked by the scheduler.
+///
+/// A host that failed is not tried again for any VM. When the env has
+/// a network, it is set up on each host before VMs are created there
+/// and finally left only on the hosts that got VMs. Each VM is a step
+/// of `job`, and is saved as soon as it exists so that a restart can
+/// roll the env back. Returns the created VMs, a message for each VM
+/// that could not be created, and network warnings.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn create_env_vms(
+    state: &CtlShared,
+    job: &Tracker,
+    client: &reqwest::Client,
+    hosts: &[Host],
+    host_images: &HashMap<String, HashSet<String>>,
+    env_id: &str,
+    env_keys: &[String],
+    network: Option<&EnvNetwork>,
+    mut placements: Vec<(VmSpec, scheduler::Placement)>,
+) -> (Vec<Vm>, Vec<String>, Vec<String>) {
+    let mut created: Vec<Vm> = Vec::new();
+    let mut failed = Vec::new();
+    let mut warnings = Vec::new();
+    let mut net_up = BTreeSet::new();
+    let mut bad = HashSet::new();
+    let step = |spec: &VmSpec| format!("create {}", spec.name.as_deref().unwrap_or_default());
+    let record = |spec: &VmSpec, r: &std::result::Result<Vm, CreateFailure>| match r {
+        Ok(vm) => {
+            let _ = state.lock_db().put_vm(vm);
+            job.step(
+                &step(spec),
+                JobState::Succeeded,
+                format!("on {}", vm.host_id),
+            );
+        }
+        Err(f) => job.step(&step(spec), JobState::Running, f.msg.clone()),
+    };
+
+    for attempt in 1..=CREATE_ATTEMPTS {
+        if let Some(n) = network {
+            let mut want = net_up.clone();
+            want.extend(
+                placements
+                    .iter()
+                    .filter(|(s, _)| s.engine != Engine::Docker)
+                    .map(|(_, p)| p.host_id.clone()),
+            );
+            warnings.extend(repeer_network(client, n.vni, hosts, &net_up, &want).await);
+            net_up = want;
+        }
+
+        for (spec, p) in &placements {
+            job.step(&step(spec), JobState::Running, format!("on {}", p.host_id));
+        }
+        let (vms, failures) = try_create_vms(
+            client,
+            env_id,
+            env_keys,
+            network,
+            &created,
+            &placements,
+            Some(&record),
+        )
+        .await;
+        created.extend(vms);
+
+        let mut retry = Vec::new();
+        for f in failures {
+            if f.transient && attempt < CREATE_ATTEMPTS {
+                info!(
+                    "creating env '{env_id}': trying another host after: {}",
+                    f.msg
+                );
+                bad.insert(f.host_id);
+                retry.push((f.spec, f.msg));
+            } else {
+                job.step(&step(&f.spec), JobState::Failed, f.msg.clone());
+                failed.push(f.msg);
+            }
+        }
+        if retry.is_empty() {
+            break;
+        }
+
+        let spare = spare_hosts(hosts, &bad, &created);
+        let specs: Vec<VmSpec> = retry.iter().map(|(s, _)| s.clone()).collect();
+        match scheduler::schedule_env(&spare, &specs, host_images, &*state.placement, &created) {
+            Ok(p) => placements = p,
+            Err(e) => {
+                for (spec, msg) in retry {
+                    let msg = format!("{msg} (no other host: {})", e.get_top_msg());
+                    job.step(&step(&spec), JobState::Failed, msg.clone());
+                    failed.push(msg);
+                }
+                break;
+            }
+        }
+    }
+
+    if let Some(n) = network {
+        let after = net_host_ids(&created);
+        warnings.extend(repeer_network(client, n.vni, hosts, &net_up, &after).await);
+    }
+    (created, failed, warnings)
+}
+
+/// Hosts left to retry VMs on: all but those in `bad`, charged with the
+/// `created` VMs since the host snapshot predates them.
+fn spare_hosts(hosts: &[Host], bad: &HashSet<String>, created: &[Vm]) -> Vec<Host> {
+    let mut spare: Vec<Host> = hosts
+        .iter()
+        .filter(|h| !bad.contains(&h.id))
+        .cloned()
+        .collect();
+    for vm in created {
+        if let Some(h) = spare.iter_mut().find(|h| h.id == vm.host_id) {
+            h.resource.cpu_used += vm.cpu;
+            h.resource.mem_used += vm.mem;
+            h.resource.disk_used += vm.disk;
+            h.resource.vm_count += 1;
+        }
+    }
+    spare
+}
+
+/// What to do with the outcome of each VM as soon as it is known.
+type OnCreated<'a> = &'a (dyn Fn(&VmSpec, &std::result::Result<Vm, CreateFailure>) + Sync);
+
+/// Create scheduled VMs on their agents, like [`create_vms`], keeping
+/// what went wrong with each VM that could not be created.
+async fn try_create_vms(
+    client: &reqwest::Client,
+    env_id: &str,
+    env_keys: &[String],
+    network: Option<&EnvNetwork>,
+    existing: &[Vm],
+    placements: &[(VmSpec, scheduler::Placement)],
+    on_created: Option<OnCreated<'_>>,
+) -> (Vec<Vm>, Vec<CreateFailure>) {
+    let taken: HashSet<&str> = existing
+        .iter()
+        .filter_map(|vm| vm.overlay.as_ref().map(|o| o.ip.as_str()))
+        .collect();
+    let mut free_ips = network.into_iter().flat_map(|n| {
+        (0..net::OVERLAY_MAX_VMS)
+            .map(|slot| net::overlay_ip(n.vni, slot))
+            .filter(|ip| !taken.contains(ip.as_str()))
+            .map(|ip| OverlayNic { vni: n.vni, ip })
+    });
+
+    // Overlay addresses are handed out in placement order up front
+    let mut jobs = Vec::with_capacity(placements.len());
+    for (spec, placement) in placements {
+        let overlay = if spec.engine == Engine::Docker {
+            None
+        } else {
+            free_ips.next()
+        };
+        let agent_req = CreateVmReq {
+            vm_id: uuid::Uuid::new_v4().to_string()[..12].to_string(),
+            env_id: env_id.to_string(),
+            image: spec.image.clone(),
+            engine: spec.engine,
+            cpu: spec.cpu.unwrap_or(VM_CPU_DEFAULT),
+            mem: spec.mem.unwrap_or(VM_MEM_DEFAULT),
+            disk: spec.disk.unwrap_or(VM_DISK_DEFAULT),
+            ports: spec.ports.clone(),
+            deny_outgoing: spec.deny_outgoing,
+            ssh_keys: vm_ssh_keys(env_keys, spec),
+            overlay,
+            name: spec.name.clone().unwrap_or_default(),
+            user_data: spec.user_data.clone(),
+            anti_affinity: spec.anti_affinity.clone(),
+            node_selector: spec.node_selector.clone(),
+            ip: None,
+            migrated: false,
+        };
+        jobs.push((spec, placement, agent_req));
+    }
+
+    // Hosts create their VMs concurrently, each one VM at a time
+    let results = fanout::map_by_key(
+        jobs,
+        |(_, placement, _)| placement.host_id.clone(),
+        |(spec, placement, agent_req)| async move {
+            let r = create_vm(client, spec, placement, agent_req).await;
+            if let Some(f) = on_created {
+                f(spec, &r);
+            }
+            r
+        },
+    )
+    .await;
+
+    let mut created = Vec::new();
+    let mut failures = Vec::new();
+    for r in results {
+        match r {
+            Ok(vm) => created.push(vm),
+            Err(f) => failures.push(f),
+        }
+    }
+    (created, failures)
+}
+
+/// Create one scheduled VM on its agent.
+///
+/// When the agent's answer is lost, the VM may exist there all the
+/// same, so it is destroyed before the caller retries it elsewhere or
+/// rolls the env back.
+#[tracing::instrument(skip_all, fields(vm = %agent_req.vm_id, host = %placement.host_id))]
+async fn create_vm(
+    client: &reqwest::Client,
+    spec: &VmSpec,
+    placement: &scheduler::Placement,
+    agent_req: CreateVmReq,
+) -> std::result::Result<Vm, CreateFailure> {
+    let url = format!("http://{}/api/vms", placement.host_addr);
+    let vm_id = agent_req.vm_id.clone();
+    let (msg, transient) = match client.post(&url).json(&agent_req).send().await {
+        Ok(r) if r.status().is_success() => {
+            if let Ok(body) = r.json::<ApiResp<CreateVmResp>>().await
+                && let Some(data) = body.data
+            {
+                return Ok(data.vm);
+            }
+            metrics::agent_error(&placement.host_addr, "create_vm");
+            discard_vm(client, &placement.host_addr, &vm_id).await;
+            (
+                format!("unparseable response from {}", placement.host_addr),
+                false,
+            )
+        }
+        Ok(r) => {
+            let status = r.status();
+            if status.is_server_error() {
+                metrics::agent_error(&placement.host_addr, "create_vm");
+            }
+            let reason = r
+                .json::<ApiResp<CreateVmResp>>()
+                .await
+                .ok()
+                .and_then(|b| b.error)
+                .map(|m| format!(": {m}"))
+                .unwrap_or_default();
+            (
+                format!("agent {} returned {status}{reason}", placement.host_addr),
+                status.is_server_error(),
+            )
+        }
+        Err(e) => {
+            metrics::agent_error(&placement.host_addr, "create_vm");
+            discard_vm(client, &placement.host_addr, &vm_id).await;
+            (
+                format!("failed to reach {}: {e}", placement.host_addr),
+                true,
+            )
+        }
+    };
+    warn!("{msg}");
+    Err(CreateFailure {
+        spec: spec.clone(),
+        host_id: placement.host_id.clone(),
+        msg,
+        transient,
+    })
+}
+
+/// Destroy VM `vm_id` on the agent at `addr` if it exists there.
+///
+/// The agent serializes creations and deletions, so a creation still
+/// under way when the controller gave up on it finishes first.
+async fn discard_vm(client: &reqwest::Client, addr: &str, vm_id: &str) {
+    let url = format!("http://{addr}/api/vms/{vm_id}");
+    match client.delete(&url).send().await {
+        Ok(r) if r.status().is_success() || r.status() == StatusCode::NOT_FOUND => {
+            info!("removed any copy of VM {vm_id} left on {addr}")
+        }
+        Ok(r) => warn!(
+            "agent {addr} returned {} when deleting VM {vm_id}; it may be left behind",
+            r.status()
+        ),
+        Err(e) => warn!("failed to delete VM {vm_id} on {addr}; it may be left behind: {e}"),
+    }
+}
+
+/// Destroy a VM on its agent.
+///
+/// Failures are logged and returned. An agent that no longer has the
+/// VM counts as a success.
+pub(crate) async fn destroy_vm(
+    client: &reqwest::Client,
+    hosts: &[Host],
+    vm: &Vm,
+) -> std::result::Result<(), String> {
+    let Some(host) = hosts.iter().find(|h| h.id == vm.host_id) else {
+        return Ok(());
+    };
+    let url = format!("http://{}/api/vms/{}", host.addr, vm.id);
+    let err = match client.delete(&url).send().await {
+        Ok(r) if r.status().is_success() => return Ok(()),
+        Ok(r) => {
+            if r.status().is_server_error() {
+                metrics::agent_error(&host.addr, "destroy_vm");
+            }
+            format!(
+                "agent {} returned {} when deleting VM {}",
+                host.addr,
+                r.status(),
+                vm.id
+            )
+        }
+        Err(e) => {
+            metrics::agent_error(&host.addr, "destroy_vm");
+            format!(
+                "failed to contact agent {} to delete VM {}: {e}",
+                host.addr, vm.id
+            )
+        }
+    };
+    warn!("{err}");
+    Err(err)
+}
+
+/// Destroy `vms` on their agents, hosts concurrently; see [`destroy_vm`].
+pub(crate) async fn destroy_vms<'a>(
+    client: &reqwest::Client,
+    hosts: &[Host],
+    vms: impl IntoIterator<Item = &'a Vm>,
+) {
+    fanout::map_by_key(
+        vms,
+        |vm| vm.host_id.clone(),
+        |vm| destroy_vm(client, hosts, vm),
+    )
+    .await;
+}
+
 /// Fetch available images from all online hosts.
-async fn fetch_host_images(
+pub(crate) async fn fetch_host_images(
     hosts: &[Host],
     client: &reqwest::Client,
 ) -> HashMap<String, HashSet<String>> {
-    let mut result = HashMap::new();
-    for host in hosts {
-        if host.state != HostState::Online {
-            continue;
+    let online: Vec<&Host> = hosts
+        .iter()
+        .filter(|h| h.state == HostState::Online)
+        .collect();
+    let found = fanout::map(online, |host| host_images(client, host)).await;
+    found.into_iter().flatten().collect()
+}
+
+/// Images available on one host.
+async fn host_images(client: &reqwest::Client, host: &Host) -> Option<(String, HashSet<String>)> {
+    let url = format!("http://{}/api/images", host.addr);
+    let names = match client.get(&url).timeout(PROBE_TIMEOUT).send().await {
+        Ok(resp) => resp
+            .json::<ApiResp<Vec<String>>>()
+            .await
+            .ok()
+            .and_then(|b| b.data),
+        Err(_) => None,
+    };
+    if names.is_none() {
+        metrics::agent_error(&host.addr, "list_images");
+    }
+    Some((host.id.clone(), names?.into_iter().collect()))
+}
+
+/// Hosts running at least one of `vms`.
+pub fn env_hosts<'a>(hosts: &'a [Host], vms: &[Vm]) -> Vec<&'a Host> {
+    hosts
+        .iter()
+        .filter(|h| vms.iter().any(|vm| vm.host_id == h.id))
+        .collect()
+}
+
+/// Create env network `vni` on `hosts`, each peering with all the others.
+///
+/// Returns a warning per host that could not be set up; VMs on such a
+/// host still start but cannot reach the rest of the env.
+pub(crate) async fn setup_network(
+    client: &reqwest::Client,
+    vni: u32,
+    hosts: &[&Host],
+) -> Vec<String> {
+    // VXLAN needs plain IPs, while agents may be registered by hostname
+    let addrs = fanout::map(hosts, |host| async move {
+        let mut it = tokio::net::lookup_host(host.addr.as_str()).await.ok()?;
+        it.next().map(|a| a.ip().to_string())
+    })
+    .await;
+
+    let addrs = &addrs;
+    let puts = fanout::map(hosts.iter().enumerate(), |(i, host)| async move {
+        let peers = addrs
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| *j != i)
+            .filter_map(|(_, ip)| ip.clone())
+            .collect();
+        let url = format!("http://{}/api/networks/{vni}", host.addr);
+        match client.put(&url).json(&NetworkReq { peers }).send().await {
+            Ok(r) if r.status().is_success() => None,
+            Ok(r) => {
+                if r.status().is_server_error() {
+                    metrics::agent_error(&host.addr, "setup_network");
+                }
+                Some(format!(
+                    "agent {} returned {} setting up env network {vni}",
+                    host.addr,
+                    r.status()
+                ))
+            }
+            Err(e) => {
+                metrics::agent_error(&host.addr, "setup_network");
+                Some(format!(
+                    "failed to reach {} to set up env network {vni}: {e}",
+                    host.addr
+                ))
+            }
         }
-        let url = format!("http://{}/api/images", host.addr);
-        if let Ok(resp) = client.get(&url).send().await
-            && let Ok(body) = resp.json::<ApiResp<Vec<String>>>().await
-            && let Some(names) = body.data
-        {
-            result.insert(host.id.clone(), names.into_iter().collect());
+    })
+    .await;
+    let mut warnings: Vec<String> = puts.into_iter().flatten().collect();
+    for (host, ip) in hosts.iter().zip(addrs) {
+        if ip.is_none() {
+            warnings.push(format!(
+                "could not resolve {} for env network {vni}",
+                host.addr
+            ));
         }
     }
-    result
+    warnings
+}
+
+/// Hosts that `vms` put on their env network; containers stay off it.
+pub(crate) fn net_host_ids<'a>(vms: impl IntoIterator<Item = &'a Vm>) -> BTreeSet<String> {
+    vms.into_iter()
+        .filter(|vm| vm.engine != Engine::Docker)
+        .map(|vm| vm.host_id.clone())
+        .collect()
+}
+
+/// Re-peer env network `vni` when the hosts it spans change from
+/// `before` to `after`: every remaining host learns the new peer set,
+/// and hosts left without VMs drop the network.
+pub(crate) async fn repeer_network(
+    client: &reqwest::Client,
+    vni: u32,
+    hosts: &[Host],
+    before: &BTreeSet<String>,
+    after: &BTreeSet<String>,
+) -> Vec<String> {
+    if before == after {
+        return vec![];
+    }
+    let pick = |ids: &BTreeSet<String>| -> Vec<&Host> {
+        hosts.iter().filter(|h| ids.contains(&h.id)).collect()
+    };
+    let warnings = setup_network(client, vni, &pick(after)).await;
+    let dropped = before.difference(after).cloned().collect();
+    teardown_network(client, vni, &pick(&dropped)).await;
+    warnings
+}
+
+/// Remove env network `vni` from `hosts` once its VMs are gone.
+pub async fn teardown_network(client: &reqwest::Client, vni: u32, hosts: &[&Host]) {
+    fanout::map(hosts, |host| async move {
+        let url = format!("http://{}/api/networks/{vni}", host.addr);
+        match client.delete(&url).send().await {
+            Ok(r) if !r.status().is_success() => {
+                if r.status().is_server_error() {
+                    metrics::agent_error(&host.addr, "teardown_network");
+                }
+                warn!(
+                    "agent {} returned {} when removing env network {vni}",
+                    host.addr,
+                    r.status()
+                );
+            }
+            Err(e) => {
+                metrics::agent_error(&host.addr, "teardown_network");
+                warn!(
+                    "failed to contact agent {} to remove env network {vni}: {e}",
+                    host.addr
+                );
+            }
+            _ => {}
+        }
+    })
+    .await;
 }
 
 /// Refresh a single VM's state from the agent and update the controller DB.
@@ -675,36 +2085,362 @@ async fn refresh_vm(state: &CtlState, client: &reqwest::Client, host: &Host, vm_
         && let Some(vm) = body.data
     {
         let db = state.lock_db();
+        let from = db.get_vm(&vm.id).ok().flatten().map(|v| v.state);
         let _ = db.put_vm(&vm);
+        if let Some(from) = from {
+            announce_vm_state(state, &db, &vm, from);
+        }
     }
 }
 
-/// Refresh resource snapshots for all hosts from their agents.
-pub async fn refresh_all_hosts(state: &CtlState, client: &reqwest::Client) {
-    let hosts = {
-        let db = state.lock_db();
-        db.list_hosts().unwrap_or_default()
+/// Publish a VM's move from state `from` to its current one, if it moved.
+fn announce_vm_state(state: &CtlState, db: &Db, vm: &Vm, from: VmState) {
+    if vm.state == from {
+        return;
+    }
+    let owner = db
+        .get_env(&vm.env_id)
+        .ok()
+        .flatten()
+        .map(|e| e.owner)
+        .unwrap_or_default();
+    state.events.publish(FleetChange::VmState {
+        vm: vm.id.clone(),
+        env: vm.env_id.clone(),
+        owner,
+        from,
+        to: vm.state,
+    });
+}
+
+/// Publish a host's move from state `from` to its current one, if it moved.
+pub(crate) fn announce_host_state(state: &CtlState, host: &Host, from: HostState) {
+    if host.state != from {
+        state.events.publish(FleetChange::HostState {
+            host: host.id.clone(),
+            from,
+            to: host.state,
+        });
+    }
+}
+
+/// What a host said when probed.
+struct Probe {
+    /// Whether it answered at all.
+    reachable: bool,
+    /// Its resources, if the answer made sense.
+    resource: Option<Resource>,
+    /// The VMs it runs, if it listed them.
+    vms: Option<Vec<Vm>>,
+}
+
+/// Ask a host for its info and its VMs.
+async fn probe_host(client: &reqwest::Client, host: &Host) -> Probe {
+    let url = format!("http://{}/api/info", host.addr);
+    let resource = match client.get(&url).timeout(PROBE_TIMEOUT).send().await {
+        Ok(resp) => {
+            let info = resp.json::<ApiResp<AgentInfo>>().await.ok();
+            info.and_then(|b| b.data).map(|i| i.resource)
+        }
+        Err(_) => {
+            metrics::agent_error(&host.addr, "probe");
+            return Probe {
+                reachable: false,
+                resource: None,
+                vms: None,
+            };
+        }
+    };
+    let url = format!("http://{}/api/vms", host.addr);
+    let vms = match client.get(&url).timeout(PROBE_TIMEOUT).send().await {
+        Ok(resp) => resp
+            .json::<ApiResp<Vec<Vm>>>()
+            .await
+            .ok()
+            .and_then(|b| b.data),
+        Err(_) => None,
     };
+    Probe {
+        reachable: true,
+        resource,
+        vms,
+    }
+}
 
-    for host in &hosts {
-        let url = format!("http://{}/api/info", host.addr);
-        let mut updated = host.clone();
+/// Refresh resource snapshots and VM states for all hosts from their
+/// agents, publishing the changes.
+pub async fn refresh_all_hosts(state: &CtlState, client: &reqwest::Client) {
+    let hosts = state.lock_db().list_hosts().unwrap_or_default();
+    refresh_hosts(state, client, &hosts).await;
+}
 
-        match client.get(&url).send().await {
-            Ok(resp) => {
-                if let Ok(body) = resp.json::<ApiResp<AgentInfo>>().await
-                    && let Some(info) = body.data
-                {
-                    updated.resource = info.resource;
-                    updated.state = HostState::Online;
-                }
-            }
-            Err(_) => {
-                updated.state = HostState::Offline;
-            }
+/// Refresh the hosts that do not send heartbeats, which tell the
+/// controller the same.
+pub async fn poll_hosts(state: &CtlState, client: &reqwest::Client) {
+    let mut hosts = state.lock_db().list_hosts().unwrap_or_default();
+    hosts.retain(|h| !state.heartbeats.is_live(&h.id));
+    refresh_hosts(state, client, &hosts).await;
+}
+
+/// Probe `hosts` and record what they said.
+///
+/// Reachability only moves hosts between `Online` and `Offline`; the
+/// states an admin sets are kept. Hosts that send heartbeats go
+/// offline when those stop, not when a probe fails: the controller may
+/// not be able to reach them at all.
+async fn refresh_hosts(state: &CtlState, client: &reqwest::Client, hosts: &[Host]) {
+    let probes = fanout::map(hosts, |host| probe_host(client, host)).await;
+
+    let db = state.lock_db();
+    for (host, probe) in hosts.iter().zip(probes) {
+        // The host may have been drained or removed meanwhile
+        let Ok(Some(mut updated)) = db.get_host(&host.id) else {
+            continue;
+        };
+        let from = updated.state;
+        updated.state = match (from, probe.reachable) {
+            (HostState::Offline, true) if probe.resource.is_some() => HostState::Online,
+            (HostState::Online, false) if !state.heartbeats.is_live(&host.id) => HostState::Offline,
+            (s, _) => s,
+        };
+        if let Some(r) = probe.resource {
+            updated.resource = r;
+        }
+        let _ = db.put_host(&updated);
+        announce_host_state(state, &updated, from);
+
+        // VMs the agent does not list may still be being created
+        for live in probe.vms.unwrap_or_default() {
+            update_vm_state(state, &db, &host.id, &live.id, live.state);
+        }
+    }
+}
+
+/// Record that VM `id` is in `live` state on host `host_id`, as its
+/// agent reported, unless it is not known to run there.
+pub(crate) fn update_vm_state(state: &CtlState, db: &Db, host_id: &str, id: &str, live: VmState) {
+    if let Ok(Some(mut vm)) = db.get_vm(id)
+        && vm.host_id == host_id
+        && vm.state != live
+    {
+        let from = vm.state;
+        vm.state = live;
+        let _ = db.put_vm(&vm);
+        announce_vm_state(state, db, &vm, from);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(name: Option<&str>) -> VmSpec {
+        VmSpec {
+            image: "alpine".into(),
+            engine: Engine::Qemu,
+            cpu: None,
+            mem: None,
+            disk: None,
+            ports: vec![],
+            deny_outgoing: false,
+            ssh_keys: vec![],
+            name: name.map(String::from),
+            user_data: None,
+            anti_affinity: None,
+            node_selector: Default::default(),
         }
+    }
+
+    fn vm(id: &str, name: &str) -> Vm {
+        Vm {
+            id: id.into(),
+            env_id: "e1".into(),
+            host_id: "h1".into(),
+            image: "alpine".into(),
+            cpu: 1,
+            mem: 256,
+            disk: 1024,
+            state: VmState::Running,
+            name: name.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn name_specs_skips_taken_names() {
+        let mut specs = vec![spec(None), spec(Some("vm2")), spec(None)];
+        name_specs(&mut specs, &[]);
+        let names: Vec<_> = specs.iter().filter_map(|s| s.name.as_deref()).collect();
+        assert_eq!(names, ["vm1", "vm2", "vm3"]);
+
+        // Specs added to an env skip the names of its VMs
+        let mut specs = vec![spec(None), spec(None)];
+        name_specs(&mut specs, &["vm1", "vm3"]);
+        let names: Vec<_> = specs.iter().filter_map(|s| s.name.as_deref()).collect();
+        assert_eq!(names, ["vm2", "vm4"]);
+    }
 
+    #[test]
+    fn spare_hosts_skip_failed_and_count_created() {
+        let host = |id: &str| Host {
+            id: id.into(),
+            addr: format!("{id}:9100"),
+            engines: vec![Engine::Qemu],
+            ..Default::default()
+        };
+        let hosts = vec![host("h1"), host("h2"), host("h3")];
+        let bad = HashSet::from(["h2".to_string()]);
+        let created = vec![vm("aaa", "web"), vm("bbb", "db")];
+
+        let spare = spare_hosts(&hosts, &bad, &created);
+        let ids: Vec<_> = spare.iter().map(|h| h.id.as_str()).collect();
+        assert_eq!(ids, ["h1", "h3"]);
+        assert_eq!(spare[0].resource.cpu_used, 2);
+        assert_eq!(spare[0].resource.mem_used, 512);
+        assert_eq!(spare[0].resource.vm_count, 2);
+        assert_eq!(spare[1].resource.vm_count, 0);
+    }
+
+    #[test]
+    fn find_env_vm_by_name_or_id() {
+        let vms = vec![vm("aaa", "web"), vm("bbb", "")];
+        assert_eq!(find_env_vm(&vms, "web").unwrap().id, "aaa");
+        assert_eq!(find_env_vm(&vms, "aaa").unwrap().id, "aaa");
+        assert_eq!(find_env_vm(&vms, "bbb").unwrap().id, "bbb");
+        assert!(find_env_vm(&vms, "db").is_none());
+    }
+
+    #[tokio::test]
+    async fn vm_created_after_a_timeout_is_destroyed() {
+        use axum::routing::{delete, post};
+        use std::sync::Arc;
+        type Vms = Arc<tokio::sync::Mutex<HashSet<String>>>;
+
+        // An agent that creates the VM but answers too late; like the
+        // real one, it deletes VMs only once creations are done
+        let vms = Vms::default();
+        let agent = axum::Router::new()
+            .route(
+                "/api/vms",
+                post(
+                    |State(vms): State<Vms>, Json(req): Json<CreateVmReq>| async move {
+                        let mut vms = vms.lock().await;
+                        vms.insert(req.vm_id);
+                        tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    },
+                ),
+            )
+            .route(
+                "/api/vms/{id}",
+                delete(
+                    |State(vms): State<Vms>, Path(id): Path<String>| async move {
+                        vms.lock().await.remove(&id);
+                        StatusCode::OK
+                    },
+                ),
+            )
+            .with_state(vms.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move { axum::serve(listener, agent).await });
+
+        let placement = scheduler::Placement {
+            host_id: "h1".into(),
+            host_addr: addr,
+        };
+        let client = agent_client(None, 1);
+        let (created, failures) = try_create_vms(
+            &client,
+            "e1",
+            &[],
+            None,
+            &[],
+            &[(spec(None), placement)],
+            None,
+        )
+        .await;
+        assert!(created.is_empty());
+        assert!(failures[0].transient);
+        assert!(vms.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn vms_the_agent_fails_to_destroy_stay_on_record() {
+        use axum::routing::delete;
+
+        let agent = axum::Router::new().route(
+            "/api/vms/{id}",
+            delete(|| async { StatusCode::INTERNAL_SERVER_ERROR }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move { axum::serve(listener, agent).await });
+
+        let state = Arc::new(CtlShared::new(
+            Db::open(":memory:").unwrap(),
+            None,
+            Box::new(scheduler::BestFit),
+        ));
+        {
+            let db = state.lock_db();
+            db.put_host(&Host {
+                id: "h1".into(),
+                addr,
+                ..Default::default()
+            })
+            .unwrap();
+            db.put_vm(&vm("aaa", "web")).unwrap();
+            db.put_env(&Env {
+                id: "e1".into(),
+                owner: "alice".into(),
+                vm_ids: vec!["aaa".into()],
+                created_at: 0,
+                expires_at: 0,
+                state: EnvState::Active,
+                network: None,
+                reserved: None,
+            })
+            .unwrap();
+        }
+        let caller = Caller {
+            user: "alice".into(),
+            role: Role::User,
+            token: None,
+            host: None,
+        };
+
+        let (code, _) = remove_vm(&state, &caller, "e1", "web").await;
+        assert_eq!(code, StatusCode::BAD_GATEWAY);
         let db = state.lock_db();
-        let _ = db.put_host(&updated);
+        assert!(db.get_vm("aaa").unwrap().is_some());
+        assert_eq!(db.get_env("e1").unwrap().unwrap().vm_ids, ["aaa"]);
+    }
+
+    #[test]
+    fn envs_take_vms_one_change_at_a_time() {
+        let state = Arc::new(CtlShared::new(
+            Db::open(":memory:").unwrap(),
+            None,
+            Box::new(scheduler::BestFit),
+        ));
+        let mut env = Env {
+            id: "e1".into(),
+            owner: "alice".into(),
+            vm_ids: vec![],
+            created_at: 0,
+            expires_at: 0,
+            state: EnvState::Active,
+            network: None,
+            reserved: None,
+        };
+        assert_eq!(env_busy(&state.lock_db(), &env), None);
+
+        env.reserved = Some(Usage::default());
+        assert!(env_busy(&state.lock_db(), &env).is_some());
+
+        env.reserved = None;
+        Tracker::start(&state, JobKind::DeleteEnv, "e1", "alice", vec![]).unwrap();
+        assert!(env_busy(&state.lock_db(), &env).is_some());
     }
 }
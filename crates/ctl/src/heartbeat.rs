@@ -0,0 +1,291 @@
+//! Hosts that report in on their own.
+//!
+//! Agents started with `--controller` push a heartbeat to [`heartbeat`]
+//! every few seconds; the first one registers the host. Such hosts are
+//! left out of the periodic poll while their heartbeats keep coming,
+//! and are marked offline by [`check_missed`] once `--heartbeat-misses`
+//! of them in a row fail to arrive. Heartbeats are tracked in memory:
+//! after a restart the controller polls every host until it hears from
+//! it again.
+//!
+//! Agents authenticate with their host's key (`tt host key`), which
+//! lets them send that host's heartbeats and nothing else.
+//!
+//! A host added with `tt host add` keeps the address it was added at,
+//! whatever its heartbeats advertise, so that a heartbeat cannot point
+//! the controller at another machine; re-adding it changes it.
+
+use crate::auth::Caller;
+use crate::handler::{CtlState, announce_host_state, now, update_vm_state};
+use axum::extract::{ConnectInfo, Path, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Mutex, MutexGuard};
+use tracing::{debug, info, warn};
+use ttcore::api::{AgentInfo, ApiResp, Heartbeat};
+use ttcore::auth;
+use ttcore::model::{Host, HostState, MAX_HOSTS, validate_name};
+
+/// When each host that sends heartbeats was last heard from.
+#[derive(Default)]
+pub struct Heartbeats(Mutex<HashMap<String, Beat>>);
+
+struct Beat {
+    at: u64,
+    /// Seconds the host said it would wait before the next one.
+    interval: u64,
+}
+
+impl Heartbeats {
+    /// Whether host `id` sends heartbeats and has not missed too many.
+    pub fn is_live(&self, id: &str) -> bool {
+        self.lock().contains_key(id)
+    }
+
+    fn record(&self, id: &str, at: u64, interval: u64) {
+        self.lock().insert(id.to_string(), Beat { at, interval });
+    }
+
+    /// Lock the heartbeats, recovering from poisoning.
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Beat>> {
+        self.0.lock().unwrap_or_else(|e| {
+            warn!("heartbeat mutex was poisoned, recovering");
+            e.into_inner()
+        })
+    }
+
+    /// Forget the hosts that have missed `misses` heartbeats by `now`,
+    /// returning their IDs.
+    fn take_missed(&self, now: u64, misses: u64) -> Vec<String> {
+        let mut beats = self.lock();
+        let missed: Vec<String> = beats
+            .iter()
+            .filter(|(_, b)| now > b.at + b.interval * misses)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in &missed {
+            beats.remove(id);
+        }
+        missed
+    }
+}
+
+/// POST /api/hosts/:id/heartbeat — an agent reporting in (admin or the
+/// host's key).
+///
+/// Registers the host if it is new, otherwise updates its resources and
+/// capabilities (and its address, if it registered itself), brings it
+/// back online if it was offline, and records the VM states that changed.
+pub async fn heartbeat(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Path(id): Path<String>,
+    Json(beat): Json<Heartbeat>,
+) -> impl IntoResponse {
+    if let Err((code, msg)) = caller.require_host(&id) {
+        return (code, Json(ApiResp::<Host>::err(msg)));
+    }
+    if beat.info.host_id != id {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ApiResp::err(format!(
+                "heartbeat for host {id} comes from host {}",
+                beat.info.host_id
+            ))),
+        );
+    }
+    if beat.interval == 0 {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ApiResp::err("heartbeat interval must be at least 1s")),
+        );
+    }
+    let addr = advertised_addr(&beat.addr, peer.ip());
+
+    let host = {
+        let db = state.lock_db();
+        let (host, from) = match db.get_host(&id) {
+            Ok(Some(mut host)) => {
+                let from = host.state;
+                if host.state == HostState::Offline {
+                    host.state = HostState::Online;
+                }
+                refresh(&mut host, addr, beat.info);
+                (host, from)
+            }
+            Ok(None) => {
+                if db.host_count().unwrap_or(0) >= MAX_HOSTS {
+                    return (
+                        StatusCode::CONFLICT,
+                        Json(ApiResp::err(format!(
+                            "fleet limit reached ({MAX_HOSTS} hosts)"
+                        ))),
+                    );
+                }
+                info!("host {id} registered itself from {addr}");
+                let host = Host {
+                    id: id.clone(),
+                    addr,
+                    resource: beat.info.resource,
+                    state: HostState::Online,
+                    engines: beat.info.engines,
+                    storage: beat.info.storage,
+                    registered_at: now(),
+                    labels: beat.info.labels,
+                    self_registered: true,
+                };
+                (host, HostState::Online)
+            }
+            Err(e) => {
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(ApiResp::err(e.to_string())),
+                );
+            }
+        };
+        if let Err(e) = db.put_host(&host) {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiResp::err(e.to_string())),
+            );
+        }
+        announce_host_state(&state, &host, from);
+        for vm in &beat.vms {
+            update_vm_state(&state, &db, &id, &vm.id, vm.state);
+        }
+        host
+    };
+
+    state.heartbeats.record(&id, now(), beat.interval);
+    (StatusCode::OK, Json(ApiResp::success(host)))
+}
+
+/// GET /api/hosts/:id/key — the key host `id` is to send its heartbeats
+/// with (admin only).
+pub async fn host_key(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    if let Err((code, msg)) = caller.require_admin() {
+        return (code, Json(ApiResp::<String>::err(msg)));
+    }
+    if let Err(e) = validate_name(&id, "host ID") {
+        return (StatusCode::BAD_REQUEST, Json(ApiResp::err(e)));
+    }
+    match &state.api_key {
+        Some(key) => (
+            StatusCode::OK,
+            Json(ApiResp::success(auth::host_key(key, &id))),
+        ),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(ApiResp::err(
+                "the controller runs without --api-key: hosts need no key",
+            )),
+        ),
+    }
+}
+
+/// Update `host` from a heartbeat advertising `addr` and `info`.
+fn refresh(host: &mut Host, addr: String, info: AgentInfo) {
+    if host.self_registered {
+        host.addr = addr;
+    } else if host.addr != addr {
+        debug!(
+            "host {} advertises {addr}, keeping {} it was added at",
+            host.id, host.addr
+        );
+    }
+    host.resource = info.resource;
+    host.engines = info.engines;
+    host.storage = info.storage;
+    // Labels set with `tt host add` stay
+    host.labels.extend(info.labels);
+}
+
+/// The address to reach an agent at: the one it advertises, with an
+/// unspecified IP (it listens on all of them) replaced by the one its
+/// heartbeat came from.
+fn advertised_addr(addr: &str, peer: IpAddr) -> String {
+    match addr.parse::<SocketAddr>() {
+        Ok(a) if a.ip().is_unspecified() => SocketAddr::new(peer, a.port()).to_string(),
+        _ => addr.to_string(),
+    }
+}
+
+/// Mark the hosts that stopped sending heartbeats offline.
+pub fn check_missed(state: &CtlState, misses: u64) {
+    for id in state.heartbeats.take_missed(now(), misses) {
+        let db = state.lock_db();
+        let Ok(Some(mut host)) = db.get_host(&id) else {
+            continue;
+        };
+        if host.state != HostState::Online {
+            continue;
+        }
+        warn!("host {id} missed {misses} heartbeats, marking it offline");
+        host.state = HostState::Offline;
+        let _ = db.put_host(&host);
+        announce_host_state(state, &host, HostState::Online);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hosts_are_forgotten_after_missed_heartbeats() {
+        let beats = Heartbeats::default();
+        beats.record("h1", 1000, 10);
+        beats.record("h2", 1025, 10);
+        assert!(beats.is_live("h1"));
+        assert!(!beats.is_live("h3"));
+
+        // Three missed heartbeats: 30s after the last one
+        assert!(beats.take_missed(1030, 3).is_empty());
+        assert_eq!(beats.take_missed(1031, 3), ["h1"]);
+        assert!(!beats.is_live("h1"));
+        assert!(beats.is_live("h2"));
+    }
+
+    #[test]
+    fn only_self_registered_hosts_follow_their_address() {
+        let info = || AgentInfo {
+            host_id: "h1".into(),
+            resource: Default::default(),
+            engines: vec![],
+            storage: ttcore::model::Storage::File,
+            images: vec![],
+            labels: Default::default(),
+        };
+        let mut added = Host {
+            id: "h1".into(),
+            addr: "10.0.0.1:9100".into(),
+            ..Default::default()
+        };
+        refresh(&mut added, "10.9.9.9:9100".into(), info());
+        assert_eq!(added.addr, "10.0.0.1:9100");
+
+        let mut joined = Host {
+            self_registered: true,
+            ..added
+        };
+        refresh(&mut joined, "10.0.0.2:9100".into(), info());
+        assert_eq!(joined.addr, "10.0.0.2:9100");
+    }
+
+    #[test]
+    fn unspecified_addresses_take_the_peer_ip() {
+        let peer: IpAddr = "10.0.0.7".parse().unwrap();
+        assert_eq!(advertised_addr("0.0.0.0:9100", peer), "10.0.0.7:9100");
+        assert_eq!(advertised_addr("[::]:9100", peer), "10.0.0.7:9100");
+        assert_eq!(advertised_addr("10.0.0.2:9100", peer), "10.0.0.2:9100");
+        assert_eq!(advertised_addr("node-1:9100", peer), "node-1:9100");
+    }
+}
@@ -0,0 +1,309 @@
+//! Background jobs for long-running operations.
+//!
+//! Creating or deleting an env, migrating a VM and pushing an image can
+//! take much longer than an HTTP request should stay open. Their handlers check the
+//! request, start a [`Job`] and answer `202 Accepted` with it; the work
+//! goes on in a task that records the progress of each step in the DB,
+//! where `GET /api/jobs/{id}` reads it.
+//!
+//! Jobs outlive the controller: on startup, [`recover`] takes over the
+//! jobs the previous run left unfinished. An env that was being deleted
+//! is deleted, one that was being created is rolled back, and a VM that
+//! was being moved is switched to the host found to hold it. Image
+//! pushes are failed, to be started again.
+
+use crate::auth::Caller;
+use crate::db::Db;
+use crate::handler::{CtlState, now, teardown_env};
+use crate::migrate;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use ruc::*;
+use tracing::{info, warn};
+use ttcore::api::*;
+use ttcore::model::*;
+
+/// How long finished jobs are kept, in seconds.
+pub const JOB_RETENTION: u64 = 7 * 24 * 3600;
+
+/// Records the progress of a running job in the DB.
+pub(crate) struct Tracker {
+    state: CtlState,
+    id: String,
+}
+
+impl Tracker {
+    /// Persist a new running job whose `steps` are all pending.
+    pub(crate) fn start(
+        state: &CtlState,
+        kind: JobKind,
+        target: &str,
+        owner: &str,
+        steps: Vec<String>,
+    ) -> Result<(Job, Self)> {
+        Self::start_locked(state, &state.lock_db(), kind, target, owner, steps)
+    }
+
+    /// [`Tracker::start`] for callers holding the DB lock, so that no
+    /// conflicting job can start between their [`busy`] check and this one.
+    pub(crate) fn start_locked(
+        state: &CtlState,
+        db: &Db,
+        kind: JobKind,
+        target: &str,
+        owner: &str,
+        steps: Vec<String>,
+    ) -> Result<(Job, Self)> {
+        let job = Job {
+            id: uuid::Uuid::new_v4().to_string()[..12].to_string(),
+            kind,
+            target: target.to_string(),
+            owner: owner.to_string(),
+            state: JobState::Running,
+            steps: steps
+                .into_iter()
+                .map(|name| JobStep {
+                    name,
+                    state: JobState::Pending,
+                    detail: String::new(),
+                })
+                .collect(),
+            warnings: vec![],
+            error: None,
+            created_at: now(),
+            finished_at: 0,
+        };
+        db.put_job(&job).c(d!())?;
+        state.events.publish(FleetChange::Job { job: job.clone() });
+        let tracker = Self {
+            state: state.clone(),
+            id: job.id.clone(),
+        };
+        Ok((job, tracker))
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Job)) {
+        let db = self.state.lock_db();
+        if let Ok(Some(mut job)) = db.get_job(&self.id) {
+            f(&mut job);
+            if let Err(e) = db.put_job(&job) {
+                warn!("failed to record job {}: {e}", self.id);
+            }
+            self.state.events.publish(FleetChange::Job { job });
+        }
+    }
+
+    /// Move step `name` to `state`, adding it if the job did not plan it.
+    pub(crate) fn step(&self, name: &str, state: JobState, detail: impl Into<String>) {
+        let detail = detail.into();
+        self.update(|job| match job.steps.iter_mut().find(|s| s.name == name) {
+            Some(s) => {
+                s.state = state;
+                s.detail = detail;
+            }
+            None => job.steps.push(JobStep {
+                name: name.to_string(),
+                state,
+                detail,
+            }),
+        });
+    }
+
+    pub(crate) fn succeed(&self, warnings: Vec<String>) {
+        self.update(|job| {
+            job.state = JobState::Succeeded;
+            job.warnings.extend(warnings);
+            job.finished_at = now();
+        });
+    }
+
+    /// Fail the job, along with the steps it was in the middle of.
+    pub(crate) fn fail(&self, error: impl Into<String>) {
+        let error = error.into();
+        self.update(|job| {
+            for s in &mut job.steps {
+                if s.state == JobState::Running {
+                    s.state = JobState::Failed;
+                }
+            }
+            job.state = JobState::Failed;
+            job.error = Some(error);
+            job.finished_at = now();
+        });
+    }
+}
+
+/// The unfinished job acting on env or VM `target`, if any.
+pub(crate) fn busy(db: &Db, target: &str) -> Option<Job> {
+    db.list_jobs()
+        .unwrap_or_default()
+        .into_iter()
+        // Image pushes target images, whose names envs may share
+        .find(|j| !j.state.is_finished() && j.kind != JobKind::PushImage && j.target == target)
+}
+
+/// Take over the jobs a previous run of the controller left unfinished.
+pub fn recover(state: &CtlState) {
+    let jobs = state.lock_db().list_jobs().unwrap_or_default();
+    for job in jobs.into_iter().filter(|j| !j.state.is_finished()) {
+        info!("resuming {} job {} on {}", job.kind, job.id, job.target);
+        let tracker = Tracker {
+            state: state.clone(),
+            id: job.id.clone(),
+        };
+        match job.kind {
+            JobKind::DeleteEnv => {
+                let owner = match state.lock_db().get_env(&job.target) {
+                    Ok(Some(env)) => env.owner,
+                    _ => job.owner,
+                };
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let warnings = teardown_env(&state, &tracker, &job.target).await;
+                    state.events.publish(FleetChange::EnvDeleted {
+                        env: job.target,
+                        owner,
+                    });
+                    tracker.succeed(warnings);
+                });
+            }
+            JobKind::CreateEnv => {
+                // The env is only written out in full once all its VMs exist
+                let env = state.lock_db().get_env(&job.target).ok().flatten();
+                if env.is_some_and(|e| !e.vm_ids.is_empty()) {
+                    tracker.succeed(vec![]);
+                    continue;
+                }
+                let state = state.clone();
+                tokio::spawn(async move {
+                    teardown_env(&state, &tracker, &job.target).await;
+                    tracker.fail("interrupted by a controller restart; the env was rolled back");
+                });
+            }
+            JobKind::MigrateVm => {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    match migrate::recover_move(&state, &job.target).await {
+                        Ok(done) => {
+                            for s in &job.steps {
+                                tracker.step(&s.name, JobState::Succeeded, "");
+                            }
+                            tracker.succeed(vec![done]);
+                        }
+                        Err(e) => tracker.fail(e),
+                    }
+                });
+            }
+            // Targets clear their partial copies on the next push
+            JobKind::PushImage => {
+                tracker.fail("interrupted by a controller restart; push the image again");
+            }
+        }
+    }
+}
+
+/// Prune finished jobs older than [`JOB_RETENTION`].
+pub fn prune(db: &Db) {
+    if let Err(e) = db.prune_jobs(now().saturating_sub(JOB_RETENTION)) {
+        warn!("failed to prune jobs: {e}");
+    }
+}
+
+/// GET /api/jobs — the caller's jobs (everyone's for admins), newest first.
+pub async fn list_jobs(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+) -> impl IntoResponse {
+    let db = state.lock_db();
+    match db.list_jobs() {
+        Ok(jobs) => {
+            let jobs: Vec<Job> = jobs
+                .into_iter()
+                .filter(|j| caller.is_admin() || j.owner == caller.user)
+                .collect();
+            (StatusCode::OK, Json(ApiResp::success(jobs)))
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResp::<Vec<Job>>::err(e.to_string())),
+        ),
+    }
+}
+
+/// GET /api/jobs/:id
+pub async fn get_job(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let db = state.lock_db();
+    match db.get_job(&id) {
+        Ok(Some(job)) if caller.is_admin() || job.owner == caller.user => {
+            (StatusCode::OK, Json(ApiResp::success(job)))
+        }
+        Ok(Some(job)) => (
+            StatusCode::FORBIDDEN,
+            Json(ApiResp::<Job>::err(format!(
+                "permission denied: job {id} belongs to {}",
+                job.owner
+            ))),
+        ),
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            Json(ApiResp::<Job>::err(format!("job not found: {id}"))),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResp::<Job>::err(e.to_string())),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::CtlShared;
+    use crate::scheduler::BestFit;
+    use std::sync::Arc;
+
+    fn state() -> CtlState {
+        Arc::new(CtlShared::new(
+            Db::open(":memory:").unwrap(),
+            None,
+            Box::new(BestFit),
+        ))
+    }
+
+    #[test]
+    fn tracker_records_steps_and_failure() {
+        let state = state();
+        let (job, t) = Tracker::start(
+            &state,
+            JobKind::CreateEnv,
+            "e1",
+            "alice",
+            vec!["create vm1".into(), "create vm2".into()],
+        )
+        .unwrap();
+        assert!(busy(&state.lock_db(), "e1").is_some());
+
+        t.step("create vm1", JobState::Succeeded, "on h1");
+        t.step("create vm2", JobState::Running, "on h2");
+        t.step("network", JobState::Running, "");
+        t.fail("h2 is down");
+
+        let job = state.lock_db().get_job(&job.id).unwrap().unwrap();
+        assert_eq!(job.state, JobState::Failed);
+        assert_eq!(job.error.as_deref(), Some("h2 is down"));
+        assert!(job.finished_at > 0);
+        let states: Vec<_> = job.steps.iter().map(|s| s.state).collect();
+        assert_eq!(
+            states,
+            [JobState::Succeeded, JobState::Failed, JobState::Failed]
+        );
+        assert_eq!(job.steps[0].detail, "on h1");
+        assert!(busy(&state.lock_db(), "e1").is_none());
+    }
+}
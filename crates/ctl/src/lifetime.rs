@@ -0,0 +1,268 @@
+//! Environment lifetimes: extension, never-expiring envs and the
+//! per-env event log.
+//!
+//! An env is created with a lifetime of at most [`MAX_LIFETIME`]. Its
+//! owner may push the expiry back any number of times, as long as the
+//! env's total lifetime, counted from creation, stays within their
+//! quotas' `max_lifetime` (default [`MAX_EXTENDED_LIFETIME`]). Admins
+//! are not capped and may mark an env to never expire.
+
+use crate::auth::Caller;
+use crate::db::Db;
+use crate::handler::{CtlState, now};
+use crate::quota;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use tracing::{info, warn};
+use ttcore::api::*;
+use ttcore::model::*;
+
+/// Record an event of an env, logging (not failing) on DB errors.
+pub fn record(db: &Db, env_id: &str, kind: EnvEventKind, message: String) {
+    add_event(
+        db,
+        EnvEvent {
+            env_id: env_id.to_string(),
+            at: now(),
+            kind,
+            message,
+        },
+    );
+}
+
+fn add_event(db: &Db, event: EnvEvent) {
+    if let Err(e) = db.add_event(&event) {
+        warn!(
+            "failed to record {} event of env '{}': {e}",
+            event.kind, event.env_id
+        );
+    }
+}
+
+/// New expiry of an env extended by `by` seconds at `now`.
+///
+/// An env that already expired (but was not yet reaped) is extended
+/// from now, not from its old expiry.
+pub fn extended_expiry(env: &Env, by: u64, now: u64) -> u64 {
+    env.expires_at.max(now).saturating_add(by)
+}
+
+/// Whether an env is within [`EXPIRY_WARNING`] of expiring and has not
+/// been warned about its current expiry yet.
+pub fn expiry_warning_due(env: &Env, events: &[EnvEvent], now: u64) -> bool {
+    if env.expires_at <= now || env.expires_at - now > EXPIRY_WARNING {
+        return false;
+    }
+    let window = env.expires_at - EXPIRY_WARNING;
+    !events
+        .iter()
+        .any(|e| e.kind == EnvEventKind::ExpiryWarning && e.at >= window)
+}
+
+/// Record a warning for every env about to expire.
+///
+/// Runs with the expiry sweep, so each env is warned about once per
+/// expiry; extending it re-arms the warning.
+pub fn warn_expiring(db: &Db, now: u64) {
+    for env in db.list_envs().unwrap_or_default() {
+        let events = db.events_by_env(&env.id).unwrap_or_default();
+        if !expiry_warning_due(&env, &events, now) {
+            continue;
+        }
+        let left = env.expires_at - now;
+        info!("environment '{}' expires in {left}s", env.id);
+        add_event(
+            db,
+            EnvEvent {
+                env_id: env.id,
+                at: now,
+                kind: EnvEventKind::ExpiryWarning,
+                message: format!("expires in {} min", left.div_ceil(60)),
+            },
+        );
+    }
+}
+
+// ── Handlers ────────────────────────────────────────────────────────
+
+/// POST /api/envs/:id/extend — push back an env's expiry.
+pub async fn extend_env(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+    Json(req): Json<ExtendEnvReq>,
+) -> impl IntoResponse {
+    if req.never_expire {
+        if let Err((code, msg)) = caller.require_admin() {
+            return (code, Json(ApiResp::<Env>::err(msg)));
+        }
+    } else if req.by == 0 {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ApiResp::<Env>::err("extension must be > 0 seconds")),
+        );
+    }
+
+    let db = state.lock_db();
+    let mut env = match db.get_env(&id) {
+        Ok(Some(e)) => e,
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(ApiResp::<Env>::err(format!("environment not found: {id}"))),
+            );
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiResp::<Env>::err(e.to_string())),
+            );
+        }
+    };
+    if let Err((code, msg)) = caller.require_owner(&env) {
+        return (code, Json(ApiResp::<Env>::err(msg)));
+    }
+    if env.expires_at == 0 {
+        return (
+            StatusCode::CONFLICT,
+            Json(ApiResp::<Env>::err(format!(
+                "environment '{id}' never expires"
+            ))),
+        );
+    }
+
+    let message = if req.never_expire {
+        env.expires_at = 0;
+        format!("set to never expire by {}", caller.user)
+    } else {
+        let from = env.expires_at.max(now());
+        let expires_at = extended_expiry(&env, req.by, from);
+        if !caller.is_admin() {
+            let cap = match quota::lifetime_cap(&db, &env.owner) {
+                Ok(c) => c,
+                Err((code, msg)) => return (code, Json(ApiResp::<Env>::err(msg))),
+            };
+            if expires_at - env.created_at > cap {
+                return (
+                    StatusCode::FORBIDDEN,
+                    Json(ApiResp::<Env>::err(format!(
+                        "environment '{id}' may live at most {cap}s from creation; \
+                         it has {}s left to extend",
+                        (env.created_at + cap).saturating_sub(from)
+                    ))),
+                );
+            }
+        }
+        env.expires_at = expires_at;
+        format!("extended by {}s by {}", req.by, caller.user)
+    };
+
+    if let Err(e) = db.put_env(&env) {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResp::<Env>::err(e.to_string())),
+        );
+    }
+    record(&db, &id, EnvEventKind::Extended, message);
+
+    (StatusCode::OK, Json(ApiResp::success(env)))
+}
+
+/// GET /api/envs/:id/events — an env's events, oldest first.
+pub async fn env_events(
+    State(state): State<CtlState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let db = state.lock_db();
+    match db.get_env(&id) {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(ApiResp::<Vec<EnvEvent>>::err(format!(
+                    "environment not found: {id}"
+                ))),
+            );
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiResp::<Vec<EnvEvent>>::err(e.to_string())),
+            );
+        }
+    }
+    match db.events_by_env(&id) {
+        Ok(events) => (StatusCode::OK, Json(ApiResp::success(events))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResp::<Vec<EnvEvent>>::err(e.to_string())),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(expires_at: u64) -> Env {
+        Env {
+            id: "e1".into(),
+            owner: "alice".into(),
+            vm_ids: vec![],
+            created_at: 1000,
+            expires_at,
+            state: EnvState::Active,
+            network: None,
+            reserved: None,
+        }
+    }
+
+    fn warning(at: u64) -> EnvEvent {
+        EnvEvent {
+            env_id: "e1".into(),
+            at,
+            kind: EnvEventKind::ExpiryWarning,
+            message: String::new(),
+        }
+    }
+
+    #[test]
+    fn extension_starts_from_later_of_expiry_and_now() {
+        assert_eq!(extended_expiry(&env(5000), 3600, 2000), 8600);
+        // Already past its expiry: extend from now
+        assert_eq!(extended_expiry(&env(5000), 3600, 6000), 9600);
+    }
+
+    #[test]
+    fn expiry_warning_once_per_expiry() {
+        let e = env(10_000);
+        let soon = 10_000 - EXPIRY_WARNING + 60;
+
+        assert!(!expiry_warning_due(&e, &[], 10_000 - EXPIRY_WARNING - 60));
+        assert!(expiry_warning_due(&e, &[], soon));
+        assert!(!expiry_warning_due(&e, &[warning(soon)], soon + 60));
+        // Never-expiring and expired envs are not warned about
+        assert!(!expiry_warning_due(&env(0), &[], soon));
+        assert!(!expiry_warning_due(&e, &[], 10_000));
+
+        // An extension re-arms the warning
+        let extended = env(10_000 + 3600);
+        assert!(expiry_warning_due(&extended, &[warning(soon)], soon + 3600));
+    }
+
+    #[test]
+    fn warn_expiring_records_events() {
+        let db = Db::open(":memory:").unwrap();
+        db.put_env(&env(10_000)).unwrap();
+        let soon = 10_000 - 300;
+
+        warn_expiring(&db, soon);
+        warn_expiring(&db, soon + 60);
+        let events = db.events_by_env("e1").unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, EnvEventKind::ExpiryWarning);
+        assert_eq!(events[0].message, "expires in 5 min");
+    }
+}
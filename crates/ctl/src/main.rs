@@ -3,64 +3,108 @@
 //! The controller manages the fleet of hosts, schedules VM placement,
 //! and exposes an HTTP API for the CLI client and web interface.
 
+mod apply;
+mod audit;
 mod auth;
 mod config;
+mod console;
 mod db;
+mod drain;
+mod events;
+mod fanout;
 mod handler;
+mod heartbeat;
+mod jobs;
+mod lifetime;
+mod metrics;
+mod migrate;
+mod quota;
 mod scheduler;
+mod trace;
+mod transfer;
+mod users;
 mod web;
 
 use axum::Router;
-use axum::routing::{get, post};
+use axum::routing::{delete, get, post, put};
 use clap::Parser;
 use config::Config;
 use db::Db;
 use handler::CtlState;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use tracing::{error, info, warn};
+use ttcore::api::FleetChange;
 
 #[tokio::main]
 async fn main() {
     let cfg = Config::parse();
+    if let Err(e) = ttcore::log::init(cfg.log_format, &cfg.log_level) {
+        eprintln!("Failed to set up logging: {e}");
+        std::process::exit(1);
+    }
 
     std::fs::create_dir_all(&cfg.data_dir).unwrap_or_else(|e| {
-        eprintln!("Failed to create data dir {}: {e}", cfg.data_dir);
+        error!("Failed to create data dir {}: {e}", cfg.data_dir);
         std::process::exit(1);
     });
 
     let db_path = format!("{}/ctl.db", cfg.data_dir);
     let db = Db::open(&db_path).unwrap_or_else(|e| {
-        eprintln!("Failed to open database: {e}");
+        error!("Failed to open database: {e}");
         std::process::exit(1);
     });
 
-    let state: CtlState = Arc::new(handler::CtlShared::new(db, cfg.api_key.clone()));
+    let state: CtlState = Arc::new(handler::CtlShared::new(
+        db,
+        cfg.api_key.clone(),
+        cfg.placement_policy(),
+    ));
+
+    // Pick up jobs the previous run did not finish
+    jobs::recover(&state);
 
-    // Background task: expire old environments
+    // Background task: expire old environments, jobs and audit entries
     let expiry_state = state.clone();
+    let audit_retention_days = cfg.audit_retention_days;
     tokio::spawn(async move {
         loop {
             tokio::time::sleep(std::time::Duration::from_secs(60)).await;
             expire_envs(&expiry_state).await;
+            jobs::prune(&expiry_state.lock_db());
+            audit::prune(&expiry_state.lock_db(), audit_retention_days);
         }
     });
 
-    // Background task: periodic host health check
-    let heartbeat_state = state.clone();
+    // Background task: periodic health check of hosts that do not send
+    // heartbeats
+    let poll_state = state.clone();
     tokio::spawn(async move {
-        let client = handler::agent_client(heartbeat_state.api_key.as_deref(), 10);
+        let client = handler::agent_client(poll_state.api_key.as_deref(), 10);
         loop {
             tokio::time::sleep(std::time::Duration::from_secs(30)).await;
-            handler::refresh_all_hosts(&heartbeat_state, &client).await;
+            handler::poll_hosts(&poll_state, &client).await;
+        }
+    });
+
+    // Background task: mark hosts offline once their heartbeats stop
+    let heartbeat_state = state.clone();
+    let heartbeat_misses = cfg.heartbeat_misses;
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            heartbeat::check_missed(&heartbeat_state, heartbeat_misses);
         }
     });
 
     if cfg.api_key.is_some() {
-        eprintln!("API key authentication enabled");
+        info!("API key authentication enabled (bootstrap key acts as admin)");
     } else {
-        eprintln!("WARNING: no --api-key set, all API endpoints are unauthenticated!");
+        warn!("no --api-key set, all API endpoints are unauthenticated!");
     }
 
     let api_routes = Router::new()
+        .route("/metrics", get(metrics::metrics))
         .route(
             "/api/hosts",
             get(handler::list_hosts).post(handler::register_host),
@@ -69,6 +113,18 @@ async fn main() {
             "/api/hosts/{id}",
             get(handler::get_host).delete(handler::remove_host),
         )
+        .route(
+            "/api/hosts/{id}/drain",
+            get(drain::drain_progress).post(drain::drain_host),
+        )
+        .route("/api/hosts/{id}/undrain", post(drain::undrain_host))
+        .route("/api/hosts/{id}/heartbeat", post(heartbeat::heartbeat))
+        .route("/api/hosts/{id}/key", get(heartbeat::host_key))
+        .route("/api/hosts/{id}/usage", get(handler::host_usage))
+        .route(
+            "/api/hosts/{id}/log-level",
+            get(trace::get_host_log_level).put(trace::set_host_log_level),
+        )
         .route(
             "/api/envs",
             get(handler::list_envs).post(handler::create_env),
@@ -77,43 +133,114 @@ async fn main() {
             "/api/envs/{id}",
             get(handler::get_env).delete(handler::delete_env),
         )
+        .route("/api/envs/{id}/apply", post(apply::apply_env))
+        .route("/api/envs/{id}/vms", post(handler::add_vms))
+        .route("/api/envs/{id}/vms/{vm}", delete(handler::remove_env_vm))
+        .route("/api/envs/{id}/extend", post(lifetime::extend_env))
+        .route("/api/envs/{id}/events", get(lifetime::env_events))
         .route("/api/envs/{id}/stop", post(handler::stop_env))
         .route("/api/envs/{id}/start", post(handler::start_env))
-        .route("/api/vms/{id}", get(handler::get_vm))
+        .route("/api/envs/{id}/hibernate", post(handler::hibernate_env))
+        .route("/api/envs/{id}/resume", post(handler::resume_env))
+        .route(
+            "/api/vms/{id}",
+            get(handler::get_vm).delete(handler::delete_vm),
+        )
+        .route("/api/vms/{id}/stop", post(handler::stop_vm))
+        .route("/api/vms/{id}/start", post(handler::start_vm))
+        .route("/api/vms/{id}/suspend", post(handler::suspend_vm))
+        .route("/api/vms/{id}/resume", post(handler::resume_vm))
+        .route("/api/vms/{id}/hibernate", post(handler::hibernate_vm))
+        .route("/api/vms/{id}/migrate", post(migrate::migrate_vm))
+        .route("/api/vms/{id}/reset", post(handler::reset_vm))
+        .route("/api/vms/{id}/reboot", post(handler::reboot_vm))
+        .route("/api/vms/{id}/console", get(console::console))
+        .route("/api/vms/{id}/vnc", get(console::vnc))
+        .route("/api/vms/{id}/usage", get(handler::vm_usage))
+        .route(
+            "/api/vms/{id}/snapshots",
+            get(handler::list_snapshots).post(handler::create_snapshot),
+        )
+        .route(
+            "/api/vms/{id}/snapshots/{name}",
+            delete(handler::delete_snapshot),
+        )
+        .route(
+            "/api/vms/{id}/snapshots/{name}/rollback",
+            post(handler::rollback_snapshot),
+        )
         .route("/api/images", get(handler::list_images))
+        .route("/api/images/{name}/push", post(handler::push_image))
         .route("/api/status", get(handler::fleet_status))
+        .route("/api/events", get(events::stream_events))
+        .route("/api/jobs", get(jobs::list_jobs))
+        .route("/api/jobs/{id}", get(jobs::get_job))
+        .route("/api/audit", get(audit::list_audit))
+        .route(
+            "/api/log-level",
+            get(trace::get_log_level).put(trace::set_log_level),
+        )
+        .route(
+            "/api/users",
+            get(users::list_users).post(users::create_user),
+        )
+        .route(
+            "/api/users/{name}",
+            put(users::update_user).delete(users::delete_user),
+        )
+        .route(
+            "/api/tokens",
+            get(users::list_tokens).post(users::create_token),
+        )
+        .route("/api/tokens/{id}", delete(users::revoke_token))
+        .route("/api/whoami", get(users::whoami))
+        .route("/api/quotas", get(quota::list_quotas))
+        .route(
+            "/api/quotas/{subject}",
+            get(quota::get_quota)
+                .put(quota::set_quota)
+                .delete(quota::delete_quota),
+        )
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            audit::record,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            auth::authenticate,
+        ))
+        .layer(axum::middleware::from_fn(trace::request_span))
         .with_state(state);
 
-    let api_routes = if let Some(ref key) = cfg.api_key {
-        api_routes.layer(axum::middleware::from_fn(auth::make_auth_layer(
-            key.clone(),
-        )))
-    } else {
-        api_routes
-    };
-
-    let app = Router::new().route("/", get(web::index)).merge(api_routes);
+    let app = Router::new()
+        .route("/", get(web::index))
+        .route("/web/{file}", get(web::asset))
+        .with_state(Arc::new(std::path::PathBuf::from(&cfg.web_dir)))
+        .merge(api_routes);
 
     let listener = tokio::net::TcpListener::bind(&cfg.listen)
         .await
         .unwrap_or_else(|e| {
-            eprintln!("Failed to bind {}: {e}", cfg.listen);
+            error!("Failed to bind {}: {e}", cfg.listen);
             std::process::exit(1);
         });
 
-    eprintln!("tt-ctl listening on {}", cfg.listen);
+    info!("tt-ctl listening on {}", cfg.listen);
 
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .unwrap_or_else(|e| eprintln!("Server error: {e}"));
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .unwrap_or_else(|e| error!("Server error: {e}"));
 
-    eprintln!("tt-ctl shutting down");
+    info!("tt-ctl shutting down");
 }
 
 async fn shutdown_signal() {
     let _ = tokio::signal::ctrl_c().await;
-    eprintln!("received shutdown signal");
+    info!("received shutdown signal");
 }
 
 /// Maximum retries when contacting an agent during expiry cleanup.
@@ -128,18 +255,19 @@ async fn expire_envs(state: &CtlState) {
 
     let expired = {
         let db = state.lock_db();
+        lifetime::warn_expiring(&db, now);
         db.list_envs()
             .unwrap_or_default()
             .into_iter()
             .filter(|e| e.expires_at > 0 && e.expires_at <= now)
-            .map(|e| e.id)
             .collect::<Vec<_>>()
     };
 
     let client = handler::agent_client(state.api_key.as_deref(), 15);
 
-    for env_id in expired {
-        eprintln!("expiring environment: {env_id}");
+    for env in expired {
+        let env_id = env.id;
+        info!("expiring environment: {env_id}");
 
         let (vms, hosts) = {
             let db = state.lock_db();
@@ -159,8 +287,11 @@ async fn expire_envs(state: &CtlState) {
                             break;
                         }
                         Ok(r) => {
-                            eprintln!(
-                                "[ctl] WARN: agent {} returned {} deleting VM {} (attempt {}/{})",
+                            if r.status().is_server_error() {
+                                metrics::agent_error(&host.addr, "destroy_vm");
+                            }
+                            warn!(
+                                "agent {} returned {} deleting VM {} (attempt {}/{})",
                                 host.addr,
                                 r.status(),
                                 vm.id,
@@ -169,8 +300,9 @@ async fn expire_envs(state: &CtlState) {
                             );
                         }
                         Err(e) => {
-                            eprintln!(
-                                "[ctl] WARN: failed to reach {} to delete VM {} (attempt {}/{}): {e}",
+                            metrics::agent_error(&host.addr, "destroy_vm");
+                            warn!(
+                                "failed to reach {} to delete VM {} (attempt {}/{}): {e}",
                                 host.addr,
                                 vm.id,
                                 attempt + 1,
@@ -183,8 +315,8 @@ async fn expire_envs(state: &CtlState) {
                     }
                 }
                 if !ok {
-                    eprintln!(
-                        "[ctl] ERROR: could not delete VM {} on host {} after {} attempts; \
+                    error!(
+                        "could not delete VM {} on host {} after {} attempts; \
                          VM may be orphaned",
                         vm.id,
                         host.addr,
@@ -194,10 +326,21 @@ async fn expire_envs(state: &CtlState) {
             }
         }
 
-        let db = state.lock_db();
-        for vm in &vms {
-            let _ = db.remove_vm(&vm.id);
+        if let Some(n) = &env.network {
+            handler::teardown_network(&client, n.vni, &handler::env_hosts(&hosts, &vms)).await;
         }
-        let _ = db.remove_env(&env_id);
+
+        {
+            let db = state.lock_db();
+            for vm in &vms {
+                let _ = db.remove_vm(&vm.id);
+            }
+            let _ = db.remove_env(&env_id);
+        }
+        metrics::ENV_EXPIRATIONS.inc(&[]);
+        state.events.publish(FleetChange::EnvExpired {
+            env: env_id,
+            owner: env.owner,
+        });
     }
 }
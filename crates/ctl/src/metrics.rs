@@ -0,0 +1,183 @@
+//! `GET /metrics`: the controller's Prometheus metrics.
+//!
+//! Fleet gauges are read from the database when scraped, so they are
+//! as fresh as the last host refresh. Scheduling failures, agent call
+//! failures and env expirations are counted as they happen.
+
+use crate::auth::Caller;
+use crate::handler::CtlState;
+use axum::Extension;
+use axum::Json;
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::{IntoResponse, Response};
+use std::collections::BTreeMap;
+use ttcore::api::ApiResp;
+use ttcore::metrics::{self, CounterVec, Kind, Text};
+use ttcore::model::{Host, Vm};
+
+/// VMs that could not be placed, by why not; see
+/// [`place_vm`](crate::scheduler::place_vm).
+pub static SCHEDULE_FAILURES: CounterVec = CounterVec::new(
+    "tt_ctl_schedule_failures_total",
+    "VMs no host could be found for, by reason.",
+    &["reason"],
+);
+
+static AGENT_ERRORS: CounterVec = CounterVec::new(
+    "tt_ctl_agent_errors_total",
+    "Calls to agents that failed, by agent and operation.",
+    &["agent", "op"],
+);
+
+pub static ENV_EXPIRATIONS: CounterVec = CounterVec::new(
+    "tt_ctl_env_expirations_total",
+    "Environments deleted because their lifetime ran out.",
+    &[],
+);
+
+/// Count a failed call to the agent at `addr`: it could not be reached,
+/// failed with a server error, or answered with something unreadable.
+/// Requests it turned down with a 4xx status are not its failure.
+pub fn agent_error(addr: &str, op: &str) {
+    AGENT_ERRORS.inc(&[addr, op]);
+}
+
+/// GET /metrics — fleet gauges and controller counters, in the
+/// Prometheus text format (admin only).
+pub async fn metrics(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+) -> Response {
+    if let Err((code, msg)) = caller.require_admin() {
+        return (code, Json(ApiResp::<()>::err(msg))).into_response();
+    }
+    let (hosts, envs, vms) = {
+        let db = state.lock_db();
+        (
+            db.list_hosts().unwrap_or_default(),
+            db.env_count().unwrap_or_default(),
+            db.list_vms().unwrap_or_default(),
+        )
+    };
+    (
+        [(CONTENT_TYPE, metrics::CONTENT_TYPE)],
+        render(&hosts, envs, &vms),
+    )
+        .into_response()
+}
+
+const MIB: f64 = 1024.0 * 1024.0;
+
+fn render(hosts: &[Host], envs: usize, vms: &[Vm]) -> String {
+    let mut t = Text::default();
+
+    let mut by_state: BTreeMap<String, u32> = BTreeMap::new();
+    for h in hosts {
+        *by_state.entry(h.state.to_string()).or_default() += 1;
+    }
+    t.family("tt_ctl_hosts", Kind::Gauge, "Registered hosts, by state.");
+    for (state, n) in &by_state {
+        t.sample("tt_ctl_hosts", &[("state", state)], *n as f64);
+    }
+
+    t.gauge("tt_ctl_envs", "Environments.", envs as f64);
+
+    let mut by_state: BTreeMap<(String, String), u32> = BTreeMap::new();
+    for vm in vms {
+        *by_state
+            .entry((vm.engine.to_string(), vm.state.to_string()))
+            .or_default() += 1;
+    }
+    t.family("tt_ctl_vms", Kind::Gauge, "VMs, by engine and state.");
+    for ((engine, state), n) in &by_state {
+        t.sample(
+            "tt_ctl_vms",
+            &[("engine", engine), ("state", state)],
+            *n as f64,
+        );
+    }
+
+    let sum = |f: fn(&Host) -> u32| hosts.iter().map(|h| f(h) as f64).sum::<f64>();
+    t.gauge(
+        "tt_ctl_cpus",
+        "CPUs the hosts offer to VMs.",
+        sum(|h| h.resource.cpu_total),
+    );
+    t.gauge(
+        "tt_ctl_cpus_allocated",
+        "CPUs allocated to VMs.",
+        sum(|h| h.resource.cpu_used),
+    );
+    t.gauge(
+        "tt_ctl_memory_bytes",
+        "Memory the hosts offer to VMs.",
+        sum(|h| h.resource.mem_total) * MIB,
+    );
+    t.gauge(
+        "tt_ctl_memory_allocated_bytes",
+        "Memory allocated to VMs.",
+        sum(|h| h.resource.mem_used) * MIB,
+    );
+    t.gauge(
+        "tt_ctl_disk_bytes",
+        "Disk the hosts offer to VMs.",
+        sum(|h| h.resource.disk_total) * MIB,
+    );
+    t.gauge(
+        "tt_ctl_disk_allocated_bytes",
+        "Disk allocated to VMs.",
+        sum(|h| h.resource.disk_used) * MIB,
+    );
+
+    SCHEDULE_FAILURES.write(&mut t);
+    AGENT_ERRORS.write(&mut t);
+    ENV_EXPIRATIONS.write(&mut t);
+    t.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ttcore::model::*;
+
+    fn host(id: &str, state: HostState) -> Host {
+        Host {
+            id: id.into(),
+            addr: format!("{id}:9100"),
+            resource: Resource {
+                cpu_total: 8,
+                cpu_used: 2,
+                mem_total: 1024,
+                ..Default::default()
+            },
+            state,
+            engines: vec![Engine::Qemu],
+            registered_at: 1000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn render_sums_fleet_and_counts_by_state() {
+        let hosts = [
+            host("h1", HostState::Online),
+            host("h2", HostState::Online),
+            host("h3", HostState::Offline),
+        ];
+        agent_error("metrics-test:9100", "probe");
+        let text = render(&hosts, 4, &[]);
+        for line in [
+            "tt_ctl_hosts{state=\"online\"} 2\n",
+            "tt_ctl_hosts{state=\"offline\"} 1\n",
+            "tt_ctl_envs 4\n",
+            "tt_ctl_cpus 24\n",
+            "tt_ctl_cpus_allocated 6\n",
+            "tt_ctl_memory_bytes 3221225472\n",
+            "tt_ctl_agent_errors_total{agent=\"metrics-test:9100\",op=\"probe\"} 1\n",
+            "# TYPE tt_ctl_env_expirations_total counter\ntt_ctl_env_expirations_total 0\n",
+        ] {
+            assert!(text.contains(line), "missing {line:?} in\n{text}");
+        }
+    }
+}
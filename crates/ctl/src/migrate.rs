@@ -0,0 +1,965 @@
+//! Moving VMs between hosts, e.g. to drain a host for maintenance.
+//!
+//! Running QEMU VMs move **live** unless a cold move is asked for; all
+//! other VMs move **cold**:
+//!
+//! - **live**: the target launches the VM waiting for its state and
+//!   exporting an empty disk; the source mirrors its disk, then its
+//!   memory into it, and the target takes over. The guest only pauses
+//!   for the final switch-over.
+//! - **cold**: the VM is stopped, its disk is copied between agents like
+//!   an image (zvol: a full stream while it still runs, then the
+//!   increment since; file: checksummed chunks), and it is booted on the
+//!   target. Containers are recreated there from their image.
+//!
+//! Draining a host may also **recreate** VMs whose disk cannot be
+//! copied, e.g. because the host is down: they are built afresh from
+//! their image on the target, losing what was on their disk.
+//!
+//! Either way the VM keeps its ID, address and port numbers. Once the
+//! target runs it, the copy on the source is destroyed and the
+//! controller switches the VM to its new host in a single write. On
+//! failure the VM carries on on the source.
+
+use crate::auth::Caller;
+use crate::db::Db;
+use crate::fanout;
+use crate::handler::{
+    CtlState, agent_client, locate_vm, net_host_ids, refresh_all_hosts, repeer_network,
+};
+use crate::jobs::{self, Tracker};
+use crate::lifetime;
+use crate::trace;
+use crate::transfer::{TRANSFER_TIMEOUT_SECS, decode, expect_ok, relay_files};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use tracing::warn;
+use ttcore::api::*;
+use ttcore::model::*;
+
+/// POST /api/vms/:id/migrate — move a VM to another host (admin only)
+/// in a background job, which is returned.
+pub async fn migrate_vm(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+    Json(req): Json<MigrateVmReq>,
+) -> impl IntoResponse {
+    if let Err((code, msg)) = caller.require_admin() {
+        return (code, Json(ApiResp::<Job>::err(msg)));
+    }
+    let (vm, src) = match locate_vm(&state, &caller, &id) {
+        Ok(l) => l,
+        Err((code, msg)) => return (code, Json(ApiResp::err(msg))),
+    };
+    let dst = match state.lock_db().get_host(&req.to) {
+        Ok(Some(h)) => h,
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(ApiResp::err(format!("host not found: {}", req.to))),
+            );
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiResp::err(e.to_string())),
+            );
+        }
+    };
+    if let Err((code, msg)) = check_target(&vm, &src, &dst) {
+        return (code, Json(ApiResp::err(msg)));
+    }
+
+    let how = Move::for_vm(&vm, req.cold);
+    let step = match how {
+        Move::Live => format!("live move to {}", dst.id),
+        _ => format!("cold move to {}", dst.id),
+    };
+    let started = start_move(&state, &state.lock_db(), &vm, &src.id, &caller.user, &step);
+    let (job, tracker) = match started {
+        Ok(j) => j,
+        Err((code, msg)) => return (code, Json(ApiResp::err(msg))),
+    };
+    let state = state.clone();
+    trace::spawn(async move {
+        tracker.step(&step, JobState::Running, "");
+        match move_vm(&state, &vm, &src, &dst, how, &caller.user).await {
+            Ok(_) => {
+                tracker.step(&step, JobState::Succeeded, "");
+                let client = agent_client(state.api_key.as_deref(), 60);
+                refresh_all_hosts(&state, &client).await;
+                tracker.succeed(vec![]);
+            }
+            Err((_, msg)) => tracker.fail(msg),
+        }
+    });
+
+    (StatusCode::ACCEPTED, Json(ApiResp::success(job)))
+}
+
+/// Start a `migrate_vm` job moving `vm` off host `src`, with a single
+/// step. Refused while another job acts on the VM, or once it has left
+/// `src`: checking and recording the job under the caller's `db` guard
+/// keeps a manual migration and a drain from moving it at once.
+pub(crate) fn start_move(
+    state: &CtlState,
+    db: &Db,
+    vm: &Vm,
+    src: &str,
+    owner: &str,
+    step: &str,
+) -> std::result::Result<(Job, Tracker), (StatusCode, String)> {
+    if let Some(job) = jobs::busy(db, &vm.id) {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("VM {} is busy with {} job {}", vm.id, job.kind, job.id),
+        ));
+    }
+    if !db
+        .get_vm(&vm.id)
+        .is_ok_and(|v| v.is_some_and(|v| v.host_id == src))
+    {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("VM {} was moved or removed meanwhile", vm.id),
+        ));
+    }
+    Tracker::start_locked(
+        state,
+        db,
+        JobKind::MigrateVm,
+        &vm.id,
+        owner,
+        vec![step.to_string()],
+    )
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// How a VM gets to its new host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Move {
+    Live,
+    Cold,
+    /// Build it afresh from its image; for VMs whose disk cannot be
+    /// copied, e.g. because their host is down.
+    Recreate,
+}
+
+impl Move {
+    /// Live for running QEMU VMs unless `cold`, cold otherwise.
+    pub(crate) fn for_vm(vm: &Vm, cold: bool) -> Self {
+        if !cold && vm.engine == Engine::Qemu && vm.state == VmState::Running {
+            Self::Live
+        } else {
+            Self::Cold
+        }
+    }
+}
+
+/// Move `vm` from `src` to `dst` and switch it over in the DB, keeping
+/// the env network in step. `dst` must have passed [`check_target`],
+/// or the scheduler for [`Move::Recreate`].
+pub(crate) async fn move_vm(
+    state: &CtlState,
+    vm: &Vm,
+    src: &Host,
+    dst: &Host,
+    how: Move,
+    by: &str,
+) -> std::result::Result<Vm, (StatusCode, String)> {
+    let id = &vm.id;
+    let (env_vms, hosts, network) = {
+        let db = state.lock_db();
+        let network = db
+            .get_env(&vm.env_id)
+            .ok()
+            .flatten()
+            .and_then(|e| e.network);
+        (
+            db.vms_by_env(&vm.env_id).unwrap_or_default(),
+            db.list_hosts().unwrap_or_default(),
+            network,
+        )
+    };
+
+    let client = agent_client(state.api_key.as_deref(), 60);
+
+    // Snapshots are not carried over
+    if how != Move::Recreate && vm.engine != Engine::Docker {
+        let url = format!("http://{}/api/vms/{id}/snapshots", src.addr);
+        let snaps = match expect_ok(client.get(&url).send().await, &src.addr).await {
+            Ok(resp) => decode::<Vec<String>>(resp).await,
+            Err(e) => Err(e),
+        };
+        match snaps {
+            Ok(Some(s)) if !s.is_empty() => {
+                return Err((
+                    StatusCode::CONFLICT,
+                    format!(
+                        "VM {id} has snapshots ({}); delete them before migrating",
+                        s.join(", ")
+                    ),
+                ));
+            }
+            Ok(_) => {}
+            Err(e) => return Err((StatusCode::BAD_GATEWAY, e)),
+        }
+    }
+
+    // The target joins the env network before the VM arrives
+    let before = net_host_ids(&env_vms);
+    let mut during = before.clone();
+    if vm.engine != Engine::Docker {
+        during.insert(dst.id.clone());
+    }
+    if let Some(n) = &network {
+        for w in repeer_network(&client, n.vni, &hosts, &before, &during).await {
+            warn!("{w}");
+        }
+    }
+
+    let slow = agent_client(state.api_key.as_deref(), TRANSFER_TIMEOUT_SECS);
+    let result = match how {
+        Move::Live => live_migrate(&slow, vm, src, dst).await,
+        Move::Cold => cold_migrate(&slow, vm, src, dst).await,
+        Move::Recreate => recreate(&slow, vm, src, dst).await,
+    };
+    let moved = match result {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("moving VM {id} from {} to {} failed: {e}", src.id, dst.id);
+            if let Some(n) = &network {
+                for w in repeer_network(&client, n.vni, &hosts, &during, &before).await {
+                    warn!("{w}");
+                }
+            }
+            return Err((
+                StatusCode::BAD_GATEWAY,
+                format!("failed to migrate VM {id} to {}: {e}", dst.id),
+            ));
+        }
+    };
+
+    {
+        let db = state.lock_db();
+        db.put_vm(&moved)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        let (kind, message) = match how {
+            Move::Live | Move::Cold => (
+                EnvEventKind::VmMigrated,
+                format!(
+                    "{} migrated {} from {} to {} by {by}",
+                    vm.name_or_id(),
+                    if how == Move::Live { "live" } else { "cold" },
+                    src.id,
+                    dst.id
+                ),
+            ),
+            Move::Recreate => (
+                EnvEventKind::VmRecreated,
+                format!(
+                    "{} recreated from image {} on {}, replacing its copy on {}, by {by}",
+                    vm.name_or_id(),
+                    vm.image,
+                    dst.id,
+                    src.id
+                ),
+            ),
+        };
+        lifetime::record(&db, &vm.env_id, kind, message);
+    }
+
+    // The source leaves the env network if no other VM of the env is left
+    if let Some(n) = &network {
+        let after = net_host_ids(
+            env_vms
+                .iter()
+                .map(|v| if v.id == moved.id { &moved } else { v }),
+        );
+        for w in repeer_network(&client, n.vni, &hosts, &during, &after).await {
+            warn!("{w}");
+        }
+    }
+
+    Ok(moved)
+}
+
+/// Check that `vm` can move from `src` to `dst`.
+pub(crate) fn check_target(
+    vm: &Vm,
+    src: &Host,
+    dst: &Host,
+) -> std::result::Result<(), (StatusCode, String)> {
+    if dst.id == src.id {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("VM {} is already on host {}", vm.id, dst.id),
+        ));
+    }
+    if !matches!(
+        vm.state,
+        VmState::Running | VmState::Paused | VmState::Stopped
+    ) {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("cannot migrate VM in state {}", vm.state),
+        ));
+    }
+    if dst.state != HostState::Online {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("host {} is {}", dst.id, dst.state),
+        ));
+    }
+    if !dst.engines.contains(&vm.engine) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("host {} does not support {}", dst.id, vm.engine),
+        ));
+    }
+    if vm
+        .node_selector
+        .iter()
+        .any(|(k, v)| dst.labels.get(k) != Some(v))
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "host {} does not match the node selector {} of VM {}",
+                dst.id,
+                format_labels(&vm.node_selector),
+                vm.id
+            ),
+        ));
+    }
+    if vm.engine != Engine::Docker && dst.storage != src.storage {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "host {} uses {} storage, but {} uses {}",
+                dst.id, dst.storage, src.id, src.storage
+            ),
+        ));
+    }
+    if !dst.resource.can_fit(vm.cpu, vm.mem, vm.disk) {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("insufficient resources on host {}", dst.id),
+        ));
+    }
+    Ok(())
+}
+
+/// Request recreating `vm` on another host from its transferred disk,
+/// with the same ID, address and ports.
+pub(crate) fn recreate_req(vm: &Vm) -> CreateVmReq {
+    // Host ports were handed out in the order of the requested guest ports
+    let mut ports: Vec<(u16, u16)> = vm.port_map.iter().map(|(&g, &h)| (h, g)).collect();
+    ports.sort();
+    CreateVmReq {
+        vm_id: vm.id.clone(),
+        env_id: vm.env_id.clone(),
+        image: vm.image.clone(),
+        engine: vm.engine,
+        cpu: vm.cpu,
+        mem: vm.mem,
+        disk: vm.disk,
+        ports: ports.into_iter().map(|(_, g)| g).collect(),
+        deny_outgoing: vm.deny_outgoing,
+        ssh_keys: vm.ssh_keys.clone(),
+        overlay: vm.overlay.clone(),
+        name: vm.name.clone(),
+        user_data: vm.user_data.clone(),
+        anti_affinity: vm.anti_affinity.clone(),
+        node_selector: vm.node_selector.clone(),
+        ip: Some(vm.ip.clone()),
+        migrated: true,
+    }
+}
+
+/// Stop `vm`, copy its disk to `dst` and boot it there.
+async fn cold_migrate(
+    client: &reqwest::Client,
+    vm: &Vm,
+    src: &Host,
+    dst: &Host,
+) -> std::result::Result<Vm, String> {
+    let src_vm = format!("http://{}/api/vms/{}", src.addr, vm.id);
+    let dst_vm = format!("http://{}/api/vms/{}", dst.addr, vm.id);
+    let running = matches!(vm.state, VmState::Running | VmState::Paused);
+    let has_disk = vm.engine != Engine::Docker;
+
+    // Copy the bulk of a zvol while the VM still runs, so only what it
+    // writes meanwhile is left to send once it is stopped
+    let mut base = None;
+    if has_disk && src.storage == Storage::Zvol && running {
+        match send_zvol(client, src, dst, &vm.id, None).await {
+            Ok(snap) => base = Some(snap),
+            Err(e) => {
+                discard_disk(client, dst, &vm.id).await;
+                return Err(e);
+            }
+        }
+    }
+
+    if running {
+        let resp = client.post(format!("{src_vm}/stop")).send().await;
+        if let Err(e) = expect_ok(resp, &src.addr).await {
+            if has_disk {
+                discard_disk(client, dst, &vm.id).await;
+            }
+            return Err(e);
+        }
+    }
+
+    let created = async {
+        if has_disk {
+            match src.storage {
+                Storage::Zvol => {
+                    send_zvol(client, src, dst, &vm.id, base.as_deref()).await?;
+                }
+                Storage::File => {
+                    let resp = client.get(format!("{src_vm}/disk/manifest")).send().await;
+                    let manifest = decode::<ImageManifest>(expect_ok(resp, &src.addr).await?)
+                        .await?
+                        .ok_or_else(|| format!("agent {} sent no disk manifest", src.addr))?;
+                    let src_disk = format!("{src_vm}/disk");
+                    let dst_disk = format!("{dst_vm}/disk");
+                    relay_files(client, src, &src_disk, dst, &dst_disk, &manifest).await?;
+                }
+            }
+        }
+        let resp = client
+            .post(format!("http://{}/api/vms", dst.addr))
+            .json(&recreate_req(vm))
+            .send()
+            .await;
+        decode::<CreateVmResp>(expect_ok(resp, &dst.addr).await?)
+            .await?
+            .map(|r| r.vm)
+            .ok_or_else(|| format!("agent {} returned no VM", dst.addr))
+    }
+    .await;
+    let mut moved = match created {
+        Ok(v) => v,
+        Err(e) => {
+            if has_disk {
+                discard_disk(client, dst, &vm.id).await;
+            }
+            if running {
+                let resp = client.post(format!("{src_vm}/start")).send().await;
+                if let Err(e) = expect_ok(resp, &src.addr).await {
+                    warn!("failed to restart VM {} on {}: {e}", vm.id, src.id);
+                }
+            }
+            return Err(e);
+        }
+    };
+
+    // A VM that was stopped stays stopped
+    if !running {
+        let resp = client.post(format!("{dst_vm}/stop")).send().await;
+        match expect_ok(resp, &dst.addr).await {
+            Ok(_) => moved.state = VmState::Stopped,
+            Err(e) => warn!("failed to stop VM {} on {}: {e}", vm.id, dst.id),
+        }
+    }
+
+    remove_vm(client, src, &vm.id).await;
+    Ok(moved)
+}
+
+/// Build `vm` afresh on `dst` from its image, then destroy what is
+/// left of it on `src`.
+async fn recreate(
+    client: &reqwest::Client,
+    vm: &Vm,
+    src: &Host,
+    dst: &Host,
+) -> std::result::Result<Vm, String> {
+    let req = CreateVmReq {
+        migrated: false,
+        ..recreate_req(vm)
+    };
+    let resp = client
+        .post(format!("http://{}/api/vms", dst.addr))
+        .json(&req)
+        .send()
+        .await;
+    let mut created = decode::<CreateVmResp>(expect_ok(resp, &dst.addr).await?)
+        .await?
+        .map(|r| r.vm)
+        .ok_or_else(|| format!("agent {} returned no VM", dst.addr))?;
+
+    if vm.state == VmState::Stopped {
+        let resp = client
+            .post(format!("http://{}/api/vms/{}/stop", dst.addr, vm.id))
+            .send()
+            .await;
+        match expect_ok(resp, &dst.addr).await {
+            Ok(_) => created.state = VmState::Stopped,
+            Err(e) => warn!("failed to stop VM {} on {}: {e}", vm.id, dst.id),
+        }
+    }
+
+    // An unreachable source keeps its copy until it is cleaned up by hand
+    remove_vm(client, src, &vm.id).await;
+    Ok(created)
+}
+
+/// IP address the controller reaches `host` at.
+async fn host_ip(host: &Host) -> std::result::Result<String, String> {
+    match tokio::net::lookup_host(host.addr.as_str()).await {
+        Ok(mut it) => it.next().map(|a| a.ip().to_string()),
+        Err(_) => None,
+    }
+    .ok_or_else(|| format!("could not resolve {}", host.addr))
+}
+
+/// Move running QEMU VM `vm` to `dst` while it keeps running.
+async fn live_migrate(
+    client: &reqwest::Client,
+    vm: &Vm,
+    src: &Host,
+    dst: &Host,
+) -> std::result::Result<Vm, String> {
+    let src_vm = format!("http://{}/api/vms/{}", src.addr, vm.id);
+    let dst_vm = format!("http://{}/api/vms/{}", dst.addr, vm.id);
+
+    let resp = client.get(format!("{src_vm}/disk/size")).send().await;
+    let disk_bytes = decode::<u64>(expect_ok(resp, &src.addr).await?)
+        .await?
+        .ok_or_else(|| format!("agent {} sent no disk size", src.addr))?;
+    // QEMU on the source connects to the target directly, and the
+    // target lets nothing else in
+    let host = host_ip(dst).await?;
+    let req = IncomingVmReq {
+        vm: recreate_req(vm),
+        disk_bytes,
+        listen: host.clone(),
+        peer: host_ip(src).await?,
+    };
+    let resp = client
+        .post(format!("http://{}/api/vms/incoming", dst.addr))
+        .json(&req)
+        .send()
+        .await;
+    let ports = decode::<MigrationPorts>(expect_ok(resp, &dst.addr).await?)
+        .await?
+        .ok_or_else(|| format!("agent {} sent no migration ports", dst.addr))?;
+
+    let resp = client
+        .post(format!("{src_vm}/migrate"))
+        .json(&MigrateOutReq { host, ports })
+        .send()
+        .await;
+    if let Err(e) = expect_ok(resp, &src.addr).await {
+        remove_vm(client, dst, &vm.id).await;
+        return Err(e);
+    }
+
+    let finished = async {
+        let resp = client
+            .post(format!("{dst_vm}/incoming/finish"))
+            .send()
+            .await;
+        decode::<Vm>(expect_ok(resp, &dst.addr).await?)
+            .await?
+            .ok_or_else(|| format!("agent {} returned no VM", dst.addr))
+    }
+    .await;
+    let moved = match finished {
+        Ok(v) => v,
+        Err(e) => {
+            // The source still holds the paused VM and its disk
+            remove_vm(client, dst, &vm.id).await;
+            let resp = client.post(format!("{src_vm}/resume")).send().await;
+            if let Err(e) = expect_ok(resp, &src.addr).await {
+                warn!("failed to resume VM {} on {}: {e}", vm.id, src.id);
+            }
+            return Err(e);
+        }
+    };
+
+    remove_vm(client, src, &vm.id).await;
+    Ok(moved)
+}
+
+/// Pipe a fresh zfs snapshot of VM `id`'s disk from `src` into `dst`,
+/// incrementally from snapshot `from`. Returns the snapshot sent.
+async fn send_zvol(
+    client: &reqwest::Client,
+    src: &Host,
+    dst: &Host,
+    id: &str,
+    from: Option<&str>,
+) -> std::result::Result<String, String> {
+    let mut req = client.get(format!("http://{}/api/vms/{id}/disk/send", src.addr));
+    if let Some(from) = from {
+        req = req.query(&[("from", from)]);
+    }
+    let stream = expect_ok(req.send().await, &src.addr).await?;
+    let snap = stream
+        .headers()
+        .get(DISK_SNAPSHOT_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+        .ok_or_else(|| format!("agent {} sent a disk without snapshot name", src.addr))?;
+
+    let resp = client
+        .put(format!("http://{}/api/vms/{id}/disk/recv", dst.addr))
+        .body(reqwest::Body::wrap_stream(stream.bytes_stream()))
+        .send()
+        .await;
+    expect_ok(resp, &dst.addr).await?;
+    Ok(snap)
+}
+
+/// Drop a disk received by `host` for VM `id`, logging failures.
+async fn discard_disk(client: &reqwest::Client, host: &Host, id: &str) {
+    let url = format!("http://{}/api/vms/{id}/disk", host.addr);
+    if let Err(e) = expect_ok(client.delete(&url).send().await, &host.addr).await {
+        warn!("failed to discard disk of VM {id} on {}: {e}", host.id);
+    }
+}
+
+/// Settle VM `id` after the controller was interrupted while moving it.
+///
+/// Every host is asked for its copy of the VM. If a host other than the
+/// one on record holds a copy it finished setting up, the move went
+/// through: the VM is switched to that host and the copy left on the
+/// source destroyed. Otherwise the VM stays on record, is put back in
+/// its recorded state, and half-built copies elsewhere are destroyed.
+/// Returns what became of the VM, as an error if the move was undone.
+pub(crate) async fn recover_move(
+    state: &CtlState,
+    id: &str,
+) -> std::result::Result<String, String> {
+    let (vm, hosts) = {
+        let db = state.lock_db();
+        match db.get_vm(id) {
+            Ok(Some(vm)) => (vm, db.list_hosts().unwrap_or_default()),
+            _ => return Err(format!("VM {id} no longer exists")),
+        }
+    };
+    let client = agent_client(state.api_key.as_deref(), 60);
+    let copies: Vec<(&Host, Vm)> = fanout::map(&hosts, |host| {
+        let client = &client;
+        async move {
+            let url = format!("http://{}/api/vms/{id}", host.addr);
+            let resp = expect_ok(client.get(&url).send().await, &host.addr).await;
+            match resp {
+                Ok(r) => decode::<Vm>(r).await.ok().flatten().map(|v| (host, v)),
+                Err(_) => None,
+            }
+        }
+    })
+    .await
+    .into_iter()
+    .flatten()
+    .collect();
+
+    if let Some((dst, moved)) = copies
+        .iter()
+        .find(|(h, v)| h.id != vm.host_id && v.state != VmState::Creating)
+    {
+        {
+            let db = state.lock_db();
+            db.put_vm(moved).map_err(|e| e.to_string())?;
+            lifetime::record(
+                &db,
+                &vm.env_id,
+                EnvEventKind::VmMigrated,
+                format!(
+                    "{} found on {} after a controller restart and switched over",
+                    vm.name_or_id(),
+                    dst.id
+                ),
+            );
+        }
+        for (host, _) in copies.iter().filter(|(h, _)| h.id != dst.id) {
+            remove_vm(&client, host, id).await;
+        }
+        return Ok(format!(
+            "VM {id} had reached {} and was switched over",
+            dst.id
+        ));
+    }
+
+    let mut on_record = None;
+    for (host, copy) in &copies {
+        if host.id == vm.host_id {
+            on_record = Some((*host, copy));
+        } else {
+            remove_vm(&client, host, id).await;
+        }
+    }
+    let Some((src, copy)) = on_record else {
+        return Err(format!(
+            "interrupted by a controller restart; VM {id} was not found on any reachable host"
+        ));
+    };
+    // The move may have paused or stopped it on the way out
+    let action = match (vm.state, copy.state) {
+        (VmState::Running, VmState::Paused) => Some("resume"),
+        (VmState::Running, VmState::Stopped) => Some("start"),
+        _ => None,
+    };
+    if let Some(action) = action {
+        let url = format!("http://{}/api/vms/{id}/{action}", src.addr);
+        if let Err(e) = expect_ok(client.post(&url).send().await, &src.addr).await {
+            warn!("failed to {action} VM {id} on {}: {e}", src.id);
+        }
+    }
+    Err(format!(
+        "interrupted by a controller restart; VM {id} stays on {}",
+        src.id
+    ))
+}
+
+/// Destroy the copy of VM `id` on `host`, logging failures.
+async fn remove_vm(client: &reqwest::Client, host: &Host, id: &str) {
+    let url = format!("http://{}/api/vms/{id}", host.addr);
+    if let Err(e) = expect_ok(client.delete(&url).send().await, &host.addr).await {
+        warn!("failed to destroy VM {id} on {}: {e}", host.id);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::collections::{BTreeMap, HashMap};
+
+    pub(crate) fn host(id: &str, storage: Storage) -> Host {
+        Host {
+            id: id.into(),
+            addr: format!("{id}:9100"),
+            resource: Resource {
+                cpu_total: 8,
+                mem_total: 8192,
+                disk_total: 102400,
+                ..Default::default()
+            },
+            engines: vec![Engine::Qemu, Engine::Docker],
+            storage,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn vm(engine: Engine, state: VmState) -> Vm {
+        Vm {
+            id: "v1".into(),
+            env_id: "e1".into(),
+            host_id: "h1".into(),
+            image: "alpine".into(),
+            engine,
+            cpu: 2,
+            mem: 1024,
+            disk: 10240,
+            ip: "10.10.0.7".into(),
+            port_map: BTreeMap::from([(22, 20500), (8080, 20501), (80, 20502)]),
+            state,
+            name: "web".into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn check_target_rules() {
+        let src = host("h1", Storage::Zvol);
+        let dst = host("h2", Storage::Zvol);
+        let running = vm(Engine::Qemu, VmState::Running);
+        assert!(check_target(&running, &src, &dst).is_ok());
+
+        let code = |r: std::result::Result<(), (StatusCode, String)>| r.unwrap_err().0;
+        assert_eq!(
+            code(check_target(&running, &src, &src)),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            code(check_target(
+                &vm(Engine::Qemu, VmState::Hibernated),
+                &src,
+                &dst
+            )),
+            StatusCode::CONFLICT
+        );
+        let offline = Host {
+            state: HostState::Offline,
+            ..dst.clone()
+        };
+        assert_eq!(
+            code(check_target(&running, &src, &offline)),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            code(check_target(
+                &vm(Engine::Firecracker, VmState::Running),
+                &src,
+                &dst
+            )),
+            StatusCode::BAD_REQUEST
+        );
+        let full = Host {
+            resource: Resource {
+                cpu_used: 7,
+                ..dst.resource.clone()
+            },
+            ..dst.clone()
+        };
+        assert_eq!(
+            code(check_target(&running, &src, &full)),
+            StatusCode::CONFLICT
+        );
+
+        let pinned = Vm {
+            node_selector: BTreeMap::from([("rack".into(), "b".into())]),
+            ..running.clone()
+        };
+        assert_eq!(
+            code(check_target(&pinned, &src, &dst)),
+            StatusCode::BAD_REQUEST
+        );
+        let rack_b = Host {
+            labels: BTreeMap::from([("rack".into(), "b".into())]),
+            ..dst.clone()
+        };
+        assert!(check_target(&pinned, &src, &rack_b).is_ok());
+
+        // Only VMs with a disk need the same storage on both hosts
+        let file = host("h3", Storage::File);
+        assert_eq!(
+            code(check_target(&running, &src, &file)),
+            StatusCode::BAD_REQUEST
+        );
+        assert!(check_target(&vm(Engine::Docker, VmState::Stopped), &src, &file).is_ok());
+    }
+
+    #[test]
+    fn recreate_req_keeps_address_and_port_order() {
+        let req = recreate_req(&vm(Engine::Qemu, VmState::Running));
+        assert_eq!(req.ip.as_deref(), Some("10.10.0.7"));
+        assert!(req.migrated);
+        // Guest ports in the order their host ports were handed out
+        assert_eq!(req.ports, [22, 8080, 80]);
+    }
+
+    type Vms = std::sync::Arc<std::sync::Mutex<HashMap<String, Vm>>>;
+
+    /// An agent holding `vms` that answers the calls [`recover_move`]
+    /// makes. Returns its address and VMs.
+    async fn fake_agent(vms: Vec<Vm>) -> (String, Vms) {
+        use axum::routing::{get, post};
+
+        let held: Vms = Default::default();
+        held.lock()
+            .unwrap()
+            .extend(vms.into_iter().map(|v| (v.id.clone(), v)));
+        let agent = axum::Router::new()
+            .route(
+                "/api/vms/{id}",
+                get(
+                    |State(vms): State<Vms>, Path(id): Path<String>| async move {
+                        match vms.lock().unwrap().get(&id) {
+                            Some(vm) => (StatusCode::OK, Json(ApiResp::success(vm.clone()))),
+                            None => (StatusCode::NOT_FOUND, Json(ApiResp::err("VM not found"))),
+                        }
+                    },
+                )
+                .delete(
+                    |State(vms): State<Vms>, Path(id): Path<String>| async move {
+                        vms.lock().unwrap().remove(&id);
+                        Json(ApiRespEmpty::ok())
+                    },
+                ),
+            )
+            .route(
+                "/api/vms/{id}/resume",
+                post(
+                    |State(vms): State<Vms>, Path(id): Path<String>| async move {
+                        if let Some(vm) = vms.lock().unwrap().get_mut(&id) {
+                            vm.state = VmState::Running;
+                        }
+                        Json(ApiRespEmpty::ok())
+                    },
+                ),
+            )
+            .with_state(held.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move { axum::serve(listener, agent).await });
+        (addr, held)
+    }
+
+    #[test]
+    fn one_move_at_a_time() {
+        let state: CtlState = std::sync::Arc::new(crate::handler::CtlShared::new(
+            crate::db::Db::open(":memory:").unwrap(),
+            None,
+            Box::new(crate::scheduler::BestFit),
+        ));
+        let vm = vm(Engine::Qemu, VmState::Running);
+        state.lock_db().put_vm(&vm).unwrap();
+        let start = || start_move(&state, &state.lock_db(), &vm, "h1", "admin", "move");
+
+        let (_, tracker) = start().unwrap();
+        let (code, msg) = start().err().unwrap();
+        assert_eq!(code, StatusCode::CONFLICT);
+        assert!(msg.contains("busy with migrate_vm job"), "{msg}");
+
+        // Finished elsewhere before the second move was asked for
+        tracker.succeed(vec![]);
+        let moved = Vm {
+            host_id: "h2".into(),
+            ..vm.clone()
+        };
+        state.lock_db().put_vm(&moved).unwrap();
+        let (code, _) = start().err().unwrap();
+        assert_eq!(code, StatusCode::CONFLICT);
+        assert!(start_move(&state, &state.lock_db(), &moved, "h2", "admin", "move").is_ok());
+    }
+
+    #[tokio::test]
+    async fn recovery_switches_to_the_host_holding_the_vm() {
+        let state: CtlState = std::sync::Arc::new(crate::handler::CtlShared::new(
+            crate::db::Db::open(":memory:").unwrap(),
+            None,
+            Box::new(crate::scheduler::BestFit),
+        ));
+        let on = |host_id: &str, state: VmState| Vm {
+            host_id: host_id.into(),
+            ..vm(Engine::Qemu, state)
+        };
+        let add_hosts = |a1: &str, a2: &str| {
+            let db = state.lock_db();
+            for (id, addr) in [("h1", a1), ("h2", a2)] {
+                let h = Host {
+                    addr: addr.into(),
+                    ..host(id, Storage::Zvol)
+                };
+                db.put_host(&h).unwrap();
+            }
+            db.put_vm(&on("h1", VmState::Running)).unwrap();
+        };
+
+        // The source copy was destroyed before the VM was switched over
+        let (a1, _) = fake_agent(vec![]).await;
+        let (a2, _) = fake_agent(vec![on("h2", VmState::Running)]).await;
+        add_hosts(&a1, &a2);
+        assert!(recover_move(&state, "v1").await.is_ok());
+        assert_eq!(state.lock_db().get_vm("v1").unwrap().unwrap().host_id, "h2");
+
+        // The target had not taken over: the paused source carries on
+        let (a1, src) = fake_agent(vec![on("h1", VmState::Paused)]).await;
+        let (a2, dst) = fake_agent(vec![on("h2", VmState::Creating)]).await;
+        add_hosts(&a1, &a2);
+        let err = recover_move(&state, "v1").await.unwrap_err();
+        assert!(err.contains("stays on h1"), "{err}");
+        assert_eq!(state.lock_db().get_vm("v1").unwrap().unwrap().host_id, "h1");
+        assert_eq!(src.lock().unwrap()["v1"].state, VmState::Running);
+        assert!(dst.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,631 @@
+//! Per-user and per-group resource quotas.
+//!
+//! A quota caps what an env owner holds across all of their
+//! environments: vCPUs, memory, disk, VM and env counts, the lifetime
+//! of each env, and how far it may be extended. A user is held to their
+//! own quota and to their group's, which counts the envs of every group
+//! member. Stopped envs still count, since their VMs keep their disks
+//! and reservations.
+//!
+//! Quotas are keyed by subject: `user:<name>` or `group:<name>`.
+
+use crate::auth::Caller;
+use crate::db::Db;
+use crate::handler::CtlState;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use std::collections::HashSet;
+use ttcore::api::*;
+use ttcore::model::*;
+
+type Failure = (StatusCode, String);
+
+// ── Subjects & Accounting ───────────────────────────────────────────
+
+/// Canonical subject of a user's own quota.
+pub fn user_subject(name: &str) -> String {
+    format!("user:{name}")
+}
+
+/// Canonical subject of a group quota.
+pub fn group_subject(name: &str) -> String {
+    format!("group:{name}")
+}
+
+/// Parse `user:<name>`, `group:<name>` or a bare user name.
+pub fn parse_subject(s: &str) -> std::result::Result<String, String> {
+    let (kind, name) = s.split_once(':').unwrap_or(("user", s));
+    match kind {
+        "user" => validate_name(name, "user name").map(|_| user_subject(name)),
+        "group" => validate_name(name, "group name").map(|_| group_subject(name)),
+        _ => Err(format!(
+            "invalid quota subject '{s}': expected user:<name> or group:<name>"
+        )),
+    }
+}
+
+/// Resources held by the envs whose owner satisfies `owned`.
+///
+/// An env still being created holds what it reserved rather than the
+/// VMs it has so far.
+pub fn usage(envs: &[Env], vms: &[Vm], owned: impl Fn(&str) -> bool) -> Usage {
+    let owned: Vec<&Env> = envs.iter().filter(|e| owned(&e.owner)).collect();
+    let mut u = Usage {
+        envs: owned.len() as u32,
+        ..Default::default()
+    };
+    let mut ids = HashSet::new();
+    for env in owned {
+        match &env.reserved {
+            Some(r) => {
+                u.cpu += r.cpu;
+                u.mem += r.mem;
+                u.disk += r.disk;
+                u.vms += r.vms;
+            }
+            None => {
+                ids.insert(env.id.as_str());
+            }
+        }
+    }
+    for vm in vms.iter().filter(|v| ids.contains(v.env_id.as_str())) {
+        u.cpu += vm.cpu;
+        u.mem += vm.mem;
+        u.disk += vm.disk;
+        u.vms += 1;
+    }
+    u
+}
+
+/// Resources a new env built from `specs` adds.
+pub fn demand(specs: &[VmSpec]) -> Usage {
+    let mut d = Usage {
+        envs: 1,
+        vms: specs.len() as u32,
+        ..Default::default()
+    };
+    for s in specs {
+        d.cpu += s.cpu.unwrap_or(VM_CPU_DEFAULT);
+        d.mem += s.mem.unwrap_or(VM_MEM_DEFAULT);
+        d.disk += s.disk.unwrap_or(VM_DISK_DEFAULT);
+    }
+    d
+}
+
+/// Check one quota against current usage plus a new env's demand.
+///
+/// `lifetime` is the lifetime the caller asked for, if any.
+fn check_one(
+    subject: &str,
+    quota: &Quota,
+    used: &Usage,
+    want: &Usage,
+    lifetime: Option<u64>,
+) -> std::result::Result<(), String> {
+    let limits = [
+        ("cpu", quota.cpu, used.cpu, want.cpu),
+        ("mem", quota.mem, used.mem, want.mem),
+        ("disk", quota.disk, used.disk, want.disk),
+        ("vms", quota.vms, used.vms, want.vms),
+        ("envs", quota.envs, used.envs, want.envs),
+    ];
+    for (what, limit, used, want) in limits {
+        if let Some(limit) = limit
+            && used.saturating_add(want) > limit
+        {
+            return Err(format!(
+                "quota exceeded for {subject}: {what} {used} in use + {want} requested > {limit}"
+            ));
+        }
+    }
+    if let (Some(max), Some(lt)) = (quota.lifetime, lifetime)
+        && lt > max
+    {
+        return Err(format!(
+            "quota exceeded for {subject}: lifetime {lt}s > {max}s"
+        ));
+    }
+    Ok(())
+}
+
+/// Users whose envs count against a subject's quota.
+fn members(db: &Db, subject: &str) -> ruc::Result<HashSet<String>> {
+    if let Some(group) = subject.strip_prefix("group:") {
+        Ok(db
+            .list_users()?
+            .into_iter()
+            .filter(|u| u.group.as_deref() == Some(group))
+            .map(|u| u.name)
+            .collect())
+    } else {
+        let name = subject.strip_prefix("user:").unwrap_or(subject);
+        Ok(HashSet::from([name.to_string()]))
+    }
+}
+
+/// Current usage of a subject.
+fn usage_of(db: &Db, subject: &str) -> ruc::Result<Usage> {
+    let members = members(db, subject)?;
+    let envs = db.list_envs()?;
+    let vms = db.list_vms()?;
+    Ok(usage(&envs, &vms, |owner| members.contains(owner)))
+}
+
+/// Check every quota that applies to `owner` before an env is created.
+///
+/// Returns the tightest lifetime limit, which becomes the default
+/// lifetime of the env.
+pub fn check(
+    db: &Db,
+    owner: &str,
+    specs: &[VmSpec],
+    lifetime: Option<u64>,
+) -> std::result::Result<Option<u64>, Failure> {
+    check_change(db, owner, &demand(specs), &Usage::default(), lifetime)
+}
+
+/// Check every quota that applies to `owner` before resources are
+/// added to their envs: `want` is added while `freed` is released in
+/// the same change.
+///
+/// Returns the tightest lifetime limit.
+pub fn check_change(
+    db: &Db,
+    owner: &str,
+    want: &Usage,
+    freed: &Usage,
+    lifetime: Option<u64>,
+) -> std::result::Result<Option<u64>, Failure> {
+    let mut cap: Option<u64> = None;
+    for subject in subjects(db, owner).map_err(internal)? {
+        let Some(quota) = db.get_quota(&subject).map_err(internal)? else {
+            continue;
+        };
+        let used = release(usage_of(db, &subject).map_err(internal)?, freed);
+        check_one(&subject, &quota, &used, want, lifetime)
+            .map_err(|msg| (StatusCode::FORBIDDEN, msg))?;
+        if let Some(max) = quota.lifetime {
+            cap = Some(cap.map_or(max, |c| c.min(max)));
+        }
+    }
+    Ok(cap)
+}
+
+/// Longest total lifetime, counted from creation, that `owner` may
+/// extend an env to: the tightest `max_lifetime` of their quotas, or
+/// [`MAX_EXTENDED_LIFETIME`] when none sets one.
+pub fn lifetime_cap(db: &Db, owner: &str) -> std::result::Result<u64, Failure> {
+    let mut cap = MAX_EXTENDED_LIFETIME;
+    for subject in subjects(db, owner).map_err(internal)? {
+        if let Some(max) = db
+            .get_quota(&subject)
+            .map_err(internal)?
+            .and_then(|q| q.max_lifetime)
+        {
+            cap = cap.min(max);
+        }
+    }
+    Ok(cap)
+}
+
+/// Quota subjects that apply to `owner`: their own and their group's.
+fn subjects(db: &Db, owner: &str) -> ruc::Result<Vec<String>> {
+    let mut subjects = vec![user_subject(owner)];
+    if let Some(g) = db.get_user(owner)?.and_then(|u| u.group) {
+        subjects.push(group_subject(&g));
+    }
+    Ok(subjects)
+}
+
+fn internal(e: Box<dyn ruc::RucError>) -> Failure {
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+/// Usage left after `freed` is released.
+fn release(used: Usage, freed: &Usage) -> Usage {
+    Usage {
+        cpu: used.cpu.saturating_sub(freed.cpu),
+        mem: used.mem.saturating_sub(freed.mem),
+        disk: used.disk.saturating_sub(freed.disk),
+        vms: used.vms.saturating_sub(freed.vms),
+        envs: used.envs.saturating_sub(freed.envs),
+    }
+}
+
+fn status(db: &Db, subject: String, quota: Quota) -> std::result::Result<QuotaStatus, Failure> {
+    let usage =
+        usage_of(db, &subject).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(QuotaStatus {
+        subject,
+        quota,
+        usage,
+    })
+}
+
+// ── Handlers ────────────────────────────────────────────────────────
+
+/// GET /api/quotas — every quota with current usage (admin only).
+pub async fn list_quotas(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+) -> impl IntoResponse {
+    if let Err((code, msg)) = caller.require_admin() {
+        return (code, Json(ApiResp::<Vec<QuotaStatus>>::err(msg)));
+    }
+    let db = state.lock_db();
+    let quotas = match db.list_quotas() {
+        Ok(q) => q,
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiResp::<Vec<QuotaStatus>>::err(e.to_string())),
+            );
+        }
+    };
+    let mut result = Vec::new();
+    for (subject, quota) in quotas {
+        match status(&db, subject, quota) {
+            Ok(s) => result.push(s),
+            Err((code, msg)) => return (code, Json(ApiResp::<Vec<QuotaStatus>>::err(msg))),
+        }
+    }
+    (StatusCode::OK, Json(ApiResp::success(result)))
+}
+
+/// GET /api/quotas/:subject — a quota and its usage.
+///
+/// Users may view their own quota and their group's. A subject without
+/// a quota is reported with no limits.
+pub async fn get_quota(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(subject): Path<String>,
+) -> impl IntoResponse {
+    let subject = match parse_subject(&subject) {
+        Ok(s) => s,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(ApiResp::<QuotaStatus>::err(e)),
+            );
+        }
+    };
+
+    let db = state.lock_db();
+    if !caller.is_admin() && subject != user_subject(&caller.user) {
+        let group = db
+            .get_user(&caller.user)
+            .ok()
+            .flatten()
+            .and_then(|u| u.group);
+        if group.map(|g| group_subject(&g)) != Some(subject.clone()) {
+            return (
+                StatusCode::FORBIDDEN,
+                Json(ApiResp::<QuotaStatus>::err(format!(
+                    "permission denied: cannot view the quota of {subject}"
+                ))),
+            );
+        }
+    }
+
+    let quota = match db.get_quota(&subject) {
+        Ok(q) => q.unwrap_or_default(),
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiResp::<QuotaStatus>::err(e.to_string())),
+            );
+        }
+    };
+    match status(&db, subject, quota) {
+        Ok(s) => (StatusCode::OK, Json(ApiResp::success(s))),
+        Err((code, msg)) => (code, Json(ApiResp::<QuotaStatus>::err(msg))),
+    }
+}
+
+/// PUT /api/quotas/:subject — set (replace) a quota (admin only).
+pub async fn set_quota(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(subject): Path<String>,
+    Json(quota): Json<Quota>,
+) -> impl IntoResponse {
+    if let Err((code, msg)) = caller.require_admin() {
+        return (code, Json(ApiResp::<QuotaStatus>::err(msg)));
+    }
+    let subject = match parse_subject(&subject) {
+        Ok(s) => s,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(ApiResp::<QuotaStatus>::err(e)),
+            );
+        }
+    };
+    if quota.lifetime == Some(0) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ApiResp::<QuotaStatus>::err("lifetime limit must be > 0")),
+        );
+    }
+    if quota.max_lifetime == Some(0) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ApiResp::<QuotaStatus>::err(
+                "max lifetime limit must be > 0",
+            )),
+        );
+    }
+
+    let db = state.lock_db();
+    if let Err(e) = db.put_quota(&subject, &quota) {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResp::<QuotaStatus>::err(e.to_string())),
+        );
+    }
+    match status(&db, subject, quota) {
+        Ok(s) => (StatusCode::OK, Json(ApiResp::success(s))),
+        Err((code, msg)) => (code, Json(ApiResp::<QuotaStatus>::err(msg))),
+    }
+}
+
+/// DELETE /api/quotas/:subject — lift a quota (admin only).
+pub async fn delete_quota(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(subject): Path<String>,
+) -> impl IntoResponse {
+    if let Err((code, msg)) = caller.require_admin() {
+        return (code, Json(ApiRespEmpty::err(msg)));
+    }
+    let subject = match parse_subject(&subject) {
+        Ok(s) => s,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(ApiRespEmpty::err(e))),
+    };
+    match state.lock_db().remove_quota(&subject) {
+        Ok(()) => (StatusCode::OK, Json(ApiRespEmpty::ok())),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiRespEmpty::err(e.to_string())),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(id: &str, owner: &str) -> Env {
+        Env {
+            id: id.into(),
+            owner: owner.into(),
+            vm_ids: vec![],
+            created_at: 0,
+            expires_at: 0,
+            state: EnvState::Active,
+            network: None,
+            reserved: None,
+        }
+    }
+
+    fn vm(env_id: &str, cpu: u32, mem: u32) -> Vm {
+        Vm {
+            id: format!("{env_id}-{cpu}-{mem}"),
+            env_id: env_id.into(),
+            host_id: "h1".into(),
+            image: "alpine".into(),
+            cpu,
+            mem,
+            disk: 1024,
+            state: VmState::Running,
+            ..Default::default()
+        }
+    }
+
+    fn spec(cpu: u32) -> VmSpec {
+        VmSpec {
+            image: "alpine".into(),
+            engine: Engine::Qemu,
+            cpu: Some(cpu),
+            mem: None,
+            disk: None,
+            ports: vec![],
+            deny_outgoing: false,
+            ssh_keys: vec![],
+            name: None,
+            user_data: None,
+            anti_affinity: None,
+            node_selector: Default::default(),
+        }
+    }
+
+    #[test]
+    fn parse_subjects() {
+        assert_eq!(parse_subject("alice").unwrap(), "user:alice");
+        assert_eq!(parse_subject("user:alice").unwrap(), "user:alice");
+        assert_eq!(parse_subject("group:dev").unwrap(), "group:dev");
+        assert!(parse_subject("team:dev").is_err());
+        assert!(parse_subject("group:../x").is_err());
+    }
+
+    #[test]
+    fn usage_counts_only_owned_envs() {
+        let envs = [env("e1", "alice"), env("e2", "alice"), env("e3", "bob")];
+        let vms = [vm("e1", 2, 1024), vm("e2", 4, 2048), vm("e3", 8, 4096)];
+        let u = usage(&envs, &vms, |o| o == "alice");
+        assert_eq!(u.envs, 2);
+        assert_eq!(u.vms, 2);
+        assert_eq!(u.cpu, 6);
+        assert_eq!(u.mem, 3072);
+        assert_eq!(u.disk, 2048);
+    }
+
+    #[test]
+    fn demand_applies_defaults() {
+        let mut s = spec(1);
+        s.cpu = None;
+        let d = demand(&[s, spec(3)]);
+        assert_eq!(d.envs, 1);
+        assert_eq!(d.vms, 2);
+        assert_eq!(d.cpu, VM_CPU_DEFAULT + 3);
+        assert_eq!(d.mem, 2 * VM_MEM_DEFAULT);
+    }
+
+    #[test]
+    fn check_one_enforces_limits() {
+        let quota = Quota {
+            cpu: Some(8),
+            envs: Some(2),
+            lifetime: Some(3600),
+            ..Default::default()
+        };
+        let used = Usage {
+            cpu: 6,
+            envs: 1,
+            ..Default::default()
+        };
+        let small = demand(&[spec(2)]);
+        assert!(check_one("user:a", &quota, &used, &small, None).is_ok());
+        assert!(check_one("user:a", &quota, &used, &small, Some(3600)).is_ok());
+
+        let err = check_one("user:a", &quota, &used, &demand(&[spec(3)]), None).unwrap_err();
+        assert!(err.contains("cpu"), "{err}");
+
+        let err = check_one("user:a", &quota, &used, &small, Some(7200)).unwrap_err();
+        assert!(err.contains("lifetime"), "{err}");
+
+        let full = Usage { envs: 2, ..used };
+        let err = check_one("user:a", &quota, &full, &small, None).unwrap_err();
+        assert!(err.contains("envs"), "{err}");
+    }
+
+    #[test]
+    fn check_applies_user_and_group_quotas() {
+        let db = Db::open(":memory:").unwrap();
+        for (name, group) in [("alice", "dev"), ("bob", "dev")] {
+            db.put_user(&User {
+                name: name.into(),
+                role: Role::User,
+                group: Some(group.into()),
+                created_at: 0,
+            })
+            .unwrap();
+        }
+        db.put_env(&env("e1", "bob")).unwrap();
+        db.put_vm(&vm("e1", 4, 1024)).unwrap();
+
+        // No quotas: anything goes
+        assert_eq!(check(&db, "alice", &[spec(8)], None).unwrap(), None);
+
+        // Bob's usage counts against the shared group quota
+        db.put_quota(
+            "group:dev",
+            &Quota {
+                cpu: Some(10),
+                lifetime: Some(7200),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(check(&db, "alice", &[spec(6)], None).unwrap(), Some(7200));
+        let (code, _) = check(&db, "alice", &[spec(7)], None).unwrap_err();
+        assert_eq!(code, StatusCode::FORBIDDEN);
+
+        // The tighter lifetime wins
+        db.put_quota(
+            "user:alice",
+            &Quota {
+                lifetime: Some(600),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(check(&db, "alice", &[spec(1)], None).unwrap(), Some(600));
+    }
+
+    #[test]
+    fn envs_being_created_hold_their_reservation() {
+        let db = Db::open(":memory:").unwrap();
+        db.put_quota(
+            "user:alice",
+            &Quota {
+                cpu: Some(8),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // A placeholder with no VMs yet still holds what it asked for
+        let pending = Env {
+            reserved: Some(demand(&[spec(6)])),
+            ..env("e1", "alice")
+        };
+        db.put_env(&pending).unwrap();
+        let (code, _) = check(&db, "alice", &[spec(4)], None).unwrap_err();
+        assert_eq!(code, StatusCode::FORBIDDEN);
+
+        // Its first VMs do not count on top of the reservation
+        db.put_vm(&vm("e1", 4, 1024)).unwrap();
+        assert_eq!(usage_of(&db, "user:alice").unwrap().cpu, 6);
+        assert!(check(&db, "alice", &[spec(2)], None).is_ok());
+    }
+
+    #[test]
+    fn lifetime_cap_takes_tightest_quota() {
+        let db = Db::open(":memory:").unwrap();
+        db.put_user(&User {
+            name: "alice".into(),
+            role: Role::User,
+            group: Some("dev".into()),
+            created_at: 0,
+        })
+        .unwrap();
+        assert_eq!(lifetime_cap(&db, "alice").unwrap(), MAX_EXTENDED_LIFETIME);
+
+        for (subject, max) in [("group:dev", 86400), ("user:alice", 2 * 86400)] {
+            db.put_quota(
+                subject,
+                &Quota {
+                    max_lifetime: Some(max),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        }
+        assert_eq!(lifetime_cap(&db, "alice").unwrap(), 86400);
+        assert_eq!(lifetime_cap(&db, "bob").unwrap(), MAX_EXTENDED_LIFETIME);
+    }
+
+    #[test]
+    fn check_change_credits_freed_resources() {
+        let db = Db::open(":memory:").unwrap();
+        db.put_env(&env("e1", "alice")).unwrap();
+        db.put_vm(&vm("e1", 4, 1024)).unwrap();
+        db.put_quota(
+            "user:alice",
+            &Quota {
+                cpu: Some(6),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // Replacing the 4-vCPU VM with a 6-vCPU one fits...
+        let want = Usage {
+            envs: 0,
+            ..demand(&[spec(6)])
+        };
+        let freed = Usage {
+            cpu: 4,
+            mem: 1024,
+            vms: 1,
+            ..Default::default()
+        };
+        assert!(check_change(&db, "alice", &want, &freed, None).is_ok());
+        // ...adding it next to the old one does not
+        assert!(check_change(&db, "alice", &want, &Usage::default(), None).is_err());
+    }
+}
@@ -1,12 +1,16 @@
 //! VM placement scheduler.
 //!
 //! Decides which host should run each VM based on available resources,
-//! supported engines, image availability, and a simple best-fit strategy.
+//! supported engines, image availability, node selectors and
+//! anti-affinity groups; a [`PlacementPolicy`] ranks the hosts that
+//! qualify.
 
+use crate::metrics::SCHEDULE_FAILURES;
 use ruc::*;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use ttcore::api::VmSpec;
 use ttcore::model::*;
+use ttcore::net;
 
 /// Result of scheduling: VM spec + chosen host.
 #[derive(Debug)]
@@ -15,18 +19,72 @@ pub struct Placement {
     pub host_addr: String,
 }
 
-/// Choose the best host for a VM spec using a best-fit strategy.
+/// How the scheduler ranks the hosts that can run a VM.
 ///
-/// Prefers the host with the least free resources that can still
-/// accommodate the VM, to pack hosts densely and leave larger hosts
-/// available for bigger workloads.
+/// Only hosts that are online, support the VM's engine and image, and
+/// have room for it are scored.
+pub trait PlacementPolicy: Send + Sync {
+    /// Score of placing a VM needing `cpu` vCPUs, `mem` MiB of memory
+    /// and `disk` MiB of disk on `host`; the highest score wins, the
+    /// first host listed on a tie.
+    fn score(&self, host: &Host, cpu: u32, mem: u32, disk: u32) -> f64;
+}
+
+/// Pack hosts densely: prefer the host with the least free memory,
+/// leaving larger hosts available for bigger workloads.
+pub struct BestFit;
+
+impl PlacementPolicy for BestFit {
+    fn score(&self, host: &Host, _cpu: u32, _mem: u32, _disk: u32) -> f64 {
+        -(host.resource.mem_free() as f64)
+    }
+}
+
+/// Spread load: prefer the host with the most free memory.
+pub struct Spread;
+
+impl PlacementPolicy for Spread {
+    fn score(&self, host: &Host, _cpu: u32, _mem: u32, _disk: u32) -> f64 {
+        host.resource.mem_free() as f64
+    }
+}
+
+/// Pack hosts by both CPU and memory: prefer the host whose weighted
+/// share of used CPU and memory is highest once the VM is placed, so a
+/// host with spare memory but busy CPUs fills up before an idle one.
+pub struct Weighted {
+    pub cpu: f64,
+    pub mem: f64,
+}
+
+impl PlacementPolicy for Weighted {
+    fn score(&self, host: &Host, cpu: u32, mem: u32, _disk: u32) -> f64 {
+        let share = |used: u32, want: u32, total: u32| {
+            if total == 0 {
+                1.0
+            } else {
+                (used + want) as f64 / total as f64
+            }
+        };
+        let r = &host.resource;
+        self.cpu * share(r.cpu_used, cpu, r.cpu_total)
+            + self.mem * share(r.mem_used, mem, r.mem_total)
+    }
+}
+
+/// Choose the best host for a VM spec.
 ///
 /// `host_images` maps host_id → set of available image names.
 /// If the map is empty, image validation is skipped (for backward compat).
+///
+/// Hosts in `avoid` (those already running a VM of the spec's
+/// anti-affinity group) are only used when no other host qualifies.
 pub fn place_vm(
     hosts: &[Host],
     spec: &VmSpec,
     host_images: &HashMap<String, HashSet<String>>,
+    policy: &dyn PlacementPolicy,
+    avoid: &HashSet<String>,
 ) -> Result<Placement> {
     let cpu = spec.cpu.unwrap_or(VM_CPU_DEFAULT);
     let mem = spec.mem.unwrap_or(VM_MEM_DEFAULT);
@@ -35,11 +93,12 @@ pub fn place_vm(
     // Docker images are managed by Docker, not by the image directory
     let check_images = !host_images.is_empty() && spec.engine != Engine::Docker;
 
-    let mut candidates: Vec<&Host> = hosts
+    let candidates: Vec<&Host> = hosts
         .iter()
         .filter(|h| {
             h.state == HostState::Online
                 && h.engines.contains(&spec.engine)
+                && selector_mismatch(h, &spec.node_selector).is_none()
                 && h.resource.can_fit(cpu, mem, disk)
                 && (!check_images
                     || host_images
@@ -54,24 +113,38 @@ pub fn place_vm(
             .iter()
             .filter(|h| h.state == HostState::Online)
             .count();
-        let with_engine = hosts
+        let with_engine: Vec<&Host> = hosts
             .iter()
             .filter(|h| h.state == HostState::Online && h.engines.contains(&spec.engine))
-            .count();
-        let with_resource = hosts
+            .collect();
+        let mismatches: Vec<String> = with_engine
+            .iter()
+            .filter_map(|h| selector_mismatch(h, &spec.node_selector))
+            .collect();
+        let with_resource = with_engine
             .iter()
             .filter(|h| {
-                h.state == HostState::Online
-                    && h.engines.contains(&spec.engine)
+                selector_mismatch(h, &spec.node_selector).is_none()
                     && h.resource.can_fit(cpu, mem, disk)
             })
             .count();
 
         if online == 0 {
+            SCHEDULE_FAILURES.inc(&["no_online_host"]);
             return Err(eg!("no online hosts available"));
-        } else if with_engine == 0 {
+        } else if with_engine.is_empty() {
+            SCHEDULE_FAILURES.inc(&["engine"]);
             return Err(eg!("no online host supports engine={}", spec.engine,));
+        } else if mismatches.len() == with_engine.len() {
+            SCHEDULE_FAILURES.inc(&["node_selector"]);
+            return Err(eg!(
+                "no online host with engine={} matches node selector {} ({})",
+                spec.engine,
+                format_labels(&spec.node_selector),
+                mismatches.join("; "),
+            ));
         } else if with_resource == 0 {
+            SCHEDULE_FAILURES.inc(&["resources"]);
             return Err(eg!(
                 "no host has enough resources for engine={}, cpu={}, mem={}MB, disk={}MB",
                 spec.engine,
@@ -80,6 +153,7 @@ pub fn place_vm(
                 disk,
             ));
         } else {
+            SCHEDULE_FAILURES.inc(&["image"]);
             return Err(eg!(
                 "no host has image '{}' for engine={}",
                 spec.image,
@@ -88,31 +162,70 @@ pub fn place_vm(
         }
     }
 
-    // Sort by free memory ascending (best-fit)
-    candidates.sort_by_key(|h| h.resource.mem_free());
-
-    let host = candidates[0];
+    let apart: Vec<&Host> = candidates
+        .iter()
+        .copied()
+        .filter(|h| !avoid.contains(&h.id))
+        .collect();
+    let pool = if apart.is_empty() { candidates } else { apart };
+
+    let mut best = pool[0];
+    let mut best_score = policy.score(best, cpu, mem, disk);
+    for &h in &pool[1..] {
+        let score = policy.score(h, cpu, mem, disk);
+        if score > best_score {
+            (best, best_score) = (h, score);
+        }
+    }
+    let host = best;
     Ok(Placement {
         host_id: host.id.clone(),
         host_addr: host.addr.clone(),
     })
 }
 
+/// Why `host` does not carry all labels of `selector`, if it doesn't.
+fn selector_mismatch(host: &Host, selector: &BTreeMap<String, String>) -> Option<String> {
+    selector.iter().find_map(|(k, v)| match host.labels.get(k) {
+        Some(have) if have == v => None,
+        Some(have) => Some(format!("{} has {k}={have}", host.id)),
+        None => Some(format!("{} has no {k} label", host.id)),
+    })
+}
+
 /// Schedule an entire environment's VMs across the fleet.
 ///
+/// `existing` are the VMs the env keeps; VMs of their anti-affinity
+/// groups avoid their hosts.
+///
 /// Returns a list of (VmSpec, Placement) pairs.
 pub fn schedule_env(
     hosts: &[Host],
     specs: &[VmSpec],
     host_images: &HashMap<String, HashSet<String>>,
+    policy: &dyn PlacementPolicy,
+    existing: &[Vm],
 ) -> Result<Vec<(VmSpec, Placement)>> {
     let mut result = Vec::with_capacity(specs.len());
 
     // Work with a mutable copy of host resources for multi-VM scheduling
     let mut shadow: Vec<Host> = hosts.to_vec();
+    let mut groups = group_hosts(existing);
+    let none = HashSet::new();
 
     for spec in specs {
-        let placement = place_vm(&shadow, spec, host_images)?;
+        let avoid = spec
+            .anti_affinity
+            .as_ref()
+            .and_then(|g| groups.get(g))
+            .unwrap_or(&none);
+        let placement = place_vm(&shadow, spec, host_images, policy, avoid)?;
+        if let Some(g) = &spec.anti_affinity {
+            groups
+                .entry(g.clone())
+                .or_default()
+                .insert(placement.host_id.clone());
+        }
 
         // Update shadow resources to account for this allocation
         if let Some(h) = shadow.iter_mut().find(|h| h.id == placement.host_id) {
@@ -131,6 +244,32 @@ pub fn schedule_env(
     Ok(result)
 }
 
+/// Hosts running each anti-affinity group of `vms`.
+pub fn group_hosts(vms: &[Vm]) -> HashMap<String, HashSet<String>> {
+    let mut groups: HashMap<String, HashSet<String>> = HashMap::new();
+    for vm in vms {
+        if let Some(g) = &vm.anti_affinity {
+            groups
+                .entry(g.clone())
+                .or_default()
+                .insert(vm.host_id.clone());
+        }
+    }
+    groups
+}
+
+/// Pick the lowest env network not used by any of `envs`.
+pub fn allocate_network(envs: &[Env]) -> Result<EnvNetwork> {
+    let used: HashSet<u32> = envs
+        .iter()
+        .filter_map(|e| e.network.as_ref().map(|n| n.vni))
+        .collect();
+    (0..net::OVERLAY_MAX_NETS)
+        .map(net::overlay_network)
+        .find(|n| !used.contains(&n.vni))
+        .ok_or_else(|| eg!("all {} env networks are in use", net::OVERLAY_MAX_NETS))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -148,10 +287,8 @@ mod tests {
                 disk_used: 0,
                 vm_count: 0,
             },
-            state: HostState::Online,
             engines,
-            storage: Storage::File,
-            registered_at: 0,
+            ..Default::default()
         }
     }
 
@@ -165,9 +302,22 @@ mod tests {
             ports: vec![22],
             deny_outgoing: false,
             ssh_keys: vec![],
+            name: None,
+            user_data: None,
+            anti_affinity: None,
+            node_selector: Default::default(),
         }
     }
 
+    /// Place with the default policy and no anti-affinity.
+    fn place(
+        hosts: &[Host],
+        spec: &VmSpec,
+        imgs: &HashMap<String, HashSet<String>>,
+    ) -> Result<Placement> {
+        place_vm(hosts, spec, imgs, &BestFit, &HashSet::new())
+    }
+
     fn empty_images() -> HashMap<String, HashSet<String>> {
         HashMap::new()
     }
@@ -181,7 +331,7 @@ mod tests {
     #[test]
     fn place_vm_picks_online_host() {
         let hosts = vec![make_host("h1", 8, 16384, vec![Engine::Qemu])];
-        let p = place_vm(&hosts, &make_spec(), &empty_images()).unwrap();
+        let p = place(&hosts, &make_spec(), &empty_images()).unwrap();
         assert_eq!(p.host_id, "h1");
     }
 
@@ -190,21 +340,36 @@ mod tests {
         let mut h = make_host("h1", 8, 16384, vec![Engine::Qemu]);
         h.state = HostState::Offline;
         let hosts = vec![h];
-        assert!(place_vm(&hosts, &make_spec(), &empty_images()).is_err());
+        assert!(place(&hosts, &make_spec(), &empty_images()).is_err());
+    }
+
+    #[test]
+    fn place_vm_skips_hosts_out_of_service() {
+        let mut draining = make_host("h1", 64, 65536, vec![Engine::Qemu]);
+        draining.state = HostState::Draining;
+        let mut maintenance = make_host("h2", 64, 65536, vec![Engine::Qemu]);
+        maintenance.state = HostState::Maintenance;
+        let hosts = vec![draining, maintenance];
+        assert!(place(&hosts, &make_spec(), &empty_images()).is_err());
+
+        let mut hosts = hosts;
+        hosts.push(make_host("h3", 8, 16384, vec![Engine::Qemu]));
+        let p = place(&hosts, &make_spec(), &empty_images()).unwrap();
+        assert_eq!(p.host_id, "h3");
     }
 
     #[test]
     fn place_vm_skips_wrong_engine() {
         let hosts = vec![make_host("h1", 8, 16384, vec![Engine::Docker])];
         let spec = make_spec(); // wants Qemu
-        assert!(place_vm(&hosts, &spec, &empty_images()).is_err());
+        assert!(place(&hosts, &spec, &empty_images()).is_err());
     }
 
     #[test]
     fn place_vm_skips_insufficient_resources() {
         let hosts = vec![make_host("h1", 1, 512, vec![Engine::Qemu])];
         let spec = make_spec(); // needs 2 CPU, 1024 mem
-        assert!(place_vm(&hosts, &spec, &empty_images()).is_err());
+        assert!(place(&hosts, &spec, &empty_images()).is_err());
     }
 
     #[test]
@@ -214,7 +379,7 @@ mod tests {
             make_host("h1", 16, 32768, vec![Engine::Qemu]),
             make_host("h2", 4, 4096, vec![Engine::Qemu]),
         ];
-        let p = place_vm(&hosts, &make_spec(), &empty_images()).unwrap();
+        let p = place(&hosts, &make_spec(), &empty_images()).unwrap();
         assert_eq!(p.host_id, "h2"); // best-fit picks smaller
     }
 
@@ -223,7 +388,7 @@ mod tests {
         let hosts = vec![make_host("h1", 8, 16384, vec![Engine::Qemu])];
         let imgs = images_for("h1", &["alpine"]);
         let spec = make_spec(); // wants "ubuntu"
-        let result = place_vm(&hosts, &spec, &imgs);
+        let result = place(&hosts, &spec, &imgs);
         assert!(result.is_err());
         assert!(result.unwrap_err().to_string().contains("image"));
     }
@@ -232,7 +397,7 @@ mod tests {
     fn place_vm_with_matching_image() {
         let hosts = vec![make_host("h1", 8, 16384, vec![Engine::Qemu])];
         let imgs = images_for("h1", &["ubuntu", "alpine"]);
-        let p = place_vm(&hosts, &make_spec(), &imgs).unwrap();
+        let p = place(&hosts, &make_spec(), &imgs).unwrap();
         assert_eq!(p.host_id, "h1");
     }
 
@@ -243,7 +408,7 @@ mod tests {
         let mut spec = make_spec();
         spec.engine = Engine::Docker;
         // Should succeed — Docker images are not checked against host_images
-        let p = place_vm(&hosts, &spec, &imgs).unwrap();
+        let p = place(&hosts, &spec, &imgs).unwrap();
         assert_eq!(p.host_id, "h1");
     }
 
@@ -256,7 +421,7 @@ mod tests {
 
         // 3 VMs each needing 2 CPU: h1 takes 2 (filling up), h2 takes 1
         let specs: Vec<VmSpec> = (0..3).map(|_| make_spec()).collect();
-        let placements = schedule_env(&hosts, &specs, &empty_images()).unwrap();
+        let placements = schedule_env(&hosts, &specs, &empty_images(), &BestFit, &[]).unwrap();
         assert_eq!(placements.len(), 3);
 
         let on_h1 = placements.iter().filter(|(_, p)| p.host_id == "h1").count();
@@ -269,13 +434,13 @@ mod tests {
     fn schedule_env_fails_if_no_capacity() {
         let hosts = vec![make_host("h1", 2, 2048, vec![Engine::Qemu])];
         let specs: Vec<VmSpec> = (0..2).map(|_| make_spec()).collect();
-        assert!(schedule_env(&hosts, &specs, &empty_images()).is_err());
+        assert!(schedule_env(&hosts, &specs, &empty_images(), &BestFit, &[]).is_err());
     }
 
     #[test]
     fn schedule_env_empty_specs_ok() {
         let hosts = vec![make_host("h1", 8, 16384, vec![Engine::Qemu])];
-        let placements = schedule_env(&hosts, &[], &empty_images()).unwrap();
+        let placements = schedule_env(&hosts, &[], &empty_images(), &BestFit, &[]).unwrap();
         assert!(placements.is_empty());
     }
 
@@ -283,7 +448,7 @@ mod tests {
     fn error_message_no_online() {
         let mut h = make_host("h1", 8, 16384, vec![Engine::Qemu]);
         h.state = HostState::Offline;
-        let err = place_vm(&[h], &make_spec(), &empty_images())
+        let err = place(&[h], &make_spec(), &empty_images())
             .unwrap_err()
             .to_string();
         assert!(err.contains("no online hosts"));
@@ -292,7 +457,7 @@ mod tests {
     #[test]
     fn error_message_no_engine() {
         let hosts = vec![make_host("h1", 8, 16384, vec![Engine::Docker])];
-        let err = place_vm(&hosts, &make_spec(), &empty_images())
+        let err = place(&hosts, &make_spec(), &empty_images())
             .unwrap_err()
             .to_string();
         assert!(err.contains("engine=qemu"));
@@ -301,9 +466,149 @@ mod tests {
     #[test]
     fn error_message_no_resource() {
         let hosts = vec![make_host("h1", 1, 512, vec![Engine::Qemu])];
-        let err = place_vm(&hosts, &make_spec(), &empty_images())
+        let err = place(&hosts, &make_spec(), &empty_images())
             .unwrap_err()
             .to_string();
         assert!(err.contains("resources"));
     }
+
+    #[test]
+    fn node_selector_pins_hosts() {
+        let mut nvme = make_host("h2", 16, 32768, vec![Engine::Qemu]);
+        nvme.labels = BTreeMap::from([("disk".into(), "nvme".into())]);
+        let hosts = vec![make_host("h1", 4, 4096, vec![Engine::Qemu]), nvme];
+        let spec = VmSpec {
+            node_selector: BTreeMap::from([("disk".into(), "nvme".into())]),
+            ..make_spec()
+        };
+        // Best-fit alone would pick h1
+        let p = place(&hosts, &spec, &empty_images()).unwrap();
+        assert_eq!(p.host_id, "h2");
+    }
+
+    #[test]
+    fn error_message_explains_selector_mismatch() {
+        let mut ssd = make_host("h1", 8, 16384, vec![Engine::Qemu]);
+        ssd.labels = BTreeMap::from([("disk".into(), "ssd".into())]);
+        let hosts = vec![ssd, make_host("h2", 8, 16384, vec![Engine::Qemu])];
+        let spec = VmSpec {
+            node_selector: BTreeMap::from([("disk".into(), "nvme".into())]),
+            ..make_spec()
+        };
+        let err = place(&hosts, &spec, &empty_images())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("node selector disk=nvme"));
+        assert!(err.contains("h1 has disk=ssd"));
+        assert!(err.contains("h2 has no disk label"));
+    }
+
+    #[test]
+    fn spread_prefers_most_free_memory() {
+        let hosts = vec![
+            make_host("h1", 4, 4096, vec![Engine::Qemu]),
+            make_host("h2", 16, 32768, vec![Engine::Qemu]),
+        ];
+        let none = HashSet::new();
+        let p = place_vm(&hosts, &make_spec(), &empty_images(), &Spread, &none).unwrap();
+        assert_eq!(p.host_id, "h2");
+    }
+
+    #[test]
+    fn weighted_counts_cpu() {
+        // Same free memory, but h2's CPUs are busier
+        let mut h2 = make_host("h2", 8, 16384, vec![Engine::Qemu]);
+        h2.resource.cpu_used = 4;
+        let hosts = vec![make_host("h1", 8, 16384, vec![Engine::Qemu]), h2];
+        let none = HashSet::new();
+        let policy = Weighted { cpu: 1.0, mem: 1.0 };
+        let p = place_vm(&hosts, &make_spec(), &empty_images(), &policy, &none).unwrap();
+        assert_eq!(p.host_id, "h2");
+
+        // Without a CPU weight the tie goes to the first host
+        let policy = Weighted { cpu: 0.0, mem: 1.0 };
+        let p = place_vm(&hosts, &make_spec(), &empty_images(), &policy, &none).unwrap();
+        assert_eq!(p.host_id, "h1");
+    }
+
+    #[test]
+    fn anti_affinity_spreads_group() {
+        let hosts = vec![
+            make_host("h1", 16, 32768, vec![Engine::Qemu]),
+            make_host("h2", 16, 32768, vec![Engine::Qemu]),
+            make_host("h3", 16, 32768, vec![Engine::Qemu]),
+        ];
+        let replica = VmSpec {
+            anti_affinity: Some("web".into()),
+            node_selector: Default::default(),
+            ..make_spec()
+        };
+        let specs = vec![replica.clone(), replica.clone(), make_spec()];
+        let placements = schedule_env(&hosts, &specs, &empty_images(), &BestFit, &[]).unwrap();
+        let on: Vec<&str> = placements.iter().map(|(_, p)| p.host_id.as_str()).collect();
+        // Best-fit packs the ungrouped VM next to the first replica
+        assert_eq!(on, ["h1", "h2", "h1"]);
+
+        // VMs the env already has count too
+        let existing = Vm {
+            id: "v0".into(),
+            env_id: "e1".into(),
+            host_id: "h1".into(),
+            image: "ubuntu".into(),
+            cpu: 2,
+            mem: 1024,
+            disk: 40960,
+            ip: "10.10.0.2".into(),
+            state: VmState::Running,
+            name: "vm1".into(),
+            anti_affinity: Some("web".into()),
+            ..Default::default()
+        };
+        let placements =
+            schedule_env(&hosts, &[replica], &empty_images(), &BestFit, &[existing]).unwrap();
+        assert_eq!(placements[0].1.host_id, "h2");
+    }
+
+    #[test]
+    fn anti_affinity_shares_host_when_no_other_fits() {
+        let hosts = vec![make_host("h1", 16, 32768, vec![Engine::Qemu])];
+        let replica = VmSpec {
+            anti_affinity: Some("web".into()),
+            node_selector: Default::default(),
+            ..make_spec()
+        };
+        let placements = schedule_env(
+            &hosts,
+            &[replica.clone(), replica],
+            &empty_images(),
+            &BestFit,
+            &[],
+        )
+        .unwrap();
+        assert!(placements.iter().all(|(_, p)| p.host_id == "h1"));
+    }
+
+    #[test]
+    fn allocate_network_reuses_lowest_free() {
+        let env = |id: &str, index: Option<u32>| Env {
+            id: id.into(),
+            owner: "tester".into(),
+            vm_ids: vec![],
+            created_at: 0,
+            expires_at: 0,
+            state: EnvState::Active,
+            network: index.map(net::overlay_network),
+            reserved: None,
+        };
+
+        let first = allocate_network(&[]).unwrap();
+        assert_eq!(first.vni, net::OVERLAY_VNI_BASE);
+        assert_eq!(first.subnet, "172.16.0.0/24");
+
+        let envs = vec![env("a", Some(0)), env("b", None), env("c", Some(2))];
+        assert_eq!(
+            allocate_network(&envs).unwrap().vni,
+            net::OVERLAY_VNI_BASE + 1
+        );
+    }
 }
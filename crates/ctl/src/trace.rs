@@ -0,0 +1,156 @@
+//! Request IDs and the runtime log filter.
+//!
+//! Every call is given a request ID by [`request_span`] and served in a
+//! `request` span carrying it. The ID is also kept in a task-local, so
+//! that [`crate::handler::agent_client`] can pass it on to agents and
+//! the audit log can record it; background work started for a call is
+//! spawned with [`spawn`] to keep both.
+
+use crate::auth::Caller;
+use crate::handler::{CtlState, agent_client, relay};
+use axum::body::Body;
+use axum::extract::{Path, State};
+use axum::http::{HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use std::future::Future;
+use tokio::task::JoinHandle;
+use tracing::{Instrument, Span, info, info_span};
+use ttcore::api::{ApiResp, LogLevel};
+use ttcore::log::{self, REQUEST_ID_HEADER};
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// ID of the call being served, if any.
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Middleware that gives each call its request ID and `request` span,
+/// and returns the ID in the response headers.
+pub async fn request_span(req: Request<Body>, next: Next) -> Response {
+    let id = log::new_request_id();
+    let span = info_span!(
+        "request",
+        request_id = %id,
+        method = %req.method(),
+        path = req.uri().path(),
+    );
+    let mut resp = REQUEST_ID
+        .scope(id.clone(), next.run(req).instrument(span))
+        .await;
+    if let Ok(val) = HeaderValue::from_str(&id) {
+        resp.headers_mut().insert(REQUEST_ID_HEADER, val);
+    }
+    resp
+}
+
+/// `tokio::spawn` keeping the current request ID and span.
+pub fn spawn<F>(fut: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let fut = fut.instrument(Span::current());
+    match request_id() {
+        Some(id) => tokio::spawn(REQUEST_ID.scope(id, fut)),
+        None => tokio::spawn(fut),
+    }
+}
+
+/// GET /api/log-level
+pub async fn get_log_level(Extension(caller): Extension<Caller>) -> impl IntoResponse {
+    if let Err((status, msg)) = caller.require_admin() {
+        return (status, Json(ApiResp::<LogLevel>::err(msg)));
+    }
+    match log::filter() {
+        Some(filter) => (StatusCode::OK, Json(ApiResp::success(LogLevel { filter }))),
+        None => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResp::err("logging is not initialized")),
+        ),
+    }
+}
+
+/// PUT /api/log-level — replace the log filter until the controller
+/// restarts.
+pub async fn set_log_level(
+    Extension(caller): Extension<Caller>,
+    Json(req): Json<LogLevel>,
+) -> impl IntoResponse {
+    if let Err((status, msg)) = caller.require_admin() {
+        return (status, Json(ApiResp::<LogLevel>::err(msg)));
+    }
+    match log::set_filter(&req.filter) {
+        Ok(()) => {
+            info!("log filter set to {} by {}", req.filter, caller.user);
+            (StatusCode::OK, Json(ApiResp::success(req)))
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, Json(ApiResp::err(e.to_string()))),
+    }
+}
+
+/// GET /api/hosts/{id}/log-level
+pub async fn get_host_log_level(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let addr = match host_addr(&state, &caller, &id) {
+        Ok(addr) => addr,
+        Err((status, msg)) => return (status, Json(ApiResp::<LogLevel>::err(msg))),
+    };
+    let client = agent_client(state.api_key.as_deref(), 10);
+    let url = format!("http://{addr}/api/log-level");
+    relay(client.get(&url).send().await, &addr).await
+}
+
+/// PUT /api/hosts/{id}/log-level
+pub async fn set_host_log_level(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+    Json(req): Json<LogLevel>,
+) -> impl IntoResponse {
+    let addr = match host_addr(&state, &caller, &id) {
+        Ok(addr) => addr,
+        Err((status, msg)) => return (status, Json(ApiResp::<LogLevel>::err(msg))),
+    };
+    let client = agent_client(state.api_key.as_deref(), 10);
+    let url = format!("http://{addr}/api/log-level");
+    relay(client.put(&url).json(&req).send().await, &addr).await
+}
+
+/// Address of host `id`, for admins only.
+fn host_addr(
+    state: &CtlState,
+    caller: &Caller,
+    id: &str,
+) -> std::result::Result<String, (StatusCode, String)> {
+    caller.require_admin()?;
+    match state.lock_db().get_host(id) {
+        Ok(Some(h)) => Ok(h.addr),
+        Ok(None) => Err((StatusCode::NOT_FOUND, format!("host not found: {id}"))),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn spawned_work_keeps_the_request_id() {
+        assert_eq!(request_id(), None);
+        let id = REQUEST_ID
+            .scope("abc123".to_string(), async {
+                spawn(async { request_id() }).await.unwrap()
+            })
+            .await;
+        assert_eq!(id.as_deref(), Some("abc123"));
+        assert_eq!(spawn(async { request_id() }).await.unwrap(), None);
+    }
+}
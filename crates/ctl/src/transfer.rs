@@ -0,0 +1,439 @@
+//! Controller-driven image distribution.
+//!
+//! Copies a base image from a host that has it to hosts that don't (or
+//! that hold an older copy). The controller only relays data between
+//! agents:
+//!
+//! - **zvol**: the source's `zfs send` stream is piped straight into the
+//!   target's `zfs recv`, incrementally when the target already holds an
+//!   earlier snapshot of the image.
+//! - **file**: every file is relayed in checksummed chunks into a staging
+//!   path on the target, which verifies the whole image before installing.
+//!
+//! Only hosts with the same storage backend can exchange images.
+
+use crate::fanout;
+use crate::handler::{CtlState, agent_client};
+use crate::jobs::Tracker;
+use crate::metrics;
+use crate::trace;
+use axum::http::StatusCode;
+use serde::de::DeserializeOwned;
+use tracing::warn;
+use ttcore::api::*;
+use ttcore::model::*;
+
+/// Upper bound for a single image transfer.
+pub(crate) const TRANSFER_TIMEOUT_SECS: u64 = 4 * 3600;
+/// Upper bound for an agent to describe an image, checksums included.
+const MANIFEST_TIMEOUT_SECS: u64 = 3600;
+
+/// Start a job pushing `image` to the online hosts selected by `req`,
+/// with a step per target host.
+///
+/// Requests that cannot be planned fail before the job starts.
+pub(crate) fn start_push(
+    state: &CtlState,
+    user: &str,
+    image: &str,
+    req: PushImageReq,
+) -> std::result::Result<Job, (StatusCode, String)> {
+    if let Err(e) = validate_name(image, "image") {
+        return Err((StatusCode::BAD_REQUEST, e));
+    }
+
+    let db = state.lock_db();
+    let hosts: Vec<Host> = db
+        .list_hosts()
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .into_iter()
+        .filter(|h| h.state == HostState::Online)
+        .collect();
+
+    let targets: Vec<String> = if req.to == "all" {
+        hosts.iter().map(|h| h.id.clone()).collect()
+    } else if hosts.iter().any(|h| h.id == req.to) {
+        vec![req.to.clone()]
+    } else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("host {} is not registered or not online", req.to),
+        ));
+    };
+
+    let steps: Vec<String> = targets.iter().map(|t| push_step(t)).collect();
+    // Targets stage an image in one place, which a second push would
+    // wipe; checked and recorded under one lock
+    let pushing = db
+        .list_jobs()
+        .unwrap_or_default()
+        .into_iter()
+        .find_map(|j| {
+            let same = j.kind == JobKind::PushImage && j.target == image && !j.state.is_finished();
+            let step = j.steps.iter().find(|s| same && steps.contains(&s.name))?;
+            Some((step.name.clone(), j.id))
+        });
+    if let Some((step, id)) = pushing {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("image '{image}' is already being pushed ({step}) by job {id}"),
+        ));
+    }
+    let (job, tracker) = Tracker::start_locked(state, &db, JobKind::PushImage, image, user, steps)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    drop(db);
+    let state = state.clone();
+    let image = image.to_string();
+    trace::spawn(async move {
+        let pushed = push_image(
+            &state,
+            &tracker,
+            &image,
+            req.from.as_deref(),
+            &hosts,
+            &targets,
+        );
+        match pushed.await {
+            Ok(0) => tracker.succeed(vec![]),
+            Ok(failed) => tracker.fail(format!("{failed} of {} host(s) failed", targets.len())),
+            Err(e) => tracker.fail(e),
+        }
+    });
+    Ok(job)
+}
+
+/// Name of the job step pushing to host `id`.
+fn push_step(id: &str) -> String {
+    format!("push to {id}")
+}
+
+/// Push `image` to the `targets` among `hosts`, to several at once,
+/// recording each as a step of `job`.
+///
+/// Returns how many targets failed; the error variant is reserved for
+/// pushes that cannot start at all.
+async fn push_image(
+    state: &CtlState,
+    job: &Tracker,
+    image: &str,
+    from: Option<&str>,
+    hosts: &[Host],
+    targets: &[String],
+) -> std::result::Result<usize, String> {
+    // Agents checksum every file of the image for its manifest
+    let client = agent_client(state.api_key.as_deref(), MANIFEST_TIMEOUT_SECS);
+    let manifests = fanout::map(hosts, |host| fetch_manifest(&client, host, image)).await;
+
+    let sources: Vec<(&Host, &ImageManifest)> = hosts
+        .iter()
+        .zip(&manifests)
+        .filter_map(|(h, m)| m.as_ref().map(|m| (h, m)))
+        .filter(|(h, _)| from.is_none_or(|from| h.id == from))
+        .collect();
+    if sources.is_empty() {
+        return Err(match from {
+            Some(from) => format!("image '{image}' not found on host {from}"),
+            None => format!("image '{image}' not found on any online host"),
+        });
+    }
+
+    let client = agent_client(state.api_key.as_deref(), TRANSFER_TIMEOUT_SECS);
+    let targets = hosts
+        .iter()
+        .zip(&manifests)
+        .filter(|(h, _)| targets.contains(&h.id));
+    let results = fanout::map(targets, |(target, existing)| {
+        let existing = existing.as_ref();
+        let source = sources
+            .iter()
+            .find(|(h, _)| h.id != target.id && h.storage == target.storage);
+        let client = &client;
+        async move {
+            let step = push_step(&target.id);
+            job.step(&step, JobState::Running, "");
+            let outcome = match source {
+                None if existing.is_some() => Ok("already up to date".to_string()),
+                None => Err(format!(
+                    "no other {} host has image '{image}'",
+                    target.storage
+                )),
+                Some((source, manifest)) => {
+                    let pushed = match target.storage {
+                        Storage::Zvol => {
+                            push_zvol(client, source, target, image, manifest, existing).await
+                        }
+                        Storage::File => {
+                            push_files(client, source, target, image, manifest, existing).await
+                        }
+                    };
+                    if let Err(e) = &pushed {
+                        warn!(
+                            "pushing image {image} from {} to {} failed: {e}",
+                            source.id, target.id
+                        );
+                    }
+                    pushed
+                }
+            };
+            match outcome {
+                Ok(detail) => {
+                    job.step(&step, JobState::Succeeded, detail);
+                    true
+                }
+                Err(e) => {
+                    job.step(&step, JobState::Failed, e);
+                    false
+                }
+            }
+        }
+    })
+    .await;
+
+    Ok(results.into_iter().filter(|ok| !ok).count())
+}
+
+/// Pipe a zfs send stream from `src` into `dst`.
+async fn push_zvol(
+    client: &reqwest::Client,
+    src: &Host,
+    dst: &Host,
+    image: &str,
+    manifest: &ImageManifest,
+    existing: Option<&ImageManifest>,
+) -> std::result::Result<String, String> {
+    let from = match existing {
+        None => None,
+        Some(have) => match have.snapshots.last() {
+            Some(last) if manifest.snapshots.last() == Some(last) => {
+                return Ok("already up to date".to_string());
+            }
+            Some(last) if manifest.snapshots.contains(last) => Some(last.clone()),
+            _ => {
+                return Err(format!(
+                    "image on {} has diverged from {}; remove it and push again",
+                    dst.id, src.id
+                ));
+            }
+        },
+    };
+
+    let mut req = client.get(format!("http://{}/api/images/{image}/send", src.addr));
+    if let Some(from) = &from {
+        req = req.query(&[("from", from)]);
+    }
+    let stream = expect_ok(req.send().await, &src.addr).await?;
+
+    let resp = client
+        .put(format!("http://{}/api/images/{image}/recv", dst.addr))
+        .body(reqwest::Body::wrap_stream(stream.bytes_stream()))
+        .send()
+        .await;
+    expect_ok(resp, &dst.addr).await?;
+
+    Ok(match from {
+        Some(from) => format!("incremental zfs stream from {} (since @{from})", src.id),
+        None => format!("full zfs stream from {}", src.id),
+    })
+}
+
+/// Relay every file of `manifest` from `src` to `dst` in checksummed chunks.
+async fn push_files(
+    client: &reqwest::Client,
+    src: &Host,
+    dst: &Host,
+    image: &str,
+    manifest: &ImageManifest,
+    existing: Option<&ImageManifest>,
+) -> std::result::Result<String, String> {
+    if let Some(have) = existing
+        && have.is_dir == manifest.is_dir
+        && have.files == manifest.files
+        && have.links == manifest.links
+    {
+        return Ok("already up to date".to_string());
+    }
+
+    let total = relay_files(
+        client,
+        src,
+        &format!("http://{}/api/images/{image}", src.addr),
+        dst,
+        &format!("http://{}/api/images/{image}", dst.addr),
+        manifest,
+    )
+    .await?;
+
+    Ok(format!(
+        "{} file(s), {:.1} MiB from {}",
+        manifest.files.len(),
+        total as f64 / (1024.0 * 1024.0),
+        src.id
+    ))
+}
+
+/// Relay every file of `manifest` in checksummed chunks between the
+/// transfer endpoints under `src_url` and `dst_url` (`…/chunk`, plus
+/// `…/incoming` and `…/install` on `dst`). Returns the bytes copied.
+pub(crate) async fn relay_files(
+    client: &reqwest::Client,
+    src: &Host,
+    src_url: &str,
+    dst: &Host,
+    dst_url: &str,
+    manifest: &ImageManifest,
+) -> std::result::Result<u64, String> {
+    let resp = client.delete(format!("{dst_url}/incoming")).send().await;
+    expect_ok(resp, &dst.addr).await?;
+
+    let src_chunk = format!("{src_url}/chunk");
+    let dst_chunk = format!("{dst_url}/chunk");
+    let mut total = 0u64;
+    for f in &manifest.files {
+        // Always send at least one chunk so empty files get created
+        let mut offset = 0u64;
+        loop {
+            let offset_s = offset.to_string();
+            let query = [("file", f.path.as_str()), ("offset", offset_s.as_str())];
+
+            let resp = client.get(&src_chunk).query(&query).send().await;
+            let resp = expect_ok(resp, &src.addr).await?;
+            let sum = resp
+                .headers()
+                .get(CHUNK_SHA256_HEADER)
+                .cloned()
+                .ok_or_else(|| format!("agent {} sent a chunk without checksum", src.addr))?;
+            let data = resp
+                .bytes()
+                .await
+                .map_err(|e| format!("failed to read chunk from agent {}: {e}", src.addr))?;
+            let len = data.len() as u64;
+
+            let resp = client
+                .put(&dst_chunk)
+                .query(&query)
+                .header(CHUNK_SHA256_HEADER, sum)
+                .body(data)
+                .send()
+                .await;
+            expect_ok(resp, &dst.addr).await?;
+
+            offset += len;
+            if offset >= f.size {
+                break;
+            }
+            if len == 0 {
+                return Err(format!(
+                    "'{}' on {} is shorter than expected",
+                    f.path, src.id
+                ));
+            }
+        }
+        total += f.size;
+    }
+
+    let resp = client
+        .post(format!("{dst_url}/install"))
+        .json(manifest)
+        .send()
+        .await;
+    expect_ok(resp, &dst.addr).await?;
+
+    Ok(total)
+}
+
+/// Ask a host for its manifest of `image`; `None` if it doesn't have it.
+async fn fetch_manifest(
+    client: &reqwest::Client,
+    host: &Host,
+    image: &str,
+) -> Option<ImageManifest> {
+    let url = format!("http://{}/api/images/{image}/manifest", host.addr);
+    let resp = client.get(&url).send().await.ok()?;
+    if !resp.status().is_success() {
+        return None;
+    }
+    decode::<ImageManifest>(resp).await.ok().flatten()
+}
+
+/// Turn a non-2xx agent response (or transport error) into a message.
+pub(crate) async fn expect_ok(
+    resp: reqwest::Result<reqwest::Response>,
+    addr: &str,
+) -> std::result::Result<reqwest::Response, String> {
+    let resp = resp.map_err(|e| {
+        metrics::agent_error(addr, "transfer");
+        format!("failed to reach agent {addr}: {e}")
+    })?;
+    if resp.status().is_success() {
+        return Ok(resp);
+    }
+    let status = resp.status();
+    if status.is_server_error() {
+        metrics::agent_error(addr, "transfer");
+    }
+    let msg = match decode::<()>(resp).await {
+        Err(e) => e,
+        Ok(_) => status.to_string(),
+    };
+    Err(format!("agent {addr}: {msg}"))
+}
+
+/// Decode an API envelope, mapping `ok: false` to its error message.
+pub(crate) async fn decode<T: DeserializeOwned>(
+    resp: reqwest::Response,
+) -> std::result::Result<Option<T>, String> {
+    let body = resp
+        .json::<ApiResp<T>>()
+        .await
+        .map_err(|e| format!("invalid response: {e}"))?;
+    if body.ok {
+        Ok(body.data)
+    } else {
+        Err(body.error.unwrap_or_else(|| "unknown error".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Db;
+    use crate::handler::CtlShared;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn one_push_per_image_and_target() {
+        let state: CtlState = Arc::new(CtlShared::new(
+            Db::open(":memory:").unwrap(),
+            None,
+            Box::new(crate::scheduler::BestFit),
+        ));
+        // Agents that never answer keep the first push running
+        let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = silent.local_addr().unwrap().to_string();
+        for id in ["h1", "h2"] {
+            let host = Host {
+                id: id.into(),
+                addr: addr.clone(),
+                state: HostState::Online,
+                ..Default::default()
+            };
+            state.lock_db().put_host(&host).unwrap();
+        }
+        let push = |image: &str, to: &str| {
+            let req = PushImageReq {
+                to: to.into(),
+                from: None,
+            };
+            start_push(&state, "admin", image, req)
+        };
+
+        let job = push("alpine", "h1").unwrap();
+        assert_eq!(job.kind, JobKind::PushImage);
+        assert_eq!(job.steps[0].name, "push to h1");
+        let (code, msg) = push("alpine", "all").unwrap_err();
+        assert_eq!(code, StatusCode::CONFLICT);
+        assert!(msg.contains(&job.id), "{msg}");
+        assert!(push("alpine", "h2").is_ok());
+        assert!(push("debian", "h1").is_ok());
+    }
+}
@@ -0,0 +1,290 @@
+//! User accounts and API tokens.
+//!
+//! Admins create users and may issue or revoke anyone's tokens; users
+//! manage their own tokens. Token secrets are returned once, at
+//! issuance, and only their SHA-256 hash is stored.
+
+use crate::auth::Caller;
+use crate::handler::{CtlState, now};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use ttcore::api::*;
+use ttcore::model::*;
+use ttcore::storage::sha256_hex;
+
+// ── Users ───────────────────────────────────────────────────────────
+
+/// POST /api/users — create a user (admin only).
+pub async fn create_user(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Json(req): Json<CreateUserReq>,
+) -> impl IntoResponse {
+    if let Err((code, msg)) = caller.require_admin() {
+        return (code, Json(ApiResp::<User>::err(msg)));
+    }
+    if let Err(e) = validate_name(&req.name, "user name") {
+        return (StatusCode::BAD_REQUEST, Json(ApiResp::<User>::err(e)));
+    }
+    if let Some(g) = &req.group
+        && let Err(e) = validate_name(g, "group name")
+    {
+        return (StatusCode::BAD_REQUEST, Json(ApiResp::<User>::err(e)));
+    }
+
+    let db = state.lock_db();
+    if let Ok(Some(_)) = db.get_user(&req.name) {
+        return (
+            StatusCode::CONFLICT,
+            Json(ApiResp::<User>::err(format!(
+                "user '{}' already exists",
+                req.name
+            ))),
+        );
+    }
+
+    let user = User {
+        name: req.name,
+        role: req.role,
+        group: req.group,
+        created_at: now(),
+    };
+    match db.put_user(&user) {
+        Ok(()) => (StatusCode::CREATED, Json(ApiResp::success(user))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResp::<User>::err(e.to_string())),
+        ),
+    }
+}
+
+/// GET /api/users (admin only)
+pub async fn list_users(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+) -> impl IntoResponse {
+    if let Err((code, msg)) = caller.require_admin() {
+        return (code, Json(ApiResp::<Vec<User>>::err(msg)));
+    }
+    let db = state.lock_db();
+    match db.list_users() {
+        Ok(users) => (StatusCode::OK, Json(ApiResp::success(users))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResp::<Vec<User>>::err(e.to_string())),
+        ),
+    }
+}
+
+/// PUT /api/users/:name — change a user's role or group (admin only).
+pub async fn update_user(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(name): Path<String>,
+    Json(req): Json<UpdateUserReq>,
+) -> impl IntoResponse {
+    if let Err((code, msg)) = caller.require_admin() {
+        return (code, Json(ApiResp::<User>::err(msg)));
+    }
+    if let Some(g) = req.group.as_deref().filter(|g| !g.is_empty())
+        && let Err(e) = validate_name(g, "group name")
+    {
+        return (StatusCode::BAD_REQUEST, Json(ApiResp::<User>::err(e)));
+    }
+
+    let db = state.lock_db();
+    let mut user = match db.get_user(&name) {
+        Ok(Some(u)) => u,
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(ApiResp::<User>::err(format!("user not found: {name}"))),
+            );
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiResp::<User>::err(e.to_string())),
+            );
+        }
+    };
+    if let Some(role) = req.role {
+        user.role = role;
+    }
+    if let Some(group) = req.group {
+        user.group = Some(group).filter(|g| !g.is_empty());
+    }
+
+    match db.put_user(&user) {
+        Ok(()) => (StatusCode::OK, Json(ApiResp::success(user))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResp::<User>::err(e.to_string())),
+        ),
+    }
+}
+
+/// DELETE /api/users/:name — remove a user and revoke their tokens
+/// (admin only). Their environments are kept.
+pub async fn delete_user(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    if let Err((code, msg)) = caller.require_admin() {
+        return (code, Json(ApiRespEmpty::err(msg)));
+    }
+    let db = state.lock_db();
+    match db.get_user(&name) {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(ApiRespEmpty::err(format!("user not found: {name}"))),
+            );
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiRespEmpty::err(e.to_string())),
+            );
+        }
+    }
+    match db.remove_user(&name) {
+        Ok(()) => (StatusCode::OK, Json(ApiRespEmpty::ok())),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiRespEmpty::err(e.to_string())),
+        ),
+    }
+}
+
+/// GET /api/whoami — the user the request is authenticated as.
+pub async fn whoami(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+) -> impl IntoResponse {
+    // The bootstrap identity has no account of its own
+    let user = state.lock_db().get_user(&caller.user).ok().flatten();
+    Json(ApiResp::success(User {
+        name: caller.user,
+        role: caller.role,
+        group: user.as_ref().and_then(|u| u.group.clone()),
+        created_at: user.map_or(0, |u| u.created_at),
+    }))
+}
+
+// ── Tokens ──────────────────────────────────────────────────────────
+
+/// POST /api/tokens — issue a token for the caller, or for any user
+/// when the caller is an admin.
+pub async fn create_token(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Json(req): Json<CreateTokenReq>,
+) -> impl IntoResponse {
+    let user = req.user.unwrap_or_else(|| caller.user.clone());
+    if user != caller.user
+        && let Err((code, msg)) = caller.require_admin()
+    {
+        return (code, Json(ApiResp::<NewToken>::err(msg)));
+    }
+
+    let db = state.lock_db();
+    match db.get_user(&user) {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(ApiResp::<NewToken>::err(format!("user not found: {user}"))),
+            );
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiResp::<NewToken>::err(e.to_string())),
+            );
+        }
+    }
+
+    let secret = format!(
+        "tt-{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    );
+    let token = ApiToken {
+        id: uuid::Uuid::new_v4().to_string()[..12].to_string(),
+        user,
+        label: req.label,
+        created_at: now(),
+    };
+    match db.put_token(&token, &sha256_hex(secret.as_bytes())) {
+        Ok(()) => (
+            StatusCode::CREATED,
+            Json(ApiResp::success(NewToken { token, secret })),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResp::<NewToken>::err(e.to_string())),
+        ),
+    }
+}
+
+/// GET /api/tokens — the caller's tokens; admins see all tokens.
+pub async fn list_tokens(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+) -> impl IntoResponse {
+    let db = state.lock_db();
+    let tokens = if caller.is_admin() {
+        db.list_tokens()
+    } else {
+        db.tokens_by_user(&caller.user)
+    };
+    match tokens {
+        Ok(t) => (StatusCode::OK, Json(ApiResp::success(t))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResp::<Vec<ApiToken>>::err(e.to_string())),
+        ),
+    }
+}
+
+/// DELETE /api/tokens/:id — revoke a token.
+pub async fn revoke_token(
+    State(state): State<CtlState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let db = state.lock_db();
+    let token = match db.get_token(&id) {
+        Ok(Some(t)) => t,
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(ApiRespEmpty::err(format!("token not found: {id}"))),
+            );
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiRespEmpty::err(e.to_string())),
+            );
+        }
+    };
+    if token.user != caller.user
+        && let Err((code, msg)) = caller.require_admin()
+    {
+        return (code, Json(ApiRespEmpty::err(msg)));
+    }
+
+    match db.remove_token(&id) {
+        Ok(()) => (StatusCode::OK, Json(ApiRespEmpty::ok())),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiRespEmpty::err(e.to_string())),
+        ),
+    }
+}
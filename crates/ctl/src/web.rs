@@ -1,19 +1,40 @@
 //! Embedded web frontend for TTstack management.
 //!
 //! Serves a single-page application directly from the controller binary.
-//! No external files or build tools required.
+//! No external files or build tools required; only the VM console view
+//! needs xterm.js, which the controller serves from `--web-dir` so that
+//! the page never loads code from another origin.
 
-use axum::response::Html;
+use axum::extract::{Path, State};
+use axum::http::{StatusCode, header};
+use axum::response::{Html, IntoResponse};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Files the dashboard loads from `--web-dir`, with their content types.
+const ASSETS: &[(&str, &str)] = &[("xterm.js", "text/javascript"), ("xterm.css", "text/css")];
 
 /// GET / — serve the management dashboard.
 ///
 /// The page itself requires no authentication. When API key auth is
 /// enabled, the JS client detects 401 responses and prompts the user
-/// to enter the key (stored in sessionStorage).
+/// to enter the key or a user token (stored in sessionStorage). Console WebSockets
+/// pass the key as an `access_token` query parameter.
 pub async fn index() -> Html<&'static str> {
     Html(FRONTEND_HTML)
 }
 
+/// GET /web/:file — one of [`ASSETS`], read from `dir`.
+pub async fn asset(State(dir): State<Arc<PathBuf>>, Path(file): Path<String>) -> impl IntoResponse {
+    let Some((name, mime)) = ASSETS.iter().find(|(name, _)| *name == file) else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    match tokio::fs::read(dir.join(name)).await {
+        Ok(body) => Ok(([(header::CONTENT_TYPE, *mime)], body)),
+        Err(_) => Err(StatusCode::NOT_FOUND),
+    }
+}
+
 const FRONTEND_HTML: &str = r##"<!DOCTYPE html>
 <html lang="en">
 <head>
@@ -69,7 +90,8 @@ const FRONTEND_HTML: &str = r##"<!DOCTYPE html>
   .badge-online, .badge-active, .badge-running { background: rgba(74,222,128,0.15); color: var(--green); }
   .badge-offline, .badge-stopped { background: rgba(248,113,113,0.15); color: var(--red); }
   .badge-failed { background: rgba(248,113,113,0.25); color: var(--red); }
-  .badge-creating, .badge-paused { background: rgba(251,191,36,0.15); color: var(--yellow); }
+  .badge-creating, .badge-paused, .badge-hibernated,
+  .badge-draining, .badge-maintenance { background: rgba(251,191,36,0.15); color: var(--yellow); }
 
   .btn { background: var(--accent); color: #fff; border: none; padding: 0.35rem 0.8rem;
     border-radius: 5px; cursor: pointer; font-size: 0.8rem; }
@@ -102,6 +124,11 @@ const FRONTEND_HTML: &str = r##"<!DOCTYPE html>
   .toast.show { display: block; }
   .toast.error { border-color: var(--red); }
   .expiry { font-size: 0.75rem; color: var(--muted); }
+  .spark { vertical-align: middle; margin-right: 0.4rem; }
+  .spark polyline { fill: none; stroke: var(--accent); stroke-width: 1.5; }
+  .spark.mem polyline { stroke: var(--green); }
+  .modal.console { max-width: 960px; }
+  .modal.console .term { background: #000; padding: 0.25rem; border-radius: 5px; height: 480px; }
 </style>
 </head>
 <body>
@@ -129,8 +156,8 @@ const FRONTEND_HTML: &str = r##"<!DOCTYPE html>
         <button class="btn" onclick="showModal('add-host')">+ Add Host</button>
       </div>
       <table><thead><tr>
-        <th>ID</th><th>Address</th><th>State</th><th>Engines</th><th>CPU</th><th>Memory</th><th>VMs</th><th></th>
-      </tr></thead><tbody id="hosts-body"><tr><td colspan="8" class="loading">Loading...</td></tr></tbody></table>
+        <th>ID</th><th>Address</th><th>State</th><th>Engines</th><th>CPU</th><th>Memory</th><th>VMs</th><th>Usage</th><th></th>
+      </tr></thead><tbody id="hosts-body"><tr><td colspan="9" class="loading">Loading...</td></tr></tbody></table>
     </div>
   </div>
 
@@ -148,7 +175,7 @@ const FRONTEND_HTML: &str = r##"<!DOCTYPE html>
     <div class="panel" id="env-detail-panel" style="display:none">
       <div class="panel-header"><h2 id="env-detail-title">VM Details</h2></div>
       <table><thead><tr>
-        <th>ID</th><th>Image</th><th>Engine</th><th>State</th><th>IP</th><th>Ports</th>
+        <th>ID</th><th>Image</th><th>Engine</th><th>State</th><th>IP</th><th>Ports</th><th>Usage</th><th></th>
       </tr></thead><tbody id="env-vms-body"></tbody></table>
     </div>
   </div>
@@ -184,7 +211,7 @@ const FRONTEND_HTML: &str = r##"<!DOCTYPE html>
     <label>Name</label>
     <input id="env-name" placeholder="my-test-env">
     <div class="row">
-      <div><label>Owner</label><input id="env-owner" placeholder="web" value="web"></div>
+      <div><label>Owner</label><input id="env-owner" placeholder="you (admins may set another user)"></div>
       <div><label>Image</label><input id="env-image" placeholder="ubuntu-22.04"></div>
     </div>
     <div class="row">
@@ -225,15 +252,29 @@ const FRONTEND_HTML: &str = r##"<!DOCTYPE html>
   </div>
 </div>
 
+<!-- VM Console Modal -->
+<div class="modal-overlay" id="modal-console">
+  <div class="modal console">
+    <h3 id="console-title">Console</h3>
+    <div class="term" id="console-term"></div>
+    <div class="actions">
+      <button class="btn" style="background:var(--border)" onclick="closeConsole()">Close</button>
+    </div>
+  </div>
+</div>
+
 <div class="toast" id="toast"></div>
 
 <script>
 const API = '';
 let refreshTimer = null;
+let pollTimer = null;
+let events = null;
 let currentTab = 'status';
 
 function getApiKey() { return sessionStorage.getItem('tt_api_key'); }
-function setApiKey(k) { sessionStorage.setItem('tt_api_key', k); }
+// A new key may see more of the fleet, so the event stream starts over
+function setApiKey(k) { sessionStorage.setItem('tt_api_key', k); startEvents(); }
 
 // HTML-escape to prevent XSS
 function esc(s) {
@@ -250,7 +291,7 @@ async function api(method, path, body) {
   if (body) opts.body = JSON.stringify(body);
   const res = await fetch(API + path, opts);
   if (res.status === 401) {
-    const k = prompt('API key required:');
+    const k = prompt('API key or token required:');
     if (k) { setApiKey(k); return api(method, path, body); }
     throw new Error('Authentication required');
   }
@@ -259,6 +300,16 @@ async function api(method, path, body) {
   return data.data;
 }
 
+// Poll a background job until it finishes; rejects if it fails.
+async function waitJob(job) {
+  while (job.state === 'pending' || job.state === 'running') {
+    await new Promise(function(r) { setTimeout(r, 1000); });
+    job = await api('GET', '/api/jobs/' + encodeURIComponent(job.id));
+  }
+  if (job.state === 'failed') throw new Error(job.error || 'Job failed');
+  return job;
+}
+
 function switchTab(name) {
   currentTab = name;
   document.querySelectorAll('.tab-content').forEach(el => el.style.display = 'none');
@@ -272,6 +323,36 @@ function badge(state) {
   return '<span class="badge badge-' + esc(state) + '">' + esc(state) + '</span>';
 }
 
+// Usage history charted: the last hour of samples
+var USAGE_WINDOW = 3600;
+
+function sparkline(values, cls, title) {
+  var w = 80, h = 20;
+  if (values.length < 2) return '';
+  var max = Math.max.apply(null, values) || 1;
+  var points = values.map(function(v, i) {
+    return (i * w / (values.length - 1)).toFixed(1) + ',' + (h - 1 - v * (h - 2) / max).toFixed(1);
+  }).join(' ');
+  return '<svg class="spark ' + cls + '" width="' + w + '" height="' + h + '"><title>' + esc(title) + '</title>' +
+    '<polyline points="' + points + '"/></svg>';
+}
+
+async function loadUsage(path, cell) {
+  try {
+    var since = Math.floor(Date.now() / 1000) - USAGE_WINDOW;
+    var samples = await api('GET', path + '/usage?since=' + since);
+    var td = document.getElementById(cell);
+    if (!td) return;
+    var last = samples[samples.length - 1];
+    if (!last) { td.textContent = '-'; return; }
+    var cpu = samples.map(function(s) { return s.cpu || 0; });
+    var mem = samples.map(function(s) { return (s.mem_bytes || 0) / 1048576; });
+    td.innerHTML =
+      sparkline(cpu, 'cpu', 'CPU: ' + (last.cpu || 0).toFixed(2) + ' cores') +
+      sparkline(mem, 'mem', 'Memory: ' + Math.round((last.mem_bytes || 0) / 1048576) + ' MB');
+  } catch (e) { /* usage is best effort */ }
+}
+
 function toast(msg, isError) {
   const el = document.getElementById('toast');
   el.textContent = msg;
@@ -321,7 +402,7 @@ async function loadStatus() {
 async function loadHosts() {
   var hosts = await api('GET', '/api/hosts');
   var tbody = document.getElementById('hosts-body');
-  if (!hosts.length) { tbody.innerHTML = '<tr><td colspan="8" class="empty">No hosts registered</td></tr>'; return; }
+  if (!hosts.length) { tbody.innerHTML = '<tr><td colspan="9" class="empty">No hosts registered</td></tr>'; return; }
   tbody.innerHTML = hosts.map(function(h) {
     return '<tr>' +
       '<td>' + esc(h.id) + '</td>' +
@@ -331,9 +412,11 @@ async function loadHosts() {
       '<td>' + esc(h.resource.cpu_used) + '/' + esc(h.resource.cpu_total) + '</td>' +
       '<td>' + esc(h.resource.mem_used) + '/' + esc(h.resource.mem_total) + ' MB</td>' +
       '<td>' + esc(h.resource.vm_count) + '</td>' +
+      '<td id="usage-host-' + esc(h.id) + '">-</td>' +
       '<td><button class="btn btn-sm btn-danger" onclick="removeHost(\'' + esc(h.id) + '\')">Remove</button></td>' +
       '</tr>';
   }).join('');
+  hosts.forEach(function(h) { loadUsage('/api/hosts/' + encodeURIComponent(h.id), 'usage-host-' + h.id); });
 }
 
 async function loadEnvs() {
@@ -371,8 +454,12 @@ async function showEnv(id) {
         '<td>' + badge(vm.state) + '</td>' +
         '<td>' + esc(vm.ip) + '</td>' +
         '<td>' + esc(ports || '-') + '</td>' +
+        '<td id="usage-vm-' + esc(vm.id) + '">-</td>' +
+        '<td><button class="btn btn-sm" onclick="openConsole(\'' + esc(vm.id) + '\')"' +
+          (vm.state === 'running' ? '' : ' disabled') + '>Console</button></td>' +
         '</tr>';
     }).join('');
+    detail.vms.forEach(function(vm) { loadUsage('/api/vms/' + encodeURIComponent(vm.id), 'usage-vm-' + vm.id); });
   } catch (e) { toast(e.message, true); }
 }
 
@@ -404,7 +491,7 @@ async function removeHost(id) {
 
 async function createEnv() {
   var name = document.getElementById('env-name').value.trim();
-  var owner = document.getElementById('env-owner').value.trim() || 'web';
+  var owner = document.getElementById('env-owner').value.trim();
   var image = document.getElementById('env-image').value.trim();
   var engine = document.getElementById('env-engine').value;
   var cpu = parseInt(document.getElementById('env-cpu').value) || 2;
@@ -430,10 +517,12 @@ async function createEnv() {
 
   setBtn('btn-create-env', true);
   try {
-    var result = await api('POST', '/api/envs', body);
-    hideModals(); toast('Environment created');
-    if (result && result.warnings && result.warnings.length) {
-      toast('Warnings: ' + result.warnings.join('; '), true);
+    var job = await api('POST', '/api/envs', body);
+    hideModals(); toast('Creating environment ' + name + '...'); loadEnvs();
+    job = await waitJob(job);
+    toast('Environment created');
+    if (job.warnings && job.warnings.length) {
+      toast('Warnings: ' + job.warnings.join('; '), true);
     }
     loadEnvs();
   } catch (e) { toast(e.message, true); }
@@ -442,8 +531,12 @@ async function createEnv() {
 
 async function deleteEnv(id) {
   if (!confirm('Delete environment ' + id + '? This will destroy all VMs.')) return;
-  try { await api('DELETE', '/api/envs/' + encodeURIComponent(id)); toast('Environment deleted'); loadEnvs(); }
-  catch (e) { toast(e.message, true); }
+  try {
+    var job = await api('DELETE', '/api/envs/' + encodeURIComponent(id));
+    toast('Deleting environment ' + id + '...');
+    await waitJob(job);
+    toast('Environment deleted'); loadEnvs();
+  } catch (e) { toast(e.message, true); }
 }
 
 async function toggleEnv(id, state) {
@@ -452,16 +545,115 @@ async function toggleEnv(id, state) {
   catch (e) { toast(e.message, true); }
 }
 
-// Auto-refresh every 30 seconds
-function startAutoRefresh() {
-  if (refreshTimer) clearInterval(refreshTimer);
-  refreshTimer = setInterval(function() { refresh(currentTab); }, 30000);
+// VM console: xterm.js attached to /api/vms/:id/console over a WebSocket
+var XTERM = '/web';
+var consoleTerm = null, consoleWs = null;
+
+function loadXterm() {
+  if (window.Terminal) return Promise.resolve();
+  return new Promise(function(resolve, reject) {
+    var css = document.createElement('link');
+    css.rel = 'stylesheet'; css.href = XTERM + '/xterm.css';
+    document.head.appendChild(css);
+    var js = document.createElement('script');
+    js.src = XTERM + '/xterm.js';
+    js.onload = resolve;
+    js.onerror = function() { reject(new Error('xterm.js is not installed in the controller\'s --web-dir')); };
+    document.head.appendChild(js);
+  });
+}
+
+async function openConsole(id) {
+  try { await loadXterm(); } catch (e) { toast(e.message, true); return; }
+  closeConsole();
+  document.getElementById('console-title').textContent = 'Console: ' + id;
+  showModal('console');
+  var el = document.getElementById('console-term');
+  el.innerHTML = '';
+  consoleTerm = new Terminal({ convertEol: false, fontSize: 14, rows: 26 });
+  consoleTerm.open(el);
+  consoleTerm.focus();
+
+  var url = (location.protocol === 'https:' ? 'wss://' : 'ws://') + location.host +
+    '/api/vms/' + encodeURIComponent(id) + '/console';
+  var key = getApiKey();
+  if (key) url += '?access_token=' + encodeURIComponent(key);
+  var ws = new WebSocket(url);
+  ws.binaryType = 'arraybuffer';
+  ws.onmessage = function(e) {
+    consoleTerm.write(typeof e.data === 'string' ? e.data : new Uint8Array(e.data));
+  };
+  ws.onclose = function() {
+    if (consoleWs === ws && consoleTerm) consoleTerm.write('\r\n[connection closed]\r\n');
+  };
+  consoleTerm.onData(function(d) { if (ws.readyState === WebSocket.OPEN) ws.send(d); });
+  consoleWs = ws;
+}
+
+function closeConsole() {
+  var ws = consoleWs;
+  consoleWs = null;
+  if (ws) ws.close();
+  if (consoleTerm) { consoleTerm.dispose(); consoleTerm = null; }
+  document.getElementById('modal-console').classList.remove('show');
+}
+
+// Refresh the open tab shortly after a change; a burst of changes
+// (the steps of a job, say) causes a single refresh.
+function scheduleRefresh() {
+  if (refreshTimer) return;
+  refreshTimer = setTimeout(function() { refreshTimer = null; refresh(currentTab); }, 500);
+}
+
+// Live updates from the controller's event stream. EventSource
+// reconnects by itself and resumes after the last event it saw; if the
+// stream is refused for good, fall back to polling every 30 seconds.
+function startEvents() {
+  if (events) events.close();
+  if (pollTimer) { clearInterval(pollTimer); pollTimer = null; }
+  var url = API + '/api/events';
+  var key = getApiKey();
+  if (key) url += '?access_token=' + encodeURIComponent(key);
+  var es = new EventSource(url);
+  es.onmessage = scheduleRefresh;
+  es.addEventListener('lagged', scheduleRefresh);
+  es.onerror = function() {
+    if (es === events && es.readyState === EventSource.CLOSED && !pollTimer) {
+      pollTimer = setInterval(function() { refresh(currentTab); }, 30000);
+    }
+  };
+  events = es;
 }
 
 // Initial load
 loadStatus();
-startAutoRefresh();
+startEvents();
 </script>
 </body>
 </html>
 "##;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn only_listed_assets_are_served() {
+        let dir = std::env::temp_dir().join(format!("tt-web-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("xterm.js"), "var Terminal;").unwrap();
+        std::fs::write(dir.join("secret.txt"), "no").unwrap();
+        let get = |file: &str| asset(State(Arc::new(dir.clone())), Path(file.to_string()));
+
+        let resp = get("xterm.js").await.into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/javascript");
+        for file in ["xterm.css", "secret.txt", "../xterm.js"] {
+            assert_eq!(
+                get(file).await.into_response().status(),
+                StatusCode::NOT_FOUND
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}